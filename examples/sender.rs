@@ -31,12 +31,19 @@ fn main() {
                     println!("Error connecting to SPSP server {:?}", err);
                 }).and_then(|connection| {
                     println!("Creating new stream and sending money");
-                    let mut stream = connection.create_stream();
-                    stream
-                        .money
-                        .clone()
-                        .send(1000)
-                        .and_then(move |_| {
+                    connection
+                        .create_stream()
+                        .and_then(|stream| {
+                            stream
+                                .money
+                                .clone()
+                                .send(1000)
+                                .map(|_| stream)
+                                .map_err(|err| {
+                                    println!("Error sending money: {}", err);
+                                })
+                        })
+                        .and_then(move |mut stream| {
                             println!("Sent money");
                             let bytes = b"hey there";
                             stream
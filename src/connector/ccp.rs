@@ -0,0 +1,406 @@
+use byteorder::{BigEndian, ReadBytesExt};
+use bytes::{BufMut, Bytes};
+use chrono::Utc;
+use errors::ParseError;
+use ildcp::{PEER_PROTOCOL_CONDITION, PEER_PROTOCOL_EXPIRY_DURATION};
+use ilp::{Address, IlpPrepare};
+use num_bigint::BigUint;
+use num_traits::ToPrimitive;
+use oer::{MutBufOerExt, ReadOerExt};
+use std::io::prelude::*;
+use std::io::Cursor;
+
+pub static CCP_CONTROL_DESTINATION: &'static str = "peer.route.control";
+pub static CCP_UPDATE_DESTINATION: &'static str = "peer.route.update";
+
+const PROP_OPTIONAL: u8 = 0x80;
+const PROP_TRANSITIVE: u8 = 0x40;
+const PROP_PARTIAL: u8 = 0x20;
+const PROP_UTF8: u8 = 0x10;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+#[repr(u8)]
+pub enum Mode {
+    Idle = 0,
+    Sync = 1,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct RouteControlRequest {
+    pub mode: Mode,
+    pub last_known_routing_table_id: [u8; 16],
+    pub last_known_epoch: u32,
+    pub features: Vec<String>,
+}
+
+impl RouteControlRequest {
+    pub fn from_prepare(prepare: &IlpPrepare) -> Result<Self, ParseError> {
+        if prepare.destination != CCP_CONTROL_DESTINATION {
+            return Err(ParseError::InvalidPacket(format!(
+                "Route control request must be addressed to {}, got: {}",
+                CCP_CONTROL_DESTINATION, prepare.destination
+            )));
+        }
+        RouteControlRequest::from_bytes(&prepare.data[..])
+    }
+
+    pub fn to_prepare(&self) -> IlpPrepare {
+        peer_protocol_prepare(CCP_CONTROL_DESTINATION, self.to_bytes())
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Result<Self, ParseError> {
+        let mut reader = Cursor::new(bytes);
+        let mode = match reader.read_u8()? {
+            0 => Mode::Idle,
+            1 => Mode::Sync,
+            mode => {
+                return Err(ParseError::InvalidPacket(format!(
+                    "Unknown route control mode: {}",
+                    mode
+                )))
+            }
+        };
+        let mut last_known_routing_table_id = [0; 16];
+        reader.read_exact(&mut last_known_routing_table_id)?;
+        let last_known_epoch = reader.read_u32::<BigEndian>()?;
+        let num_features = read_length(&mut reader)?;
+        let mut features = Vec::new();
+        for _i in 0..num_features {
+            features.push(String::from_utf8(reader.read_var_octet_string()?)?);
+        }
+
+        Ok(RouteControlRequest {
+            mode,
+            last_known_routing_table_id,
+            last_known_epoch,
+            features,
+        })
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.put_u8(self.mode as u8);
+        buf.put(&self.last_known_routing_table_id[..]);
+        buf.put_u32_be(self.last_known_epoch);
+        buf.put_var_uint(&BigUint::from(self.features.len()));
+        for feature in self.features.iter() {
+            buf.put_var_octet_string(feature.as_bytes());
+        }
+        buf
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct RouteProp {
+    pub is_optional: bool,
+    pub is_transitive: bool,
+    pub is_partial: bool,
+    pub is_utf8: bool,
+    pub id: u16,
+    pub value: Bytes,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Route {
+    pub prefix: String,
+    pub path: Vec<String>,
+    pub auth: [u8; 32],
+    pub props: Vec<RouteProp>,
+}
+
+impl Route {
+    pub fn new(prefix: &str) -> Self {
+        Route {
+            prefix: prefix.to_string(),
+            path: Vec::new(),
+            auth: [0; 32],
+            props: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct RouteUpdateRequest {
+    pub routing_table_id: [u8; 16],
+    pub current_epoch_index: u32,
+    pub from_epoch_index: u32,
+    pub to_epoch_index: u32,
+    pub hold_down_time: u32,
+    pub speaker: String,
+    pub new_routes: Vec<Route>,
+    pub withdrawn_routes: Vec<String>,
+}
+
+impl RouteUpdateRequest {
+    pub fn from_prepare(prepare: &IlpPrepare) -> Result<Self, ParseError> {
+        if prepare.destination != CCP_UPDATE_DESTINATION {
+            return Err(ParseError::InvalidPacket(format!(
+                "Route update request must be addressed to {}, got: {}",
+                CCP_UPDATE_DESTINATION, prepare.destination
+            )));
+        }
+        RouteUpdateRequest::from_bytes(&prepare.data[..])
+    }
+
+    pub fn to_prepare(&self) -> IlpPrepare {
+        peer_protocol_prepare(CCP_UPDATE_DESTINATION, self.to_bytes())
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Result<Self, ParseError> {
+        let mut reader = Cursor::new(bytes);
+        let mut routing_table_id = [0; 16];
+        reader.read_exact(&mut routing_table_id)?;
+        let current_epoch_index = reader.read_u32::<BigEndian>()?;
+        let from_epoch_index = reader.read_u32::<BigEndian>()?;
+        let to_epoch_index = reader.read_u32::<BigEndian>()?;
+        let hold_down_time = reader.read_u32::<BigEndian>()?;
+        let speaker = String::from_utf8(reader.read_var_octet_string()?)?;
+
+        let num_new_routes = read_length(&mut reader)?;
+        let mut new_routes = Vec::new();
+        for _i in 0..num_new_routes {
+            new_routes.push(read_route(&mut reader)?);
+        }
+
+        let num_withdrawn_routes = read_length(&mut reader)?;
+        let mut withdrawn_routes = Vec::new();
+        for _i in 0..num_withdrawn_routes {
+            withdrawn_routes.push(String::from_utf8(reader.read_var_octet_string()?)?);
+        }
+
+        Ok(RouteUpdateRequest {
+            routing_table_id,
+            current_epoch_index,
+            from_epoch_index,
+            to_epoch_index,
+            hold_down_time,
+            speaker,
+            new_routes,
+            withdrawn_routes,
+        })
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.put(&self.routing_table_id[..]);
+        buf.put_u32_be(self.current_epoch_index);
+        buf.put_u32_be(self.from_epoch_index);
+        buf.put_u32_be(self.to_epoch_index);
+        buf.put_u32_be(self.hold_down_time);
+        buf.put_var_octet_string(self.speaker.as_bytes());
+        buf.put_var_uint(&BigUint::from(self.new_routes.len()));
+        for route in self.new_routes.iter() {
+            put_route(&mut buf, route);
+        }
+        buf.put_var_uint(&BigUint::from(self.withdrawn_routes.len()));
+        for prefix in self.withdrawn_routes.iter() {
+            buf.put_var_octet_string(prefix.as_bytes());
+        }
+        buf
+    }
+}
+
+fn peer_protocol_prepare(destination: &str, data: Vec<u8>) -> IlpPrepare {
+    IlpPrepare::new(
+        Address::new_unchecked(destination.to_string()),
+        0,
+        &PEER_PROTOCOL_CONDITION[..],
+        Utc::now() + *PEER_PROTOCOL_EXPIRY_DURATION,
+        data,
+    )
+}
+
+fn read_length<T>(reader: &mut T) -> Result<usize, ParseError>
+where
+    T: ReadOerExt,
+{
+    reader
+        .read_var_uint()?
+        .to_usize()
+        .ok_or_else(|| ParseError::InvalidPacket(String::from("Length is too large")))
+}
+
+fn read_route<T>(reader: &mut T) -> Result<Route, ParseError>
+where
+    T: ReadOerExt,
+{
+    let prefix = String::from_utf8(reader.read_var_octet_string()?)?;
+    let path_length = read_length(reader)?;
+    let mut path = Vec::new();
+    for _i in 0..path_length {
+        path.push(String::from_utf8(reader.read_var_octet_string()?)?);
+    }
+    let mut auth = [0; 32];
+    reader.read_exact(&mut auth)?;
+
+    let num_props = read_length(reader)?;
+    let mut props = Vec::new();
+    for _i in 0..num_props {
+        let meta = reader.read_u8()?;
+        let id = reader.read_u16::<BigEndian>()?;
+        let value = Bytes::from(reader.read_var_octet_string()?);
+        props.push(RouteProp {
+            is_optional: meta & PROP_OPTIONAL != 0,
+            is_transitive: meta & PROP_TRANSITIVE != 0,
+            is_partial: meta & PROP_PARTIAL != 0,
+            is_utf8: meta & PROP_UTF8 != 0,
+            id,
+            value,
+        });
+    }
+
+    Ok(Route {
+        prefix,
+        path,
+        auth,
+        props,
+    })
+}
+
+fn put_route<T>(buf: &mut T, route: &Route)
+where
+    T: BufMut,
+{
+    buf.put_var_octet_string(route.prefix.as_bytes());
+    buf.put_var_uint(&BigUint::from(route.path.len()));
+    for hop in route.path.iter() {
+        buf.put_var_octet_string(hop.as_bytes());
+    }
+    buf.put(&route.auth[..]);
+    buf.put_var_uint(&BigUint::from(route.props.len()));
+    for prop in route.props.iter() {
+        let mut meta = 0;
+        if prop.is_optional {
+            meta |= PROP_OPTIONAL;
+        }
+        if prop.is_transitive {
+            meta |= PROP_TRANSITIVE;
+        }
+        if prop.is_partial {
+            meta |= PROP_PARTIAL;
+        }
+        if prop.is_utf8 {
+            meta |= PROP_UTF8;
+        }
+        buf.put_u8(meta);
+        buf.put_u16_be(prop.id);
+        buf.put_var_octet_string(&prop.value[..]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hex;
+
+    lazy_static! {
+        static ref ROUTING_TABLE_ID: [u8; 16] = {
+            let mut id = [0; 16];
+            id.copy_from_slice(&hex::decode("21e55f8eabcd4e979ab9bf0ff00a224c").unwrap()[..]);
+            id
+        };
+    }
+
+    mod route_control_request {
+        use super::*;
+
+        lazy_static! {
+            static ref CONTROL_REQUEST: RouteControlRequest = RouteControlRequest {
+                mode: Mode::Sync,
+                last_known_routing_table_id: *ROUTING_TABLE_ID,
+                last_known_epoch: 32,
+                features: vec![String::from("foo"), String::from("bar")],
+            };
+            static ref CONTROL_REQUEST_SERIALIZED: Vec<u8> =
+                hex::decode("0121e55f8eabcd4e979ab9bf0ff00a224c00000020010203666f6f03626172")
+                    .unwrap();
+        }
+
+        #[test]
+        fn from_bytes() {
+            assert_eq!(
+                RouteControlRequest::from_bytes(&CONTROL_REQUEST_SERIALIZED).unwrap(),
+                *CONTROL_REQUEST
+            );
+        }
+
+        #[test]
+        fn to_bytes() {
+            assert_eq!(CONTROL_REQUEST.to_bytes(), *CONTROL_REQUEST_SERIALIZED);
+        }
+
+        #[test]
+        fn rejects_huge_feature_count() {
+            let bytes =
+                hex::decode("0121e55f8eabcd4e979ab9bf0ff00a224c0000002008ffffffffffffffff03666f6f")
+                    .unwrap();
+            assert!(RouteControlRequest::from_bytes(&bytes).is_err());
+        }
+
+        #[test]
+        fn to_prepare() {
+            let prepare = CONTROL_REQUEST.to_prepare();
+            assert_eq!(prepare.destination, "peer.route.control");
+            assert_eq!(prepare.amount, 0);
+            assert_eq!(
+                RouteControlRequest::from_prepare(&prepare).unwrap(),
+                *CONTROL_REQUEST
+            );
+        }
+    }
+
+    mod route_update_request {
+        use super::*;
+
+        lazy_static! {
+            static ref UPDATE_REQUEST: RouteUpdateRequest = RouteUpdateRequest {
+                routing_table_id: *ROUTING_TABLE_ID,
+                current_epoch_index: 52,
+                from_epoch_index: 46,
+                to_epoch_index: 50,
+                hold_down_time: 45000,
+                speaker: String::from("example.alice"),
+                new_routes: vec![Route {
+                    prefix: String::from("example.prefix1"),
+                    path: vec![String::from("example.prefix1")],
+                    auth: [7; 32],
+                    props: vec![RouteProp {
+                        is_optional: false,
+                        is_transitive: true,
+                        is_partial: false,
+                        is_utf8: true,
+                        id: 0,
+                        value: Bytes::from("hello world"),
+                    }],
+                }],
+                withdrawn_routes: vec![String::from("example.prefix3")],
+            };
+        }
+
+        #[test]
+        fn serializes_and_deserializes() {
+            let serialized = UPDATE_REQUEST.to_bytes();
+            assert_eq!(
+                RouteUpdateRequest::from_bytes(&serialized[..]).unwrap(),
+                *UPDATE_REQUEST
+            );
+        }
+
+        #[test]
+        fn to_prepare() {
+            let prepare = UPDATE_REQUEST.to_prepare();
+            assert_eq!(prepare.destination, "peer.route.update");
+            assert_eq!(
+                RouteUpdateRequest::from_prepare(&prepare).unwrap(),
+                *UPDATE_REQUEST
+            );
+        }
+
+        #[test]
+        fn rejects_wrong_destination() {
+            let mut prepare = UPDATE_REQUEST.to_prepare();
+            prepare.destination = Address::new("peer.config").unwrap();
+            assert!(RouteUpdateRequest::from_prepare(&prepare).is_err());
+        }
+    }
+}
@@ -0,0 +1,625 @@
+mod ccp;
+mod route_manager;
+mod routing_table;
+
+pub use self::ccp::{
+    Mode, Route, RouteControlRequest, RouteProp, RouteUpdateRequest, CCP_CONTROL_DESTINATION,
+    CCP_UPDATE_DESTINATION,
+};
+pub use self::route_manager::RouteManager;
+pub use self::routing_table::RoutingTable;
+
+use bytes::Bytes;
+use chrono::{DateTime, Duration, Utc};
+use futures::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+use futures::{Async, Future, Poll, Stream};
+use ildcp::PEER_PROTOCOL_FULFILLMENT;
+use ilp::{ErrorCode, IlpFulfill, IlpPacket, IlpPrepare, IlpReject};
+use plugin::{IlpRequest, Plugin};
+use ring::digest::{digest, SHA256};
+use std::collections::HashMap;
+use std::time::{Duration as StdDuration, Instant};
+use stream::plugin_to_channels;
+use tokio::timer::Interval;
+
+lazy_static! {
+    static ref DEFAULT_MIN_MESSAGE_WINDOW: Duration = Duration::seconds(1);
+}
+const EXPIRY_CHECK_INTERVAL_MS: u64 = 100;
+
+struct Account {
+    outgoing: UnboundedSender<IlpRequest>,
+    incoming: UnboundedReceiver<IlpRequest>,
+}
+
+enum RequestOrigin {
+    Forwarded {
+        from_account: String,
+        original_request_id: u32,
+        execution_condition: Bytes,
+    },
+    RouteUpdate {
+        to_epoch: u32,
+    },
+    RouteControl,
+}
+
+struct PendingRequest {
+    to_account: String,
+    origin: RequestOrigin,
+    expires_at: DateTime<Utc>,
+}
+
+/// Forwards ILP packets between multiple plugins
+///
+/// The Connector is a Future that never resolves and must be spawned on a Tokio runtime.
+/// Accounts and routes should be configured before it is spawned.
+pub struct Connector {
+    address: String,
+    accounts: HashMap<String, Account>,
+    route_manager: RouteManager,
+    pending_requests: HashMap<u32, PendingRequest>,
+    next_request_id: u32,
+    min_message_window: Duration,
+    expiry_timer: Interval,
+}
+
+impl Connector {
+    pub fn new(address: &str) -> Self {
+        let expiry_check_interval = StdDuration::from_millis(EXPIRY_CHECK_INTERVAL_MS);
+        Connector {
+            address: address.to_string(),
+            accounts: HashMap::new(),
+            route_manager: RouteManager::new(address),
+            pending_requests: HashMap::new(),
+            next_request_id: 1,
+            min_message_window: *DEFAULT_MIN_MESSAGE_WINDOW,
+            expiry_timer: Interval::new(
+                Instant::now() + expiry_check_interval,
+                expiry_check_interval,
+            ),
+        }
+    }
+
+    pub fn address(&self) -> &str {
+        &self.address
+    }
+
+    /// Set how much the expiry of each forwarded Prepare is reduced by
+    pub fn set_min_message_window(&mut self, min_message_window: Duration) {
+        self.min_message_window = min_message_window;
+    }
+
+    // Note this must be called from within a Tokio runtime
+    pub fn add_account<S>(&mut self, account: &str, plugin: S)
+    where
+        S: Plugin<Item = IlpRequest, Error = (), SinkItem = IlpRequest, SinkError = ()> + 'static,
+    {
+        debug!("Adding account {}", account);
+        let (outgoing, incoming) = plugin_to_channels(plugin);
+        self.accounts
+            .insert(account.to_string(), Account { outgoing, incoming });
+    }
+
+    /// Add an account that exchanges routes with us using the Connector-to-Connector Protocol
+    ///
+    /// Note this must be called from within a Tokio runtime
+    pub fn add_peer<S>(&mut self, account: &str, plugin: S)
+    where
+        S: Plugin<Item = IlpRequest, Error = (), SinkItem = IlpRequest, SinkError = ()> + 'static,
+    {
+        self.add_account(account, plugin);
+        self.route_manager.add_peer(account);
+        if let Some(control) = self.route_manager.control_request(account) {
+            self.send_request(account, control.to_prepare(), RequestOrigin::RouteControl);
+        }
+    }
+
+    pub fn remove_account(&mut self, account: &str) {
+        debug!("Removing account {}", account);
+        self.accounts.remove(account);
+        self.route_manager.remove_account(account);
+    }
+
+    pub fn add_route(&mut self, prefix: &str, account: &str) {
+        self.route_manager.add_local_route(prefix, account);
+    }
+
+    pub fn remove_route(&mut self, prefix: &str) {
+        self.route_manager.remove_local_route(prefix);
+    }
+
+    pub fn routing_table(&self) -> &RoutingTable {
+        self.route_manager.routing_table()
+    }
+
+    fn handle_prepare(&mut self, from_account: &str, request_id: u32, mut prepare: IlpPrepare) {
+        let now = Utc::now();
+        if prepare.expires_at <= now {
+            debug!(
+                "Rejecting request {} from account {} because it already expired at {}",
+                request_id,
+                from_account,
+                prepare.expires_at.to_rfc3339()
+            );
+            self.reject(
+                from_account,
+                request_id,
+                ErrorCode::R00TransferTimedOut,
+                "Packet expired",
+            );
+            return;
+        }
+
+        if prepare.destination == CCP_CONTROL_DESTINATION
+            || prepare.destination == CCP_UPDATE_DESTINATION
+        {
+            self.handle_ccp_request(from_account, request_id, &prepare);
+            return;
+        }
+
+        let next_hop = match self.route_manager.routing_table().resolve(&prepare.destination) {
+            Some(next_hop) if next_hop != from_account => next_hop.to_string(),
+            _ => {
+                debug!(
+                    "No route for request {} from account {} to destination {}",
+                    request_id, from_account, prepare.destination
+                );
+                let message = format!("No route found for address: {}", prepare.destination);
+                self.reject(from_account, request_id, ErrorCode::F02Unreachable, &message);
+                return;
+            }
+        };
+
+        let outgoing_expires_at = prepare.expires_at - self.min_message_window;
+        if outgoing_expires_at <= now {
+            debug!(
+                "Rejecting request {} from account {} because there is not enough time left to forward it",
+                request_id, from_account
+            );
+            self.reject(
+                from_account,
+                request_id,
+                ErrorCode::R02InsufficientTimeout,
+                "Insufficient time left to forward packet",
+            );
+            return;
+        }
+        prepare.expires_at = outgoing_expires_at;
+        let execution_condition = prepare.execution_condition.clone();
+
+        let outgoing_request_id = self.next_request_id;
+        self.next_request_id = self.next_request_id.wrapping_add(1);
+
+        let sent = if let Some(account) = self.accounts.get(&next_hop) {
+            trace!(
+                "Forwarding request {} from account {} to account {} as request {}",
+                request_id,
+                from_account,
+                next_hop,
+                outgoing_request_id
+            );
+            account
+                .outgoing
+                .unbounded_send((outgoing_request_id, IlpPacket::Prepare(prepare)))
+                .is_ok()
+        } else {
+            false
+        };
+
+        if sent {
+            self.pending_requests.insert(
+                outgoing_request_id,
+                PendingRequest {
+                    to_account: next_hop,
+                    origin: RequestOrigin::Forwarded {
+                        from_account: from_account.to_string(),
+                        original_request_id: request_id,
+                        execution_condition,
+                    },
+                    expires_at: outgoing_expires_at,
+                },
+            );
+        } else {
+            warn!("Unable to forward request {} to account {}", request_id, next_hop);
+            self.reject(
+                from_account,
+                request_id,
+                ErrorCode::T01PeerUnreachable,
+                "Next hop is unavailable",
+            );
+        }
+    }
+
+    fn handle_ccp_request(&mut self, from_account: &str, request_id: u32, prepare: &IlpPrepare) {
+        let result = if prepare.destination == CCP_CONTROL_DESTINATION {
+            RouteControlRequest::from_prepare(prepare)
+                .map_err(|err| format!("Invalid route control request: {:?}", err))
+                .and_then(|control| {
+                    self.route_manager
+                        .handle_route_control(from_account, &control)
+                }).map(|_| None)
+        } else {
+            RouteUpdateRequest::from_prepare(prepare)
+                .map_err(|err| format!("Invalid route update request: {:?}", err))
+                .and_then(|update| {
+                    self.route_manager
+                        .handle_route_update(from_account, &update, Instant::now())
+                })
+        };
+
+        match result {
+            Ok(resync) => {
+                let fulfill = IlpFulfill::new(&PEER_PROTOCOL_FULFILLMENT[..], Bytes::new());
+                self.send(from_account, request_id, IlpPacket::Fulfill(fulfill));
+                if let Some(control) = resync {
+                    self.send_request(
+                        from_account,
+                        control.to_prepare(),
+                        RequestOrigin::RouteControl,
+                    );
+                }
+            }
+            Err(message) => {
+                warn!(
+                    "Error handling {} request from account {}: {}",
+                    prepare.destination, from_account, message
+                );
+                self.reject(from_account, request_id, ErrorCode::F00BadRequest, &message);
+            }
+        }
+    }
+
+    fn handle_response(&mut self, from_account: &str, request_id: u32, response: IlpPacket) {
+        let is_pending = match self.pending_requests.get(&request_id) {
+            Some(pending) => pending.to_account == from_account,
+            None => false,
+        };
+        if !is_pending {
+            warn!(
+                "Ignoring response from account {} that did not correspond to a pending request: {} {:?}",
+                from_account, request_id, response
+            );
+            return;
+        }
+        let pending = self.pending_requests.remove(&request_id).unwrap();
+
+        match pending.origin {
+            RequestOrigin::Forwarded {
+                from_account: original_account,
+                original_request_id,
+                execution_condition,
+            } => {
+                if let IlpPacket::Fulfill(ref fulfill) = response {
+                    let fulfillment_hash = digest(&SHA256, &fulfill.fulfillment[..]);
+                    if fulfillment_hash.as_ref() != &execution_condition[..] {
+                        warn!(
+                            "Account {} sent a fulfillment for request {} that does not match the condition",
+                            from_account, request_id
+                        );
+                        self.reject(
+                            &original_account,
+                            original_request_id,
+                            ErrorCode::F05WrongCondition,
+                            "Fulfillment did not match the condition",
+                        );
+                        return;
+                    }
+                }
+                trace!(
+                    "Relaying response to request {} back to account {} as request {}",
+                    request_id,
+                    original_account,
+                    original_request_id
+                );
+                self.send(&original_account, original_request_id, response);
+            }
+            RequestOrigin::RouteUpdate { to_epoch } => {
+                let success = if let IlpPacket::Fulfill(_) = response {
+                    true
+                } else {
+                    debug!(
+                        "Route update to account {} was rejected: {:?}",
+                        pending.to_account, response
+                    );
+                    false
+                };
+                self.route_manager
+                    .update_sent(&pending.to_account, to_epoch, success);
+            }
+            RequestOrigin::RouteControl => {
+                if let IlpPacket::Reject(reject) = response {
+                    warn!(
+                        "Route control request to account {} was rejected: {:?}",
+                        pending.to_account, reject
+                    );
+                }
+            }
+        }
+    }
+
+    fn expire_pending_requests(&mut self) {
+        let now = Utc::now();
+        let expired: Vec<u32> = self
+            .pending_requests
+            .iter()
+            .filter(|(_id, pending)| pending.expires_at <= now)
+            .map(|(id, _pending)| *id)
+            .collect();
+
+        for request_id in expired {
+            if let Some(pending) = self.pending_requests.remove(&request_id) {
+                debug!(
+                    "Request {} to account {} expired without a response",
+                    request_id, pending.to_account
+                );
+                match pending.origin {
+                    RequestOrigin::Forwarded {
+                        from_account,
+                        original_request_id,
+                        ..
+                    } => self.reject(
+                        &from_account,
+                        original_request_id,
+                        ErrorCode::R00TransferTimedOut,
+                        "Timed out waiting for response from next hop",
+                    ),
+                    RequestOrigin::RouteUpdate { to_epoch } => {
+                        self.route_manager
+                            .update_sent(&pending.to_account, to_epoch, false)
+                    }
+                    RequestOrigin::RouteControl => {}
+                }
+            }
+        }
+    }
+
+    fn broadcast_routes(&mut self) {
+        let now = Instant::now();
+        self.route_manager.expire_routes(now);
+        for (account, update) in self.route_manager.updates_to_send(now) {
+            let to_epoch = update.to_epoch_index;
+            self.send_request(
+                &account,
+                update.to_prepare(),
+                RequestOrigin::RouteUpdate { to_epoch },
+            );
+        }
+    }
+
+    fn send_request(&mut self, account: &str, prepare: IlpPrepare, origin: RequestOrigin) {
+        let request_id = self.next_request_id;
+        self.next_request_id = self.next_request_id.wrapping_add(1);
+        self.pending_requests.insert(
+            request_id,
+            PendingRequest {
+                to_account: account.to_string(),
+                origin,
+                expires_at: prepare.expires_at,
+            },
+        );
+        self.send(account, request_id, IlpPacket::Prepare(prepare));
+    }
+
+    fn reject(&self, account: &str, request_id: u32, code: ErrorCode, message: &str) {
+        let reject = IlpReject::new(code, message, self.address.as_str(), Bytes::new());
+        self.send(account, request_id, IlpPacket::Reject(reject));
+    }
+
+    fn send(&self, account: &str, request_id: u32, packet: IlpPacket) {
+        if let Some(account) = self.accounts.get(account) {
+            account
+                .outgoing
+                .unbounded_send((request_id, packet))
+                .unwrap_or_else(|err| {
+                    error!("Error sending packet {}: {:?}", request_id, err);
+                });
+        } else {
+            warn!(
+                "Unable to send packet {} because account {} no longer exists",
+                request_id, account
+            );
+        }
+    }
+}
+
+impl Future for Connector {
+    type Item = ();
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        // Reject packets whose next hop took too long to respond and send out route updates
+        loop {
+            match self.expiry_timer.poll() {
+                Ok(Async::Ready(Some(_))) => {
+                    self.expire_pending_requests();
+                    self.broadcast_routes();
+                }
+                Ok(Async::Ready(None)) | Ok(Async::NotReady) => break,
+                Err(err) => {
+                    warn!("Error polling expiry timer: {:?}", err);
+                    break;
+                }
+            }
+        }
+
+        // Note: polling each account until it returns NotReady ensures we are woken up for more packets
+        let mut requests: Vec<(String, IlpRequest)> = Vec::new();
+        let mut closed_accounts: Vec<String> = Vec::new();
+        for (account_id, account) in self.accounts.iter_mut() {
+            loop {
+                match account.incoming.poll() {
+                    Ok(Async::Ready(Some(request))) => {
+                        requests.push((account_id.to_string(), request))
+                    }
+                    Ok(Async::Ready(None)) | Err(_) => {
+                        closed_accounts.push(account_id.to_string());
+                        break;
+                    }
+                    Ok(Async::NotReady) => break,
+                }
+            }
+        }
+
+        for (account_id, (request_id, packet)) in requests {
+            match packet {
+                IlpPacket::Prepare(prepare) => self.handle_prepare(&account_id, request_id, prepare),
+                response => self.handle_response(&account_id, request_id, response),
+            }
+        }
+
+        for account_id in closed_accounts {
+            debug!("Account {} disconnected", account_id);
+            self.remove_account(&account_id);
+        }
+
+        Ok(Async::NotReady)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::sync::mpsc::unbounded;
+    use ilp::Address;
+
+    const FULFILLMENT: [u8; 32] = [1; 32];
+
+    lazy_static! {
+        static ref CONDITION: Vec<u8> = digest(&SHA256, &FULFILLMENT[..]).as_ref().to_vec();
+    }
+
+    // The packets the connector sends to one account
+    struct TestAccount {
+        outgoing: UnboundedReceiver<IlpRequest>,
+    }
+
+    impl TestAccount {
+        // Only call this when the connector has sent something, otherwise it blocks
+        fn next_packet(&mut self) -> IlpRequest {
+            self.outgoing.by_ref().wait().next().unwrap().unwrap()
+        }
+    }
+
+    fn add_test_account(connector: &mut Connector, account: &str) -> TestAccount {
+        let (_incoming_tx, incoming_rx) = unbounded();
+        let (outgoing_tx, outgoing_rx) = unbounded();
+        connector.accounts.insert(
+            account.to_string(),
+            Account {
+                outgoing: outgoing_tx,
+                incoming: incoming_rx,
+            },
+        );
+        TestAccount {
+            outgoing: outgoing_rx,
+        }
+    }
+
+    fn prepare(destination: &str, expires_at: DateTime<Utc>) -> IlpPrepare {
+        IlpPrepare::new(
+            Address::new(destination).unwrap(),
+            100,
+            &CONDITION[..],
+            expires_at,
+            Bytes::new(),
+        )
+    }
+
+    fn test_connector() -> (Connector, TestAccount, TestAccount) {
+        let mut connector = Connector::new("example.connector");
+        let alice = add_test_account(&mut connector, "alice");
+        let bob = add_test_account(&mut connector, "bob");
+        connector.add_route("example.bob", "bob");
+        (connector, alice, bob)
+    }
+
+    fn expect_reject(account: &mut TestAccount, request_id: u32, code: ErrorCode) {
+        match account.next_packet() {
+            (id, IlpPacket::Reject(reject)) => {
+                assert_eq!(id, request_id);
+                assert_eq!(reject.code, code);
+                assert_eq!(reject.triggered_by, "example.connector");
+            }
+            other => panic!("Expected Reject, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn forwards_prepares_with_reduced_expiry() {
+        let (mut connector, _alice, mut bob) = test_connector();
+        let expires_at = Utc::now() + Duration::seconds(30);
+        connector.handle_prepare("alice", 1, prepare("example.bob.x", expires_at));
+
+        match bob.next_packet() {
+            (_, IlpPacket::Prepare(prepare)) => {
+                assert_eq!(prepare.expires_at, expires_at - Duration::seconds(1));
+                assert_eq!(prepare.amount, 100);
+            }
+            other => panic!("Expected Prepare, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn rejects_prepares_without_route() {
+        let (mut connector, mut alice, _bob) = test_connector();
+        let expires_at = Utc::now() + Duration::seconds(30);
+        connector.handle_prepare("alice", 1, prepare("example.bobby", expires_at));
+        expect_reject(&mut alice, 1, ErrorCode::F02Unreachable);
+    }
+
+    #[test]
+    fn rejects_expired_prepares() {
+        let (mut connector, mut alice, _bob) = test_connector();
+        let expires_at = Utc::now() - Duration::seconds(1);
+        connector.handle_prepare("alice", 1, prepare("example.bob", expires_at));
+        expect_reject(&mut alice, 1, ErrorCode::R00TransferTimedOut);
+    }
+
+    #[test]
+    fn rejects_prepares_without_enough_time_to_forward() {
+        let (mut connector, mut alice, _bob) = test_connector();
+        let expires_at = Utc::now() + Duration::milliseconds(500);
+        connector.handle_prepare("alice", 1, prepare("example.bob", expires_at));
+        expect_reject(&mut alice, 1, ErrorCode::R02InsufficientTimeout);
+    }
+
+    #[test]
+    fn rejects_prepares_when_next_hop_is_gone() {
+        let (mut connector, mut alice, bob) = test_connector();
+        drop(bob);
+        let expires_at = Utc::now() + Duration::seconds(30);
+        connector.handle_prepare("alice", 1, prepare("example.bob", expires_at));
+        expect_reject(&mut alice, 1, ErrorCode::T01PeerUnreachable);
+    }
+
+    #[test]
+    fn relays_responses_to_original_request() {
+        let (mut connector, mut alice, mut bob) = test_connector();
+        let expires_at = Utc::now() + Duration::seconds(30);
+        connector.handle_prepare("alice", 1, prepare("example.bob", expires_at));
+        connector.handle_prepare("alice", 2, prepare("example.bob", expires_at));
+        let (first_id, _) = bob.next_packet();
+        let (second_id, _) = bob.next_packet();
+
+        let fulfill = IlpFulfill::new(&FULFILLMENT[..], Bytes::new());
+        connector.handle_response("bob", first_id, IlpPacket::Fulfill(fulfill.clone()));
+        assert_eq!(alice.next_packet(), (1, IlpPacket::Fulfill(fulfill)));
+
+        let reject = IlpReject::new(ErrorCode::F99ApplicationError, "", "", Bytes::new());
+        connector.handle_response("bob", second_id, IlpPacket::Reject(reject.clone()));
+        assert_eq!(alice.next_packet(), (2, IlpPacket::Reject(reject)));
+    }
+
+    #[test]
+    fn rejects_fulfillments_that_do_not_match_condition() {
+        let (mut connector, mut alice, mut bob) = test_connector();
+        let expires_at = Utc::now() + Duration::seconds(30);
+        connector.handle_prepare("alice", 1, prepare("example.bob", expires_at));
+        let (request_id, _) = bob.next_packet();
+
+        let fulfill = IlpFulfill::new(&[2; 32][..], Bytes::new());
+        connector.handle_response("bob", request_id, IlpPacket::Fulfill(fulfill));
+        expect_reject(&mut alice, 1, ErrorCode::F05WrongCondition);
+    }
+}
@@ -0,0 +1,559 @@
+use super::ccp::{Mode, Route, RouteControlRequest, RouteUpdateRequest};
+use super::RoutingTable;
+use ring::rand::{SecureRandom, SystemRandom};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+const HOLD_DOWN_TIME_MS: u32 = 45_000;
+const ROUTE_BROADCAST_INTERVAL_MS: u64 = 30_000;
+
+struct RouteLogEntry {
+    // The epoch this change moved the routing table on from
+    epoch: u32,
+    prefix: String,
+    // (next hop, route) or None if the route was withdrawn
+    route: Option<(String, Route)>,
+}
+
+struct Peer {
+    // State of the routes we are receiving from this peer
+    routing_table_id: [u8; 16],
+    epoch: u32,
+    routes: HashMap<String, Route>,
+    routes_expire_at: Option<Instant>,
+    // State of the routes we are sending to this peer
+    send_mode: Mode,
+    last_sent_epoch: u32,
+    last_sent_at: Option<Instant>,
+    update_in_flight: bool,
+}
+
+impl Peer {
+    fn new() -> Self {
+        Peer {
+            routing_table_id: [0; 16],
+            epoch: 0,
+            routes: HashMap::new(),
+            routes_expire_at: None,
+            send_mode: Mode::Idle,
+            last_sent_epoch: 0,
+            last_sent_at: None,
+            update_in_flight: false,
+        }
+    }
+}
+
+/// Keeps track of static routes and routes learned from peers via the
+/// Connector-to-Connector Protocol (CCP) and picks the best one for each prefix.
+///
+/// Every change to the best routes is recorded as a new epoch so that peers
+/// can be sent only the changes they have not seen yet. The log only keeps the
+/// latest change for each prefix, because that is all an update includes.
+pub struct RouteManager {
+    address: String,
+    routing_table_id: [u8; 16],
+    routing_table: RoutingTable,
+    local_routes: HashMap<String, String>,
+    peers: HashMap<String, Peer>,
+    best_routes: HashMap<String, (String, Route)>,
+    epoch: u32,
+    log: Vec<RouteLogEntry>,
+}
+
+impl RouteManager {
+    pub fn new(address: &str) -> Self {
+        let mut routing_table_id = [0; 16];
+        SystemRandom::new().fill(&mut routing_table_id).unwrap();
+
+        RouteManager {
+            address: address.to_string(),
+            routing_table_id,
+            routing_table: RoutingTable::new(),
+            local_routes: HashMap::new(),
+            peers: HashMap::new(),
+            best_routes: HashMap::new(),
+            epoch: 0,
+            log: Vec::new(),
+        }
+    }
+
+    pub fn routing_table(&self) -> &RoutingTable {
+        &self.routing_table
+    }
+
+    pub fn current_epoch(&self) -> u32 {
+        self.epoch
+    }
+
+    pub fn is_peer(&self, account: &str) -> bool {
+        self.peers.contains_key(account)
+    }
+
+    pub fn add_local_route(&mut self, prefix: &str, account: &str) {
+        self.local_routes
+            .insert(prefix.to_string(), account.to_string());
+        self.update_best_route(prefix);
+    }
+
+    pub fn remove_local_route(&mut self, prefix: &str) {
+        self.local_routes.remove(prefix);
+        self.update_best_route(prefix);
+    }
+
+    pub fn add_peer(&mut self, account: &str) {
+        self.peers.insert(account.to_string(), Peer::new());
+    }
+
+    /// Remove all routes that go through the given account
+    pub fn remove_account(&mut self, account: &str) {
+        let mut prefixes: Vec<String> = self
+            .local_routes
+            .iter()
+            .filter(|(_prefix, next_hop)| next_hop.as_str() == account)
+            .map(|(prefix, _next_hop)| prefix.to_string())
+            .collect();
+        for prefix in prefixes.iter() {
+            self.local_routes.remove(prefix);
+        }
+        if let Some(peer) = self.peers.remove(account) {
+            prefixes.extend(peer.routes.into_iter().map(|(prefix, _route)| prefix));
+        }
+
+        for prefix in prefixes.iter() {
+            self.update_best_route(prefix);
+        }
+    }
+
+    /// The request that should be sent to a peer to get the routes we're missing
+    pub fn control_request(&self, account: &str) -> Option<RouteControlRequest> {
+        self.peers.get(account).map(|peer| RouteControlRequest {
+            mode: Mode::Sync,
+            last_known_routing_table_id: peer.routing_table_id,
+            last_known_epoch: peer.epoch,
+            features: Vec::new(),
+        })
+    }
+
+    pub fn handle_route_control(
+        &mut self,
+        account: &str,
+        request: &RouteControlRequest,
+    ) -> Result<(), String> {
+        let current_epoch = self.current_epoch();
+        let routing_table_id = self.routing_table_id;
+        let peer = self
+            .peers
+            .get_mut(account)
+            .ok_or_else(|| format!("Account {} is not a CCP peer", account))?;
+
+        debug!(
+            "Peer {} requested route mode {:?} from epoch {}",
+            account, request.mode, request.last_known_epoch
+        );
+        peer.send_mode = request.mode;
+        peer.last_sent_epoch = if request.last_known_routing_table_id == routing_table_id {
+            request.last_known_epoch.min(current_epoch)
+        } else {
+            0
+        };
+        peer.last_sent_at = None;
+        Ok(())
+    }
+
+    /// Apply a route update from a peer.
+    /// Returns a route control request to send back if we missed some of their updates.
+    pub fn handle_route_update(
+        &mut self,
+        account: &str,
+        update: &RouteUpdateRequest,
+        now: Instant,
+    ) -> Result<Option<RouteControlRequest>, String> {
+        let mut changed_prefixes: Vec<String> = Vec::new();
+        let needs_resync = {
+            let peer = self
+                .peers
+                .get_mut(account)
+                .ok_or_else(|| format!("Account {} is not a CCP peer", account))?;
+
+            if peer.routing_table_id != update.routing_table_id {
+                debug!(
+                    "Peer {} has a new routing table, discarding the routes we had from it",
+                    account
+                );
+                peer.routing_table_id = update.routing_table_id;
+                peer.epoch = 0;
+                changed_prefixes.extend(peer.routes.drain().map(|(prefix, _route)| prefix));
+            }
+
+            if update.from_epoch_index > peer.epoch {
+                debug!(
+                    "Missed route updates from peer {} (our epoch: {}, update starts from: {})",
+                    account, peer.epoch, update.from_epoch_index
+                );
+                true
+            } else {
+                peer.routes_expire_at =
+                    Some(now + Duration::from_millis(u64::from(update.hold_down_time)));
+
+                if update.to_epoch_index > peer.epoch {
+                    for prefix in update.withdrawn_routes.iter() {
+                        if peer.routes.remove(prefix).is_some() {
+                            changed_prefixes.push(prefix.to_string());
+                        }
+                    }
+                    for route in update.new_routes.iter() {
+                        if route.path.contains(&self.address) {
+                            trace!(
+                                "Ignoring route for {} from peer {} because it would create a loop",
+                                route.prefix,
+                                account
+                            );
+                            continue;
+                        }
+                        peer.routes.insert(route.prefix.to_string(), route.clone());
+                        changed_prefixes.push(route.prefix.to_string());
+                    }
+                    peer.epoch = update.to_epoch_index;
+                }
+                false
+            }
+        };
+
+        for prefix in changed_prefixes.iter() {
+            self.update_best_route(prefix);
+        }
+
+        if needs_resync {
+            Ok(self.control_request(account))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Withdraw the routes of peers that stopped sending us updates
+    pub fn expire_routes(&mut self, now: Instant) {
+        let mut changed_prefixes: Vec<String> = Vec::new();
+        for (account, peer) in self.peers.iter_mut() {
+            let expired = match peer.routes_expire_at {
+                Some(expires_at) => expires_at <= now,
+                None => false,
+            };
+            if expired {
+                debug!("Routes from peer {} expired", account);
+                peer.routes_expire_at = None;
+                changed_prefixes.extend(peer.routes.drain().map(|(prefix, _route)| prefix));
+            }
+        }
+
+        for prefix in changed_prefixes.iter() {
+            self.update_best_route(prefix);
+        }
+    }
+
+    /// Create route updates for the peers that have new routes or have not heard from us in a while
+    pub fn updates_to_send(&mut self, now: Instant) -> Vec<(String, RouteUpdateRequest)> {
+        let current_epoch = self.current_epoch();
+        let broadcast_interval = Duration::from_millis(ROUTE_BROADCAST_INTERVAL_MS);
+        let accounts: Vec<String> = self
+            .peers
+            .iter()
+            .filter(|(_account, peer)| {
+                let broadcast_due = match peer.last_sent_at {
+                    Some(last_sent_at) => now.duration_since(last_sent_at) >= broadcast_interval,
+                    None => true,
+                };
+                peer.send_mode == Mode::Sync
+                    && !peer.update_in_flight
+                    && (peer.last_sent_epoch < current_epoch || broadcast_due)
+            }).map(|(account, _peer)| account.to_string())
+            .collect();
+
+        let mut updates = Vec::with_capacity(accounts.len());
+        for account in accounts {
+            let update = {
+                let peer = &self.peers[&account];
+                self.create_update(&account, peer.last_sent_epoch)
+            };
+            if let Some(peer) = self.peers.get_mut(&account) {
+                peer.update_in_flight = true;
+                peer.last_sent_at = Some(now);
+            }
+            updates.push((account, update));
+        }
+        updates
+    }
+
+    pub fn update_sent(&mut self, account: &str, to_epoch: u32, success: bool) {
+        if let Some(peer) = self.peers.get_mut(account) {
+            peer.update_in_flight = false;
+            if success {
+                peer.last_sent_epoch = to_epoch;
+            }
+        }
+    }
+
+    fn create_update(&self, account: &str, from_epoch: u32) -> RouteUpdateRequest {
+        // Only include the latest change for each prefix
+        let mut latest: HashMap<&str, Option<&(String, Route)>> = HashMap::new();
+        for entry in self.log.iter().filter(|entry| entry.epoch >= from_epoch) {
+            latest.insert(entry.prefix.as_str(), entry.route.as_ref());
+        }
+
+        let mut new_routes: Vec<Route> = Vec::new();
+        let mut withdrawn_routes: Vec<String> = Vec::new();
+        for (prefix, entry) in latest {
+            match entry {
+                // Don't send peers back their own routes
+                Some((next_hop, route)) if next_hop != account => {
+                    let mut path = vec![self.address.to_string()];
+                    path.extend(route.path.iter().cloned());
+                    new_routes.push(Route {
+                        prefix: prefix.to_string(),
+                        path,
+                        auth: route.auth,
+                        props: route
+                            .props
+                            .iter()
+                            .filter(|prop| prop.is_transitive)
+                            .cloned()
+                            .collect(),
+                    });
+                }
+                _ => withdrawn_routes.push(prefix.to_string()),
+            }
+        }
+
+        RouteUpdateRequest {
+            routing_table_id: self.routing_table_id,
+            current_epoch_index: self.current_epoch(),
+            from_epoch_index: from_epoch,
+            to_epoch_index: self.current_epoch(),
+            hold_down_time: HOLD_DOWN_TIME_MS,
+            speaker: self.address.to_string(),
+            new_routes,
+            withdrawn_routes,
+        }
+    }
+
+    // Local routes are always preferred, followed by the route with the shortest path
+    fn update_best_route(&mut self, prefix: &str) {
+        let best = if let Some(next_hop) = self.local_routes.get(prefix) {
+            Some((next_hop.to_string(), Route::new(prefix)))
+        } else {
+            self.peers
+                .iter()
+                .filter_map(|(account, peer)| peer.routes.get(prefix).map(|route| (account, route)))
+                .min_by(|(account_a, route_a), (account_b, route_b)| {
+                    route_a
+                        .path
+                        .len()
+                        .cmp(&route_b.path.len())
+                        .then_with(|| account_a.cmp(account_b))
+                }).map(|(account, route)| (account.to_string(), route.clone()))
+        };
+
+        if self.best_routes.get(prefix) == best.as_ref() {
+            return;
+        }
+
+        match best {
+            Some((next_hop, route)) => {
+                self.routing_table.add_route(prefix, &next_hop);
+                self.best_routes
+                    .insert(prefix.to_string(), (next_hop.to_string(), route.clone()));
+                self.log_change(prefix, Some((next_hop, route)));
+            }
+            None => {
+                self.routing_table.remove_route(prefix);
+                self.best_routes.remove(prefix);
+                self.log_change(prefix, None);
+            }
+        }
+    }
+
+    fn log_change(&mut self, prefix: &str, route: Option<(String, Route)>) {
+        self.log.retain(|entry| entry.prefix != prefix);
+        self.log.push(RouteLogEntry {
+            epoch: self.epoch,
+            prefix: prefix.to_string(),
+            route,
+        });
+        self.epoch += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ilp::Address;
+
+    fn update_from(routing_table_id: [u8; 16], from: u32, to: u32) -> RouteUpdateRequest {
+        RouteUpdateRequest {
+            routing_table_id,
+            current_epoch_index: to,
+            from_epoch_index: from,
+            to_epoch_index: to,
+            hold_down_time: 45000,
+            speaker: String::from("example.peer"),
+            new_routes: Vec::new(),
+            withdrawn_routes: Vec::new(),
+        }
+    }
+
+    fn route(prefix: &str, path: &[&str]) -> Route {
+        let mut route = Route::new(prefix);
+        route.path = path.iter().map(|hop| hop.to_string()).collect();
+        route
+    }
+
+    fn resolve<'a>(manager: &'a RouteManager, destination: &str) -> Option<&'a str> {
+        manager
+            .routing_table()
+            .resolve(&Address::new(destination).unwrap())
+    }
+
+    fn manager_with_peers() -> RouteManager {
+        let mut manager = RouteManager::new("example.connector");
+        manager.add_peer("a");
+        manager.add_peer("b");
+        manager
+    }
+
+    #[test]
+    fn applies_new_and_withdrawn_routes() {
+        let mut manager = manager_with_peers();
+        let mut update = update_from([1; 16], 0, 2);
+        update.new_routes = vec![
+            route("example.x", &["example.peer"]),
+            route("example.y", &["example.peer"]),
+        ];
+        let resync = manager
+            .handle_route_update("a", &update, Instant::now())
+            .unwrap();
+        assert!(resync.is_none());
+        assert_eq!(resolve(&manager, "example.x.1"), Some("a"));
+        assert_eq!(resolve(&manager, "example.y.1"), Some("a"));
+
+        let mut update = update_from([1; 16], 2, 3);
+        update.withdrawn_routes = vec![String::from("example.x")];
+        manager
+            .handle_route_update("a", &update, Instant::now())
+            .unwrap();
+        assert_eq!(resolve(&manager, "example.x.1"), None);
+        assert_eq!(resolve(&manager, "example.y.1"), Some("a"));
+    }
+
+    #[test]
+    fn prefers_local_then_shortest_routes() {
+        let mut manager = manager_with_peers();
+        let mut update = update_from([1; 16], 0, 1);
+        update.new_routes = vec![route("example.x", &["example.a1", "example.a2"])];
+        manager
+            .handle_route_update("a", &update, Instant::now())
+            .unwrap();
+        let mut update = update_from([2; 16], 0, 1);
+        update.new_routes = vec![route("example.x", &["example.b1"])];
+        manager
+            .handle_route_update("b", &update, Instant::now())
+            .unwrap();
+        assert_eq!(resolve(&manager, "example.x"), Some("b"));
+
+        manager.add_local_route("example.x", "a");
+        assert_eq!(resolve(&manager, "example.x"), Some("a"));
+    }
+
+    #[test]
+    fn requests_resync_after_missing_epochs() {
+        let mut manager = manager_with_peers();
+        let resync = manager
+            .handle_route_update("a", &update_from([1; 16], 5, 6), Instant::now())
+            .unwrap()
+            .unwrap();
+        assert_eq!(resync.mode, Mode::Sync);
+        assert_eq!(resync.last_known_routing_table_id, [1; 16]);
+        assert_eq!(resync.last_known_epoch, 0);
+    }
+
+    #[test]
+    fn ignores_routes_that_would_loop() {
+        let mut manager = manager_with_peers();
+        let mut update = update_from([1; 16], 0, 1);
+        update.new_routes = vec![route("example.x", &["example.peer", "example.connector"])];
+        manager
+            .handle_route_update("a", &update, Instant::now())
+            .unwrap();
+        assert_eq!(resolve(&manager, "example.x"), None);
+    }
+
+    #[test]
+    fn sends_updates_to_syncing_peers() {
+        let mut manager = manager_with_peers();
+        manager.add_local_route("example.connector.child", "child");
+        let mut update = update_from([1; 16], 0, 1);
+        update.new_routes = vec![route("example.x", &["example.peer"])];
+        manager
+            .handle_route_update("a", &update, Instant::now())
+            .unwrap();
+
+        let control = RouteControlRequest {
+            mode: Mode::Sync,
+            last_known_routing_table_id: [0; 16],
+            last_known_epoch: 0,
+            features: Vec::new(),
+        };
+        manager.handle_route_control("a", &control).unwrap();
+
+        let updates = manager.updates_to_send(Instant::now());
+        assert_eq!(updates.len(), 1);
+        let (account, update) = &updates[0];
+        assert_eq!(account, "a");
+        assert_eq!(update.from_epoch_index, 0);
+        assert_eq!(update.to_epoch_index, 2);
+        assert_eq!(
+            update.new_routes,
+            vec![route("example.connector.child", &["example.connector"])]
+        );
+        assert_eq!(update.withdrawn_routes, vec![String::from("example.x")]);
+
+        // Nothing else is sent until the peer acknowledges the update
+        assert!(manager.updates_to_send(Instant::now()).is_empty());
+        manager.update_sent("a", 2, true);
+        assert!(manager.updates_to_send(Instant::now()).is_empty());
+    }
+
+    #[test]
+    fn keeps_only_latest_change_for_each_prefix() {
+        let mut manager = manager_with_peers();
+        manager.add_local_route("example.connector.x", "child1");
+        manager.add_local_route("example.connector.y", "child1");
+        manager.remove_local_route("example.connector.x");
+        manager.add_local_route("example.connector.x", "child2");
+        assert_eq!(manager.current_epoch(), 4);
+        assert_eq!(manager.log.len(), 2);
+
+        let update = manager.create_update("a", 2);
+        assert_eq!(update.from_epoch_index, 2);
+        assert_eq!(update.to_epoch_index, 4);
+        assert_eq!(
+            update.new_routes,
+            vec![route("example.connector.x", &["example.connector"])]
+        );
+        assert!(update.withdrawn_routes.is_empty());
+
+        let update = manager.create_update("a", 4);
+        assert!(update.new_routes.is_empty());
+        assert!(update.withdrawn_routes.is_empty());
+    }
+
+    #[test]
+    fn removes_routes_when_peer_is_removed() {
+        let mut manager = manager_with_peers();
+        let mut update = update_from([1; 16], 0, 1);
+        update.new_routes = vec![route("example.x", &["example.peer"])];
+        manager
+            .handle_route_update("a", &update, Instant::now())
+            .unwrap();
+        manager.remove_account("a");
+        assert_eq!(resolve(&manager, "example.x"), None);
+    }
+}
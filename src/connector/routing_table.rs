@@ -0,0 +1,112 @@
+use ilp::Address;
+use std::collections::HashMap;
+
+#[derive(Debug, Default, Clone)]
+pub struct RoutingTable {
+    // prefix: account
+    routes: HashMap<String, String>,
+}
+
+impl RoutingTable {
+    pub fn new() -> Self {
+        RoutingTable {
+            routes: HashMap::new(),
+        }
+    }
+
+    pub fn add_route(&mut self, prefix: &str, account: &str) -> Option<String> {
+        debug!("Adding route for prefix {} via account {}", prefix, account);
+        self.routes.insert(prefix.to_string(), account.to_string())
+    }
+
+    pub fn remove_route(&mut self, prefix: &str) -> Option<String> {
+        debug!("Removing route for prefix {}", prefix);
+        self.routes.remove(prefix)
+    }
+
+    pub fn remove_routes_via(&mut self, account: &str) {
+        self.routes.retain(|_prefix, next_hop| next_hop != account);
+    }
+
+    pub fn get_route(&self, prefix: &str) -> Option<&str> {
+        self.routes.get(prefix).map(|account| account.as_str())
+    }
+
+    pub fn routes(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.routes
+            .iter()
+            .map(|(prefix, account)| (prefix.as_str(), account.as_str()))
+    }
+
+    /// Find the account to forward a packet to, using the longest prefix that matches whole segments
+    pub fn resolve(&self, destination: &Address) -> Option<&str> {
+        self.routes
+            .iter()
+            .filter(|(prefix, _account)| destination.starts_with(prefix.as_str()))
+            .max_by_key(|(prefix, _account)| prefix.len())
+            .map(|(_prefix, account)| account.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn address(address: &str) -> Address {
+        Address::new(address).unwrap()
+    }
+
+    #[test]
+    fn resolves_longest_prefix() {
+        let mut table = RoutingTable::new();
+        table.add_route("example.", "a");
+        table.add_route("example.bob.", "b");
+        table.add_route("example.bob.charlie", "c");
+
+        assert_eq!(table.resolve(&address("example.alice")), Some("a"));
+        assert_eq!(table.resolve(&address("example.bob.dave")), Some("b"));
+        assert_eq!(
+            table.resolve(&address("example.bob.charlie.123")),
+            Some("c")
+        );
+    }
+
+    #[test]
+    fn only_matches_whole_segments() {
+        let mut table = RoutingTable::new();
+        table.add_route("example.bob", "b");
+
+        assert_eq!(table.resolve(&address("example.bob")), Some("b"));
+        assert_eq!(table.resolve(&address("example.bob.1")), Some("b"));
+        assert_eq!(table.resolve(&address("example.bobby")), None);
+    }
+
+    #[test]
+    fn returns_none_without_matching_route() {
+        let mut table = RoutingTable::new();
+        table.add_route("example.", "a");
+
+        assert_eq!(table.resolve(&address("test.alice")), None);
+    }
+
+    #[test]
+    fn empty_prefix_is_default_route() {
+        let mut table = RoutingTable::new();
+        table.add_route("", "parent");
+        table.add_route("example.", "a");
+
+        assert_eq!(table.resolve(&address("g.bob")), Some("parent"));
+        assert_eq!(table.resolve(&address("example.bob")), Some("a"));
+    }
+
+    #[test]
+    fn removes_routes_via_account() {
+        let mut table = RoutingTable::new();
+        table.add_route("example.a", "a");
+        table.add_route("example.b", "b");
+        table.remove_routes_via("a");
+
+        assert_eq!(table.resolve(&address("example.a")), None);
+        assert_eq!(table.resolve(&address("example.b")), Some("b"));
+    }
+}
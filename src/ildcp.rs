@@ -1,18 +1,22 @@
 use byteorder::ReadBytesExt;
-use bytes::Bytes;
+use bytes::{BufMut, Bytes};
 use chrono::{Duration, Utc};
 use errors::ParseError;
-use futures::Future;
-use ilp::{IlpFulfill, IlpPacket, IlpPrepare};
-use oer::ReadOerExt;
-use plugin::Plugin;
+use futures::future::Either;
+use futures::{Async, AsyncSink, Future, Poll, Sink, StartSend, Stream};
+use ilp::{Address, ErrorCode, IlpFulfill, IlpPacket, IlpPrepare, IlpReject};
+use oer::{MutBufOerExt, ReadOerExt};
+use plugin::{IlpRequest, Plugin, PluginService};
+use std::collections::VecDeque;
 use std::io::Cursor;
 
 static ILDCP_DESTINATION: &'static str = "peer.config";
+// ILDCP requests are rejected if this many responses are already waiting to be sent
+const MAX_PENDING_RESPONSES: usize = 64;
 lazy_static! {
-    static ref PEER_PROTOCOL_EXPIRY_DURATION: Duration = Duration::minutes(1);
-    static ref PEER_PROTOCOL_FULFILLMENT: Bytes = Bytes::from(vec![0; 32]);
-    static ref PEER_PROTOCOL_CONDITION: Bytes = Bytes::from(vec![
+    pub(crate) static ref PEER_PROTOCOL_EXPIRY_DURATION: Duration = Duration::minutes(1);
+    pub(crate) static ref PEER_PROTOCOL_FULFILLMENT: Bytes = Bytes::from(vec![0; 32]);
+    pub(crate) static ref PEER_PROTOCOL_CONDITION: Bytes = Bytes::from(vec![
         102, 104, 122, 173, 248, 98, 189, 119, 108, 143, 193, 139, 142, 159, 142, 32, 8, 151, 20,
         133, 110, 226, 51, 179, 144, 42, 89, 29, 13, 95, 41, 37
     ]);
@@ -28,7 +32,7 @@ impl IldcpRequest {
 
     pub fn to_prepare(&self) -> IlpPrepare {
         IlpPrepare::new(
-            ILDCP_DESTINATION,
+            Address::new_unchecked(ILDCP_DESTINATION.to_string()),
             0,
             &PEER_PROTOCOL_CONDITION[..],
             Utc::now() + *PEER_PROTOCOL_EXPIRY_DURATION,
@@ -37,17 +41,28 @@ impl IldcpRequest {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq, Clone)]
 pub struct IldcpResponse {
-    pub client_address: String,
+    pub client_address: Address,
     pub asset_scale: u8,
     pub asset_code: String,
 }
 
 impl IldcpResponse {
+    pub fn new(client_address: Address, asset_scale: u8, asset_code: &str) -> Self {
+        IldcpResponse {
+            client_address,
+            asset_scale,
+            asset_code: asset_code.to_string(),
+        }
+    }
+
     pub fn from_fulfill(fulfill: &IlpFulfill) -> Result<Self, ParseError> {
         let mut reader = Cursor::new(&fulfill.data[..]);
         let client_address = String::from_utf8(reader.read_var_octet_string()?)?;
+        let client_address = Address::new(&client_address).map_err(|err| {
+            ParseError::InvalidPacket(format!("Invalid client address: {}", err))
+        })?;
         let asset_scale = reader.read_u8()?;
         let asset_code = String::from_utf8(reader.read_var_octet_string()?)?;
         Ok(IldcpResponse {
@@ -56,6 +71,116 @@ impl IldcpResponse {
             asset_code,
         })
     }
+
+    pub fn to_fulfill(&self) -> IlpFulfill {
+        let mut data = Vec::new();
+        data.put_var_octet_string(self.client_address.as_bytes());
+        data.put_u8(self.asset_scale);
+        data.put_var_octet_string(self.asset_code.as_bytes());
+        IlpFulfill::new(&PEER_PROTOCOL_FULFILLMENT[..], data)
+    }
+}
+
+/// Wraps a plugin and answers the ILDCP requests sent by the other side
+/// with the given client address and asset details.
+///
+/// All other packets are passed through untouched.
+pub struct IldcpServer<S> {
+    inner: S,
+    response: IldcpResponse,
+    pending_responses: VecDeque<IlpRequest>,
+}
+
+impl<S> IldcpServer<S>
+where
+    S: Plugin<Item = IlpRequest, Error = (), SinkItem = IlpRequest, SinkError = ()>,
+{
+    pub fn new(plugin: S, response: IldcpResponse) -> Self {
+        IldcpServer {
+            inner: plugin,
+            response,
+            pending_responses: VecDeque::new(),
+        }
+    }
+
+    pub fn response(&self) -> &IldcpResponse {
+        &self.response
+    }
+
+    fn send_pending_responses(&mut self) -> Poll<(), ()> {
+        while let Some(response) = self.pending_responses.pop_front() {
+            if let AsyncSink::NotReady(response) = self.inner.start_send(response)? {
+                self.pending_responses.push_front(response);
+                return Ok(Async::NotReady);
+            }
+        }
+        self.inner.poll_complete()
+    }
+}
+
+impl<S> Plugin for IldcpServer<S> where
+    S: Plugin<Item = IlpRequest, Error = (), SinkItem = IlpRequest, SinkError = ()>
+{}
+
+impl<S> Stream for IldcpServer<S>
+where
+    S: Plugin<Item = IlpRequest, Error = (), SinkItem = IlpRequest, SinkError = ()>,
+{
+    type Item = IlpRequest;
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        // Responses that did not fit before should not wait for the next packet
+        self.send_pending_responses()?;
+        loop {
+            match try_ready!(self.inner.poll()) {
+                Some((request_id, IlpPacket::Prepare(ref prepare)))
+                    if prepare.destination == ILDCP_DESTINATION =>
+                {
+                    if self.pending_responses.len() >= MAX_PENDING_RESPONSES {
+                        warn!(
+                            "Rejecting ILDCP request {} because {} responses are waiting to be sent",
+                            request_id, MAX_PENDING_RESPONSES
+                        );
+                        let reject =
+                            IlpReject::new(ErrorCode::T03ConnectorBusy, "", "", Bytes::new());
+                        // If the plugin is still full the request is dropped and will expire
+                        self.inner
+                            .start_send((request_id, IlpPacket::Reject(reject)))?;
+                        continue;
+                    }
+                    debug!(
+                        "Responding to ILDCP request {} with: {:?}",
+                        request_id, self.response
+                    );
+                    let fulfill = self.response.to_fulfill();
+                    self.pending_responses
+                        .push_back((request_id, IlpPacket::Fulfill(fulfill)));
+                    self.send_pending_responses()?;
+                }
+                item => return Ok(Async::Ready(item)),
+            }
+        }
+    }
+}
+
+impl<S> Sink for IldcpServer<S>
+where
+    S: Plugin<Item = IlpRequest, Error = (), SinkItem = IlpRequest, SinkError = ()>,
+{
+    type SinkItem = IlpRequest;
+    type SinkError = ();
+
+    fn start_send(&mut self, item: Self::SinkItem) -> StartSend<Self::SinkItem, Self::SinkError> {
+        if self.send_pending_responses()?.is_not_ready() {
+            return Ok(AsyncSink::NotReady(item));
+        }
+        self.inner.start_send(item)
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), Self::SinkError> {
+        self.send_pending_responses()
+    }
 }
 
 // On error only returns the plugin if it can continue to be used
@@ -63,37 +188,138 @@ pub fn get_config(
     plugin: impl Plugin,
 ) -> impl Future<Item = (IldcpResponse, impl Plugin), Error = Error> {
     let prepare = IldcpRequest::new().to_prepare();
-    // TODO make sure this doesn't conflict with other packets
-    let original_request_id = 0;
-    plugin
-        .send((original_request_id, IlpPacket::Prepare(prepare)))
-        .map_err(move |_| Error("Error sending ILDCP request".to_string()))
-        .and_then(|plugin| {
-            plugin
-                .into_future()
-                .map_err(|(_, _plugin)| Error("Error listening for ILDCP response".to_string()))
-                .and_then(|(next, plugin)| {
-                    if let Some((_request_id, IlpPacket::Fulfill(fulfill))) = next {
-                        match IldcpResponse::from_fulfill(&fulfill) {
-                            Ok(response) => {
-                                debug!("Got ILDCP response: {:?}", response);
-                                Ok((response, plugin))
-                            }
-                            Err(err) => Err(Error(format!(
-                                "Unable to parse ILDCP response from fulfill: {:?}",
-                                err
-                            ))),
-                        }
-                    } else {
-                        Err(Error(format!(
-                            "Expected Fulfill packet in response to ILDCP request, got: {:?}",
-                            next
-                        )))
-                    }
-                })
-        })
+    let service = PluginService::new(plugin);
+    let request = service.sender().send_request(prepare);
+    service.select2(request).then(|result| match result {
+        Ok(Either::B((Ok(fulfill), service))) => match IldcpResponse::from_fulfill(&fulfill) {
+            Ok(response) => {
+                debug!("Got ILDCP response: {:?}", response);
+                Ok((response, service.into_inner()))
+            }
+            Err(err) => Err(Error(format!(
+                "Unable to parse ILDCP response from fulfill: {:?}",
+                err
+            ))),
+        },
+        Ok(Either::B((Err(reject), _service))) => Err(Error(format!(
+            "Expected Fulfill packet in response to ILDCP request, got: {:?}",
+            reject
+        ))),
+        Ok(Either::A(_)) => Err(Error("Plugin closed before ILDCP response".to_string())),
+        Err(Either::A(_)) => Err(Error("Error listening for ILDCP response".to_string())),
+        Err(Either::B((err, _service))) => {
+            Err(Error(format!("Error sending ILDCP request: {}", err)))
+        }
+    })
 }
 
 #[derive(Fail, Debug)]
 #[fail(display = "Error getting ILDCP info: {}", _0)]
 pub struct Error(String);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::future::lazy;
+    use plugin::channel_plugin::{channel_plugin, channel_plugin_with_capacity};
+    use tokio::runtime::current_thread::block_on_all;
+
+    fn test_response() -> IldcpResponse {
+        IldcpResponse::new(Address::new("example.connector.alice").unwrap(), 9, "XRP")
+    }
+
+    fn ildcp_request(request_id: u32) -> IlpRequest {
+        (
+            request_id,
+            IlpPacket::Prepare(IldcpRequest::new().to_prepare()),
+        )
+    }
+
+    #[test]
+    fn response_roundtrip() {
+        let response =
+            IldcpResponse::new(Address::new("example.connector.alice").unwrap(), 9, "XRP");
+        let fulfill = response.to_fulfill();
+        assert_eq!(fulfill.data, Bytes::from(&b"\x17example.connector.alice\x09\x03XRP"[..]));
+        assert_eq!(IldcpResponse::from_fulfill(&fulfill).unwrap(), response);
+    }
+
+    #[test]
+    fn answers_ildcp_requests_and_passes_on_other_packets() {
+        let (plugin, incoming, outgoing) = channel_plugin();
+        let server = IldcpServer::new(plugin, test_response());
+        incoming.unbounded_send(ildcp_request(1)).unwrap();
+        let prepare = IlpPrepare::new(
+            Address::new("example.bob").unwrap(),
+            100,
+            &[0; 32][..],
+            Utc::now() + Duration::seconds(30),
+            Bytes::new(),
+        );
+        incoming
+            .unbounded_send((2, IlpPacket::Prepare(prepare)))
+            .unwrap();
+
+        let (request, _server) = server.into_future().wait().ok().unwrap();
+        match request {
+            Some((2, IlpPacket::Prepare(ref prepare))) => assert_eq!(prepare.amount, 100),
+            _ => panic!("Expected Prepare"),
+        }
+        let (response, _outgoing) = outgoing.into_future().wait().ok().unwrap();
+        match response {
+            Some((1, IlpPacket::Fulfill(ref fulfill))) => {
+                assert_eq!(
+                    IldcpResponse::from_fulfill(fulfill).unwrap(),
+                    test_response()
+                )
+            }
+            _ => panic!("Expected Fulfill"),
+        }
+    }
+
+    #[test]
+    fn sends_queued_responses_when_polled() {
+        let (plugin, incoming, outgoing) = channel_plugin_with_capacity(0);
+        let mut server = IldcpServer::new(plugin, test_response());
+        incoming.unbounded_send(ildcp_request(1)).unwrap();
+        incoming.unbounded_send(ildcp_request(2)).unwrap();
+
+        let outgoing = block_on_all(lazy(move || {
+            // Only the first response fits in the outgoing channel
+            assert!(server.poll().unwrap().is_not_ready());
+            assert_eq!(server.pending_responses.len(), 1);
+            outgoing
+                .into_future()
+                .map_err(|_| ())
+                .map(move |(first, outgoing)| {
+                    assert_eq!(first.unwrap().0, 1);
+                    // No more packets come in but the queued response is sent anyway
+                    assert!(server.poll().unwrap().is_not_ready());
+                    assert!(server.pending_responses.is_empty());
+                    outgoing
+                })
+        }))
+        .unwrap();
+        let (second, _outgoing) = outgoing.into_future().wait().ok().unwrap();
+        assert_eq!(second.unwrap().0, 2);
+    }
+
+    #[test]
+    fn limits_queued_responses() {
+        let (plugin, incoming, _outgoing) = channel_plugin_with_capacity(0);
+        let mut server = IldcpServer::new(plugin, test_response());
+        // One response fits in the outgoing channel
+        for request_id in 0..MAX_PENDING_RESPONSES + 10 {
+            incoming
+                .unbounded_send(ildcp_request(request_id as u32))
+                .unwrap();
+        }
+
+        block_on_all(lazy(move || {
+            assert!(server.poll().unwrap().is_not_ready());
+            assert_eq!(server.pending_responses.len(), MAX_PENDING_RESPONSES);
+            Ok(()) as Result<(), ()>
+        }))
+        .unwrap();
+    }
+}
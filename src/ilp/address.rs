@@ -0,0 +1,211 @@
+use super::errors::ParseError;
+use std::fmt;
+use std::ops::Deref;
+use std::str::FromStr;
+
+const MAX_ADDRESS_LENGTH: usize = 1023;
+const SCHEMES: &[&str] = &[
+    "g", "private", "example", "peer", "self", "test", "test1", "test2", "test3", "local",
+];
+
+/// An ILP address, validated according to RFC 0015 (ILP Addresses).
+///
+/// Addresses start with an allocation scheme (such as `g` or `test`) followed by
+/// one or more segments separated by `.`. Segments may only contain ASCII letters,
+/// digits, `_`, `~` and `-`.
+#[derive(Debug, PartialEq, Eq, Clone, Hash, PartialOrd, Ord)]
+pub struct Address(String);
+
+impl Address {
+    pub fn new(address: &str) -> Result<Self, ParseError> {
+        if address.len() > MAX_ADDRESS_LENGTH {
+            return Err(ParseError::InvalidPacket(format!(
+                "address is longer than {} bytes",
+                MAX_ADDRESS_LENGTH
+            )));
+        }
+
+        let mut segments = address.split('.');
+        let scheme = segments.next().unwrap_or("");
+        if !SCHEMES.contains(&scheme) {
+            return Err(ParseError::InvalidPacket(format!(
+                "address has invalid allocation scheme: {}",
+                address
+            )));
+        }
+
+        let mut has_segments = false;
+        for segment in segments {
+            if !is_valid_segment(segment) {
+                return Err(ParseError::InvalidPacket(format!(
+                    "address has invalid segment: {}",
+                    address
+                )));
+            }
+            has_segments = true;
+        }
+        if !has_segments {
+            return Err(ParseError::InvalidPacket(format!(
+                "address must have at least one segment after the allocation scheme: {}",
+                address
+            )));
+        }
+
+        Ok(Address(address.to_string()))
+    }
+
+    // Only for strings that are already known to be valid addresses
+    pub(crate) fn new_unchecked(address: String) -> Self {
+        Address(address)
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// The allocation scheme, for example `g` or `test`
+    pub fn scheme(&self) -> &str {
+        self.segments().next().unwrap_or("")
+    }
+
+    /// All parts of the address, including the allocation scheme
+    pub fn segments(&self) -> impl Iterator<Item = &str> {
+        self.0.split('.')
+    }
+
+    /// Append a segment (or multiple segments separated by `.`) to this address
+    pub fn with_suffix(&self, suffix: &str) -> Result<Address, ParseError> {
+        Address::new(&format!("{}.{}", self.0, suffix))
+    }
+
+    /// Check whether this address is equal to or under the given prefix.
+    ///
+    /// Unlike `str::starts_with`, this only matches whole segments
+    /// (so `g.alice` does not start with `g.ali`), unless the prefix ends with a `.`.
+    pub fn starts_with(&self, prefix: &str) -> bool {
+        if !self.0.starts_with(prefix) {
+            false
+        } else if prefix.is_empty() || prefix.ends_with('.') || self.0.len() == prefix.len() {
+            true
+        } else {
+            self.0.as_bytes()[prefix.len()] == b'.'
+        }
+    }
+}
+
+fn is_valid_segment(segment: &str) -> bool {
+    !segment.is_empty()
+        && segment
+            .bytes()
+            .all(|c| c.is_ascii_alphanumeric() || c == b'_' || c == b'~' || c == b'-')
+}
+
+impl FromStr for Address {
+    type Err = ParseError;
+
+    fn from_str(address: &str) -> Result<Self, ParseError> {
+        Address::new(address)
+    }
+}
+
+impl Deref for Address {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl AsRef<str> for Address {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl From<Address> for String {
+    fn from(address: Address) -> String {
+        address.0
+    }
+}
+
+impl PartialEq<str> for Address {
+    fn eq(&self, other: &str) -> bool {
+        self.0 == other
+    }
+}
+
+impl<'a> PartialEq<&'a str> for Address {
+    fn eq(&self, other: &&'a str) -> bool {
+        self.0 == *other
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accepts_valid_addresses() {
+        for address in &[
+            "g.acme.bob",
+            "test.alice.~~~",
+            "peer.config",
+            "example.connector.child-1_a",
+            "test3.x",
+        ] {
+            assert!(Address::new(address).is_ok(), "{}", address);
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_addresses() {
+        for address in &[
+            "",
+            "g",
+            "g.",
+            "g..bob",
+            "h.alice",
+            "G.alice",
+            "g.alice bob",
+            "g.alicé",
+        ] {
+            assert!(Address::new(address).is_err(), "{}", address);
+        }
+        let too_long = format!("g.{}", "a".repeat(1022));
+        assert!(Address::new(&too_long).is_err());
+    }
+
+    #[test]
+    fn helpers() {
+        let address = Address::new("g.acme.bob").unwrap();
+        assert_eq!(address.scheme(), "g");
+        assert_eq!(
+            address.segments().collect::<Vec<&str>>(),
+            vec!["g", "acme", "bob"]
+        );
+        assert_eq!(
+            address.with_suffix("conn~1").unwrap(),
+            Address::new("g.acme.bob.conn~1").unwrap()
+        );
+        assert!(address.with_suffix("a b").is_err());
+        assert_eq!(address, "g.acme.bob");
+        assert_eq!(address.to_string(), "g.acme.bob");
+    }
+
+    #[test]
+    fn starts_with_matches_whole_segments() {
+        let address = Address::new("g.alice.wallet").unwrap();
+        assert!(address.starts_with("g.alice"));
+        assert!(address.starts_with("g.alice."));
+        assert!(address.starts_with("g.alice.wallet"));
+        assert!(address.starts_with(""));
+        assert!(!address.starts_with("g.ali"));
+        assert!(!address.starts_with("g.bob"));
+    }
+}
@@ -0,0 +1,216 @@
+use super::errors::ParseError;
+use std::fmt;
+use std::str::{self, FromStr};
+
+/// ILP error codes, as defined in RFC 0027 (Interledger Protocol V4).
+///
+/// The first character of the code indicates the class of error:
+/// F (final), T (temporary), or R (relative).
+/// Codes that are well-formed but not defined in the RFC are kept as `Unknown`
+/// so that they can still be classified and relayed.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash)]
+pub enum ErrorCode {
+    F00BadRequest,
+    F01InvalidPacket,
+    F02Unreachable,
+    F03InvalidAmount,
+    F04InsufficientDestinationAmount,
+    F05WrongCondition,
+    F06UnexpectedPayment,
+    F07CannotReceive,
+    F08AmountTooLarge,
+    F99ApplicationError,
+    T00InternalError,
+    T01PeerUnreachable,
+    T02PeerBusy,
+    T03ConnectorBusy,
+    T04InsufficientLiquidity,
+    T05RateLimited,
+    T99ApplicationError,
+    R00TransferTimedOut,
+    R01InsufficientSourceAmount,
+    R02InsufficientTimeout,
+    R99ApplicationError,
+    Unknown([u8; 3]),
+}
+
+impl ErrorCode {
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ParseError> {
+        if bytes.len() != 3 {
+            return Err(ParseError::InvalidPacket(format!(
+                "error code must be 3 bytes long, got: {:x?}",
+                bytes
+            )));
+        }
+
+        let code = match bytes {
+            b"F00" => ErrorCode::F00BadRequest,
+            b"F01" => ErrorCode::F01InvalidPacket,
+            b"F02" => ErrorCode::F02Unreachable,
+            b"F03" => ErrorCode::F03InvalidAmount,
+            b"F04" => ErrorCode::F04InsufficientDestinationAmount,
+            b"F05" => ErrorCode::F05WrongCondition,
+            b"F06" => ErrorCode::F06UnexpectedPayment,
+            b"F07" => ErrorCode::F07CannotReceive,
+            b"F08" => ErrorCode::F08AmountTooLarge,
+            b"F99" => ErrorCode::F99ApplicationError,
+            b"T00" => ErrorCode::T00InternalError,
+            b"T01" => ErrorCode::T01PeerUnreachable,
+            b"T02" => ErrorCode::T02PeerBusy,
+            b"T03" => ErrorCode::T03ConnectorBusy,
+            b"T04" => ErrorCode::T04InsufficientLiquidity,
+            b"T05" => ErrorCode::T05RateLimited,
+            b"T99" => ErrorCode::T99ApplicationError,
+            b"R00" => ErrorCode::R00TransferTimedOut,
+            b"R01" => ErrorCode::R01InsufficientSourceAmount,
+            b"R02" => ErrorCode::R02InsufficientTimeout,
+            b"R99" => ErrorCode::R99ApplicationError,
+            _ => {
+                let is_valid = (bytes[0] == b'F' || bytes[0] == b'T' || bytes[0] == b'R')
+                    && bytes[1].is_ascii_digit()
+                    && bytes[2].is_ascii_digit();
+                if !is_valid {
+                    return Err(ParseError::InvalidPacket(format!(
+                        "invalid error code: {:x?}",
+                        bytes
+                    )));
+                }
+                let mut code = [0; 3];
+                code.copy_from_slice(bytes);
+                ErrorCode::Unknown(code)
+            }
+        };
+        Ok(code)
+    }
+
+    pub fn as_bytes(&self) -> [u8; 3] {
+        let code: &[u8; 3] = match self {
+            ErrorCode::F00BadRequest => b"F00",
+            ErrorCode::F01InvalidPacket => b"F01",
+            ErrorCode::F02Unreachable => b"F02",
+            ErrorCode::F03InvalidAmount => b"F03",
+            ErrorCode::F04InsufficientDestinationAmount => b"F04",
+            ErrorCode::F05WrongCondition => b"F05",
+            ErrorCode::F06UnexpectedPayment => b"F06",
+            ErrorCode::F07CannotReceive => b"F07",
+            ErrorCode::F08AmountTooLarge => b"F08",
+            ErrorCode::F99ApplicationError => b"F99",
+            ErrorCode::T00InternalError => b"T00",
+            ErrorCode::T01PeerUnreachable => b"T01",
+            ErrorCode::T02PeerBusy => b"T02",
+            ErrorCode::T03ConnectorBusy => b"T03",
+            ErrorCode::T04InsufficientLiquidity => b"T04",
+            ErrorCode::T05RateLimited => b"T05",
+            ErrorCode::T99ApplicationError => b"T99",
+            ErrorCode::R00TransferTimedOut => b"R00",
+            ErrorCode::R01InsufficientSourceAmount => b"R01",
+            ErrorCode::R02InsufficientTimeout => b"R02",
+            ErrorCode::R99ApplicationError => b"R99",
+            ErrorCode::Unknown(code) => code,
+        };
+        *code
+    }
+
+    /// The human-readable name of the error, as listed in the RFC
+    pub fn name(&self) -> &'static str {
+        match self {
+            ErrorCode::F00BadRequest => "Bad Request",
+            ErrorCode::F01InvalidPacket => "Invalid Packet",
+            ErrorCode::F02Unreachable => "Unreachable",
+            ErrorCode::F03InvalidAmount => "Invalid Amount",
+            ErrorCode::F04InsufficientDestinationAmount => "Insufficient Destination Amount",
+            ErrorCode::F05WrongCondition => "Wrong Condition",
+            ErrorCode::F06UnexpectedPayment => "Unexpected Payment",
+            ErrorCode::F07CannotReceive => "Cannot Receive",
+            ErrorCode::F08AmountTooLarge => "Amount Too Large",
+            ErrorCode::F99ApplicationError => "Application Error",
+            ErrorCode::T00InternalError => "Internal Error",
+            ErrorCode::T01PeerUnreachable => "Peer Unreachable",
+            ErrorCode::T02PeerBusy => "Peer Busy",
+            ErrorCode::T03ConnectorBusy => "Connector Busy",
+            ErrorCode::T04InsufficientLiquidity => "Insufficient Liquidity",
+            ErrorCode::T05RateLimited => "Rate Limited",
+            ErrorCode::T99ApplicationError => "Application Error",
+            ErrorCode::R00TransferTimedOut => "Transfer Timed Out",
+            ErrorCode::R01InsufficientSourceAmount => "Insufficient Source Amount",
+            ErrorCode::R02InsufficientTimeout => "Insufficient Timeout",
+            ErrorCode::R99ApplicationError => "Application Error",
+            ErrorCode::Unknown(_) => "Unknown Error",
+        }
+    }
+
+    /// Final errors indicate the payment is invalid and should not be retried unless the details are changed
+    pub fn is_final(&self) -> bool {
+        self.as_bytes()[0] == b'F'
+    }
+
+    /// Temporary errors indicate a failure on the part of the receiver or an intermediary system
+    /// and the payment may be retried later
+    pub fn is_temporary(&self) -> bool {
+        self.as_bytes()[0] == b'T'
+    }
+
+    /// Relative errors indicate the payment cannot be completed as sent
+    /// but may succeed if the amount or expiry is changed
+    pub fn is_relative(&self) -> bool {
+        self.as_bytes()[0] == b'R'
+    }
+}
+
+impl FromStr for ErrorCode {
+    type Err = ParseError;
+
+    fn from_str(code: &str) -> Result<Self, ParseError> {
+        ErrorCode::from_bytes(code.as_bytes())
+    }
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Error codes are always ASCII
+        f.write_str(str::from_utf8(&self.as_bytes()[..]).unwrap_or("???"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_known_codes() {
+        assert_eq!(
+            ErrorCode::from_str("F08").unwrap(),
+            ErrorCode::F08AmountTooLarge
+        );
+        assert_eq!(
+            ErrorCode::from_bytes(b"T04").unwrap(),
+            ErrorCode::T04InsufficientLiquidity
+        );
+        assert_eq!(ErrorCode::R00TransferTimedOut.to_string(), "R00");
+    }
+
+    #[test]
+    fn keeps_unknown_codes_with_valid_format() {
+        let code = ErrorCode::from_str("T42").unwrap();
+        assert_eq!(code, ErrorCode::Unknown(*b"T42"));
+        assert!(code.is_temporary());
+        assert_eq!(code.to_string(), "T42");
+    }
+
+    #[test]
+    fn rejects_invalid_codes() {
+        assert!(ErrorCode::from_str("X00").is_err());
+        assert!(ErrorCode::from_str("F0").is_err());
+        assert!(ErrorCode::from_str("F0a").is_err());
+        assert!(ErrorCode::from_str("F000").is_err());
+    }
+
+    #[test]
+    fn classifies_codes() {
+        assert!(ErrorCode::F02Unreachable.is_final());
+        assert!(!ErrorCode::F02Unreachable.is_temporary());
+        assert!(ErrorCode::T01PeerUnreachable.is_temporary());
+        assert!(ErrorCode::R02InsufficientTimeout.is_relative());
+        assert!(!ErrorCode::R02InsufficientTimeout.is_final());
+    }
+}
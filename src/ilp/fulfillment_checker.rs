@@ -1,10 +1,13 @@
 use super::IlpPacket;
 use chrono::{DateTime, Utc};
-use futures::{Async, Poll, Sink, StartSend, Stream};
+use futures::{Async, AsyncSink, Poll, Sink, StartSend, Stream};
 use hex;
 use ring::digest::{digest, SHA256};
 use std::collections::HashMap;
 
+// Never remember more than this many Prepares that are waiting for a response
+const MAX_PENDING_PACKETS: usize = 4096;
+
 pub struct IlpFulfillmentChecker<S> {
     inner: S,
     packets: HashMap<u32, (Vec<u8>, DateTime<Utc>)>,
@@ -21,6 +24,29 @@ where
             packets: HashMap::new(),
         }
     }
+
+    // Make room for another Prepare by forgetting the ones that can no longer be fulfilled
+    fn prune_packets(&mut self) {
+        if self.packets.len() < MAX_PENDING_PACKETS {
+            return;
+        }
+        let now = Utc::now();
+        self.packets.retain(|_, (_, expires_at)| *expires_at > now);
+        if self.packets.len() >= MAX_PENDING_PACKETS {
+            let soonest = self
+                .packets
+                .iter()
+                .min_by_key(|(_, (_, expires_at))| *expires_at)
+                .map(|(request_id, _)| *request_id);
+            if let Some(request_id) = soonest {
+                debug!(
+                    "Too many pending Prepares, forgetting request {}",
+                    request_id
+                );
+                self.packets.remove(&request_id);
+            }
+        }
+    }
 }
 
 impl<S> Stream for IlpFulfillmentChecker<S>
@@ -32,53 +58,55 @@ where
     type Error = ();
 
     fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
-        let item = try_ready!(self.inner.poll());
-        match item {
-            Some((request_id, IlpPacket::Fulfill(fulfill))) => {
-                if let Some((condition, expires_at)) = self.packets.remove(&request_id) {
-                    if !fulfillment_matches_condition(&fulfill.fulfillment, condition.as_ref()) {
-                        warn!("Got invalid fulfillment with request id {}: {} (invalid fulfillment. original condition: {:x?})", request_id, hex::encode(&fulfill.fulfillment[..]), hex::encode(&condition[..]));
-                        // TODO do this without removing / reinserting each time
-                        self.packets.insert(request_id, (condition, expires_at));
-                        Ok(Async::NotReady)
-                    } else if expires_at < Utc::now() {
-                        warn!(
-                            "Got invalid Fulfill with request id {} (expired at: {})",
-                            request_id,
-                            expires_at.to_rfc3339()
-                        );
-                        // TODO do this without removing / reinserting each time
-                        self.packets.insert(request_id, (condition, expires_at));
-                        Ok(Async::NotReady)
+        // Drop invalid Fulfills until we get a packet to pass on
+        // or the inner stream is NotReady and will wake us up
+        loop {
+            let item = try_ready!(self.inner.poll());
+            match item {
+                Some((request_id, IlpPacket::Fulfill(fulfill))) => {
+                    if let Some((condition, expires_at)) = self.packets.remove(&request_id) {
+                        if !fulfillment_matches_condition(&fulfill.fulfillment, condition.as_ref())
+                        {
+                            warn!("Got invalid fulfillment with request id {}: {} (invalid fulfillment. original condition: {:x?})", request_id, hex::encode(&fulfill.fulfillment[..]), hex::encode(&condition[..]));
+                            // TODO do this without removing / reinserting each time
+                            self.packets.insert(request_id, (condition, expires_at));
+                        } else if expires_at < Utc::now() {
+                            warn!(
+                                "Got invalid Fulfill with request id {} (expired at: {})",
+                                request_id,
+                                expires_at.to_rfc3339()
+                            );
+                            // TODO do this without removing / reinserting each time
+                            self.packets.insert(request_id, (condition, expires_at));
+                        } else {
+                            trace!(
+                                "Got valid Fulfill matching prepare with request id: {}: {}",
+                                request_id,
+                                hex::encode(&fulfill.fulfillment[..])
+                            );
+                            return Ok(Async::Ready(Some((
+                                request_id,
+                                IlpPacket::Fulfill(fulfill),
+                            ))));
+                        }
                     } else {
-                        trace!(
-                            "Got valid Fulfill matching prepare with request id: {}: {}",
+                        // We never saw the Prepare that corresponds to this
+                        warn!(
+                            "Got Fulfill for unknown request id {}: {}",
                             request_id,
                             hex::encode(&fulfill.fulfillment[..])
                         );
-                        Ok(Async::Ready(Some((
-                            request_id,
-                            IlpPacket::Fulfill(fulfill),
-                        ))))
                     }
-                } else {
-                    // We never saw the Prepare that corresponds to this
-                    warn!(
-                        "Got Fulfill for unknown request id {}: {}",
-                        request_id,
-                        hex::encode(&fulfill.fulfillment[..])
-                    );
-                    Ok(Async::NotReady)
                 }
-            }
-            Some((request_id, IlpPacket::Reject(reject))) => {
-                self.packets.remove(&request_id);
-                Ok(Async::Ready(Some((request_id, IlpPacket::Reject(reject)))))
-            }
-            Some(item) => Ok(Async::Ready(Some(item))),
-            None => {
-                trace!("Stream ended");
-                Ok(Async::Ready(None))
+                Some((request_id, IlpPacket::Reject(reject))) => {
+                    self.packets.remove(&request_id);
+                    return Ok(Async::Ready(Some((request_id, IlpPacket::Reject(reject)))));
+                }
+                Some(item) => return Ok(Async::Ready(Some(item))),
+                None => {
+                    trace!("Stream ended");
+                    return Ok(Async::Ready(None));
+                }
             }
         }
     }
@@ -92,14 +120,23 @@ where
     type SinkError = ();
 
     fn start_send(&mut self, item: Self::SinkItem) -> StartSend<Self::SinkItem, Self::SinkError> {
-        if let (request_id, IlpPacket::Prepare(prepare)) = &item {
+        let request_id = if let (request_id, IlpPacket::Prepare(prepare)) = &item {
+            self.prune_packets();
             self.packets.insert(
                 *request_id,
                 (prepare.execution_condition.to_vec(), prepare.expires_at),
             );
-        }
+            Some(*request_id)
+        } else {
+            None
+        };
 
-        self.inner.start_send(item)
+        let result = self.inner.start_send(item);
+        // The same packet will be sent again when the inner sink is ready
+        if let (Ok(AsyncSink::NotReady(_)), Some(request_id)) = (&result, request_id) {
+            self.packets.remove(&request_id);
+        }
+        result
     }
 
     fn poll_complete(&mut self) -> Poll<(), Self::SinkError> {
@@ -1,9 +1,16 @@
+pub(crate) mod address;
+pub(crate) mod error_code;
 pub(crate) mod errors;
 pub(crate) mod fulfillment_checker;
 pub(crate) mod packet;
+pub(crate) mod packet_buf;
 
+pub use self::address::Address;
+pub use self::error_code::ErrorCode;
 pub use self::errors::ParseError;
 pub use self::fulfillment_checker::IlpFulfillmentChecker;
 pub use self::packet::{
-    parse_f08_error, IlpFulfill, IlpPacket, IlpPrepare, IlpReject, PacketType, Serializable,
+    create_f08_error, parse_f08_error, IlpFulfill, IlpPacket, IlpPrepare, IlpReject,
+    IlpRejectBuilder, MaxPacketAmountDetails, PacketType, Serializable,
 };
+pub use self::packet_buf::{IlpFulfillBuf, IlpPrepareBuf, IlpRejectBuf};
@@ -1,17 +1,18 @@
+use super::address::Address;
+use super::error_code::ErrorCode;
 use super::errors::ParseError;
+use super::packet_buf::{
+    FulfillOffsets, IlpFulfillBuf, IlpPrepareBuf, IlpRejectBuf, PrepareOffsets, RejectOffsets,
+};
 use byteorder::{BigEndian, ReadBytesExt};
 use bytes::BufMut;
 use bytes::Bytes;
-use chrono::{DateTime, TimeZone, Utc};
-use oer::{MutBufOerExt, ReadOerExt};
-use std::io::prelude::*;
+use chrono::{DateTime, Utc};
+use oer::MutBufOerExt;
 use std::io::Cursor;
-use std::str;
-
-// TODO zero-copy (de)serialization
 
 // Note this format includes a dot before the milliseconds so we need to remove that before using the output
-static INTERLEDGER_TIMESTAMP_FORMAT: &'static str = "%Y%m%d%H%M%S%3f";
+pub(crate) static INTERLEDGER_TIMESTAMP_FORMAT: &'static str = "%Y%m%d%H%M%S%3f";
 
 pub trait Serializable<T> {
     fn from_bytes(bytes: &[u8]) -> Result<T, ParseError>;
@@ -45,12 +46,6 @@ fn serialize_envelope(packet_type: PacketType, contents: &[u8]) -> Vec<u8> {
     buf
 }
 
-fn deserialize_envelope(bytes: &[u8]) -> Result<(PacketType, Vec<u8>), ParseError> {
-    let mut reader = Cursor::new(bytes);
-    let packet_type = PacketType::from(reader.read_u8()?);
-    Ok((packet_type, reader.read_var_octet_string()?))
-}
-
 #[derive(Debug, PartialEq, Clone)]
 pub enum IlpPacket {
     Prepare(IlpPrepare),
@@ -58,8 +53,35 @@ pub enum IlpPacket {
     Reject(IlpReject),
 }
 
+impl IlpPacket {
+    /// Parse a packet without copying its fields out of the buffer
+    pub fn from_buf(buffer: Bytes) -> Result<Self, ParseError> {
+        if buffer.is_empty() {
+            return Err(ParseError::InvalidPacket(String::from("packet is empty")));
+        }
+        match PacketType::from(buffer[0]) {
+            PacketType::IlpPrepare => Ok(IlpPacket::Prepare(
+                IlpPrepareBuf::from_bytes(buffer)?.to_prepare(),
+            )),
+            PacketType::IlpFulfill => Ok(IlpPacket::Fulfill(
+                IlpFulfillBuf::from_bytes(buffer)?.to_fulfill(),
+            )),
+            PacketType::IlpReject => Ok(IlpPacket::Reject(
+                IlpRejectBuf::from_bytes(buffer)?.to_reject(),
+            )),
+            _ => Err(ParseError::InvalidPacket(format!(
+                "Unknown packet type: {}",
+                buffer[0]
+            ))),
+        }
+    }
+}
+
 impl Serializable<IlpPacket> for IlpPacket {
     fn from_bytes(bytes: &[u8]) -> Result<Self, ParseError> {
+        if bytes.is_empty() {
+            return Err(ParseError::InvalidPacket(String::from("packet is empty")));
+        }
         match PacketType::from(bytes[0]) {
             PacketType::IlpPrepare => Ok(IlpPacket::Prepare(IlpPrepare::from_bytes(bytes)?)),
             PacketType::IlpFulfill => Ok(IlpPacket::Fulfill(IlpFulfill::from_bytes(bytes)?)),
@@ -86,27 +108,26 @@ pub struct IlpPrepare {
     pub expires_at: DateTime<Utc>,
     // TODO make this just a pointer
     pub execution_condition: Bytes,
-    pub destination: String,
+    pub destination: Address,
     pub data: Bytes,
 }
 
 impl IlpPrepare {
-    pub fn new<A, B, C, D>(
-        destination: A,
+    pub fn new<B, C, D>(
+        destination: Address,
         amount: u64,
         execution_condition: B,
         expires_at: C,
         data: D,
     ) -> Self
     where
-        String: From<A>,
         Bytes: From<B>,
         DateTime<Utc>: From<C>,
         Bytes: From<D>,
     {
         IlpPrepare {
             amount,
-            destination: String::from(destination),
+            destination,
             execution_condition: Bytes::from(execution_condition),
             expires_at: DateTime::from(expires_at),
             data: Bytes::from(data),
@@ -116,35 +137,8 @@ impl IlpPrepare {
 
 impl Serializable<IlpPrepare> for IlpPrepare {
     fn from_bytes(bytes: &[u8]) -> Result<IlpPrepare, ParseError> {
-        let (packet_type, contents) = deserialize_envelope(bytes)?;
-        if packet_type != PacketType::IlpPrepare {
-            return Err(ParseError::WrongType(String::from(
-                "attempted to deserialize other packet type as IlpPrepare",
-            )));
-        }
-
-        let mut reader = Cursor::new(contents);
-        let amount = reader.read_u64::<BigEndian>()?;
-        let mut expires_at_buf = [0; 17];
-        reader.read_exact(&mut expires_at_buf)?;
-        let expires_at_str = String::from_utf8(expires_at_buf.to_vec())?;
-        let expires_at = Utc
-            .datetime_from_str(&expires_at_str, INTERLEDGER_TIMESTAMP_FORMAT)?
-            .with_timezone(&Utc);
-        let mut execution_condition: [u8; 32] = [0; 32];
-        reader.read_exact(&mut execution_condition)?;
-        let destination_bytes = reader.read_var_octet_string()?;
-        // TODO make sure address is only ASCII characters
-        let destination = String::from_utf8(destination_bytes.to_vec())
-            .map_err(|_| ParseError::InvalidPacket(String::from("destination is not utf8")))?;
-        let data = Bytes::from(reader.read_var_octet_string()?);
-        Ok(IlpPrepare {
-            amount,
-            expires_at,
-            execution_condition: Bytes::from(&execution_condition[..]),
-            destination,
-            data,
-        })
+        let (offsets, expires_at) = PrepareOffsets::parse(bytes)?;
+        Ok(offsets.to_prepare(&Bytes::from(bytes), expires_at))
     }
 
     fn to_bytes(&self) -> Vec<u8> {
@@ -158,7 +152,7 @@ impl Serializable<IlpPrepare> for IlpPrepare {
                 .as_bytes(),
         );
         buf.put(&self.execution_condition);
-        buf.put_var_octet_string(&self.destination.to_string().into_bytes());
+        buf.put_var_octet_string(self.destination.as_bytes());
         buf.put_var_octet_string(&self.data);
         serialize_envelope(PacketType::IlpPrepare, &buf)
     }
@@ -186,18 +180,8 @@ impl IlpFulfill {
 
 impl Serializable<IlpFulfill> for IlpFulfill {
     fn from_bytes(bytes: &[u8]) -> Result<IlpFulfill, ParseError> {
-        let (packet_type, contents) = deserialize_envelope(bytes)?;
-        if packet_type != PacketType::IlpFulfill {
-            return Err(ParseError::WrongType(String::from(
-                "attempted to deserialize other packet type as IlpFulfill",
-            )));
-        }
-
-        let mut reader = Cursor::new(contents);
-        let mut fulfillment = [0; 32];
-        reader.read_exact(&mut fulfillment)?;
-        let data = reader.read_var_octet_string()?;
-        Ok(IlpFulfill::new(&fulfillment[..], data))
+        let offsets = FulfillOffsets::parse(bytes)?;
+        Ok(offsets.to_fulfill(&Bytes::from(bytes)))
     }
 
     fn to_bytes(&self) -> Vec<u8> {
@@ -210,58 +194,105 @@ impl Serializable<IlpFulfill> for IlpFulfill {
 
 #[derive(Debug, PartialEq, Clone)]
 pub struct IlpReject {
-    pub code: String,
+    pub code: ErrorCode,
     pub message: String,
     pub triggered_by: String,
     pub data: Bytes,
 }
 
 impl IlpReject {
-    pub fn new<C, M, T, D>(code: C, message: M, triggered_by: T, data: D) -> Self
+    pub fn new<M, T, D>(code: ErrorCode, message: M, triggered_by: T, data: D) -> Self
     where
-        String: From<C>,
         String: From<M>,
         String: From<T>,
         Bytes: From<D>,
     {
         IlpReject {
-            code: String::from(code),
+            code,
             message: String::from(message),
             triggered_by: String::from(triggered_by),
             data: Bytes::from(data),
         }
     }
+
+    pub fn builder(code: ErrorCode) -> IlpRejectBuilder {
+        IlpRejectBuilder::new(code)
+    }
+
+    /// Parse the amount details from an F08 (Amount Too Large) error
+    pub fn max_packet_amount_details(&self) -> Option<MaxPacketAmountDetails> {
+        if self.code == ErrorCode::F08AmountTooLarge {
+            MaxPacketAmountDetails::from_bytes(&self.data[..]).ok()
+        } else {
+            None
+        }
+    }
 }
 
-impl Serializable<IlpReject> for IlpReject {
-    fn from_bytes(bytes: &[u8]) -> Result<IlpReject, ParseError> {
-        let (packet_type, contents) = deserialize_envelope(bytes)?;
-        if packet_type != PacketType::IlpReject {
-            return Err(ParseError::WrongType(String::from(
-                "attempted to deserialize other packet type as IlpReject",
-            )));
+#[derive(Debug, Clone)]
+pub struct IlpRejectBuilder {
+    code: ErrorCode,
+    message: String,
+    triggered_by: String,
+    data: Bytes,
+}
+
+impl IlpRejectBuilder {
+    pub fn new(code: ErrorCode) -> Self {
+        IlpRejectBuilder {
+            code,
+            message: String::new(),
+            triggered_by: String::new(),
+            data: Bytes::new(),
+        }
+    }
+
+    pub fn message(mut self, message: &str) -> Self {
+        self.message = message.to_string();
+        self
+    }
+
+    pub fn triggered_by(mut self, triggered_by: &str) -> Self {
+        self.triggered_by = triggered_by.to_string();
+        self
+    }
+
+    pub fn data<D>(mut self, data: D) -> Self
+    where
+        Bytes: From<D>,
+    {
+        self.data = Bytes::from(data);
+        self
+    }
+
+    /// Set the data to the amount details that F08 (Amount Too Large) errors carry
+    pub fn max_packet_amount(self, amount_received: u64, max_amount: u64) -> Self {
+        let details = MaxPacketAmountDetails {
+            amount_received,
+            max_amount,
+        };
+        self.data(details.to_bytes())
+    }
+
+    pub fn build(self) -> IlpReject {
+        IlpReject {
+            code: self.code,
+            message: self.message,
+            triggered_by: self.triggered_by,
+            data: self.data,
         }
+    }
+}
 
-        let mut reader = Cursor::new(contents);
-        let mut code_bytes = [0; 3];
-        reader.read_exact(&mut code_bytes)?;
-        // TODO: make sure code is valid
-        let code = String::from_utf8(code_bytes.to_vec())
-            .map_err(|_| ParseError::InvalidPacket(String::from("code is not utf8")))?;
-        let triggered_by_bytes = reader.read_var_octet_string()?;
-        let triggered_by = String::from_utf8(triggered_by_bytes.to_vec())
-            .map_err(|_| ParseError::InvalidPacket(String::from("triggered_by is not utf8")))?;
-        let message_bytes = reader.read_var_octet_string()?;
-        let message = String::from_utf8(message_bytes.to_vec())
-            .map_err(|_| ParseError::InvalidPacket(String::from("message is not utf8")))?;
-        let data = Bytes::from(reader.read_var_octet_string()?.to_vec());
-
-        Ok(IlpReject::new(code, message, triggered_by, data))
+impl Serializable<IlpReject> for IlpReject {
+    fn from_bytes(bytes: &[u8]) -> Result<IlpReject, ParseError> {
+        let (offsets, code) = RejectOffsets::parse(bytes)?;
+        Ok(offsets.to_reject(&Bytes::from(bytes), code))
     }
 
     fn to_bytes(&self) -> Vec<u8> {
         let mut buf: Vec<u8> = Vec::new();
-        buf.put(self.code.as_bytes());
+        buf.put(&self.code.as_bytes()[..]);
         buf.put_var_octet_string(self.triggered_by.as_bytes());
         buf.put_var_octet_string(self.message.as_bytes());
         buf.put_var_octet_string(&self.data[..]);
@@ -269,38 +300,42 @@ impl Serializable<IlpReject> for IlpReject {
     }
 }
 
+/// The data included in F08 (Amount Too Large) errors
+#[derive(Debug, PartialEq, Clone)]
 pub struct MaxPacketAmountDetails {
     pub amount_received: u64,
     pub max_amount: u64,
 }
 
-pub fn parse_f08_error(reject: &IlpReject) -> Option<MaxPacketAmountDetails> {
-    if reject.code == "F08" && reject.data.len() >= 16 {
-        let mut cursor = Cursor::new(&reject.data[..]);
-        let amount_received = cursor.read_u64::<BigEndian>().ok()?;
-        let max_amount = cursor.read_u64::<BigEndian>().ok()?;
-        Some(MaxPacketAmountDetails {
+impl MaxPacketAmountDetails {
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ParseError> {
+        let mut reader = Cursor::new(bytes);
+        let amount_received = reader.read_u64::<BigEndian>()?;
+        let max_amount = reader.read_u64::<BigEndian>()?;
+        Ok(MaxPacketAmountDetails {
             amount_received,
             max_amount,
         })
-    } else {
-        None
     }
-}
 
-#[cfg(test)]
-pub(crate) fn create_f08_error(amount_received: u64, max_amount: u64) -> IlpReject {
-    let mut data: Vec<u8> = Vec::new();
-    data.put_u64_be(amount_received);
-    data.put_u64_be(max_amount);
-    IlpReject {
-        code: String::from("F08"),
-        message: String::new(),
-        triggered_by: String::new(),
-        data: Bytes::from(&data[..]),
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf: Vec<u8> = Vec::with_capacity(16);
+        buf.put_u64_be(self.amount_received);
+        buf.put_u64_be(self.max_amount);
+        buf
     }
 }
 
+pub fn parse_f08_error(reject: &IlpReject) -> Option<MaxPacketAmountDetails> {
+    reject.max_packet_amount_details()
+}
+
+pub fn create_f08_error(amount_received: u64, max_amount: u64) -> IlpReject {
+    IlpRejectBuilder::new(ErrorCode::F08AmountTooLarge)
+        .max_packet_amount(amount_received, max_amount)
+        .build()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -324,7 +359,7 @@ mod tests {
 
             static ref PREPARE_1: IlpPrepare = IlpPrepare {
                 amount: 107,
-                destination: "example.alice".to_string(),
+                destination: Address::new("example.alice").unwrap(),
                 expires_at: *EXPIRES_AT,
                 execution_condition: Bytes::from(&EXECUTION_CONDITION[..]),
                 data: Bytes::from(DATA.to_vec()),
@@ -385,7 +420,7 @@ mod tests {
 
         lazy_static! {
             static ref REJECT_1: IlpReject = IlpReject::new(
-                "F99",
+                ErrorCode::F99ApplicationError,
                 "Some error",
                 "example.connector",
                 DATA.to_vec(),
@@ -406,5 +441,36 @@ mod tests {
         fn to_bytes() {
             assert_eq!(REJECT_1.to_bytes(), *REJECT_1_SERIALIZED);
         }
+
+        #[test]
+        fn rejects_invalid_code() {
+            let mut serialized = REJECT_1_SERIALIZED.clone();
+            serialized[4] = b'X';
+            assert!(IlpReject::from_bytes(&serialized).is_err());
+        }
+
+        #[test]
+        fn builder() {
+            let reject = IlpReject::builder(ErrorCode::F99ApplicationError)
+                .message("Some error")
+                .triggered_by("example.connector")
+                .data(DATA.to_vec())
+                .build();
+            assert_eq!(reject, *REJECT_1);
+        }
+
+        #[test]
+        fn max_packet_amount_details() {
+            let reject = create_f08_error(100, 50);
+            assert_eq!(reject.code, ErrorCode::F08AmountTooLarge);
+            assert_eq!(
+                reject.max_packet_amount_details(),
+                Some(MaxPacketAmountDetails {
+                    amount_received: 100,
+                    max_amount: 50,
+                })
+            );
+            assert_eq!(REJECT_1.max_packet_amount_details(), None);
+        }
     }
 }
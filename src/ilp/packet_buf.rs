@@ -0,0 +1,456 @@
+use super::address::Address;
+use super::error_code::ErrorCode;
+use super::errors::ParseError;
+use super::packet::{IlpFulfill, IlpPrepare, IlpReject, PacketType, INTERLEDGER_TIMESTAMP_FORMAT};
+use byteorder::{BigEndian, ByteOrder};
+use bytes::{Bytes, BytesMut};
+use chrono::{DateTime, TimeZone, Utc};
+use oer::var_octet_string_range;
+use std::mem;
+use std::ops::Range;
+use std::str;
+
+const AMOUNT_LEN: usize = 8;
+const EXPIRES_AT_LEN: usize = 17;
+const CONDITION_LEN: usize = 32;
+const FULFILLMENT_LEN: usize = 32;
+const ERROR_CODE_LEN: usize = 3;
+
+// Returns the range of the packet contents inside the envelope
+fn envelope_contents(buf: &[u8], expected_type: PacketType) -> Result<Range<usize>, ParseError> {
+    let packet_type = PacketType::from(
+        *buf
+            .first()
+            .ok_or_else(|| ParseError::InvalidPacket(String::from("packet is empty")))?,
+    );
+    if packet_type != expected_type {
+        return Err(ParseError::WrongType(format!(
+            "attempted to deserialize {:?} packet as {:?}",
+            packet_type, expected_type
+        )));
+    }
+    var_octet_string_at(buf, 1)
+}
+
+fn var_octet_string_at(buf: &[u8], offset: usize) -> Result<Range<usize>, ParseError> {
+    let rest = buf
+        .get(offset..)
+        .ok_or_else(|| ParseError::InvalidPacket(String::from("packet is too short")))?;
+    let range = var_octet_string_range(rest)?;
+    Ok(range.start + offset..range.end + offset)
+}
+
+fn fixed_length_at(buf: &[u8], offset: usize, length: usize) -> Result<Range<usize>, ParseError> {
+    if offset + length > buf.len() {
+        Err(ParseError::InvalidPacket(String::from("packet is too short")))
+    } else {
+        Ok(offset..offset + length)
+    }
+}
+
+fn parse_expires_at(bytes: &[u8]) -> Result<DateTime<Utc>, ParseError> {
+    let expires_at_str = str::from_utf8(bytes)?;
+    Ok(Utc
+        .datetime_from_str(expires_at_str, INTERLEDGER_TIMESTAMP_FORMAT)?
+        .with_timezone(&Utc))
+}
+
+fn utf8_at(buf: &[u8], range: &Range<usize>, field: &str) -> Result<(), ParseError> {
+    str::from_utf8(&buf[range.clone()])
+        .map(|_| ())
+        .map_err(|_| ParseError::InvalidPacket(format!("{} is not utf8", field)))
+}
+
+// Strings are validated when the packet is parsed so this should never fall back to the default
+fn str_at<'a>(buf: &'a [u8], range: &Range<usize>) -> &'a str {
+    str::from_utf8(&buf[range.clone()]).unwrap_or_default()
+}
+
+// Shares the buffer instead of copying the field
+fn slice(buf: &Bytes, range: &Range<usize>) -> Bytes {
+    buf.slice(range.start, range.end)
+}
+
+// Only copies the buffer if it is shared with other packets
+fn make_mut(buf: &mut Bytes) -> BytesMut {
+    match mem::replace(buf, Bytes::new()).try_mut() {
+        Ok(buf) => buf,
+        Err(shared) => BytesMut::from(&shared[..]),
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub(crate) struct PrepareOffsets {
+    amount: Range<usize>,
+    expires_at: Range<usize>,
+    execution_condition: Range<usize>,
+    destination: Range<usize>,
+    data: Range<usize>,
+}
+
+impl PrepareOffsets {
+    pub(crate) fn parse(buf: &[u8]) -> Result<(Self, DateTime<Utc>), ParseError> {
+        let contents = envelope_contents(buf, PacketType::IlpPrepare)?;
+        let buf = &buf[..contents.end];
+        let amount = fixed_length_at(buf, contents.start, AMOUNT_LEN)?;
+        let expires_at = fixed_length_at(buf, amount.end, EXPIRES_AT_LEN)?;
+        let execution_condition = fixed_length_at(buf, expires_at.end, CONDITION_LEN)?;
+        let destination = var_octet_string_at(buf, execution_condition.end)?;
+        utf8_at(buf, &destination, "destination")?;
+        Address::new(str_at(buf, &destination))?;
+        let data = var_octet_string_at(buf, destination.end)?;
+        let parsed_expires_at = parse_expires_at(&buf[expires_at.clone()])?;
+        Ok((
+            PrepareOffsets {
+                amount,
+                expires_at,
+                execution_condition,
+                destination,
+                data,
+            },
+            parsed_expires_at,
+        ))
+    }
+
+    pub(crate) fn to_prepare(&self, buf: &Bytes, expires_at: DateTime<Utc>) -> IlpPrepare {
+        IlpPrepare {
+            amount: BigEndian::read_u64(&buf[self.amount.clone()]),
+            expires_at,
+            execution_condition: slice(buf, &self.execution_condition),
+            destination: Address::new_unchecked(str_at(buf, &self.destination).to_string()),
+            data: slice(buf, &self.data),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub(crate) struct FulfillOffsets {
+    fulfillment: Range<usize>,
+    data: Range<usize>,
+}
+
+impl FulfillOffsets {
+    pub(crate) fn parse(buf: &[u8]) -> Result<Self, ParseError> {
+        let contents = envelope_contents(buf, PacketType::IlpFulfill)?;
+        let buf = &buf[..contents.end];
+        let fulfillment = fixed_length_at(buf, contents.start, FULFILLMENT_LEN)?;
+        let data = var_octet_string_at(buf, fulfillment.end)?;
+        Ok(FulfillOffsets { fulfillment, data })
+    }
+
+    pub(crate) fn to_fulfill(&self, buf: &Bytes) -> IlpFulfill {
+        IlpFulfill {
+            fulfillment: slice(buf, &self.fulfillment),
+            data: slice(buf, &self.data),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub(crate) struct RejectOffsets {
+    triggered_by: Range<usize>,
+    message: Range<usize>,
+    data: Range<usize>,
+}
+
+impl RejectOffsets {
+    pub(crate) fn parse(buf: &[u8]) -> Result<(Self, ErrorCode), ParseError> {
+        let contents = envelope_contents(buf, PacketType::IlpReject)?;
+        let buf = &buf[..contents.end];
+        let code = fixed_length_at(buf, contents.start, ERROR_CODE_LEN)?;
+        let triggered_by = var_octet_string_at(buf, code.end)?;
+        utf8_at(buf, &triggered_by, "triggered_by")?;
+        let message = var_octet_string_at(buf, triggered_by.end)?;
+        utf8_at(buf, &message, "message")?;
+        let data = var_octet_string_at(buf, message.end)?;
+        Ok((
+            RejectOffsets {
+                triggered_by,
+                message,
+                data,
+            },
+            ErrorCode::from_bytes(&buf[code])?,
+        ))
+    }
+
+    pub(crate) fn to_reject(&self, buf: &Bytes, code: ErrorCode) -> IlpReject {
+        IlpReject {
+            code,
+            triggered_by: str_at(buf, &self.triggered_by).to_string(),
+            message: str_at(buf, &self.message).to_string(),
+            data: slice(buf, &self.data),
+        }
+    }
+}
+
+/// An ILP Prepare packet that reads its fields directly from the serialized buffer.
+///
+/// The amount and expiry can be changed in place, which is useful when forwarding packets.
+/// This only copies the buffer if it is shared.
+#[derive(Debug, PartialEq, Clone)]
+pub struct IlpPrepareBuf {
+    buffer: Bytes,
+    offsets: PrepareOffsets,
+    expires_at: DateTime<Utc>,
+}
+
+impl IlpPrepareBuf {
+    pub fn from_bytes(buffer: Bytes) -> Result<Self, ParseError> {
+        let (offsets, expires_at) = PrepareOffsets::parse(&buffer[..])?;
+        Ok(IlpPrepareBuf {
+            buffer,
+            offsets,
+            expires_at,
+        })
+    }
+
+    pub fn amount(&self) -> u64 {
+        BigEndian::read_u64(&self.buffer[self.offsets.amount.clone()])
+    }
+
+    pub fn set_amount(&mut self, amount: u64) {
+        let mut buffer = make_mut(&mut self.buffer);
+        BigEndian::write_u64(&mut buffer[self.offsets.amount.clone()], amount);
+        self.buffer = buffer.freeze();
+    }
+
+    pub fn expires_at(&self) -> DateTime<Utc> {
+        self.expires_at
+    }
+
+    /// Returns an error if the timestamp cannot be written in the fixed-length format, for example after the year 9999
+    pub fn set_expires_at(&mut self, expires_at: DateTime<Utc>) -> Result<(), ParseError> {
+        let formatted = expires_at.format(INTERLEDGER_TIMESTAMP_FORMAT).to_string();
+        if formatted.len() != EXPIRES_AT_LEN {
+            return Err(ParseError::InvalidPacket(format!(
+                "Cannot encode expiry: {}",
+                formatted
+            )));
+        }
+        let mut buffer = make_mut(&mut self.buffer);
+        buffer[self.offsets.expires_at.clone()].copy_from_slice(formatted.as_bytes());
+        self.buffer = buffer.freeze();
+        self.expires_at = expires_at;
+        Ok(())
+    }
+
+    pub fn execution_condition(&self) -> &[u8] {
+        &self.buffer[self.offsets.execution_condition.clone()]
+    }
+
+    pub fn destination(&self) -> &str {
+        str_at(&self.buffer[..], &self.offsets.destination)
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.buffer[self.offsets.data.clone()]
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.buffer[..]
+    }
+
+    pub fn into_bytes(self) -> Bytes {
+        self.buffer
+    }
+
+    pub fn to_prepare(&self) -> IlpPrepare {
+        self.offsets.to_prepare(&self.buffer, self.expires_at)
+    }
+}
+
+/// An ILP Fulfill packet that reads its fields directly from the serialized buffer
+#[derive(Debug, PartialEq, Clone)]
+pub struct IlpFulfillBuf {
+    buffer: Bytes,
+    offsets: FulfillOffsets,
+}
+
+impl IlpFulfillBuf {
+    pub fn from_bytes(buffer: Bytes) -> Result<Self, ParseError> {
+        let offsets = FulfillOffsets::parse(&buffer[..])?;
+        Ok(IlpFulfillBuf { buffer, offsets })
+    }
+
+    pub fn fulfillment(&self) -> &[u8] {
+        &self.buffer[self.offsets.fulfillment.clone()]
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.buffer[self.offsets.data.clone()]
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.buffer[..]
+    }
+
+    pub fn into_bytes(self) -> Bytes {
+        self.buffer
+    }
+
+    pub fn to_fulfill(&self) -> IlpFulfill {
+        self.offsets.to_fulfill(&self.buffer)
+    }
+}
+
+/// An ILP Reject packet that reads its fields directly from the serialized buffer
+#[derive(Debug, PartialEq, Clone)]
+pub struct IlpRejectBuf {
+    buffer: Bytes,
+    offsets: RejectOffsets,
+    code: ErrorCode,
+}
+
+impl IlpRejectBuf {
+    pub fn from_bytes(buffer: Bytes) -> Result<Self, ParseError> {
+        let (offsets, code) = RejectOffsets::parse(&buffer[..])?;
+        Ok(IlpRejectBuf {
+            buffer,
+            offsets,
+            code,
+        })
+    }
+
+    pub fn code(&self) -> ErrorCode {
+        self.code
+    }
+
+    pub fn triggered_by(&self) -> &str {
+        str_at(&self.buffer[..], &self.offsets.triggered_by)
+    }
+
+    pub fn message(&self) -> &str {
+        str_at(&self.buffer[..], &self.offsets.message)
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.buffer[self.offsets.data.clone()]
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.buffer[..]
+    }
+
+    pub fn into_bytes(self) -> Bytes {
+        self.buffer
+    }
+
+    pub fn to_reject(&self) -> IlpReject {
+        self.offsets.to_reject(&self.buffer, self.code)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{IlpPacket, Serializable};
+    use super::*;
+    use chrono::Duration;
+
+    lazy_static! {
+        static ref EXPIRES_AT: DateTime<Utc> =
+            DateTime::parse_from_rfc3339("2018-06-07T20:48:42.483Z")
+                .unwrap()
+                .with_timezone(&Utc);
+        static ref PREPARE: IlpPrepare = IlpPrepare::new(
+            Address::new("example.alice").unwrap(),
+            107,
+            &[7; 32][..],
+            *EXPIRES_AT,
+            &b"some data"[..],
+        );
+    }
+
+    #[test]
+    fn reads_prepare_fields() {
+        let prepare = IlpPrepareBuf::from_bytes(Bytes::from(PREPARE.to_bytes())).unwrap();
+        assert_eq!(prepare.amount(), 107);
+        assert_eq!(prepare.expires_at(), *EXPIRES_AT);
+        assert_eq!(prepare.execution_condition(), &[7; 32][..]);
+        assert_eq!(prepare.destination(), "example.alice");
+        assert_eq!(prepare.data(), &b"some data"[..]);
+        assert_eq!(prepare.to_prepare(), *PREPARE);
+    }
+
+    #[test]
+    fn updates_prepare_in_place() {
+        let mut prepare = IlpPrepareBuf::from_bytes(Bytes::from(PREPARE.to_bytes())).unwrap();
+        let new_expiry = *EXPIRES_AT - Duration::seconds(1);
+        prepare.set_amount(99);
+        prepare.set_expires_at(new_expiry).unwrap();
+
+        let mut expected = PREPARE.clone();
+        expected.amount = 99;
+        expected.expires_at = new_expiry;
+        assert_eq!(prepare.as_bytes(), &expected.to_bytes()[..]);
+        assert_eq!(IlpPrepare::from_bytes(prepare.as_bytes()).unwrap(), expected);
+    }
+
+    #[test]
+    fn rejects_expiry_that_does_not_fit() {
+        let mut prepare = IlpPrepareBuf::from_bytes(Bytes::from(PREPARE.to_bytes())).unwrap();
+        let far_future = Utc.ymd(10000, 1, 1).and_hms(0, 0, 0);
+        assert!(prepare.set_expires_at(far_future).is_err());
+        assert_eq!(prepare.as_bytes(), &PREPARE.to_bytes()[..]);
+    }
+
+    #[test]
+    fn shares_buffer_with_parsed_packet() {
+        let buffer = Bytes::from(PREPARE.to_bytes());
+        let start = buffer.as_ptr() as usize;
+        let end = start + buffer.len();
+        match IlpPacket::from_buf(buffer).unwrap() {
+            IlpPacket::Prepare(prepare) => {
+                assert_eq!(prepare, *PREPARE);
+                let data = prepare.data.as_ptr() as usize;
+                assert!(data >= start && data < end);
+            }
+            _ => panic!("Expected Prepare"),
+        }
+    }
+
+    #[test]
+    fn rejects_truncated_prepare() {
+        let serialized = PREPARE.to_bytes();
+        let truncated = Bytes::from(&serialized[..serialized.len() - 1]);
+        assert!(IlpPrepareBuf::from_bytes(truncated).is_err());
+    }
+
+    #[test]
+    fn rejects_invalid_destination() {
+        let mut serialized = PREPARE.to_bytes();
+        let destination_start = serialized.len() - PREPARE.data.len() - 1 - "example.alice".len();
+        serialized[destination_start] = b' ';
+        assert!(IlpPrepareBuf::from_bytes(Bytes::from(serialized)).is_err());
+    }
+
+    #[test]
+    fn rejects_wrong_packet_type() {
+        let fulfill = IlpFulfill::new(&[0; 32][..], &b"data"[..]);
+        assert!(IlpPrepareBuf::from_bytes(Bytes::from(fulfill.to_bytes())).is_err());
+    }
+
+    #[test]
+    fn reads_fulfill_fields() {
+        let fulfill = IlpFulfill::new(&[1; 32][..], &b"data"[..]);
+        let buf = IlpFulfillBuf::from_bytes(Bytes::from(fulfill.to_bytes())).unwrap();
+        assert_eq!(buf.fulfillment(), &[1; 32][..]);
+        assert_eq!(buf.data(), &b"data"[..]);
+        assert_eq!(buf.to_fulfill(), fulfill);
+    }
+
+    #[test]
+    fn reads_reject_fields() {
+        let reject = IlpReject::new(
+            ErrorCode::T04InsufficientLiquidity,
+            "Not enough liquidity",
+            "example.connector",
+            &b"data"[..],
+        );
+        let buf = IlpRejectBuf::from_bytes(Bytes::from(reject.to_bytes())).unwrap();
+        assert_eq!(buf.code(), ErrorCode::T04InsufficientLiquidity);
+        assert_eq!(buf.message(), "Not enough liquidity");
+        assert_eq!(buf.triggered_by(), "example.connector");
+        assert_eq!(buf.data(), &b"data"[..]);
+        assert_eq!(buf.to_reject(), reject);
+    }
+}
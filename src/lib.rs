@@ -32,6 +32,7 @@ extern crate parking_lot;
 extern crate reqwest;
 extern crate stream_cancel;
 
+pub mod connector;
 pub mod errors;
 pub mod ildcp;
 pub mod ilp;
@@ -98,11 +98,18 @@ fn send_spsp_payment(btp_server: &str, receiver: String, amount: u64) {
             ilp::spsp::pay(plugin, &receiver, amount)
                 .map_err(|err| {
                     println!("Error sending SPSP payment: {:?}", err);
-                }).and_then(move |delivered| {
-                    println!(
-                        "Sent: {}, delivered: {} (in the receiver's units)",
-                        amount, delivered
-                    );
+                }).and_then(|result| {
+                    match (result.source_asset, result.destination_asset) {
+                        (Some(source_asset), Some(destination_asset)) => println!(
+                            "Sent: {}, delivered: {}",
+                            source_asset.format_amount(result.amount_sent),
+                            destination_asset.format_amount(result.amount_delivered)
+                        ),
+                        _ => println!(
+                            "Sent: {}, delivered: {} (in the receiver's units)",
+                            result.amount_sent, result.amount_delivered
+                        ),
+                    }
                     Ok(())
                 })
         });
@@ -115,7 +122,8 @@ fn run_spsp_server(btp_server: &str, port: u16, notification_endpoint: Option<St
     // TODO make sure that the client keeps the connections alive
     let client = Arc::new(reqwest::async::Client::new());
 
-    let run = ilp::plugin::btp::connect_async(&btp_server)
+    // Keep serving if the BTP server restarts
+    let run = ilp::plugin::btp::connect_reconnecting_async(&btp_server)
     .map_err(|err| {
       println!("Error connecting to BTP server: {:?}", err);
       println!("(Hint: is moneyd running?)");
@@ -3,6 +3,7 @@ use bytes::{Buf, BufMut, Bytes, IntoBuf};
 use num_bigint::BigUint;
 use std::fmt::Debug;
 use std::io::{self, Read, Result, Write};
+use std::ops::Range;
 
 const HIGH_BIT: u8 = 0x80;
 const LOWER_SEVEN_BITS: u8 = 0x7f;
@@ -131,6 +132,45 @@ pub trait MutBufOerExt: BufMut + Sized {
 
 impl<B: BufMut + Sized> MutBufOerExt for B {}
 
+/// Find the contents of the var octet string at the start of the buffer without copying it.
+/// Returns an error if the buffer is shorter than the length prefix says it should be.
+pub fn var_octet_string_range(buf: &[u8]) -> Result<Range<usize>> {
+    let unexpected_eof = || {
+        io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "buffer is shorter than var octet string length",
+        )
+    };
+
+    let length = *buf.first().ok_or_else(unexpected_eof)?;
+    let (start, actual_length) = if length & HIGH_BIT != 0 {
+        let length_prefix_length = (length & LOWER_SEVEN_BITS) as usize;
+        if length_prefix_length == 0 || length_prefix_length > 8 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "invalid var octet string length prefix",
+            ));
+        }
+        let prefix = buf
+            .get(1..1 + length_prefix_length)
+            .ok_or_else(unexpected_eof)?;
+        let actual_length = prefix
+            .iter()
+            .fold(0u64, |length, byte| (length << 8) | u64::from(*byte));
+        (1 + length_prefix_length, actual_length as usize)
+    } else {
+        (1, length as usize)
+    };
+
+    let end = start
+        .checked_add(actual_length)
+        .ok_or_else(unexpected_eof)?;
+    if end > buf.len() {
+        return Err(unexpected_eof());
+    }
+    Ok(start..end)
+}
+
 #[cfg(test)]
 mod writer_ext {
     use super::*;
@@ -188,4 +228,18 @@ mod reader_ext {
             &larger_string[..]
         );
     }
+
+    #[test]
+    fn it_finds_var_octet_string_ranges() {
+        assert_eq!(var_octet_string_range(&[0]).unwrap(), 1..1);
+        assert_eq!(var_octet_string_range(&[0x01, 0xb0, 0xff]).unwrap(), 1..2);
+
+        let mut larger = vec![0x82, 0x01, 0x00];
+        larger.extend(&[0xb0; 256][..]);
+        assert_eq!(var_octet_string_range(&larger).unwrap(), 3..259);
+
+        assert!(var_octet_string_range(&[]).is_err());
+        assert!(var_octet_string_range(&[0x02, 0xb0]).is_err());
+        assert!(var_octet_string_range(&[0x82, 0x01]).is_err());
+    }
 }
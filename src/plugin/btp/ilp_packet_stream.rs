@@ -1,9 +1,35 @@
-use super::packet::{BtpMessage, BtpPacket, BtpResponse, ContentType, ProtocolData};
+use super::packet::{BtpError, BtpMessage, BtpPacket, BtpResponse, ContentType, ProtocolData};
+use super::request_id_checker::PendingRequestIds;
+use super::sub_protocol::{
+    error_to_result, BtpResult, BtpSender, OutgoingRequest, SubProtocolRegistry,
+};
+use bytes::Bytes;
+use futures::sync::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
+use futures::sync::oneshot;
 use futures::{Async, AsyncSink, Poll, Sink, StartSend, Stream};
-use ilp::{IlpPacket, Serializable};
+use ilp::{ErrorCode, IlpPacket, IlpReject, Serializable};
+use parking_lot::RwLock;
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+use stream::random_u32;
 
+// Stop reading incoming packets while this many packets are waiting for the underlying sink
+const MAX_OUTGOING_BUFFER: usize = 64;
+
+/// Turns a stream of BTP packets into a stream of ILP packets.
+///
+/// BTP Messages and Transfers for other sub-protocols are passed to the handlers
+/// in the `SubProtocolRegistry` and requests sent with the `BtpSender` are sent alongside the ILP packets.
 pub struct IlpPacketStream<S> {
     inner: S,
+    sub_protocols: Arc<RwLock<SubProtocolRegistry>>,
+    requests_sender: UnboundedSender<OutgoingRequest>,
+    requests: UnboundedReceiver<OutgoingRequest>,
+    pending_requests: HashMap<u32, oneshot::Sender<BtpResult>>,
+    // IDs of requests sent through this stream that are still waiting for a response
+    pending_request_ids: PendingRequestIds,
+    // Packets waiting for the underlying sink to be ready
+    outgoing: VecDeque<BtpPacket>,
 }
 
 impl<S> IlpPacketStream<S>
@@ -11,140 +37,479 @@ where
     S: Stream<Item = BtpPacket, Error = ()> + Sink<SinkItem = BtpPacket, SinkError = ()>,
 {
     pub fn new(stream: S) -> Self {
-        IlpPacketStream { inner: stream }
+        IlpPacketStream::with_shared_state(
+            stream,
+            Arc::new(RwLock::new(SubProtocolRegistry::new())),
+            PendingRequestIds::default(),
+        )
+    }
+
+    /// Use side protocol handlers that outlive this connection and pick request IDs
+    /// for BTP requests that are not already used by the ILP packets sent on it
+    pub fn with_shared_state(
+        stream: S,
+        sub_protocols: Arc<RwLock<SubProtocolRegistry>>,
+        pending_request_ids: PendingRequestIds,
+    ) -> Self {
+        let (requests_sender, requests) = unbounded();
+        IlpPacketStream {
+            inner: stream,
+            sub_protocols,
+            requests_sender,
+            requests,
+            pending_requests: HashMap::new(),
+            pending_request_ids,
+            outgoing: VecDeque::new(),
+        }
+    }
+
+    pub fn sub_protocols(&self) -> Arc<RwLock<SubProtocolRegistry>> {
+        Arc::clone(&self.sub_protocols)
+    }
+
+    pub fn btp_sender(&self) -> BtpSender {
+        BtpSender::new(self.requests_sender.clone())
+    }
+
+    // Hand as many buffered packets as possible to the underlying sink.
+    // If it is not ready, it will wake up the current task once it is
+    fn send_outgoing(&mut self) -> Result<(), ()> {
+        // Forget requests that timed out
+        self.pending_requests
+            .retain(|_, result_sender| !result_sender.is_canceled());
+
+        while self.outgoing.len() < MAX_OUTGOING_BUFFER {
+            let (mut packet, result_sender) = match self.requests.poll() {
+                Ok(Async::Ready(Some(request))) => request,
+                _ => break,
+            };
+            let request_id = self.unused_request_id();
+            match packet {
+                BtpPacket::Message(ref mut message) => message.request_id = request_id,
+                BtpPacket::Transfer(ref mut transfer) => transfer.request_id = request_id,
+                _ => {
+                    warn!("Can only send BTP Messages and Transfers as requests");
+                    continue;
+                }
+            };
+            self.pending_requests.insert(request_id, result_sender);
+            self.outgoing.push_back(packet);
+        }
+
+        while let Some(packet) = self.outgoing.pop_front() {
+            if let AsyncSink::NotReady(packet) = self.inner.start_send(packet)? {
+                self.outgoing.push_front(packet);
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    fn unused_request_id(&self) -> u32 {
+        loop {
+            let request_id = random_u32();
+            if !self.pending_requests.contains_key(&request_id)
+                && !self.pending_request_ids.contains(request_id)
+            {
+                return request_id;
+            }
+        }
     }
+
+    // Handle BTP packets that are not for ILP. Returns the packet if it should be parsed as ILP
+    fn handle_sub_protocols(&mut self, packet: BtpPacket) -> Option<BtpPacket> {
+        match packet {
+            BtpPacket::Transfer(transfer) => {
+                let response = self.sub_protocols.read().handle(
+                    transfer.request_id,
+                    transfer.amount,
+                    &transfer.protocol_data,
+                );
+                self.outgoing.push_back(response);
+                None
+            }
+            BtpPacket::Message(ref message) if !is_ilp(&message.protocol_data) => {
+                let response =
+                    self.sub_protocols
+                        .read()
+                        .handle(message.request_id, 0, &message.protocol_data);
+                self.outgoing.push_back(response);
+                None
+            }
+            BtpPacket::Response(response) => {
+                if let Some(result_sender) = self.pending_requests.remove(&response.request_id) {
+                    let _ = result_sender.send(Ok(response.protocol_data));
+                    None
+                } else {
+                    Some(BtpPacket::Response(response))
+                }
+            }
+            BtpPacket::Error(error) => {
+                if let Some(result_sender) = self.pending_requests.remove(&error.request_id) {
+                    let _ = result_sender.send(error_to_result(&error));
+                    None
+                } else {
+                    Some(BtpPacket::Error(error))
+                }
+            }
+            packet => Some(packet),
+        }
+    }
+}
+
+fn is_ilp(protocol_data: &[ProtocolData]) -> bool {
+    !protocol_data.is_empty() && protocol_data[0].protocol_name == "ilp"
+}
+
+// Takes the protocol data so the ILP packet can share its buffer instead of copying it
+fn parse_ilp_packet(request_id: u32, mut protocol_data: Vec<ProtocolData>) -> Option<IlpPacket> {
+    if !is_ilp(&protocol_data) {
+        trace!(
+            "Ignoring BTP packet {} that had no ILP packet in it",
+            request_id
+        );
+        return None;
+    }
+    let data = Bytes::from(protocol_data.swap_remove(0).data);
+    match IlpPacket::from_buf(data.clone()) {
+        Ok(packet) => {
+            trace!("Parsed ILP packet: {} {:?}", request_id, packet);
+            Some(packet)
+        }
+        Err(_) => {
+            trace!(
+                "Unable to parse ILP packet from BTP packet protocol data: {:x?}",
+                &data[..]
+            );
+            None
+        }
+    }
+}
+
+// The other side could not handle our ILP packet, so it will never be fulfilled
+fn error_to_reject(error: &BtpError) -> IlpPacket {
+    IlpPacket::Reject(IlpReject::new(
+        ErrorCode::T00InternalError,
+        format!("BTP error: {} {} {}", error.code, error.name, error.data),
+        "",
+        Bytes::new(),
+    ))
 }
 
 impl<S> Stream for IlpPacketStream<S>
 where
-    S: Stream<Item = BtpPacket, Error = ()>,
+    S: Stream<Item = BtpPacket, Error = ()> + Sink<SinkItem = BtpPacket, SinkError = ()>,
 {
     type Item = (u32, IlpPacket);
     type Error = ();
 
     fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
-        if let Some(packet) = try_ready!(self.inner.poll()) {
-            if let BtpPacket::Message(packet) = &packet {
-                if !packet.protocol_data.is_empty()
-                    && packet.protocol_data[0].protocol_name == "ilp"
-                {
-                    if let Ok(parsed_ilp_packet) =
-                        IlpPacket::from_bytes(&packet.protocol_data[0].data)
-                    {
-                        trace!(
-                            "Parsed ILP packet: {} {:?}",
-                            packet.request_id,
-                            parsed_ilp_packet
-                        );
-                        Ok(Async::Ready(Some((packet.request_id, parsed_ilp_packet))))
-                    } else {
-                        trace!(
-                            "Unable to parse ILP packet from BTP packet protocol data: {:x?}",
-                            packet.protocol_data[0].data
-                        );
-                        // TODO is this problematic that we're returning NotReady even though the underlying stream returned ready?
-                        Ok(Async::NotReady)
-                    }
-                } else {
-                    trace!(
-                        "Ignoring BTP packet that had no ILP packet in it {:?}",
-                        packet
-                    );
-                    Ok(Async::NotReady)
+        loop {
+            self.send_outgoing()?;
+            self.inner.poll_complete()?;
+
+            // Don't read more requests than we can respond to.
+            // The sink will wake us up when it has room
+            if self.outgoing.len() >= MAX_OUTGOING_BUFFER {
+                trace!("Waiting for outgoing packets to be sent before reading more");
+                return Ok(Async::NotReady);
+            }
+
+            // Keep polling until we get an ILP packet or the underlying stream is NotReady,
+            // so we are woken up when the next packet arrives
+            let packet = match try_ready!(self.inner.poll()) {
+                Some(packet) => packet,
+                None => {
+                    trace!("Stream ended");
+                    return Ok(Async::Ready(None));
                 }
-            } else if let BtpPacket::Response(packet) = &packet {
-                if !packet.protocol_data.is_empty()
-                    && packet.protocol_data[0].protocol_name == "ilp"
-                {
-                    if let Ok(parsed_ilp_packet) =
-                        IlpPacket::from_bytes(&packet.protocol_data[0].data)
-                    {
-                        trace!(
-                            "Parsed ILP packet: {} {:?}",
-                            packet.request_id,
-                            parsed_ilp_packet
-                        );
-                        Ok(Async::Ready(Some((packet.request_id, parsed_ilp_packet))))
-                    } else {
-                        trace!(
-                            "Unable to parse ILP packet from BTP packet protocol data: {:x?}",
-                            packet.protocol_data[0].data
-                        );
-                        Ok(Async::NotReady)
-                    }
-                } else {
-                    trace!(
-                        "Ignoring BTP packet that had no ILP packet in it {:?}",
-                        packet
-                    );
-                    Ok(Async::NotReady)
+            };
+            let parsed = match self.handle_sub_protocols(packet) {
+                Some(BtpPacket::Message(message)) => {
+                    let request_id = message.request_id;
+                    parse_ilp_packet(request_id, message.protocol_data)
+                        .map(|packet| (request_id, packet))
+                }
+                Some(BtpPacket::Response(response)) => {
+                    let request_id = response.request_id;
+                    parse_ilp_packet(request_id, response.protocol_data)
+                        .map(|packet| (request_id, packet))
+                }
+                Some(BtpPacket::Error(error)) => {
+                    debug!("Got BTP error in response to ILP packet: {:?}", error);
+                    Some((error.request_id, error_to_reject(&error)))
                 }
-            } else {
-                trace!("Ignoring unexpected BTP packet {:?}", packet);
-                Ok(Async::NotReady)
+                Some(packet) => {
+                    trace!("Ignoring unexpected BTP packet {:?}", packet);
+                    None
+                }
+                None => None,
+            };
+            if let Some(item) = parsed {
+                return Ok(Async::Ready(Some(item)));
             }
-        } else {
-            trace!("Stream ended");
-            Ok(Async::Ready(None))
         }
     }
 }
 
 impl<S> Sink for IlpPacketStream<S>
 where
-    S: Sink<SinkItem = BtpPacket, SinkError = ()>,
+    S: Stream<Item = BtpPacket, Error = ()> + Sink<SinkItem = BtpPacket, SinkError = ()>,
 {
     type SinkItem = (u32, IlpPacket);
     type SinkError = ();
 
     fn start_send(&mut self, item: Self::SinkItem) -> StartSend<Self::SinkItem, Self::SinkError> {
-        // TODO there must be a better way of passing this back in case the Sink isn't ready
-        let item_clone = item.clone();
-        let btp_packet_to_send = match item {
-            (request_id, IlpPacket::Prepare(ref packet)) => {
-                trace!(
-                    "Sending ILP packet with request id {}: {:?}",
-                    request_id,
-                    packet
-                );
-                BtpPacket::Message(BtpMessage {
-                    request_id,
-                    protocol_data: vec![ProtocolData {
-                        protocol_name: String::from("ilp"),
-                        content_type: ContentType::ApplicationOctetStream,
-                        data: packet.to_bytes(),
-                    }],
-                })
-            }
-            (request_id, packet) => {
-                trace!(
-                    "Sending ILP packet with request id {}: {:?}",
-                    request_id,
-                    packet
-                );
-                BtpPacket::Response(BtpResponse {
-                    request_id,
-                    protocol_data: vec![ProtocolData {
-                        protocol_name: String::from("ilp"),
-                        content_type: ContentType::ApplicationOctetStream,
-                        data: packet.to_bytes(),
-                    }],
-                })
-            }
+        // Only accept the packet if everything before it has been passed on,
+        // otherwise the underlying sink will wake us up when it is ready
+        self.send_outgoing()?;
+        if !self.outgoing.is_empty() {
+            return Ok(AsyncSink::NotReady(item));
+        }
+
+        let (request_id, packet) = item;
+        trace!(
+            "Sending ILP packet with request id {}: {:?}",
+            request_id,
+            packet
+        );
+        let protocol_data = vec![ProtocolData {
+            protocol_name: String::from("ilp"),
+            content_type: ContentType::ApplicationOctetStream,
+            data: packet.to_bytes(),
+        }];
+        let btp_packet = match packet {
+            IlpPacket::Prepare(_) => BtpPacket::Message(BtpMessage {
+                request_id,
+                protocol_data,
+            }),
+            _ => BtpPacket::Response(BtpResponse {
+                request_id,
+                protocol_data,
+            }),
         };
-        self.inner
-            .start_send(btp_packet_to_send)
-            .map(move |result| match result {
-                AsyncSink::Ready => AsyncSink::Ready,
-                AsyncSink::NotReady(_) => AsyncSink::NotReady(item_clone),
-            }).map_err(|err| {
-                error!("Error sending packet {:?}", err);
-            })
+        self.outgoing.push_back(btp_packet);
+        self.send_outgoing().map_err(|err| {
+            error!("Error sending packet {:?}", err);
+        })?;
+        Ok(AsyncSink::Ready)
     }
 
     fn poll_complete(&mut self) -> Poll<(), Self::SinkError> {
-        self.inner.poll_complete().map_err(|err| {
+        self.send_outgoing()?;
+        try_ready!(self.inner.poll_complete().map_err(|err| {
             error!("Error polling {:?}", err);
-        })
+        }));
+        if self.outgoing.is_empty() {
+            Ok(Async::Ready(()))
+        } else {
+            Ok(Async::NotReady)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::BtpTransfer;
+    use super::*;
+    use chrono::Utc;
+    use futures::future::{lazy, ok, Either};
+    use futures::sync::mpsc::{Receiver, Sender};
+    use futures::Future;
+    use plugin::channel_plugin::{channel_plugin_with_capacity, ChannelPlugin};
+    use tokio::runtime::current_thread::block_on_all;
+
+    fn test_stream() -> (
+        IlpPacketStream<ChannelPlugin<BtpPacket, Sender<BtpPacket>>>,
+        UnboundedSender<BtpPacket>,
+        Receiver<BtpPacket>,
+    ) {
+        test_stream_with_capacity(16)
+    }
+
+    fn test_stream_with_capacity(
+        capacity: usize,
+    ) -> (
+        IlpPacketStream<ChannelPlugin<BtpPacket, Sender<BtpPacket>>>,
+        UnboundedSender<BtpPacket>,
+        Receiver<BtpPacket>,
+    ) {
+        let (stream, incoming, outgoing) = channel_plugin_with_capacity(capacity);
+        (IlpPacketStream::new(stream), incoming, outgoing)
+    }
+
+    fn claim() -> Vec<ProtocolData> {
+        vec![ProtocolData {
+            protocol_name: String::from("paychan"),
+            content_type: ContentType::ApplicationOctetStream,
+            data: vec![1, 2, 3],
+        }]
+    }
+
+    #[test]
+    fn responds_to_transfers_with_handler() {
+        let (mut stream, incoming, outgoing) = test_stream();
+        stream.sub_protocols().write().register(
+            "paychan",
+            Box::new(|amount, _protocol_data| {
+                assert_eq!(amount, 100);
+                Ok(vec![])
+            }),
+        );
+        incoming
+            .unbounded_send(BtpPacket::Transfer(BtpTransfer {
+                request_id: 7,
+                amount: 100,
+                protocol_data: claim(),
+            }))
+            .unwrap();
+
+        block_on_all(lazy(move || {
+            assert!(stream.poll().unwrap().is_not_ready());
+            Ok(()) as Result<(), ()>
+        }))
+        .unwrap();
+        let (response, _outgoing) = outgoing.into_future().wait().ok().unwrap();
+        assert_eq!(
+            response,
+            Some(BtpPacket::Response(BtpResponse {
+                request_id: 7,
+                protocol_data: vec![],
+            }))
+        );
+    }
+
+    #[test]
+    fn resolves_outgoing_transfers() {
+        let (mut stream, incoming, outgoing) = test_stream();
+        let sender = stream.btp_sender();
+        let result = sender.send_transfer(100, claim());
+
+        let result = block_on_all(lazy(move || {
+            assert!(stream.poll().unwrap().is_not_ready());
+            outgoing
+                .into_future()
+                .map_err(|_| panic!("Expected Transfer"))
+                .and_then(move |(request, _outgoing)| {
+                    let request_id = match request {
+                        Some(BtpPacket::Transfer(transfer)) => {
+                            assert_eq!(transfer.amount, 100);
+                            transfer.request_id
+                        }
+                        _ => panic!("Expected Transfer"),
+                    };
+                    incoming
+                        .unbounded_send(BtpPacket::Response(BtpResponse {
+                            request_id,
+                            protocol_data: claim(),
+                        }))
+                        .unwrap();
+                    assert!(stream.poll().unwrap().is_not_ready());
+                    ok(stream)
+                })
+                .and_then(|_stream| result)
+        }))
+        .unwrap();
+        assert_eq!(result, claim());
+    }
+
+    fn ilp_protocol_data(data: Vec<u8>) -> Vec<ProtocolData> {
+        vec![ProtocolData {
+            protocol_name: String::from("ilp"),
+            content_type: ContentType::ApplicationOctetStream,
+            data,
+        }]
+    }
+
+    #[test]
+    fn skips_invalid_packets_without_stalling() {
+        let (mut stream, incoming, _outgoing) = test_stream();
+        let reject = IlpPacket::Reject(IlpReject::new(
+            ErrorCode::F02Unreachable,
+            "",
+            "",
+            Bytes::new(),
+        ));
+        incoming
+            .unbounded_send(BtpPacket::Response(BtpResponse {
+                request_id: 1,
+                protocol_data: ilp_protocol_data(vec![0xff, 0xff]),
+            }))
+            .unwrap();
+        incoming
+            .unbounded_send(BtpPacket::Response(BtpResponse {
+                request_id: 2,
+                protocol_data: ilp_protocol_data(reject.to_bytes()),
+            }))
+            .unwrap();
+
+        block_on_all(lazy(move || {
+            assert_eq!(stream.poll().unwrap(), Async::Ready(Some((2, reject))));
+            Ok(()) as Result<(), ()>
+        }))
+        .unwrap();
+    }
+
+    #[test]
+    fn turns_btp_errors_into_rejects() {
+        let (mut stream, incoming, _outgoing) = test_stream();
+        incoming
+            .unbounded_send(BtpPacket::Error(BtpError {
+                request_id: 3,
+                code: String::from("F00"),
+                name: String::from("NotAcceptedError"),
+                triggered_at: Utc::now(),
+                data: String::from("no"),
+                protocol_data: vec![],
+            }))
+            .unwrap();
+
+        block_on_all(lazy(move || {
+            match stream.poll().unwrap() {
+                Async::Ready(Some((3, IlpPacket::Reject(reject)))) => {
+                    assert_eq!(reject.code, ErrorCode::T00InternalError);
+                }
+                _ => panic!("Expected Reject"),
+            }
+            Ok(()) as Result<(), ()>
+        }))
+        .unwrap();
+    }
+
+    #[test]
+    fn stops_reading_until_responses_are_sent() {
+        let (mut stream, incoming, outgoing) = test_stream_with_capacity(0);
+        stream
+            .sub_protocols()
+            .write()
+            .register("paychan", Box::new(|_amount, _protocol_data| Ok(vec![])));
+        let count = MAX_OUTGOING_BUFFER * 2;
+        for request_id in 0..count {
+            incoming
+                .unbounded_send(BtpPacket::Transfer(BtpTransfer {
+                    request_id: request_id as u32,
+                    amount: 1,
+                    protocol_data: claim(),
+                }))
+                .unwrap();
+        }
+
+        let responses = block_on_all(lazy(move || {
+            assert!(stream.poll().unwrap().is_not_ready());
+            assert_eq!(stream.outgoing.len(), MAX_OUTGOING_BUFFER);
+
+            // The stream is only polled again if the sink wakes it up as it drains
+            let drive = stream.for_each(|_| Ok(()));
+            outgoing
+                .take(count as u64)
+                .collect()
+                .select2(drive)
+                .then(|result| match result {
+                    Ok(Either::A((responses, _drive))) => Ok(responses),
+                    _ => Err(()),
+                })
+        }))
+        .unwrap();
+        assert_eq!(responses.len(), count);
     }
 }
@@ -2,16 +2,22 @@ mod client;
 mod ilp_packet_stream;
 mod packet;
 mod packet_stream;
+mod reconnecting;
 mod request_id_checker;
+mod server;
+mod sub_protocol;
 
 pub use self::client::{connect_async, ClientPlugin, PluginBtpError};
 pub use self::ilp_packet_stream::IlpPacketStream;
 pub use self::packet::{
-    deserialize_packet, BtpError, BtpMessage, BtpPacket, BtpResponse, ContentType, ProtocolData,
-    Serializable,
+    deserialize_packet, BtpError, BtpMessage, BtpPacket, BtpResponse, BtpTransfer, ContentType,
+    ProtocolData, Serializable,
 };
 pub use self::packet_stream::BtpPacketStream;
-pub use self::request_id_checker::BtpRequestIdCheckerStream;
+pub use self::reconnecting::{connect_reconnecting_async, PluginState, ReconnectingPlugin};
+pub use self::request_id_checker::{BtpRequestIdCheckerStream, PendingRequestIds};
+pub use self::server::{listen, BtpAuthenticator, BtpServer, ServerPlugin};
+pub use self::sub_protocol::{BtpSender, SubProtocolHandler, SubProtocolRegistry};
 pub use errors::ParseError;
 
 use base64;
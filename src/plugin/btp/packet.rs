@@ -23,6 +23,7 @@ enum PacketType {
     Message = 6,
     Response = 1,
     Error = 2,
+    Transfer = 7,
     Unknown,
 }
 impl From<u8> for PacketType {
@@ -31,6 +32,7 @@ impl From<u8> for PacketType {
             6 => PacketType::Message,
             1 => PacketType::Response,
             2 => PacketType::Error,
+            7 => PacketType::Transfer,
             _ => PacketType::Unknown,
         }
     }
@@ -41,6 +43,7 @@ pub enum BtpPacket {
     Message(BtpMessage),
     Response(BtpResponse),
     Error(BtpError),
+    Transfer(BtpTransfer),
 }
 
 impl Serializable<BtpPacket> for BtpPacket {
@@ -49,6 +52,7 @@ impl Serializable<BtpPacket> for BtpPacket {
             PacketType::Message => Ok(BtpPacket::Message(BtpMessage::from_bytes(bytes)?)),
             PacketType::Response => Ok(BtpPacket::Response(BtpResponse::from_bytes(bytes)?)),
             PacketType::Error => Ok(BtpPacket::Error(BtpError::from_bytes(bytes)?)),
+            PacketType::Transfer => Ok(BtpPacket::Transfer(BtpTransfer::from_bytes(bytes)?)),
             PacketType::Unknown => Err(ParseError::InvalidPacket(format!(
                 "Unknown packet type: {}",
                 bytes[0]
@@ -61,6 +65,7 @@ impl Serializable<BtpPacket> for BtpPacket {
             BtpPacket::Message(packet) => packet.to_bytes(),
             BtpPacket::Response(packet) => packet.to_bytes(),
             BtpPacket::Error(packet) => packet.to_bytes(),
+            BtpPacket::Transfer(packet) => packet.to_bytes(),
         }
     }
 }
@@ -255,11 +260,52 @@ impl Serializable<BtpError> for BtpError {
     }
 }
 
+#[derive(Debug, PartialEq, Clone)]
+pub struct BtpTransfer {
+    pub request_id: u32,
+    pub amount: u64,
+    pub protocol_data: Vec<ProtocolData>,
+}
+impl Serializable<BtpTransfer> for BtpTransfer {
+    fn from_bytes(bytes: &[u8]) -> Result<BtpTransfer, ParseError> {
+        let mut reader = Cursor::new(bytes);
+        let packet_type = reader.read_u8()?;
+        if PacketType::from(packet_type) != PacketType::Transfer {
+            return Err(ParseError::InvalidPacket(format!(
+                "Cannot parse Transfer from packet of type {}, expected type {}",
+                packet_type,
+                PacketType::Transfer as u8
+            )));
+        }
+        let request_id = reader.read_u32::<BigEndian>()?;
+        let mut contents = Cursor::new(reader.read_var_octet_string()?);
+        let amount = contents.read_u64::<BigEndian>()?;
+        let protocol_data = read_protocol_data(&mut contents)?;
+        Ok(BtpTransfer {
+            request_id,
+            amount,
+            protocol_data,
+        })
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.put_u8(PacketType::Transfer as u8);
+        buf.put_u32_be(self.request_id);
+        let mut contents = Vec::new();
+        contents.put_u64_be(self.amount);
+        put_protocol_data(&mut contents, &self.protocol_data);
+        buf.put_var_octet_string(&contents);
+        buf
+    }
+}
+
 pub fn deserialize_packet(bytes: &[u8]) -> Result<BtpPacket, ParseError> {
     match PacketType::from(bytes[0]) {
         PacketType::Message => Ok(BtpPacket::Message(BtpMessage::from_bytes(bytes)?)),
         PacketType::Response => Ok(BtpPacket::Response(BtpResponse::from_bytes(bytes)?)),
         PacketType::Error => Ok(BtpPacket::Error(BtpError::from_bytes(bytes)?)),
+        PacketType::Transfer => Ok(BtpPacket::Transfer(BtpTransfer::from_bytes(bytes)?)),
         PacketType::Unknown => Err(ParseError::InvalidPacket(
             "Unable to read BTP packet from bytes".to_string(),
         )),
@@ -339,6 +385,45 @@ mod tests {
         }
     }
 
+    mod btp_transfer {
+        use super::*;
+
+        lazy_static! {
+            static ref TRANSFER_1: BtpTransfer = BtpTransfer {
+                request_id: 1,
+                amount: 100,
+                protocol_data: vec![ProtocolData {
+                    protocol_name: String::from("paychan"),
+                    content_type: ContentType::ApplicationOctetStream,
+                    data: hex::decode("FF").unwrap()
+                }]
+            };
+            static ref TRANSFER_1_SERIALIZED: Vec<u8> =
+                hex::decode("07000000011500000000000000640101077061796368616e0001ff").unwrap();
+        }
+
+        #[test]
+        fn from_bytes() {
+            assert_eq!(
+                BtpTransfer::from_bytes(&TRANSFER_1_SERIALIZED).unwrap(),
+                *TRANSFER_1
+            );
+        }
+
+        #[test]
+        fn to_bytes() {
+            assert_eq!(TRANSFER_1.to_bytes(), *TRANSFER_1_SERIALIZED);
+        }
+
+        #[test]
+        fn deserializes_as_packet() {
+            assert_eq!(
+                deserialize_packet(&TRANSFER_1_SERIALIZED).unwrap(),
+                BtpPacket::Transfer(TRANSFER_1.clone())
+            );
+        }
+    }
+
     mod btp_error {
         use super::*;
 
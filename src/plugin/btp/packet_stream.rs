@@ -28,21 +28,23 @@ where
     type Error = ();
 
     fn poll(&mut self) -> Poll<Option<BtpPacket>, Self::Error> {
-        let poll_result = self
-            .inner
-            .poll()
-            .map_err(|err| error!("Error polling: {:?}", err));
-        if let Some(serialized) = try_ready!(poll_result) {
-            let serialized_vec: Vec<u8> = serialized.into();
-            if let Ok(packet) = deserialize_packet(&serialized_vec) {
-                Ok(Async::Ready(Some(packet)))
+        loop {
+            let poll_result = self
+                .inner
+                .poll()
+                .map_err(|err| error!("Error polling: {:?}", err));
+            if let Some(serialized) = try_ready!(poll_result) {
+                let serialized_vec: Vec<u8> = serialized.into();
+                if let Ok(packet) = deserialize_packet(&serialized_vec) {
+                    return Ok(Async::Ready(Some(packet)));
+                } else {
+                    // Keep polling until the inner stream is NotReady so we are woken up for the next packet
+                    warn!("Ignoring unknown BTP packet {:x?}", &serialized_vec);
+                }
             } else {
-                warn!("Ignoring unknown BTP packet {:x?}", &serialized_vec);
-                Ok(Async::NotReady)
+                trace!("Stream ended");
+                return Ok(Async::Ready(None));
             }
-        } else {
-            trace!("Stream ended");
-            Ok(Async::Ready(None))
         }
     }
 }
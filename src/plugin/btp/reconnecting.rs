@@ -0,0 +1,388 @@
+use super::super::{IlpRequest, Plugin};
+use super::client::connect_with_sub_protocols;
+use super::{BtpSender, ClientPlugin, PluginBtpError, SubProtocolHandler, SubProtocolRegistry};
+use bytes::Bytes;
+use futures::sync::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
+use futures::task::{self, Task};
+use futures::{Async, AsyncSink, Future, Poll, Sink, StartSend, Stream};
+use ilp::{ErrorCode, IlpPacket, IlpReject};
+use parking_lot::{Mutex, RwLock};
+use std::cmp::min;
+use std::collections::{HashSet, VecDeque};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::timer::Delay;
+
+const INITIAL_BACKOFF_MS: u64 = 100;
+const MAX_BACKOFF_SECS: u64 = 30;
+
+/// Emitted by `ReconnectingPlugin::state_changes` when the connection to the server drops or comes back
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PluginState {
+    Connected,
+    Disconnected,
+}
+
+type ConnectFuture<P> = Box<Future<Item = P, Error = PluginBtpError> + Send>;
+type Connector<P> = Arc<Fn() -> ConnectFuture<P> + Send + Sync>;
+
+enum State<P> {
+    Connected(P),
+    // The Mutex is only there so the plugin is Sync, it is never contended
+    Connecting(Mutex<ConnectFuture<P>>),
+}
+
+/// A BTP client that redials the server with exponential backoff whenever the connection drops.
+///
+/// Prepares that were waiting for a response when the connection dropped, or that are sent
+/// while it is down, are rejected with a T01 (Peer Unreachable) error so they can be retried.
+pub struct ReconnectingPlugin<P = ClientPlugin> {
+    connect: Connector<P>,
+    state: State<P>,
+    backoff: Duration,
+    pending_requests: HashSet<u32>,
+    rejects: VecDeque<IlpRequest>,
+    state_listeners: Vec<UnboundedSender<PluginState>>,
+    // The sink side uses this to wake up the task polling for incoming packets
+    stream_task: Option<Task>,
+    // Shared with every connection so handlers do not need to be registered again
+    sub_protocols: Arc<RwLock<SubProtocolRegistry>>,
+    btp_sender: BtpSender,
+}
+
+/// Connect to the BTP server and keep reconnecting to it if the connection drops.
+///
+/// Only the first connection attempt can fail, after that the plugin retries until it succeeds.
+pub fn connect_reconnecting_async(
+    server: &str,
+) -> impl Future<Item = ReconnectingPlugin, Error = PluginBtpError> + 'static + Send {
+    let server = server.to_string();
+    let sub_protocols = Arc::new(RwLock::new(SubProtocolRegistry::new()));
+    connect_with_sub_protocols(&server, Arc::clone(&sub_protocols)).map(move |plugin| {
+        let btp_sender = plugin.btp_sender();
+        let connection_sender = btp_sender.clone();
+        let connection_sub_protocols = Arc::clone(&sub_protocols);
+        let connect: Connector<ClientPlugin> = Arc::new(move || {
+            let btp_sender = connection_sender.clone();
+            let sub_protocols = Arc::clone(&connection_sub_protocols);
+            let connect = connect_with_sub_protocols(&server, sub_protocols).map(move |plugin| {
+                // BTP requests sent from now on go over the new connection
+                btp_sender.redirect(&plugin.btp_sender());
+                plugin
+            });
+            Box::new(connect) as ConnectFuture<ClientPlugin>
+        });
+        ReconnectingPlugin::new(connect, State::Connected(plugin), sub_protocols, btp_sender)
+    })
+}
+
+impl<P> ReconnectingPlugin<P>
+where
+    P: Stream<Item = IlpRequest, Error = ()> + Sink<SinkItem = IlpRequest, SinkError = ()>,
+{
+    fn new(
+        connect: Connector<P>,
+        state: State<P>,
+        sub_protocols: Arc<RwLock<SubProtocolRegistry>>,
+        btp_sender: BtpSender,
+    ) -> Self {
+        ReconnectingPlugin {
+            connect,
+            state,
+            backoff: Duration::from_millis(INITIAL_BACKOFF_MS),
+            pending_requests: HashSet::new(),
+            rejects: VecDeque::new(),
+            state_listeners: Vec::new(),
+            stream_task: None,
+            sub_protocols,
+            btp_sender,
+        }
+    }
+
+    /// Handle incoming BTP Messages and Transfers for a side protocol such as `paychan`.
+    /// The handler keeps being used after the plugin reconnects
+    pub fn register_sub_protocol(&self, protocol_name: &str, handler: SubProtocolHandler) {
+        self.sub_protocols.write().register(protocol_name, handler);
+    }
+
+    /// Used to send BTP Transfers and side protocol Messages over the current connection.
+    /// Requests fail while the server is not connected
+    pub fn btp_sender(&self) -> BtpSender {
+        self.btp_sender.clone()
+    }
+
+    /// Get notified whenever the connection to the server is lost or reestablished
+    pub fn state_changes(&mut self) -> UnboundedReceiver<PluginState> {
+        let (sender, receiver) = unbounded();
+        self.state_listeners.push(sender);
+        receiver
+    }
+
+    pub fn is_connected(&self) -> bool {
+        match self.state {
+            State::Connected(_) => true,
+            State::Connecting(_) => false,
+        }
+    }
+
+    fn notify_state(&mut self, state: PluginState) {
+        self.state_listeners
+            .retain(|listener| listener.unbounded_send(state).is_ok());
+    }
+
+    fn wake_stream(&mut self) {
+        if let Some(task) = self.stream_task.take() {
+            task.notify();
+        }
+    }
+
+    fn reject_request(&mut self, request_id: u32) {
+        let reject = IlpReject::new(
+            ErrorCode::T01PeerUnreachable,
+            "Lost connection to BTP server",
+            "",
+            Bytes::new(),
+        );
+        self.rejects
+            .push_back((request_id, IlpPacket::Reject(reject)));
+        self.wake_stream();
+    }
+
+    fn reconnect(&mut self) {
+        let delay = self.backoff;
+        self.backoff = min(self.backoff * 2, Duration::from_secs(MAX_BACKOFF_SECS));
+        debug!("Reconnecting to BTP server in {:?}", delay);
+        let connect = Arc::clone(&self.connect);
+        let attempt = Delay::new(Instant::now() + delay)
+            .map_err(|err| {
+                PluginBtpError::ConnectionError(format!("Error waiting to reconnect: {:?}", err))
+            })
+            .and_then(move |_| connect());
+        self.state = State::Connecting(Mutex::new(Box::new(attempt)));
+    }
+
+    fn disconnected(&mut self) {
+        if self.is_connected() {
+            warn!("Lost connection to BTP server");
+            self.notify_state(PluginState::Disconnected);
+        }
+        let pending: Vec<u32> = self.pending_requests.drain().collect();
+        for request_id in pending {
+            self.reject_request(request_id);
+        }
+        self.reconnect();
+        self.wake_stream();
+    }
+
+    // Drive the current connection attempt, if there is one
+    fn poll_connect(&mut self) -> Async<()> {
+        loop {
+            let result = match self.state {
+                State::Connected(_) => return Async::Ready(()),
+                State::Connecting(ref attempt) => attempt.lock().poll(),
+            };
+            match result {
+                Ok(Async::Ready(plugin)) => {
+                    info!("Reconnected to BTP server");
+                    self.state = State::Connected(plugin);
+                    self.backoff = Duration::from_millis(INITIAL_BACKOFF_MS);
+                    self.notify_state(PluginState::Connected);
+                    // The other side of the plugin may be waiting for the connection
+                    self.wake_stream();
+                }
+                Ok(Async::NotReady) => return Async::NotReady,
+                Err(err) => {
+                    warn!("Error reconnecting to BTP server: {:?}", err);
+                    self.reconnect();
+                }
+            }
+        }
+    }
+}
+
+impl<P> Plugin for ReconnectingPlugin<P> where P: Plugin {}
+
+impl<P> Stream for ReconnectingPlugin<P>
+where
+    P: Stream<Item = IlpRequest, Error = ()> + Sink<SinkItem = IlpRequest, SinkError = ()>,
+{
+    type Item = IlpRequest;
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        loop {
+            if let Some(reject) = self.rejects.pop_front() {
+                return Ok(Async::Ready(Some(reject)));
+            }
+            self.stream_task = Some(task::current());
+            if let Async::NotReady = self.poll_connect() {
+                return Ok(Async::NotReady);
+            }
+
+            let result = match self.state {
+                State::Connected(ref mut plugin) => plugin.poll(),
+                State::Connecting(_) => continue,
+            };
+            match result {
+                Ok(Async::Ready(Some((request_id, packet)))) => {
+                    match packet {
+                        IlpPacket::Prepare(_) => {}
+                        _ => {
+                            self.pending_requests.remove(&request_id);
+                        }
+                    }
+                    return Ok(Async::Ready(Some((request_id, packet))));
+                }
+                Ok(Async::NotReady) => return Ok(Async::NotReady),
+                Ok(Async::Ready(None)) | Err(_) => self.disconnected(),
+            }
+        }
+    }
+}
+
+impl<P> Sink for ReconnectingPlugin<P>
+where
+    P: Stream<Item = IlpRequest, Error = ()> + Sink<SinkItem = IlpRequest, SinkError = ()>,
+{
+    type SinkItem = IlpRequest;
+    type SinkError = ();
+
+    fn start_send(&mut self, item: Self::SinkItem) -> StartSend<Self::SinkItem, Self::SinkError> {
+        self.poll_connect();
+
+        let request_id = item.0;
+        let is_prepare = match item.1 {
+            IlpPacket::Prepare(_) => true,
+            _ => false,
+        };
+        let result = match self.state {
+            State::Connected(ref mut plugin) => plugin.start_send(item),
+            State::Connecting(_) => {
+                if is_prepare {
+                    debug!(
+                        "Rejecting request {} because the BTP server is not connected",
+                        request_id
+                    );
+                    self.reject_request(request_id);
+                } else {
+                    debug!(
+                        "Dropping response to request {} because the BTP server is not connected",
+                        request_id
+                    );
+                }
+                return Ok(AsyncSink::Ready);
+            }
+        };
+        match result {
+            Ok(AsyncSink::Ready) => {
+                if is_prepare {
+                    self.pending_requests.insert(request_id);
+                }
+                Ok(AsyncSink::Ready)
+            }
+            Ok(AsyncSink::NotReady(item)) => Ok(AsyncSink::NotReady(item)),
+            Err(_) => {
+                self.disconnected();
+                if is_prepare {
+                    self.reject_request(request_id);
+                }
+                Ok(AsyncSink::Ready)
+            }
+        }
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), Self::SinkError> {
+        self.poll_connect();
+
+        let result = match self.state {
+            State::Connected(ref mut plugin) => plugin.poll_complete(),
+            // Nothing is buffered while the server is not connected
+            State::Connecting(_) => return Ok(Async::Ready(())),
+        };
+        if result.is_err() {
+            self.disconnected();
+            return Ok(Async::Ready(()));
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{Duration as ChronoDuration, Utc};
+    use futures::future::{err, lazy, ok, Either};
+    use ilp::{Address, IlpPrepare};
+    use plugin::channel_plugin::{channel_plugin, ChannelPlugin};
+    use tokio::runtime::current_thread::block_on_all;
+
+    fn prepare() -> IlpPacket {
+        IlpPacket::Prepare(IlpPrepare::new(
+            Address::new("example.bob").unwrap(),
+            100,
+            &[0u8; 32][..],
+            Utc::now() + ChronoDuration::seconds(30),
+            Bytes::new(),
+        ))
+    }
+
+    #[test]
+    fn rejects_pending_requests_and_reconnects() {
+        let (first, first_incoming, mut first_outgoing) = channel_plugin();
+        let (second, _second_incoming, _second_outgoing) = channel_plugin();
+        // The first attempt to reconnect fails and the second one succeeds
+        let attempts = Mutex::new(VecDeque::from(vec![None, Some(second)]));
+        let connect: Connector<ChannelPlugin<IlpRequest>> =
+            Arc::new(move || match attempts.lock().pop_front() {
+                Some(Some(plugin)) => {
+                    Box::new(ok(plugin)) as ConnectFuture<ChannelPlugin<IlpRequest>>
+                }
+                _ => Box::new(err(PluginBtpError::ConnectionError(String::from(
+                    "Connection refused",
+                )))),
+            });
+        let mut plugin = ReconnectingPlugin::new(
+            connect,
+            State::Connected(first),
+            Arc::new(RwLock::new(SubProtocolRegistry::new())),
+            BtpSender::new(unbounded().0),
+        );
+        let states = plugin.state_changes();
+
+        let states = block_on_all(
+            lazy(move || {
+                plugin.start_send((1, prepare())).unwrap();
+                assert!(first_outgoing.poll().unwrap().is_ready());
+
+                // The server goes away
+                drop(first_incoming);
+                plugin.into_future().map_err(|(err, _plugin)| err)
+            })
+            .and_then(|(request, plugin)| {
+                match request {
+                    Some((1, IlpPacket::Reject(ref reject))) => {
+                        assert_eq!(reject.code, ErrorCode::T01PeerUnreachable)
+                    }
+                    _ => panic!("Expected Reject"),
+                }
+                assert!(!plugin.is_connected());
+
+                // The plugin needs to be polled to reconnect
+                states
+                    .take(2)
+                    .collect()
+                    .select2(plugin.into_future())
+                    .map_err(|_| ())
+                    .map(|result| match result {
+                        Either::A((states, _plugin)) => states,
+                        Either::B(_) => panic!("Plugin should not get any packets"),
+                    })
+            }),
+        )
+        .unwrap();
+        assert_eq!(
+            states,
+            vec![PluginState::Disconnected, PluginState::Connected]
+        );
+    }
+}
@@ -1,10 +1,69 @@
 use super::BtpPacket;
-use futures::{Async, Poll, Sink, StartSend, Stream};
-use std::collections::HashSet;
+use futures::{Async, AsyncSink, Poll, Sink, StartSend, Stream};
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+// Requests that have not been answered in this long are assumed to be lost
+const STALE_REQUEST_SECS: u64 = 60;
+// Never remember more than this many unanswered requests
+const MAX_PENDING_REQUESTS: usize = 4096;
+
+/// The IDs of outgoing BTP requests that are still waiting for a response.
+///
+/// Shared so that other senders on the same connection can pick IDs that are not in use.
+#[derive(Clone, Default)]
+pub struct PendingRequestIds {
+    ids: Arc<Mutex<HashMap<u32, Instant>>>,
+}
+
+impl PendingRequestIds {
+    pub fn contains(&self, request_id: u32) -> bool {
+        self.ids.lock().contains_key(&request_id)
+    }
+
+    // Returns false if the ID is already in use
+    fn insert(&self, request_id: u32) -> bool {
+        let mut ids = self.ids.lock();
+        if ids.contains_key(&request_id) {
+            return false;
+        }
+        prune(&mut ids);
+        ids.insert(request_id, Instant::now());
+        true
+    }
+
+    fn remove(&self, request_id: u32) -> bool {
+        self.ids.lock().remove(&request_id).is_some()
+    }
+}
+
+// Make room for another request by forgetting the ones that will never get a response
+fn prune(ids: &mut HashMap<u32, Instant>) {
+    if ids.len() < MAX_PENDING_REQUESTS {
+        return;
+    }
+    let max_age = Duration::from_secs(STALE_REQUEST_SECS);
+    ids.retain(|_, sent_at| sent_at.elapsed() < max_age);
+    if ids.len() >= MAX_PENDING_REQUESTS {
+        let oldest = ids
+            .iter()
+            .min_by_key(|(_, sent_at)| **sent_at)
+            .map(|(request_id, _)| *request_id);
+        if let Some(request_id) = oldest {
+            debug!(
+                "Too many pending BTP requests, forgetting request {}",
+                request_id
+            );
+            ids.remove(&request_id);
+        }
+    }
+}
 
 pub struct BtpRequestIdCheckerStream<S> {
     inner: S,
-    outgoing_ids: HashSet<u32>,
+    outgoing_ids: PendingRequestIds,
 }
 
 impl<S> BtpRequestIdCheckerStream<S>
@@ -14,9 +73,13 @@ where
     pub fn new(stream: S) -> Self {
         BtpRequestIdCheckerStream {
             inner: stream,
-            outgoing_ids: HashSet::new(),
+            outgoing_ids: PendingRequestIds::default(),
         }
     }
+
+    pub fn pending_request_ids(&self) -> PendingRequestIds {
+        self.outgoing_ids.clone()
+    }
 }
 
 impl<S> Stream for BtpRequestIdCheckerStream<S>
@@ -27,29 +90,29 @@ where
     type Error = ();
 
     fn poll(&mut self) -> Poll<Option<BtpPacket>, Self::Error> {
-        if let Some(packet) = try_ready!(self.inner.poll()) {
-            match packet {
-                BtpPacket::Message(message) => Ok(Async::Ready(Some(BtpPacket::Message(message)))),
-                BtpPacket::Response(response) => {
-                    if self.outgoing_ids.remove(&response.request_id) {
-                        Ok(Async::Ready(Some(BtpPacket::Response(response))))
-                    } else {
-                        trace!("Ignoring BTP packet because there is no pending request with that ID {:?}", response);
-                        Ok(Async::NotReady)
-                    }
-                }
-                BtpPacket::Error(error) => {
-                    if self.outgoing_ids.remove(&error.request_id) {
-                        Ok(Async::Ready(Some(BtpPacket::Error(error))))
-                    } else {
-                        trace!("Ignoring BTP packet because there is no pending request with that ID {:?}", error);
-                        Ok(Async::NotReady)
-                    }
+        // Skip responses we were not expecting until we get a packet to pass on
+        // or the inner stream is NotReady and will wake us up
+        loop {
+            let packet = match try_ready!(self.inner.poll()) {
+                Some(packet) => packet,
+                None => {
+                    trace!("Stream ended");
+                    return Ok(Async::Ready(None));
                 }
+            };
+            let request_id = match packet {
+                BtpPacket::Response(ref response) => response.request_id,
+                BtpPacket::Error(ref error) => error.request_id,
+                _ => return Ok(Async::Ready(Some(packet))),
+            };
+            if self.outgoing_ids.remove(request_id) {
+                return Ok(Async::Ready(Some(packet)));
+            } else {
+                trace!(
+                    "Ignoring BTP packet because there is no pending request with that ID {:?}",
+                    packet
+                );
             }
-        } else {
-            trace!("Stream ended");
-            Ok(Async::Ready(None))
         }
     }
 }
@@ -62,16 +125,25 @@ where
     type SinkError = ();
 
     fn start_send(&mut self, item: BtpPacket) -> StartSend<Self::SinkItem, Self::SinkError> {
-        if let BtpPacket::Message(message) = &item {
-            if self.outgoing_ids.insert(message.request_id) {
-                trace!("Storing outgoing request ID {}", message.request_id);
-            } else {
-                trace!("Duplicate request ID {}", message.request_id);
+        let request_id = match &item {
+            BtpPacket::Message(message) => Some(message.request_id),
+            BtpPacket::Transfer(transfer) => Some(transfer.request_id),
+            _ => None,
+        };
+        if let Some(request_id) = request_id {
+            if !self.outgoing_ids.insert(request_id) {
+                trace!("Duplicate request ID {}", request_id);
                 return Err(());
             }
+            trace!("Storing outgoing request ID {}", request_id);
         }
 
-        self.inner.start_send(item)
+        let result = self.inner.start_send(item);
+        // The same packet will be sent again when the inner sink is ready
+        if let (Ok(AsyncSink::NotReady(_)), Some(request_id)) = (&result, request_id) {
+            self.outgoing_ids.remove(request_id);
+        }
+        result
     }
 
     fn poll_complete(&mut self) -> Result<Async<()>, Self::SinkError> {
@@ -0,0 +1,379 @@
+use super::super::{IlpRequest, Plugin};
+use super::{
+    BtpError, BtpPacket, BtpPacketStream, BtpRequestIdCheckerStream, BtpResponse, IlpPacketStream,
+    PluginBtpError, SubProtocolRegistry,
+};
+use chrono::Utc;
+use futures::future::{err, Either};
+use futures::stream::FuturesUnordered;
+use futures::{Async, Future, Poll, Sink, StartSend, Stream};
+use ilp::IlpFulfillmentChecker;
+use parking_lot::RwLock;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::timer::{Delay, Timeout};
+use tokio_tcp::{Incoming, TcpListener, TcpStream};
+use tokio_tungstenite::{accept_async as accept_websocket, WebSocketStream};
+
+const HANDSHAKE_TIMEOUT_SECS: u64 = 10;
+const ACCEPT_ERROR_DELAY_MILLIS: u64 = 100;
+
+pub type BtpServerStream = BtpPacketStream<WebSocketStream<TcpStream>>;
+
+/// Called with the `auth_username` and `auth_token` sent by each new peer.
+/// Returning `false` rejects the connection.
+pub type BtpAuthenticator = Box<dyn Fn(&str, &str) -> bool + Send + Sync>;
+
+type Handshake = Box<dyn Future<Item = ServerPlugin, Error = PluginBtpError> + Send>;
+
+pub struct ServerPlugin {
+    username: String,
+    inner: IlpFulfillmentChecker<IlpPacketStream<BtpRequestIdCheckerStream<BtpServerStream>>>,
+}
+
+impl ServerPlugin {
+    fn new(username: String, stream: BtpServerStream) -> Self {
+        let with_id_checker = BtpRequestIdCheckerStream::new(stream);
+        let pending_request_ids = with_id_checker.pending_request_ids();
+        let with_ilp_parsing = IlpPacketStream::with_shared_state(
+            with_id_checker,
+            Arc::new(RwLock::new(SubProtocolRegistry::new())),
+            pending_request_ids,
+        );
+        let with_fulfillment_checker = IlpFulfillmentChecker::new(with_ilp_parsing);
+        ServerPlugin {
+            username,
+            inner: with_fulfillment_checker,
+        }
+    }
+
+    pub fn username(&self) -> &str {
+        &self.username
+    }
+}
+
+impl Plugin for ServerPlugin {}
+
+impl Stream for ServerPlugin {
+    type Item = IlpRequest;
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, ()> {
+        self.inner.poll()
+    }
+}
+
+impl Sink for ServerPlugin {
+    type SinkItem = IlpRequest;
+    type SinkError = ();
+
+    fn start_send(&mut self, item: Self::SinkItem) -> StartSend<Self::SinkItem, Self::SinkError> {
+        self.inner.start_send(item)
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), Self::SinkError> {
+        self.inner.poll_complete()
+    }
+}
+
+/// A Stream of authenticated peers connecting to us over BTP
+pub struct BtpServer {
+    incoming: Incoming,
+    handshakes: FuturesUnordered<Handshake>,
+    authenticator: Arc<BtpAuthenticator>,
+    accept_delay: Option<Delay>,
+    handshake_timeout: Duration,
+}
+
+pub fn listen(
+    addr: &SocketAddr,
+    authenticator: BtpAuthenticator,
+) -> Result<BtpServer, PluginBtpError> {
+    let listener = TcpListener::bind(addr).map_err(|err| {
+        PluginBtpError::ConnectionError(format!("Error binding to {}: {:?}", addr, err))
+    })?;
+    debug!("Listening for BTP connections on: {}", addr);
+    Ok(serve(listener, authenticator))
+}
+
+fn serve(listener: TcpListener, authenticator: BtpAuthenticator) -> BtpServer {
+    BtpServer {
+        incoming: listener.incoming(),
+        handshakes: FuturesUnordered::new(),
+        authenticator: Arc::new(authenticator),
+        accept_delay: None,
+        handshake_timeout: Duration::from_secs(HANDSHAKE_TIMEOUT_SECS),
+    }
+}
+
+impl Stream for BtpServer {
+    type Item = ServerPlugin;
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        // Start the handshake for every new TCP connection
+        let mut incoming_ended = false;
+        loop {
+            // Errors like running out of file descriptors tend to repeat,
+            // so wait a little before trying to accept again
+            if let Some(mut delay) = self.accept_delay.take() {
+                if let Ok(Async::NotReady) = delay.poll() {
+                    self.accept_delay = Some(delay);
+                    break;
+                }
+            }
+            match self.incoming.poll() {
+                Ok(Async::Ready(Some(tcp))) => {
+                    trace!("Accepted TCP connection from: {:?}", tcp.peer_addr());
+                    let handshake = accept_btp_connection(
+                        tcp,
+                        Arc::clone(&self.authenticator),
+                        self.handshake_timeout,
+                    );
+                    self.handshakes.push(Box::new(handshake));
+                }
+                Ok(Async::Ready(None)) => {
+                    incoming_ended = true;
+                    break;
+                }
+                Ok(Async::NotReady) => break,
+                Err(err) => {
+                    error!("Error accepting TCP connection: {:?}", err);
+                    let retry_at =
+                        Instant::now() + Duration::from_millis(ACCEPT_ERROR_DELAY_MILLIS);
+                    self.accept_delay = Some(Delay::new(retry_at));
+                }
+            }
+        }
+
+        // Return the first peer that finished authenticating
+        loop {
+            match self.handshakes.poll() {
+                Ok(Async::Ready(Some(plugin))) => return Ok(Async::Ready(Some(plugin))),
+                Ok(Async::Ready(None)) => {
+                    if incoming_ended {
+                        trace!("Stream ended");
+                        return Ok(Async::Ready(None));
+                    } else {
+                        return Ok(Async::NotReady);
+                    }
+                }
+                Ok(Async::NotReady) => return Ok(Async::NotReady),
+                Err(err) => {
+                    warn!("Rejected incoming BTP connection: {}", err);
+                    continue;
+                }
+            }
+        }
+    }
+}
+
+fn accept_btp_connection(
+    tcp: TcpStream,
+    authenticator: Arc<BtpAuthenticator>,
+    timeout: Duration,
+) -> impl Future<Item = ServerPlugin, Error = PluginBtpError> + Send {
+    let handshake = accept_websocket(tcp)
+        .map_err(|err| {
+            PluginBtpError::ConnectionError(format!("Error accepting websocket: {:?}", err))
+        })
+        .and_then(|ws| Ok(BtpPacketStream::new(ws)))
+        .and_then(move |stream| {
+            stream
+                .into_future()
+                .map_err(|(err, _stream)| {
+                    PluginBtpError::ConnectionError(format!("Error getting auth packet: {:?}", err))
+                }).and_then(move |(packet, stream)| {
+                    let (request_id, username, token) =
+                        match packet.as_ref().and_then(parse_auth_packet) {
+                            Some(auth) => auth,
+                            None => {
+                                return Either::A(err(PluginBtpError::ConnectionError(format!(
+                                    "Expected auth message as first packet, got: {:?}",
+                                    packet
+                                ))))
+                            }
+                        };
+
+                    let authenticated = (authenticator)(&username, &token);
+                    let response = if authenticated {
+                        BtpPacket::Response(BtpResponse {
+                            request_id,
+                            protocol_data: vec![],
+                        })
+                    } else {
+                        BtpPacket::Error(BtpError {
+                            request_id,
+                            code: String::from("F00"),
+                            name: String::from("NotAcceptedError"),
+                            triggered_at: Utc::now(),
+                            data: String::from("invalid auth_token"),
+                            protocol_data: vec![],
+                        })
+                    };
+
+                    Either::B(
+                        stream
+                            .send(response)
+                            .map_err(|err| {
+                                PluginBtpError::ConnectionError(format!(
+                                    "Error sending auth response: {:?}",
+                                    err
+                                ))
+                            }).and_then(move |stream| {
+                                if authenticated {
+                                    info!("Accepted BTP connection from: {}", username);
+                                    Ok(ServerPlugin::new(username, stream))
+                                } else {
+                                    Err(PluginBtpError::ConnectionError(format!(
+                                        "Invalid auth token for user: {}",
+                                        username
+                                    )))
+                                }
+                            }),
+                    )
+                })
+        });
+
+    // Don't let peers that never authenticate hold on to the connection
+    Timeout::new(handshake, timeout).map_err(|err| {
+        if err.is_elapsed() {
+            PluginBtpError::ConnectionError(String::from("Timed out waiting for auth message"))
+        } else if let Some(err) = err.into_inner() {
+            err
+        } else {
+            PluginBtpError::ConnectionError(String::from("Timer error"))
+        }
+    })
+}
+
+// Returns the request ID, username and token from a BTP auth message
+fn parse_auth_packet(packet: &BtpPacket) -> Option<(u32, String, String)> {
+    if let BtpPacket::Message(message) = packet {
+        if message.protocol_data.is_empty() || message.protocol_data[0].protocol_name != "auth" {
+            return None;
+        }
+        let find_string = |name: &str| {
+            message
+                .protocol_data
+                .iter()
+                .find(|entry| entry.protocol_name == name)
+                .and_then(|entry| String::from_utf8(entry.data.clone()).ok())
+        };
+        let username = find_string("auth_username").unwrap_or_else(String::new);
+        let token = find_string("auth_token")?;
+        Some((message.request_id, username, token))
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::client::connect_async;
+    use super::super::{BtpMessage, ContentType, ProtocolData};
+    use super::*;
+    use futures::sync::mpsc::unbounded;
+    use tokio::runtime::current_thread::Runtime;
+    use tokio_io::io::read_to_end;
+
+    // Accepts the token "secret" for any user
+    fn test_server() -> (BtpServer, SocketAddr) {
+        let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = serve(listener, Box::new(|_username, token| token == "secret"));
+        (server, addr)
+    }
+
+    fn auth_message(protocol_data: Vec<ProtocolData>) -> BtpPacket {
+        BtpPacket::Message(BtpMessage {
+            request_id: 7,
+            protocol_data,
+        })
+    }
+
+    fn entry(name: &str, data: &str) -> ProtocolData {
+        ProtocolData {
+            protocol_name: String::from(name),
+            content_type: ContentType::TextPlainUtf8,
+            data: Vec::from(data),
+        }
+    }
+
+    #[test]
+    fn parses_auth_message() {
+        let packet = auth_message(vec![
+            entry("auth", ""),
+            entry("auth_username", "alice"),
+            entry("auth_token", "secret"),
+        ]);
+        assert_eq!(
+            parse_auth_packet(&packet),
+            Some((7, String::from("alice"), String::from("secret")))
+        );
+    }
+
+    #[test]
+    fn username_is_optional() {
+        let packet = auth_message(vec![entry("auth", ""), entry("auth_token", "secret")]);
+        assert_eq!(
+            parse_auth_packet(&packet),
+            Some((7, String::new(), String::from("secret")))
+        );
+    }
+
+    #[test]
+    fn rejects_non_auth_message() {
+        let packet = auth_message(vec![entry("ilp", ""), entry("auth_token", "secret")]);
+        assert_eq!(parse_auth_packet(&packet), None);
+
+        let packet = auth_message(vec![entry("auth", ""), entry("auth_username", "alice")]);
+        assert_eq!(parse_auth_packet(&packet), None);
+    }
+
+    #[test]
+    fn authenticates_client_over_tcp() {
+        let mut runtime = Runtime::new().unwrap();
+        let (server, addr) = test_server();
+        let (accepted_tx, accepted_rx) = unbounded();
+        runtime.spawn(server.for_each(move |plugin| {
+            accepted_tx
+                .unbounded_send(plugin.username().to_string())
+                .unwrap();
+            Ok(())
+        }));
+
+        let invalid = runtime.block_on(connect_async(&format!("btp+ws://bob:wrong@{}", addr)));
+        assert!(invalid.is_err());
+
+        runtime
+            .block_on(connect_async(&format!("btp+ws://alice:secret@{}", addr)))
+            .unwrap();
+        let (username, _) = runtime.block_on(accepted_rx.into_future()).ok().unwrap();
+        assert_eq!(username, Some(String::from("alice")));
+    }
+
+    #[test]
+    fn drops_clients_that_never_authenticate() {
+        let mut runtime = Runtime::new().unwrap();
+        let (mut server, addr) = test_server();
+        server.handshake_timeout = Duration::from_millis(500);
+        runtime.spawn(server.for_each(|_plugin| Ok(())));
+
+        let silent = runtime.block_on(TcpStream::connect(&addr)).unwrap();
+
+        // Other peers can still connect while that handshake is pending
+        runtime
+            .block_on(connect_async(&format!("btp+ws://alice:secret@{}", addr)))
+            .unwrap();
+
+        let read = Timeout::new(read_to_end(silent, Vec::new()), Duration::from_secs(5));
+        let (_silent, data) = runtime.block_on(read).unwrap();
+        assert!(data.is_empty());
+
+        runtime
+            .block_on(connect_async(&format!("btp+ws://alice:secret@{}", addr)))
+            .unwrap();
+    }
+}
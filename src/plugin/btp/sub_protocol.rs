@@ -0,0 +1,224 @@
+use super::{
+    BtpError, BtpMessage, BtpPacket, BtpResponse, BtpTransfer, PluginBtpError, ProtocolData,
+};
+use chrono::Utc;
+use futures::sync::mpsc::UnboundedSender;
+use futures::sync::oneshot;
+use futures::Future;
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::timer::Timeout;
+
+const REQUEST_TIMEOUT_SECS: u64 = 30;
+
+/// Handles incoming BTP Messages and Transfers for one side protocol, such as payment channel claims.
+///
+/// Called with the Transfer amount (0 for Messages) and all of the packet's protocol data.
+/// The protocol data it returns is sent back in the Response and an error is sent back as a BTP Error.
+pub type SubProtocolHandler =
+    Box<dyn Fn(u64, &[ProtocolData]) -> Result<Vec<ProtocolData>, String> + Send + Sync>;
+
+pub(super) type BtpResult = Result<Vec<ProtocolData>, PluginBtpError>;
+pub(super) type OutgoingRequest = (BtpPacket, oneshot::Sender<BtpResult>);
+
+/// Handlers for BTP side protocols, keyed by the name of the packet's first protocol data entry
+#[derive(Default)]
+pub struct SubProtocolRegistry {
+    handlers: HashMap<String, SubProtocolHandler>,
+}
+
+impl SubProtocolRegistry {
+    pub fn new() -> Self {
+        SubProtocolRegistry {
+            handlers: HashMap::new(),
+        }
+    }
+
+    pub fn register(&mut self, protocol_name: &str, handler: SubProtocolHandler) {
+        self.handlers.insert(protocol_name.to_string(), handler);
+    }
+
+    /// Returns the Response or Error to send back for a Message or Transfer
+    pub(super) fn handle(
+        &self,
+        request_id: u32,
+        amount: u64,
+        protocol_data: &[ProtocolData],
+    ) -> BtpPacket {
+        let protocol_name = protocol_data
+            .first()
+            .map(|entry| entry.protocol_name.as_str())
+            .unwrap_or("");
+        let result = match self.handlers.get(protocol_name) {
+            Some(handler) => (handler)(amount, protocol_data),
+            None => Err(format!("Unknown sub-protocol: {}", protocol_name)),
+        };
+        match result {
+            Ok(protocol_data) => BtpPacket::Response(BtpResponse {
+                request_id,
+                protocol_data,
+            }),
+            Err(message) => {
+                debug!(
+                    "Rejecting BTP request {} for sub-protocol {}: {}",
+                    request_id, protocol_name, message
+                );
+                BtpPacket::Error(BtpError {
+                    request_id,
+                    code: String::from("F00"),
+                    name: String::from("NotAcceptedError"),
+                    triggered_at: Utc::now(),
+                    data: message,
+                    protocol_data: vec![],
+                })
+            }
+        }
+    }
+}
+
+/// Sends BTP Transfers and side protocol Messages over a plugin's connection
+#[derive(Clone)]
+pub struct BtpSender {
+    // Replaced when a reconnecting plugin gets a new connection
+    requests: Arc<Mutex<UnboundedSender<OutgoingRequest>>>,
+}
+
+impl BtpSender {
+    pub(super) fn new(requests: UnboundedSender<OutgoingRequest>) -> Self {
+        BtpSender {
+            requests: Arc::new(Mutex::new(requests)),
+        }
+    }
+
+    // Send requests from this sender and all of its clones over the other sender's connection
+    pub(super) fn redirect(&self, other: &BtpSender) {
+        let requests = other.requests.lock().clone();
+        *self.requests.lock() = requests;
+    }
+
+    /// Send a Transfer for the given amount, for example with a payment channel claim as the protocol data.
+    ///
+    /// Resolves to the protocol data from the other side's Response.
+    pub fn send_transfer(
+        &self,
+        amount: u64,
+        protocol_data: Vec<ProtocolData>,
+    ) -> impl Future<Item = Vec<ProtocolData>, Error = PluginBtpError> {
+        self.send(BtpPacket::Transfer(BtpTransfer {
+            // Picked by the connection when the request is sent
+            request_id: 0,
+            amount,
+            protocol_data,
+        }))
+    }
+
+    /// Send a Message for a side protocol and resolve to the protocol data from the other side's Response
+    pub fn send_message(
+        &self,
+        protocol_data: Vec<ProtocolData>,
+    ) -> impl Future<Item = Vec<ProtocolData>, Error = PluginBtpError> {
+        self.send(BtpPacket::Message(BtpMessage {
+            // Picked by the connection when the request is sent
+            request_id: 0,
+            protocol_data,
+        }))
+    }
+
+    fn send(
+        &self,
+        packet: BtpPacket,
+    ) -> impl Future<Item = Vec<ProtocolData>, Error = PluginBtpError> {
+        let (sender, receiver) = oneshot::channel();
+        let result = self.requests.lock().unbounded_send((packet, sender));
+        if result.is_err() {
+            warn!("Cannot send BTP request because the plugin was dropped");
+        }
+        let response = receiver
+            .map_err(|_| PluginBtpError::ConnectionError(String::from("Connection closed")))
+            .and_then(|result| result);
+        Timeout::new(response, Duration::from_secs(REQUEST_TIMEOUT_SECS)).map_err(|err| {
+            if err.is_elapsed() {
+                PluginBtpError::ConnectionError(String::from("Timed out waiting for response"))
+            } else if let Some(err) = err.into_inner() {
+                err
+            } else {
+                PluginBtpError::ConnectionError(String::from("Timer error"))
+            }
+        })
+    }
+}
+
+pub(super) fn error_to_result(error: &BtpError) -> BtpResult {
+    Err(PluginBtpError::RequestError {
+        code: error.code.clone(),
+        name: error.name.clone(),
+        data: error.data.clone(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::ContentType;
+    use super::*;
+    use futures::sync::mpsc::unbounded;
+    use futures::Stream;
+
+    fn claim() -> Vec<ProtocolData> {
+        vec![ProtocolData {
+            protocol_name: String::from("paychan"),
+            content_type: ContentType::ApplicationOctetStream,
+            data: vec![1, 2, 3],
+        }]
+    }
+
+    #[test]
+    fn calls_handler_for_protocol() {
+        let mut registry = SubProtocolRegistry::new();
+        registry.register(
+            "paychan",
+            Box::new(|amount, protocol_data| {
+                assert_eq!(amount, 100);
+                assert_eq!(protocol_data[0].data, vec![1, 2, 3]);
+                Ok(vec![])
+            }),
+        );
+        assert_eq!(
+            registry.handle(5, 100, &claim()),
+            BtpPacket::Response(BtpResponse {
+                request_id: 5,
+                protocol_data: vec![],
+            })
+        );
+    }
+
+    #[test]
+    fn returns_errors_for_unknown_protocols() {
+        let registry = SubProtocolRegistry::new();
+        match registry.handle(5, 100, &claim()) {
+            BtpPacket::Error(error) => {
+                assert_eq!(error.request_id, 5);
+                assert_eq!(error.code, "F00");
+            }
+            _ => panic!("Expected Error"),
+        }
+    }
+
+    #[test]
+    fn sends_over_redirected_connection() {
+        let (first, _first_requests) = unbounded();
+        let (second, second_requests) = unbounded();
+        let sender = BtpSender::new(first);
+        let clone = sender.clone();
+        sender.redirect(&BtpSender::new(second));
+
+        let _response = clone.send_message(claim());
+        match second_requests.wait().next() {
+            Some(Ok((BtpPacket::Message(message), _result_sender))) => {
+                assert_eq!(message.protocol_data, claim())
+            }
+            _ => panic!("Expected Message"),
+        }
+    }
+}
@@ -0,0 +1,61 @@
+use super::{IlpRequest, Plugin};
+use futures::sync::mpsc::{
+    channel, unbounded, Receiver, Sender, UnboundedReceiver, UnboundedSender,
+};
+use futures::{Poll, Sink, StartSend, Stream};
+
+// Stands in for the other side of a plugin or connection in tests
+pub struct ChannelPlugin<T, S = UnboundedSender<T>> {
+    incoming: UnboundedReceiver<T>,
+    outgoing: S,
+}
+
+impl<T, S> Stream for ChannelPlugin<T, S> {
+    type Item = T;
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        self.incoming.poll()
+    }
+}
+
+impl<T, S> Sink for ChannelPlugin<T, S>
+where
+    S: Sink<SinkItem = T>,
+{
+    type SinkItem = T;
+    type SinkError = ();
+
+    fn start_send(&mut self, item: Self::SinkItem) -> StartSend<Self::SinkItem, Self::SinkError> {
+        self.outgoing.start_send(item).map_err(|_| ())
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), Self::SinkError> {
+        self.outgoing.poll_complete().map_err(|_| ())
+    }
+}
+
+impl<S> Plugin for ChannelPlugin<IlpRequest, S> where S: Sink<SinkItem = IlpRequest> + Send + Sync {}
+
+pub fn channel_plugin<T>() -> (ChannelPlugin<T>, UnboundedSender<T>, UnboundedReceiver<T>) {
+    let (incoming_tx, incoming_rx) = unbounded();
+    let (outgoing_tx, outgoing_rx) = unbounded();
+    let plugin = ChannelPlugin {
+        incoming: incoming_rx,
+        outgoing: outgoing_tx,
+    };
+    (plugin, incoming_tx, outgoing_rx)
+}
+
+// The outgoing side is full once it holds `capacity` + 1 items
+pub fn channel_plugin_with_capacity<T>(
+    capacity: usize,
+) -> (ChannelPlugin<T, Sender<T>>, UnboundedSender<T>, Receiver<T>) {
+    let (incoming_tx, incoming_rx) = unbounded();
+    let (outgoing_tx, outgoing_rx) = channel(capacity);
+    let plugin = ChannelPlugin {
+        incoming: incoming_rx,
+        outgoing: outgoing_tx,
+    };
+    (plugin, incoming_tx, outgoing_rx)
+}
@@ -2,6 +2,14 @@ use futures::{Sink, Stream};
 use ilp::IlpPacket;
 
 pub mod btp;
+#[cfg(test)]
+mod channel_plugin;
+mod service;
+
+pub use self::service::{
+    IlpResponse, IncomingPrepareHandler, PluginService, RequestSender, ServiceError,
+    UnwrappedPlugin,
+};
 
 pub type IlpRequest = (u32, IlpPacket);
 pub type PluginStream = Stream<Item = IlpRequest, Error = ()>;
@@ -0,0 +1,409 @@
+use super::{IlpRequest, Plugin};
+use bytes::Bytes;
+use futures::sync::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
+use futures::sync::oneshot;
+use futures::{Async, AsyncSink, Future, Poll, Sink, StartSend, Stream};
+use ilp::{ErrorCode, IlpFulfill, IlpPacket, IlpPrepare, IlpReject};
+use std::collections::{HashMap, VecDeque};
+use stream::random_u32;
+
+// Prepares are rejected if there is no handler and this many are already waiting
+const MAX_UNHANDLED_PREPARES: usize = 64;
+
+/// The Fulfill or Reject the other side sent back for a Prepare
+pub type IlpResponse = Result<IlpFulfill, IlpReject>;
+
+/// Called with each incoming Prepare. The Fulfill or Reject it returns is sent back
+pub type IncomingPrepareHandler = Box<dyn Fn(IlpPrepare) -> IlpResponse + Send + Sync>;
+
+type OutgoingRequest = (IlpPrepare, oneshot::Sender<IlpResponse>);
+
+#[derive(Fail, Debug)]
+pub enum ServiceError {
+    #[fail(display = "Plugin closed before the response arrived")]
+    Disconnected,
+}
+
+/// Drives a plugin so that Prepares can be sent with a `RequestSender` and
+/// resolve to the matching Fulfill or Reject, instead of correlating request IDs by hand.
+///
+/// Outgoing requests are given random IDs that do not conflict with any other pending request.
+/// Incoming Prepares are passed to the handler. If none is set they are kept for whoever
+/// takes the plugin back with `into_inner` (and rejected once too many are waiting).
+/// The future resolves when the plugin closes.
+pub struct PluginService<P> {
+    plugin: P,
+    prepare_handler: Option<IncomingPrepareHandler>,
+    requests_sender: UnboundedSender<OutgoingRequest>,
+    requests: UnboundedReceiver<OutgoingRequest>,
+    pending_requests: HashMap<u32, oneshot::Sender<IlpResponse>>,
+    // Packets waiting for the plugin to be ready
+    outgoing: VecDeque<IlpRequest>,
+    unhandled_prepares: VecDeque<IlpRequest>,
+}
+
+impl<P> PluginService<P>
+where
+    P: Plugin<Item = IlpRequest, Error = (), SinkItem = IlpRequest, SinkError = ()>,
+{
+    pub fn new(plugin: P) -> Self {
+        let (requests_sender, requests) = unbounded();
+        PluginService {
+            plugin,
+            prepare_handler: None,
+            requests_sender,
+            requests,
+            pending_requests: HashMap::new(),
+            outgoing: VecDeque::new(),
+            unhandled_prepares: VecDeque::new(),
+        }
+    }
+
+    pub fn set_prepare_handler(&mut self, handler: IncomingPrepareHandler) {
+        self.prepare_handler = Some(handler);
+    }
+
+    pub fn sender(&self) -> RequestSender {
+        RequestSender {
+            requests: self.requests_sender.clone(),
+        }
+    }
+
+    /// Stop driving the plugin and hand it back.
+    ///
+    /// Responses the service has not sent yet are sent the next time the returned plugin is used
+    /// and Prepares that were not handled are the first packets it returns.
+    /// Requests that are still waiting for a response will fail with `ServiceError::Disconnected`.
+    pub fn into_inner(mut self) -> UnwrappedPlugin<P> {
+        // Our own requests would never be resolved, so only send the responses
+        self.outgoing.retain(|(_request_id, packet)| match packet {
+            IlpPacket::Prepare(_) => false,
+            _ => true,
+        });
+        if !self.pending_requests.is_empty() {
+            warn!(
+                "Failing {} requests that were still waiting for a response",
+                self.pending_requests.len()
+            );
+        }
+        UnwrappedPlugin {
+            plugin: self.plugin,
+            incoming: self.unhandled_prepares,
+            outgoing: self.outgoing,
+        }
+    }
+
+    fn send_outgoing(&mut self) -> Result<(), ()> {
+        send_buffered(&mut self.plugin, &mut self.outgoing)?;
+        self.plugin.poll_complete()?;
+        Ok(())
+    }
+
+    fn handle_prepare(&mut self, request_id: u32, prepare: IlpPrepare) {
+        let response = match self.prepare_handler {
+            Some(ref handler) => (handler)(prepare),
+            None if self.unhandled_prepares.len() < MAX_UNHANDLED_PREPARES => {
+                trace!("Keeping request {} because there is no handler", request_id);
+                self.unhandled_prepares
+                    .push_back((request_id, IlpPacket::Prepare(prepare)));
+                return;
+            }
+            None => Err(IlpReject::new(
+                ErrorCode::F02Unreachable,
+                "",
+                "",
+                Bytes::new(),
+            )),
+        };
+        let response = match response {
+            Ok(fulfill) => IlpPacket::Fulfill(fulfill),
+            Err(reject) => IlpPacket::Reject(reject),
+        };
+        self.outgoing.push_back((request_id, response));
+    }
+
+    fn handle_response(&mut self, request_id: u32, response: IlpResponse) {
+        if let Some(sender) = self.pending_requests.remove(&request_id) {
+            let _ = sender.send(response);
+        } else {
+            warn!(
+                "Ignoring response for unknown request {}: {:?}",
+                request_id, response
+            );
+        }
+    }
+}
+
+impl<P> Future for PluginService<P>
+where
+    P: Plugin<Item = IlpRequest, Error = (), SinkItem = IlpRequest, SinkError = ()>,
+{
+    type Item = ();
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<(), ()> {
+        while let Ok(Async::Ready(Some((prepare, sender)))) = self.requests.poll() {
+            let mut request_id = random_u32();
+            while self.pending_requests.contains_key(&request_id) {
+                request_id = random_u32();
+            }
+            trace!("Sending request {}: {:?}", request_id, prepare);
+            self.pending_requests.insert(request_id, sender);
+            self.outgoing
+                .push_back((request_id, IlpPacket::Prepare(prepare)));
+        }
+
+        loop {
+            self.send_outgoing()?;
+            match try_ready!(self.plugin.poll()) {
+                Some((request_id, IlpPacket::Prepare(prepare))) => {
+                    self.handle_prepare(request_id, prepare)
+                }
+                Some((request_id, IlpPacket::Fulfill(fulfill))) => {
+                    self.handle_response(request_id, Ok(fulfill))
+                }
+                Some((request_id, IlpPacket::Reject(reject))) => {
+                    self.handle_response(request_id, Err(reject))
+                }
+                None => {
+                    debug!("Plugin closed");
+                    self.pending_requests.clear();
+                    return Ok(Async::Ready(()));
+                }
+            }
+        }
+    }
+}
+
+/// Sends Prepares through a `PluginService`
+#[derive(Clone)]
+pub struct RequestSender {
+    requests: UnboundedSender<OutgoingRequest>,
+}
+
+impl RequestSender {
+    /// Send a Prepare and resolve to the Fulfill or Reject sent back for it
+    pub fn send_request(
+        &self,
+        prepare: IlpPrepare,
+    ) -> impl Future<Item = IlpResponse, Error = ServiceError> {
+        let (sender, receiver) = oneshot::channel();
+        if self.requests.unbounded_send((prepare, sender)).is_err() {
+            warn!("Cannot send request because the plugin service was dropped");
+        }
+        receiver.map_err(|_| ServiceError::Disconnected)
+    }
+}
+
+/// The plugin handed back by `PluginService::into_inner`, along with the packets
+/// the service had not passed on yet
+pub struct UnwrappedPlugin<P> {
+    plugin: P,
+    incoming: VecDeque<IlpRequest>,
+    outgoing: VecDeque<IlpRequest>,
+}
+
+impl<P> UnwrappedPlugin<P>
+where
+    P: Plugin<Item = IlpRequest, Error = (), SinkItem = IlpRequest, SinkError = ()>,
+{
+    fn send_outgoing(&mut self) -> Poll<(), ()> {
+        send_buffered(&mut self.plugin, &mut self.outgoing)?;
+        try_ready!(self.plugin.poll_complete());
+        if self.outgoing.is_empty() {
+            Ok(Async::Ready(()))
+        } else {
+            Ok(Async::NotReady)
+        }
+    }
+}
+
+impl<P> Plugin for UnwrappedPlugin<P> where P: Plugin {}
+
+impl<P> Stream for UnwrappedPlugin<P>
+where
+    P: Plugin<Item = IlpRequest, Error = (), SinkItem = IlpRequest, SinkError = ()>,
+{
+    type Item = IlpRequest;
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        // Responses should not wait until the plugin is used to send something else
+        self.send_outgoing()?;
+        if let Some(request) = self.incoming.pop_front() {
+            return Ok(Async::Ready(Some(request)));
+        }
+        self.plugin.poll()
+    }
+}
+
+impl<P> Sink for UnwrappedPlugin<P>
+where
+    P: Plugin<Item = IlpRequest, Error = (), SinkItem = IlpRequest, SinkError = ()>,
+{
+    type SinkItem = IlpRequest;
+    type SinkError = ();
+
+    fn start_send(&mut self, item: Self::SinkItem) -> StartSend<Self::SinkItem, Self::SinkError> {
+        // Keep the packets in order
+        if self.send_outgoing()?.is_not_ready() {
+            return Ok(AsyncSink::NotReady(item));
+        }
+        self.plugin.start_send(item)
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), Self::SinkError> {
+        self.send_outgoing()
+    }
+}
+
+// Pass buffered packets to the plugin until it is not ready for more
+fn send_buffered<P>(plugin: &mut P, outgoing: &mut VecDeque<IlpRequest>) -> Result<(), ()>
+where
+    P: Sink<SinkItem = IlpRequest, SinkError = ()>,
+{
+    while let Some(request) = outgoing.pop_front() {
+        if let AsyncSink::NotReady(request) = plugin.start_send(request)? {
+            outgoing.push_front(request);
+            break;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+    use futures::future::{lazy, ok};
+    use ilp::Address;
+    use plugin::channel_plugin::{channel_plugin, ChannelPlugin};
+    use tokio::runtime::current_thread::block_on_all;
+
+    fn test_service() -> (
+        PluginService<ChannelPlugin<IlpRequest>>,
+        UnboundedSender<IlpRequest>,
+        UnboundedReceiver<IlpRequest>,
+    ) {
+        let (plugin, incoming, outgoing) = channel_plugin();
+        (PluginService::new(plugin), incoming, outgoing)
+    }
+
+    fn prepare() -> IlpPrepare {
+        IlpPrepare::new(
+            Address::new_unchecked(String::from("example.bob")),
+            100,
+            &[0; 32][..],
+            Utc::now(),
+            Bytes::new(),
+        )
+    }
+
+    #[test]
+    fn resolves_requests_with_matching_response() {
+        let (mut service, incoming, outgoing) = test_service();
+        let sender = service.sender();
+        let first = sender.send_request(prepare());
+        let second = sender.send_request(prepare());
+
+        let (first, second) = block_on_all(lazy(move || {
+            assert!(service.poll().unwrap().is_not_ready());
+            outgoing
+                .take(2)
+                .collect()
+                .and_then(move |requests| {
+                    assert_ne!(requests[0].0, requests[1].0);
+                    let reject =
+                        IlpReject::new(ErrorCode::F99ApplicationError, "", "", Bytes::new());
+                    // Respond out of order
+                    incoming
+                        .unbounded_send((requests[1].0, IlpPacket::Reject(reject)))
+                        .unwrap();
+                    incoming
+                        .unbounded_send((
+                            requests[0].0,
+                            IlpPacket::Fulfill(IlpFulfill::new(&[0; 32][..], Bytes::new())),
+                        ))
+                        .unwrap();
+                    assert!(service.poll().unwrap().is_not_ready());
+                    ok(service)
+                })
+                .and_then(|_service| first.join(second).map_err(|_| ()))
+        }))
+        .unwrap();
+        assert!(first.is_ok());
+        assert_eq!(second.unwrap_err().code, ErrorCode::F99ApplicationError);
+    }
+
+    #[test]
+    fn passes_prepares_to_handler() {
+        let (mut service, incoming, outgoing) = test_service();
+        service.set_prepare_handler(Box::new(|prepare| {
+            assert_eq!(prepare.amount, 100);
+            Ok(IlpFulfill::new(&[0; 32][..], Bytes::new()))
+        }));
+        incoming
+            .unbounded_send((5, IlpPacket::Prepare(prepare())))
+            .unwrap();
+
+        let response = block_on_all(lazy(move || {
+            assert!(service.poll().unwrap().is_not_ready());
+            outgoing.into_future().map_err(|_| ())
+        }))
+        .unwrap()
+        .0;
+        match response {
+            Some((5, IlpPacket::Fulfill(_))) => {}
+            _ => panic!("Expected Fulfill"),
+        }
+    }
+
+    #[test]
+    fn fails_pending_requests_when_plugin_closes() {
+        let (mut service, incoming, _outgoing) = test_service();
+        let request = service.sender().send_request(prepare());
+        drop(incoming);
+
+        let result = block_on_all(lazy(move || {
+            assert!(service.poll().unwrap().is_ready());
+            request.then(|result| Ok(result) as Result<_, ()>)
+        }))
+        .unwrap();
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn keeps_prepares_without_handler_for_inner_plugin() {
+        let (mut service, incoming, mut outgoing) = test_service();
+        incoming
+            .unbounded_send((5, IlpPacket::Prepare(prepare())))
+            .unwrap();
+
+        block_on_all(lazy(move || {
+            assert!(service.poll().unwrap().is_not_ready());
+            // Nothing was sent back for the Prepare
+            assert!(outgoing.poll().unwrap().is_not_ready());
+
+            let mut plugin = service.into_inner();
+            match plugin.poll().unwrap() {
+                Async::Ready(Some((5, IlpPacket::Prepare(_)))) => {}
+                _ => panic!("Expected Prepare"),
+            }
+            Ok(()) as Result<(), ()>
+        }))
+        .unwrap();
+    }
+
+    #[test]
+    fn fails_pending_requests_on_into_inner() {
+        let (service, _incoming, _outgoing) = test_service();
+        let request = service.sender().send_request(prepare());
+        drop(service.into_inner());
+
+        let result = block_on_all(request.then(|result| Ok(result) as Result<_, ()>)).unwrap();
+        match result {
+            Err(ServiceError::Disconnected) => {}
+            _ => panic!("Expected request to fail"),
+        }
+    }
+}
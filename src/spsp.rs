@@ -1,14 +1,17 @@
 use bytes::Bytes;
-use futures::{Future, Sink};
+use futures::{Future, IntoFuture, Sink};
 use hyper::header::HeaderName;
 use hyper::service::service_fn;
 use hyper::{Body, Request, Response, Server, StatusCode};
+use ilp::Address;
 use plugin::Plugin;
 use reqwest::async::Client;
 use ring::rand::{SecureRandom, SystemRandom};
 use serde_json;
 use std::sync::Arc;
-use stream::{connect_async as connect_stream, Connection, Error as StreamError, StreamListener};
+use stream::{
+    connect_async as connect_stream, AssetDetails, Connection, Error as StreamError, StreamListener,
+};
 use tokio;
 
 #[derive(Fail, Debug)]
@@ -27,6 +30,15 @@ pub enum Error {
     InvalidPaymentPointerError(String),
 }
 
+/// The outcome of a payment, with amounts in each side's own units
+#[derive(Debug, Clone)]
+pub struct PaymentResult {
+    pub amount_sent: u64,
+    pub amount_delivered: u64,
+    pub source_asset: Option<AssetDetails>,
+    pub destination_asset: Option<AssetDetails>,
+}
+
 #[derive(Debug, Deserialize, Serialize)]
 pub struct SpspResponse {
     destination_account: String,
@@ -76,29 +88,46 @@ where
     S: Plugin + 'static,
 {
     query(server).and_then(|spsp| {
-        connect_stream(plugin, spsp.destination_account, spsp.shared_secret.clone())
-            .map_err(Error::StreamError)
+        Address::new(&spsp.destination_account)
+            .map_err(|err| Error::InvalidResponseError(format!("{:?}", err)))
+            .into_future()
+            .and_then(move |destination_account| {
+                connect_stream(plugin, destination_account, spsp.shared_secret.clone())
+                    .map_err(Error::StreamError)
+            })
     })
 }
 
-pub fn pay<S>(plugin: S, server: &str, source_amount: u64) -> impl Future<Item = u64, Error = Error>
+pub fn pay<S>(
+    plugin: S,
+    server: &str,
+    source_amount: u64,
+) -> impl Future<Item = PaymentResult, Error = Error>
 where
     S: Plugin + 'static,
 {
     connect_async(plugin, server).and_then(move |conn: Connection| {
-        let stream = conn.create_stream();
-        stream
-            .money
-            .clone()
-            .send(source_amount)
+        conn.create_stream()
             .map_err(move |_| Error::SendMoneyError(source_amount))
-            .and_then(move |_| {
-                let total_delivered = stream.money.total_delivered();
-                conn.close()
-                    .or_else(|_err| {
-                        // We don't care if there was an issue closing the connection
-                        Ok(())
-                    }).and_then(move |_| Ok(total_delivered))
+            .and_then(move |stream| {
+                stream
+                    .money
+                    .clone()
+                    .send(source_amount)
+                    .map_err(Error::StreamError)
+                    .and_then(move |_| {
+                        let result = PaymentResult {
+                            amount_sent: stream.money.total_sent(),
+                            amount_delivered: stream.money.total_delivered(),
+                            source_asset: conn.source_asset(),
+                            destination_asset: conn.destination_asset(),
+                        };
+                        conn.close()
+                            .or_else(|_err| {
+                                // We don't care if there was an issue closing the connection
+                                Ok(())
+                            }).and_then(move |_| Ok(result))
+                    })
             })
     })
 }
@@ -126,7 +155,15 @@ where
                     let tag = url.unwrap_or_else(String::new);
 
                     let (destination_account, shared_secret) =
-                        secret_generator.generate_address_and_secret(&tag);
+                        match secret_generator.generate_address_and_secret(&tag) {
+                            Ok(address_and_secret) => address_and_secret,
+                            Err(err) => {
+                                warn!("Unable to generate address for SPSP query {}: {}", tag, err);
+                                return Response::builder()
+                                    .status(StatusCode::BAD_REQUEST)
+                                    .body(Body::empty());
+                            }
+                        };
                     debug!(
                         "Responding to SPSP query {} with address: {}",
                         tag, destination_account
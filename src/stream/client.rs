@@ -1,24 +1,24 @@
 use super::Error;
-use super::{plugin_to_channels, Connection};
+use super::{plugin_to_channels, AssetDetails, Connection};
 use bytes::Bytes;
 use futures::Future;
 use ildcp;
+use ilp::Address;
 use plugin::{IlpRequest, Plugin};
 
-pub fn connect_async<S, T, U>(
+pub fn connect_async<S, U>(
     plugin: S,
-    destination_account: T,
+    destination_account: Address,
     shared_secret: U,
 ) -> impl Future<Item = Connection, Error = Error>
 where
     S: Plugin<Item = IlpRequest, Error = (), SinkItem = IlpRequest, SinkError = ()> + 'static,
-    String: From<T>,
     Bytes: From<U>,
 {
     ildcp::get_config(plugin)
         .map_err(|err| Error::ConnectionError(format!("Error connecting: {}", err)))
         .and_then(move |(config, plugin)| {
-            let client_address: String = config.client_address;
+            let client_address: Address = config.client_address;
 
             let (outgoing_sender, incoming_receiver) = plugin_to_channels(plugin);
             let conn = Connection::new(
@@ -26,10 +26,14 @@ where
                 incoming_receiver,
                 Bytes::from(shared_secret),
                 client_address,
-                String::from(destination_account),
+                destination_account,
                 false,
             );
+            conn.set_source_asset(AssetDetails {
+                code: config.asset_code,
+                scale: config.asset_scale,
+            });
 
-            Ok(conn)
+            conn.handshake()
         })
 }
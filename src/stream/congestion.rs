@@ -1,7 +1,32 @@
+use ilp::ErrorCode;
 use std::cmp::{max, min};
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
 
-pub struct CongestionController {
+/// Decides how much money a Connection may have in flight at once.
+///
+/// The Connection tells the controller about every Prepare it sends and the
+/// response it gets back, and asks it how much it may send in the next packet.
+pub trait CongestionController: Send {
+    fn set_max_packet_amount(&mut self, max_packet_amount: u64);
+
+    /// The maximum amount that can be sent in the next packet
+    fn get_max_amount(&mut self) -> u64;
+
+    fn prepare(&mut self, id: u32, amount: u64);
+
+    fn fulfill(&mut self, id: u32);
+
+    fn reject(&mut self, id: u32, error_code: &ErrorCode);
+
+    /// The total amount currently allowed in flight, if the controller uses a window
+    fn congestion_window(&self) -> Option<u64> {
+        None
+    }
+}
+
+/// Additive Increase, Multiplicative Decrease (AIMD) congestion control, with a slow start phase
+pub struct AimdController {
     state: CongestionState,
     increase_amount: u64,
     decrease_factor: f64,
@@ -18,9 +43,9 @@ enum CongestionState {
     AvoidCongestion,
 }
 
-impl CongestionController {
+impl AimdController {
     pub fn new(start_amount: u64, increase_amount: u64, decrease_factor: f64) -> Self {
-        CongestionController {
+        AimdController {
             state: CongestionState::SlowStart,
             increase_amount,
             decrease_factor,
@@ -30,19 +55,24 @@ impl CongestionController {
             packets: HashMap::new(),
         }
     }
+}
 
-    pub fn default() -> Self {
+impl Default for AimdController {
+    fn default() -> Self {
         // TODO an increase amount of 1000 might be too small if the units are worth very little
         // should it be adjusted based on something like the max packet amount?
         Self::new(1000, 1000, 2.0)
     }
+}
 
-    pub fn set_max_packet_amount(&mut self, max_packet_amount: u64) {
+impl CongestionController for AimdController {
+    fn set_max_packet_amount(&mut self, max_packet_amount: u64) {
         self.max_packet_amount = Some(max_packet_amount);
     }
 
-    pub fn get_max_amount(&mut self) -> u64 {
-        let amount_left_in_window = self.max_in_flight - self.amount_in_flight;
+    fn get_max_amount(&mut self) -> u64 {
+        // A T04 can shrink the window below the amount that is still in flight
+        let amount_left_in_window = self.max_in_flight.saturating_sub(self.amount_in_flight);
         if let Some(max_packet_amount) = self.max_packet_amount {
             min(amount_left_in_window, max_packet_amount)
         } else {
@@ -50,7 +80,7 @@ impl CongestionController {
         }
     }
 
-    pub fn prepare(&mut self, id: u32, amount: u64) {
+    fn prepare(&mut self, id: u32, amount: u64) {
         self.amount_in_flight += amount;
         self.packets.insert(id, amount);
         debug!(
@@ -59,7 +89,7 @@ impl CongestionController {
         );
     }
 
-    pub fn fulfill(&mut self, id: u32) {
+    fn fulfill(&mut self, id: u32) {
         if let Some(amount) = self.packets.remove(&id) {
             self.amount_in_flight -= amount;
 
@@ -83,11 +113,11 @@ impl CongestionController {
         }
     }
 
-    pub fn reject(&mut self, id: u32, error_code: &str) {
+    fn reject(&mut self, id: u32, error_code: &ErrorCode) {
         if let Some(amount) = self.packets.remove(&id) {
             self.amount_in_flight -= amount;
 
-            if error_code == "T04" {
+            if *error_code == ErrorCode::T04InsufficientLiquidity {
                 self.state = CongestionState::AvoidCongestion;
                 self.max_in_flight = max(
                     (self.max_in_flight as f64 / self.decrease_factor).floor() as u64,
@@ -97,6 +127,221 @@ impl CongestionController {
             }
         }
     }
+
+    fn congestion_window(&self) -> Option<u64> {
+        Some(self.max_in_flight)
+    }
+}
+
+// 2 / ln(2), the smallest gain that doubles the delivery rate every round trip
+const STARTUP_GAIN: f64 = 2.885;
+const STEADY_GAIN: f64 = 2.0;
+// Startup ends when the delivery rate stops growing by 25% for this many round trips
+const FULL_RATE_THRESHOLD: f64 = 1.25;
+const FULL_RATE_ROUNDS: u32 = 3;
+const RATE_WINDOW_SECONDS: u64 = 10;
+const MIN_RTT_WINDOW_SECONDS: u64 = 10;
+// Round trips shorter than this are measured as this long
+const MIN_RTT_MILLIS: u64 = 1;
+const T04_RATE_DECREASE: f64 = 0.75;
+
+/// Rate-based congestion control, loosely modeled on BBR.
+///
+/// Instead of growing the window by a fixed amount, this measures how fast money is
+/// being delivered (the amount fulfilled per second) and the minimum round trip time,
+/// and allows roughly twice that bandwidth-delay product to be in flight.
+/// Because it only works with measured rates, it ramps up just as quickly
+/// when the units are worth very little.
+pub struct RateBasedController {
+    startup: bool,
+    start_amount: u64,
+    max_packet_amount: Option<u64>,
+    amount_in_flight: u64,
+    // Total amount fulfilled so far
+    delivered: u64,
+    // id: (amount, sent at, amount delivered when it was sent)
+    packets: HashMap<u32, (u64, Instant, u64)>,
+    // (measured at, round trip time)
+    min_rtt: Option<(Instant, Duration)>,
+    // Recent delivery rate measurements, in units per second
+    rate_samples: VecDeque<(Instant, f64)>,
+    // Used to detect when startup stops increasing the delivery rate
+    full_rate: f64,
+    full_rate_rounds: u32,
+    round_start: Option<Instant>,
+}
+
+impl RateBasedController {
+    pub fn new(start_amount: u64) -> Self {
+        RateBasedController {
+            startup: true,
+            start_amount,
+            max_packet_amount: None,
+            amount_in_flight: 0,
+            delivered: 0,
+            packets: HashMap::new(),
+            min_rtt: None,
+            rate_samples: VecDeque::new(),
+            full_rate: 0.0,
+            full_rate_rounds: 0,
+            round_start: None,
+        }
+    }
+
+    fn max_rate(&self) -> f64 {
+        self.rate_samples
+            .iter()
+            .fold(0.0, |max_rate, (_, rate)| max_rate.max(*rate))
+    }
+
+    fn max_in_flight(&self) -> u64 {
+        let min_rtt = match self.min_rtt {
+            Some((_, min_rtt)) => duration_to_secs(min_rtt),
+            None => return self.start_amount,
+        };
+        let gain = if self.startup {
+            STARTUP_GAIN
+        } else {
+            STEADY_GAIN
+        };
+        let window = (gain * self.max_rate() * min_rtt).floor() as u64;
+        if self.startup {
+            max(window, self.start_amount)
+        } else {
+            max(window, 1)
+        }
+    }
+
+    fn prepare_at(&mut self, id: u32, amount: u64, now: Instant) {
+        self.amount_in_flight += amount;
+        self.packets.insert(id, (amount, now, self.delivered));
+        if self.round_start.is_none() {
+            self.round_start = Some(now);
+        }
+        debug!(
+            "Prepare packet of {}, amount in flight is now: {}",
+            amount, self.amount_in_flight
+        );
+    }
+
+    fn fulfill_at(&mut self, id: u32, now: Instant) {
+        let (amount, sent_at, delivered_at_send) = match self.packets.remove(&id) {
+            Some(packet) => packet,
+            None => return,
+        };
+        self.amount_in_flight -= amount;
+        self.delivered += amount;
+
+        let rtt = max(
+            now.duration_since(sent_at),
+            Duration::from_millis(MIN_RTT_MILLIS),
+        );
+        // The path may have changed, so an old minimum is replaced by newer measurements
+        let min_rtt_window = Duration::from_secs(MIN_RTT_WINDOW_SECONDS);
+        let replace_min_rtt = self
+            .min_rtt
+            .map(|(measured_at, min_rtt)| {
+                rtt <= min_rtt || now.duration_since(measured_at) > min_rtt_window
+            })
+            .unwrap_or(true);
+        if replace_min_rtt {
+            self.min_rtt = Some((now, rtt));
+        }
+
+        // Everything that was fulfilled while this packet was in flight
+        // tells us how fast money is getting through
+        let rate = (self.delivered - delivered_at_send) as f64 / duration_to_secs(rtt);
+        self.rate_samples.push_back((now, rate));
+        let window = Duration::from_secs(RATE_WINDOW_SECONDS);
+        while self.rate_samples.len() > 1 && now.duration_since(self.rate_samples[0].0) > window {
+            self.rate_samples.pop_front();
+        }
+
+        // Check once per round trip whether the rate is still growing
+        let round_elapsed = self
+            .round_start
+            .map(|round_start| now.duration_since(round_start) >= rtt)
+            .unwrap_or(true);
+        if self.startup && round_elapsed {
+            self.round_start = Some(now);
+            let max_rate = self.max_rate();
+            if max_rate >= self.full_rate * FULL_RATE_THRESHOLD {
+                self.full_rate = max_rate;
+                self.full_rate_rounds = 0;
+            } else {
+                self.full_rate_rounds += 1;
+                if self.full_rate_rounds >= FULL_RATE_ROUNDS {
+                    debug!(
+                        "Delivery rate stopped growing at {} per second, leaving startup",
+                        max_rate
+                    );
+                    self.startup = false;
+                }
+            }
+        }
+
+        debug!(
+            "Fulfilled packet of {} in {:?}, max in flight is now: {}",
+            amount,
+            rtt,
+            self.max_in_flight()
+        );
+    }
+}
+
+impl Default for RateBasedController {
+    fn default() -> Self {
+        Self::new(1000)
+    }
+}
+
+impl CongestionController for RateBasedController {
+    fn set_max_packet_amount(&mut self, max_packet_amount: u64) {
+        self.max_packet_amount = Some(max_packet_amount);
+    }
+
+    fn get_max_amount(&mut self) -> u64 {
+        let amount_left_in_window = self.max_in_flight().saturating_sub(self.amount_in_flight);
+        if let Some(max_packet_amount) = self.max_packet_amount {
+            min(amount_left_in_window, max_packet_amount)
+        } else {
+            amount_left_in_window
+        }
+    }
+
+    fn prepare(&mut self, id: u32, amount: u64) {
+        self.prepare_at(id, amount, Instant::now())
+    }
+
+    fn fulfill(&mut self, id: u32) {
+        self.fulfill_at(id, Instant::now())
+    }
+
+    fn reject(&mut self, id: u32, error_code: &ErrorCode) {
+        if let Some((amount, _sent_at, _delivered)) = self.packets.remove(&id) {
+            self.amount_in_flight -= amount;
+
+            // The path cannot handle the rate we were sending at
+            if *error_code == ErrorCode::T04InsufficientLiquidity {
+                self.startup = false;
+                for sample in self.rate_samples.iter_mut() {
+                    sample.1 *= T04_RATE_DECREASE;
+                }
+                debug!(
+                    "Rejected packet with T04 error, decreasing max in flight to: {}",
+                    self.max_in_flight()
+                );
+            }
+        }
+    }
+
+    fn congestion_window(&self) -> Option<u64> {
+        Some(self.max_in_flight())
+    }
+}
+
+fn duration_to_secs(duration: Duration) -> f64 {
+    duration.as_secs() as f64 + f64::from(duration.subsec_nanos()) / 1_000_000_000.0
 }
 
 #[cfg(test)]
@@ -108,7 +353,7 @@ mod tests {
 
         #[test]
         fn doubles_max_amount_on_fulfill() {
-            let mut controller = CongestionController::new(1000, 1000, 2.0);
+            let mut controller = AimdController::new(1000, 1000, 2.0);
 
             let amount = controller.get_max_amount();
             controller.prepare(1, amount);
@@ -132,7 +377,7 @@ mod tests {
 
         #[test]
         fn additive_increase() {
-            let mut controller = CongestionController::new(1000, 1000, 2.0);
+            let mut controller = AimdController::new(1000, 1000, 2.0);
             controller.state = CongestionState::AvoidCongestion;
             for i in 1..5 {
                 controller.prepare(i as u32, i * 1000);
@@ -143,23 +388,37 @@ mod tests {
 
         #[test]
         fn multiplicative_decrease() {
-            let mut controller = CongestionController::new(1000, 1000, 2.0);
+            let mut controller = AimdController::new(1000, 1000, 2.0);
             controller.state = CongestionState::AvoidCongestion;
 
             let amount = controller.get_max_amount();
             controller.prepare(1, amount);
-            controller.reject(1, "T04");
+            controller.reject(1, &ErrorCode::T04InsufficientLiquidity);
             assert_eq!(controller.get_max_amount(), 500);
 
             let amount = controller.get_max_amount();
             controller.prepare(2, amount);
-            controller.reject(2, "T04");
+            controller.reject(2, &ErrorCode::T04InsufficientLiquidity);
             assert_eq!(controller.get_max_amount(), 250);
         }
 
+        #[test]
+        fn window_smaller_than_amount_in_flight() {
+            let mut controller = AimdController::new(1000, 1000, 2.0);
+            controller.state = CongestionState::AvoidCongestion;
+
+            controller.prepare(1, 250);
+            controller.prepare(2, 750);
+            controller.reject(1, &ErrorCode::T04InsufficientLiquidity);
+            assert_eq!(controller.get_max_amount(), 0);
+
+            controller.fulfill(2);
+            assert_eq!(controller.get_max_amount(), 1500);
+        }
+
         #[test]
         fn aimd_combined() {
-            let mut controller = CongestionController::new(1000, 1000, 2.0);
+            let mut controller = AimdController::new(1000, 1000, 2.0);
             controller.state = CongestionState::AvoidCongestion;
 
             let amount = controller.get_max_amount();
@@ -174,7 +433,7 @@ mod tests {
 
             let amount = controller.get_max_amount();
             controller.prepare(3, amount);
-            controller.reject(3, "T04");
+            controller.reject(3, &ErrorCode::T04InsufficientLiquidity);
             assert_eq!(controller.get_max_amount(), 1500);
 
             let amount = controller.get_max_amount();
@@ -185,7 +444,7 @@ mod tests {
 
         #[test]
         fn max_packet_amount() {
-            let mut controller = CongestionController::new(1000, 1000, 2.0);
+            let mut controller = AimdController::new(1000, 1000, 2.0);
             controller.set_max_packet_amount(100);
 
             assert_eq!(controller.get_max_amount(), 100);
@@ -207,7 +466,7 @@ mod tests {
 
         #[test]
         fn tracking_amount_in_flight() {
-            let mut controller = CongestionController::new(1000, 1000, 2.0);
+            let mut controller = AimdController::new(1000, 1000, 2.0);
             controller.set_max_packet_amount(600);
             assert_eq!(controller.get_max_amount(), 600);
 
@@ -216,6 +475,87 @@ mod tests {
 
             controller.prepare(2, 600);
             assert_eq!(controller.get_max_amount(), 1000 - 700);
+            assert_eq!(controller.congestion_window(), Some(1000));
+        }
+    }
+
+    mod rate_based {
+        use super::*;
+
+        #[test]
+        fn starts_with_start_amount() {
+            let mut controller = RateBasedController::new(1000);
+            assert_eq!(controller.get_max_amount(), 1000);
+            controller.prepare(1, 400);
+            assert_eq!(controller.get_max_amount(), 600);
+        }
+
+        #[test]
+        fn grows_with_measured_rate() {
+            let mut controller = RateBasedController::new(1000);
+            let start = Instant::now();
+            let rtt = Duration::from_millis(100);
+
+            // Each round trip delivers everything that was allowed in flight
+            let mut now = start;
+            for i in 0..5 {
+                let amount = controller.get_max_amount();
+                controller.prepare_at(i, amount, now);
+                now += rtt;
+                controller.fulfill_at(i, now);
+            }
+            assert!(controller.startup);
+            assert!(controller.get_max_amount() > 10_000);
+        }
+
+        #[test]
+        fn decreases_on_t04() {
+            let mut controller = RateBasedController::new(1000);
+            let start = Instant::now();
+            controller.prepare_at(1, 1000, start);
+            controller.fulfill_at(1, start + Duration::from_millis(100));
+            let before = controller.get_max_amount();
+
+            controller.prepare(2, 100);
+            controller.reject(2, &ErrorCode::T04InsufficientLiquidity);
+            assert!(!controller.startup);
+            assert!(controller.get_max_amount() < before);
+        }
+
+        #[test]
+        fn clamps_min_rtt() {
+            let mut controller = RateBasedController::new(1000);
+            let now = Instant::now();
+            controller.prepare_at(1, 1000, now);
+            controller.fulfill_at(1, now);
+            assert_eq!(
+                controller.min_rtt.map(|(_, min_rtt)| min_rtt),
+                Some(Duration::from_millis(1))
+            );
+            assert!(controller.get_max_amount() > 0);
+        }
+
+        #[test]
+        fn replaces_min_rtt_after_window() {
+            let mut controller = RateBasedController::new(1000);
+            let start = Instant::now();
+            controller.prepare_at(1, 100, start);
+            controller.fulfill_at(1, start + Duration::from_millis(10));
+
+            let later = start + Duration::from_secs(MIN_RTT_WINDOW_SECONDS + 1);
+            controller.prepare_at(2, 100, later);
+            controller.fulfill_at(2, later + Duration::from_millis(100));
+            assert_eq!(
+                controller.min_rtt.map(|(_, min_rtt)| min_rtt),
+                Some(Duration::from_millis(100))
+            );
+        }
+
+        #[test]
+        fn respects_max_packet_amount() {
+            let mut controller = RateBasedController::new(1000);
+            controller.set_max_packet_amount(100);
+            assert_eq!(controller.get_max_amount(), 100);
         }
     }
 }
@@ -1,27 +1,48 @@
-use super::congestion::CongestionController;
+use super::congestion::{AimdController, CongestionController};
 use super::crypto::{
     fulfillment_to_condition, generate_condition, generate_fulfillment, random_condition,
     random_u32,
 };
-use super::data_money_stream::DataMoneyStream;
+use super::data_money_stream::{DataMoneyStream, DEFAULT_STREAM_RECEIVE_WINDOW};
 use super::packet::*;
-use super::StreamPacket;
+use super::receipt::{random_receipt_nonce, Receipt, RECEIPT_NONCE_LENGTH};
+use super::stats::ConnectionStats;
+use super::{Error, StreamPacket};
 use bytes::{Bytes, BytesMut};
-use chrono::{Duration, Utc};
+use chrono::{DateTime, Duration, Utc};
+use futures::executor::{spawn, Notify, NotifyHandle, Spawn};
 use futures::sync::mpsc::{UnboundedReceiver, UnboundedSender};
 use futures::task;
 use futures::task::Task;
 use futures::{Async, Future, Poll, Stream};
 use hex;
-use ilp::{parse_f08_error, IlpFulfill, IlpPacket, IlpPrepare, IlpReject, PacketType};
+use ilp::{
+    parse_f08_error, Address, ErrorCode as IlpErrorCode, IlpFulfill, IlpPacket, IlpPrepare,
+    IlpReject, MaxPacketAmountDetails, PacketType,
+};
 use num_bigint::BigUint;
 use num_traits::ToPrimitive;
 use parking_lot::{Mutex, RwLock};
 use plugin::IlpRequest;
-use std::cmp::min;
+use std::cmp::{max, min};
 use std::collections::{HashMap, HashSet, VecDeque};
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
+use std::time::{Duration as StdDuration, Instant};
+use tokio::timer::Delay;
+
+lazy_static! {
+    static ref DEFAULT_PACKET_EXPIRY: Duration = Duration::seconds(30);
+}
+// Larger test packets measure the exchange rate more precisely but may exceed the path's max packet amount
+const PROBE_AMOUNTS: [u64; 3] = [1_000, 1_000_000, 1_000_000_000];
+
+/// Give up on sending a stream's remaining money after this many packets in a row are rejected
+const MAX_FAILED_MONEY_PACKETS: usize = 20;
+/// How many bytes the other side may send across all streams beyond what has been read
+const CONNECTION_RECEIVE_WINDOW: usize = 16 * DEFAULT_STREAM_RECEIVE_WINDOW;
+/// How many streams the other side may have open at once
+pub const DEFAULT_MAX_REMOTE_STREAMS: u64 = 10;
 
 pub struct CloseFuture {
     conn: Connection,
@@ -46,6 +67,86 @@ impl Future for CloseFuture {
     }
 }
 
+pub struct HandshakeFuture {
+    conn: Connection,
+}
+
+impl Future for HandshakeFuture {
+    type Item = Connection;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        self.conn
+            .try_handle_incoming()
+            .map_err(|_| Error::ConnectionError(String::from("Error handling incoming packets")))?;
+
+        if let Some(ref err) = *self.conn.handshake_error.lock() {
+            return Err(err.clone());
+        }
+        if self.conn.state.load(Ordering::SeqCst) == ConnectionState::Open as usize {
+            debug!("Handshake complete, connection is open");
+            Ok(Async::Ready(self.conn.clone()))
+        } else if self.conn.is_closed() {
+            Err(Error::ConnectionError(String::from(
+                "Connection closed before the handshake completed",
+            )))
+        } else {
+            Ok(Async::NotReady)
+        }
+    }
+}
+
+pub struct CreateStreamFuture {
+    conn: Connection,
+}
+
+impl Future for CreateStreamFuture {
+    type Item = DataMoneyStream;
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        if let Some(stream) = self.conn.try_create_stream() {
+            return Ok(Async::Ready(stream));
+        }
+        if self.conn.is_closed() {
+            warn!("Connection closed before the other side allowed us to open another stream");
+            return Err(());
+        }
+
+        (*self.conn.stream_limits.lock()).blocked_task = Some(task::current());
+        self.conn.try_handle_incoming()?;
+        if let Some(stream) = self.conn.try_create_stream() {
+            return Ok(Async::Ready(stream));
+        }
+        // Send the ConnectionStreamIdBlocked frame
+        self.conn.try_send()?;
+        Ok(Async::NotReady)
+    }
+}
+
+pub struct ExchangeRateFuture {
+    conn: Connection,
+}
+
+impl Future for ExchangeRateFuture {
+    type Item = f64;
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        self.conn.try_handle_incoming()?;
+
+        if !(*self.conn.pending_probes.lock()).is_empty() {
+            return Ok(Async::NotReady);
+        }
+        if let Some(rate) = self.conn.exchange_rate() {
+            Ok(Async::Ready(rate))
+        } else {
+            warn!("Unable to determine exchange rate, none of the test packets got through");
+            Err(())
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct Connection {
     // TODO is it okay for this to be an AtomicUsize instead of a RwLock around the enum?
@@ -55,8 +156,8 @@ pub struct Connection {
     outgoing: UnboundedSender<IlpRequest>,
     incoming: Arc<Mutex<UnboundedReceiver<IlpRequest>>>,
     shared_secret: Bytes,
-    source_account: Arc<String>,
-    destination_account: Arc<String>,
+    source_account: Arc<RwLock<Address>>,
+    destination_account: Arc<RwLock<Address>>,
     next_stream_id: Arc<AtomicUsize>,
     next_packet_sequence: Arc<AtomicUsize>,
     streams: Arc<RwLock<HashMap<u64, DataMoneyStream>>>,
@@ -64,15 +165,142 @@ pub struct Connection {
     pending_outgoing_packets: Arc<Mutex<HashMap<u32, OutgoingPacketRecord>>>,
     new_streams: Arc<Mutex<VecDeque<u64>>>,
     frames_to_resend: Arc<Mutex<Vec<Frame>>>,
+    failed_money_packets: Arc<AtomicUsize>,
     // This is used to wake the task polling for incoming streams
     recv_task: Arc<Mutex<Option<Task>>>,
-    // TODO add connection-level stats
-    congestion_controller: Arc<Mutex<CongestionController>>,
+    stats: Arc<Mutex<ConnectionStats>>,
+    congestion_controller: Arc<Mutex<Box<dyn CongestionController>>>,
+    receipt_config: Arc<RwLock<Option<ReceiptConfig>>>,
+    data_limits: Arc<Mutex<DataLimits>>,
+    stream_limits: Arc<Mutex<StreamLimits>>,
+    packet_expiry: Arc<RwLock<Duration>>,
+    // Set for the earliest expiry of the packets we are waiting on
+    expiry_timer: Arc<Mutex<Option<(DateTime<Utc>, Spawn<Delay>)>>>,
+    expiry_waiters: Arc<ExpiryWaiters>,
+    // Packets that expired locally, in case the other side fulfills them anyway
+    expired_packets: Arc<Mutex<HashMap<u32, OutgoingPacketRecord>>>,
+    pending_probes: Arc<Mutex<HashMap<u32, ProbeRecord>>>,
+    exchange_rate: Arc<RwLock<ExchangeRate>>,
+    pending_handshake: Arc<Mutex<Option<HandshakeRecord>>>,
+    handshake_error: Arc<Mutex<Option<Error>>>,
+    source_asset: Arc<RwLock<Option<AssetDetails>>>,
+    destination_asset: Arc<RwLock<Option<AssetDetails>>>,
+}
+
+/// The asset an account is denominated in, as reported by ILDCP
+/// or the other side's ConnectionAssetDetails frame
+#[derive(Debug, Clone, PartialEq)]
+pub struct AssetDetails {
+    pub code: String,
+    pub scale: u8,
+}
+
+impl AssetDetails {
+    /// Format an amount in this asset's base units, for example 1000 with scale 2 as "10.00 USD"
+    pub fn format_amount(&self, amount: u64) -> String {
+        let scale = self.scale as usize;
+        if scale == 0 {
+            return format!("{} {}", amount, self.code);
+        }
+        let digits = format!("{:0>width$}", amount, width = scale + 1);
+        let (whole, fraction) = digits.split_at(digits.len() - scale);
+        format!("{}.{} {}", whole, fraction, self.code)
+    }
+}
+
+struct HandshakeRecord {
+    request_id: u32,
+    sequence: u64,
+    expires_at: DateTime<Utc>,
+}
+
+struct ProbeRecord {
+    sequence: u64,
+    amount: u64,
+    expires_at: DateTime<Utc>,
+}
+
+#[derive(Default)]
+struct ExchangeRate {
+    // Measured rate and the source amount of the probe it came from
+    probed: Option<(f64, u64)>,
+    min_rate: Option<f64>,
+    max_slippage: Option<f64>,
+}
+
+impl ExchangeRate {
+    // The lowest rate we accept for outgoing money, if any
+    fn min_acceptable(&self) -> Option<f64> {
+        let from_slippage = match (self.probed, self.max_slippage) {
+            (Some((rate, _)), Some(slippage)) => Some(rate * (1.0 - slippage)),
+            _ => None,
+        };
+        match (self.min_rate, from_slippage) {
+            (Some(min_rate), Some(from_slippage)) => Some(min_rate.max(from_slippage)),
+            (min_rate, from_slippage) => min_rate.or(from_slippage),
+        }
+    }
+}
+
+// Connection-level flow control, counted in bytes across all streams
+struct DataLimits {
+    incoming_total: usize,
+    // Zero until we have told the other side our limit
+    advertised_max_data: usize,
+    advertise_requested: bool,
+    outgoing_total: usize,
+    remote_max_data: usize,
+    sent_blocked: bool,
+}
+
+// Limits on how many streams each side may open
+struct StreamLimits {
+    max_remote_streams: u64,
+    highest_remote_stream_id: u64,
+    // Zero until we have told the other side our limit
+    advertised_max_stream_id: u64,
+    advertise_requested: bool,
+    remote_max_stream_id: u64,
+    sent_blocked: bool,
+    // The task waiting to open a stream
+    blocked_task: Option<Task>,
+}
+
+struct ReceiptConfig {
+    nonce: [u8; RECEIPT_NONCE_LENGTH],
+    secret: Bytes,
 }
 
+#[derive(Clone)]
 struct OutgoingPacketRecord {
     original_amount: u64,
     original_packet: StreamPacket,
+    expires_at: DateTime<Utc>,
+    sent_at: Instant,
+}
+
+// Every future and stream that polls the connection waits on the expiry timer,
+// so it wakes all of them rather than only the last task to poll it
+#[derive(Default)]
+struct ExpiryWaiters {
+    tasks: Mutex<Vec<Task>>,
+}
+
+impl ExpiryWaiters {
+    fn register_current(&self) {
+        let mut tasks = self.tasks.lock();
+        if !tasks.iter().any(|task| task.will_notify_current()) {
+            tasks.push(task::current());
+        }
+    }
+}
+
+impl Notify for ExpiryWaiters {
+    fn notify(&self, _id: usize) {
+        for task in self.tasks.lock().drain(..) {
+            task.notify();
+        }
+    }
 }
 
 #[derive(PartialEq, Eq, Debug)]
@@ -90,8 +318,8 @@ impl Connection {
         outgoing: UnboundedSender<IlpRequest>,
         incoming: UnboundedReceiver<IlpRequest>,
         shared_secret: Bytes,
-        source_account: String,
-        destination_account: String,
+        source_account: Address,
+        destination_account: Address,
         is_server: bool,
     ) -> Self {
         let next_stream_id = if is_server { 2 } else { 1 };
@@ -101,8 +329,8 @@ impl Connection {
             outgoing,
             incoming: Arc::new(Mutex::new(incoming)),
             shared_secret,
-            source_account: Arc::new(source_account),
-            destination_account: Arc::new(destination_account),
+            source_account: Arc::new(RwLock::new(source_account)),
+            destination_account: Arc::new(RwLock::new(destination_account)),
             next_stream_id: Arc::new(AtomicUsize::new(next_stream_id)),
             next_packet_sequence: Arc::new(AtomicUsize::new(1)),
             streams: Arc::new(RwLock::new(HashMap::new())),
@@ -110,28 +338,170 @@ impl Connection {
             pending_outgoing_packets: Arc::new(Mutex::new(HashMap::new())),
             new_streams: Arc::new(Mutex::new(VecDeque::new())),
             frames_to_resend: Arc::new(Mutex::new(Vec::new())),
+            failed_money_packets: Arc::new(AtomicUsize::new(0)),
             recv_task: Arc::new(Mutex::new(None)),
-            congestion_controller: Arc::new(Mutex::new(CongestionController::default())),
+            stats: Arc::new(Mutex::new(ConnectionStats::default())),
+            congestion_controller: Arc::new(Mutex::new(Box::new(AimdController::default()))),
+            receipt_config: Arc::new(RwLock::new(None)),
+            data_limits: Arc::new(Mutex::new(DataLimits {
+                incoming_total: 0,
+                advertised_max_data: 0,
+                advertise_requested: false,
+                outgoing_total: 0,
+                // The other side's limit is unknown until they tell us
+                remote_max_data: usize::max_value(),
+                sent_blocked: false,
+            })),
+            stream_limits: Arc::new(Mutex::new(StreamLimits {
+                max_remote_streams: DEFAULT_MAX_REMOTE_STREAMS,
+                highest_remote_stream_id: 0,
+                advertised_max_stream_id: 0,
+                advertise_requested: false,
+                // The other side's limit is unknown until they tell us
+                remote_max_stream_id: u64::max_value(),
+                sent_blocked: false,
+                blocked_task: None,
+            })),
+            packet_expiry: Arc::new(RwLock::new(*DEFAULT_PACKET_EXPIRY)),
+            expiry_timer: Arc::new(Mutex::new(None)),
+            expiry_waiters: Arc::new(ExpiryWaiters::default()),
+            expired_packets: Arc::new(Mutex::new(HashMap::new())),
+            pending_probes: Arc::new(Mutex::new(HashMap::new())),
+            exchange_rate: Arc::new(RwLock::new(ExchangeRate::default())),
+            pending_handshake: Arc::new(Mutex::new(None)),
+            handshake_error: Arc::new(Mutex::new(None)),
+            source_asset: Arc::new(RwLock::new(None)),
+            destination_asset: Arc::new(RwLock::new(None)),
         };
 
-        // TODO figure out a better way to send the initial packet - get the exchange rate and wait for response
-        if !is_server {
-            conn.send_handshake();
+        // The client's connection only opens once the receiver replies to the handshake
+        if is_server {
+            conn.state
+                .store(ConnectionState::Open as usize, Ordering::SeqCst);
         }
 
-        // TODO wait for handshake reply before setting state to open
-        conn.state
-            .store(ConnectionState::Open as usize, Ordering::SeqCst);
-
         conn
     }
 
-    pub fn create_stream(&self) -> DataMoneyStream {
-        let id = self.next_stream_id.fetch_add(2, Ordering::SeqCst) as u64;
+    /// Send the handshake packet and wait for the receiver to respond to it.
+    ///
+    /// Fails if the receiver is unreachable or could not decrypt the packet.
+    pub fn handshake(&self) -> HandshakeFuture {
+        self.send_handshake();
+        HandshakeFuture { conn: self.clone() }
+    }
+
+    /// Open a new stream, waiting if the other side does not allow any more streams yet
+    pub fn create_stream(&self) -> CreateStreamFuture {
+        CreateStreamFuture { conn: self.clone() }
+    }
+
+    fn try_create_stream(&self) -> Option<DataMoneyStream> {
+        let mut stream_limits = self.stream_limits.lock();
+        let id = self.next_stream_id.load(Ordering::SeqCst) as u64;
+        if id > stream_limits.remote_max_stream_id {
+            if !stream_limits.sent_blocked {
+                debug!(
+                    "Cannot open stream {}, the remote max stream id is {}",
+                    id, stream_limits.remote_max_stream_id
+                );
+                stream_limits.sent_blocked = true;
+                (*self.frames_to_resend.lock()).push(Frame::ConnectionStreamIdBlocked(
+                    ConnectionStreamIdBlockedFrame {
+                        max_stream_id: BigUint::from(stream_limits.remote_max_stream_id),
+                    },
+                ));
+            }
+            return None;
+        }
+        self.next_stream_id.fetch_add(2, Ordering::SeqCst);
+        drop(stream_limits);
+
         let stream = DataMoneyStream::new(id, self.clone());
         (*self.streams.write()).insert(id, stream.clone());
         debug!("Created stream {}", id);
-        stream
+        Some(stream)
+    }
+
+    /// Limit how many streams the other side may have open at once
+    pub fn set_max_remote_streams(&self, max_remote_streams: u64) {
+        (*self.stream_limits.lock()).max_remote_streams = max_remote_streams;
+    }
+
+    pub fn source_account(&self) -> Address {
+        self.source_account.read().clone()
+    }
+
+    pub fn destination_account(&self) -> Address {
+        self.destination_account.read().clone()
+    }
+
+    /// Change our address and tell the other side to send all subsequent packets to it.
+    ///
+    /// This should be called when our address changes, for example after
+    /// re-running ILDCP when the upstream connection is re-established.
+    pub fn set_source_account(&self, source_account: Address) -> Result<(), ()> {
+        debug!("Changing source account to {}", source_account);
+        *self.source_account.write() = source_account.clone();
+        (*self.frames_to_resend.lock()).push(Frame::ConnectionNewAddress(
+            ConnectionNewAddressFrame { source_account },
+        ));
+        self.try_send()
+    }
+
+    /// Send unfulfillable test packets to find out the exchange rate to the receiver.
+    ///
+    /// The future resolves to the rate measured with the largest test packet that got through
+    pub fn probe_exchange_rate(&self) -> ExchangeRateFuture {
+        for amount in PROBE_AMOUNTS.iter() {
+            self.send_probe(*amount);
+        }
+        ExchangeRateFuture { conn: self.clone() }
+    }
+
+    /// The exchange rate measured by `probe_exchange_rate`, as destination units per source unit
+    pub fn exchange_rate(&self) -> Option<f64> {
+        self.exchange_rate.read().probed.map(|(rate, _)| rate)
+    }
+
+    /// The receiver will reject packets that deliver less than this rate
+    pub fn set_min_exchange_rate(&self, min_rate: f64) {
+        self.exchange_rate.write().min_rate = Some(min_rate);
+    }
+
+    /// The receiver will reject packets that deliver less than the probed rate minus this fraction
+    /// (for example, 0.01 allows 1% slippage). This only applies once the rate has been probed
+    pub fn set_max_slippage(&self, max_slippage: f64) {
+        self.exchange_rate.write().max_slippage = Some(max_slippage);
+    }
+
+    /// Replace the algorithm used to decide how much money to send at once.
+    /// This should be called before sending any money on the connection
+    pub fn set_congestion_controller(&self, congestion_controller: Box<dyn CongestionController>) {
+        *self.congestion_controller.lock() = congestion_controller;
+    }
+
+    /// Set how long each outgoing Prepare is valid for.
+    /// Packets that get no response before they expire are treated as rejected and their money and data is sent again
+    pub fn set_packet_expiry(&self, packet_expiry: Duration) {
+        *self.packet_expiry.write() = packet_expiry;
+    }
+
+    /// Sign a receipt for the total received on each stream whenever we fulfill a Prepare.
+    ///
+    /// Each connection uses a random nonce, which the verifier can use to tell connections apart.
+    pub fn enable_receipts(&self, receipt_secret: Bytes) {
+        *self.receipt_config.write() = Some(ReceiptConfig {
+            nonce: random_receipt_nonce(),
+            secret: receipt_secret,
+        });
+    }
+
+    pub fn receipt_nonce(&self) -> Option<[u8; RECEIPT_NONCE_LENGTH]> {
+        self.receipt_config
+            .read()
+            .as_ref()
+            .map(|config| config.nonce)
     }
 
     pub fn close(&self) -> CloseFuture {
@@ -146,6 +516,56 @@ impl Connection {
         CloseFuture { conn: self.clone() }
     }
 
+    /// Our asset, which the other side is told about during the handshake
+    pub fn source_asset(&self) -> Option<AssetDetails> {
+        self.source_asset.read().clone()
+    }
+
+    /// The other side's asset, if they have told us what it is
+    pub fn destination_asset(&self) -> Option<AssetDetails> {
+        self.destination_asset.read().clone()
+    }
+
+    /// A snapshot of the packets, money and data this connection has sent and received so far
+    pub fn stats(&self) -> ConnectionStats {
+        let mut stats = (*self.stats.lock()).clone();
+        stats.amount_in_flight = (*self.pending_outgoing_packets.lock())
+            .values()
+            .map(|record| record.original_amount)
+            .sum();
+        stats.congestion_window = (*self.congestion_controller.lock()).congestion_window();
+        if stats.amount_sent > 0 && stats.amount_delivered > 0 {
+            stats.exchange_rate = Some(stats.amount_delivered as f64 / stats.amount_sent as f64);
+        }
+        let data_limits = self.data_limits.lock();
+        stats.bytes_sent = data_limits.outgoing_total as u64;
+        stats.bytes_received = data_limits.incoming_total as u64;
+        stats
+    }
+
+    pub(super) fn set_source_asset(&self, asset: AssetDetails) {
+        *self.source_asset.write() = Some(asset);
+    }
+
+    /// Close the connection without waiting for the other side.
+    ///
+    /// Returns a request telling the other side why, which the caller is responsible for sending
+    /// because this connection will not send anything else.
+    pub(super) fn abort(&self, code: ErrorCode, message: &str) -> IlpRequest {
+        let packet = StreamPacket {
+            sequence: self.next_packet_sequence.fetch_add(1, Ordering::SeqCst) as u64,
+            ilp_packet_type: PacketType::IlpPrepare,
+            prepare_amount: 0,
+            frames: vec![Frame::ConnectionClose(ConnectionCloseFrame {
+                code,
+                message: String::from(message),
+            })],
+        };
+        let (request, _expires_at) = self.unfulfillable_prepare(&packet, 0);
+        self.close_now();
+        request
+    }
+
     pub(super) fn is_closed(&self) -> bool {
         self.state.load(Ordering::SeqCst) == ConnectionState::Closed as usize
     }
@@ -162,21 +582,45 @@ impl Connection {
             trace!("Checking if we should send an outgoing packet");
 
             let mut outgoing_amount: u64 = 0;
-            let mut frames: Vec<Frame> = Vec::new();
+            let mut frames: Vec<Frame> = (*self.frames_to_resend.lock()).drain(..).collect();
             let mut closed_streams: Vec<u64> = Vec::new();
 
             let mut congestion_controller = self.congestion_controller.lock();
             let max_packet_amount = congestion_controller.get_max_amount();
+            // Used to convert the other side's receive max into our units
+            let exchange_rate = self.exchange_rate().unwrap_or(1.0);
 
             let streams = self.streams.read();
+            let mut data_limits = self.data_limits.lock();
             for stream in streams.values() {
                 // Send money
                 if max_packet_amount > 0 {
                     trace!("Checking if stream {} has money or data to send", stream.id);
-                    let stream_amount = stream.money.send_max()
-                        - stream.money.pending()
-                        - stream.money.total_sent();
-                    let amount_to_send = min(stream_amount, max_packet_amount - outgoing_amount);
+                    let stream_amount = stream
+                        .money
+                        .send_max()
+                        .saturating_sub(stream.money.pending() + stream.money.total_sent());
+                    let remote_limit = match stream.money.remote_receivable() {
+                        Some(receivable) if exchange_rate > 0.0 => {
+                            ((receivable as f64 / exchange_rate).floor() as u64)
+                                .saturating_sub(stream.money.pending())
+                        }
+                        _ => u64::max_value(),
+                    };
+                    let amount_to_send = min(
+                        min(stream_amount, remote_limit),
+                        max_packet_amount - outgoing_amount,
+                    );
+                    if stream_amount > 0 && remote_limit == 0 && stream.money.pending() == 0 {
+                        if let Some((send_max, total_sent)) = stream.money.money_blocked() {
+                            debug!("Stream {} is blocked by the remote receive max", stream.id);
+                            frames.push(Frame::StreamMoneyBlocked(StreamMoneyBlockedFrame {
+                                stream_id: BigUint::from(stream.id),
+                                send_max: BigUint::from(send_max),
+                                total_sent: BigUint::from(total_sent),
+                            }));
+                        }
+                    }
                     if amount_to_send > 0 {
                         trace!("Stream {} sending {}", stream.id, amount_to_send);
                         stream.money.add_to_pending(amount_to_send);
@@ -189,8 +633,10 @@ impl Connection {
                 }
 
                 // Send data
-                // TODO don't send too much data
-                let max_data: usize = 1_000_000_000;
+                // TODO limit the amount of data to what fits in an ILP packet
+                let max_data = data_limits
+                    .remote_max_data
+                    .saturating_sub(data_limits.outgoing_total);
                 if let Some((data, offset)) = stream.data.get_outgoing_data(max_data) {
                     trace!(
                         "Stream {} has {} bytes to send (offset: {})",
@@ -198,6 +644,7 @@ impl Connection {
                         data.len(),
                         offset
                     );
+                    data_limits.outgoing_total += data.len();
                     frames.push(Frame::StreamData(StreamDataFrame {
                         stream_id: BigUint::from(stream.id),
                         data,
@@ -206,9 +653,19 @@ impl Connection {
                 } else {
                     trace!("Stream {} does not have any data to send", stream.id);
                 }
+                if let Some(max_offset) = stream.data.data_blocked() {
+                    debug!(
+                        "Stream {} is blocked by the remote max offset of {}",
+                        stream.id, max_offset
+                    );
+                    frames.push(Frame::StreamDataBlocked(StreamDataBlockedFrame {
+                        stream_id: BigUint::from(stream.id),
+                        max_offset: BigUint::from(max_offset),
+                    }));
+                }
 
-                // Inform other side about closing streams
-                if stream.is_closing() {
+                // Inform other side about closing streams once everything they were sending got through
+                if stream.is_closing() && self.is_drained(stream, &frames) {
                     trace!("Sending stream close frame for stream {}", stream.id);
                     frames.push(Frame::StreamClose(StreamCloseFrame {
                         stream_id: BigUint::from(stream.id),
@@ -222,7 +679,36 @@ impl Connection {
                 }
             }
 
-            if self.state.load(Ordering::SeqCst) == ConnectionState::Closing as usize {
+            let max_data_reached = data_limits.outgoing_total >= data_limits.remote_max_data;
+            if max_data_reached
+                && !data_limits.sent_blocked
+                && streams
+                    .values()
+                    .any(|stream| stream.data.has_outgoing_data())
+            {
+                debug!(
+                    "Connection is blocked by the remote max data of {}",
+                    data_limits.remote_max_data
+                );
+                data_limits.sent_blocked = true;
+                frames.push(Frame::ConnectionDataBlocked(ConnectionDataBlockedFrame {
+                    max_offset: BigUint::from(data_limits.remote_max_data),
+                }));
+            }
+            drop(data_limits);
+
+            // Tell the other side if they can send more money or data or open more streams
+            frames.extend(self.max_money_frames(&streams));
+            frames.extend(self.max_data_frames(&streams));
+            frames.extend(self.max_stream_id_frame(&streams));
+
+            // Only close the connection once all of the streams are closed
+            let streams_closed = streams
+                .values()
+                .all(|stream| closed_streams.contains(&stream.id));
+            if self.state.load(Ordering::SeqCst) == ConnectionState::Closing as usize
+                && streams_closed
+            {
                 trace!("Sending connection close frame");
                 frames.push(Frame::ConnectionClose(ConnectionCloseFrame {
                     code: ErrorCode::NoError,
@@ -246,21 +732,27 @@ impl Connection {
                 }
             }
 
+            // Tell the receiver the minimum they should accept for this packet
+            let min_destination_amount = match self.exchange_rate.read().min_acceptable() {
+                Some(min_rate) => (outgoing_amount as f64 * min_rate).floor() as u64,
+                None => 0,
+            };
             let stream_packet = StreamPacket {
                 sequence: self.next_packet_sequence.fetch_add(1, Ordering::SeqCst) as u64,
                 ilp_packet_type: PacketType::IlpPrepare,
-                prepare_amount: 0, // TODO set min amount
+                prepare_amount: min_destination_amount,
                 frames,
             };
 
             let encrypted = stream_packet.to_encrypted(&self.shared_secret).unwrap();
             let condition = generate_condition(&self.shared_secret, &encrypted);
+            // TODO use less predictable timeout
+            let expires_at = Utc::now() + *self.packet_expiry.read();
             let prepare = IlpPrepare::new(
-                self.destination_account.to_string(),
+                self.destination_account(),
                 outgoing_amount,
                 condition,
-                // TODO use less predictable timeout
-                Utc::now() + Duration::seconds(30),
+                expires_at,
                 encrypted,
             );
             let request_id = random_u32();
@@ -277,8 +769,11 @@ impl Connection {
                 OutgoingPacketRecord {
                     original_amount: outgoing_amount,
                     original_packet: stream_packet.clone(),
+                    expires_at,
+                    sent_at: Instant::now(),
                 },
             );
+            (*self.stats.lock()).packets_sent += 1;
 
             self.outgoing.unbounded_send(request).map_err(|err| {
                 error!("Error sending outgoing packet: {:?}", err);
@@ -287,6 +782,8 @@ impl Connection {
     }
 
     pub(super) fn try_handle_incoming(&self) -> Result<(), ()> {
+        self.poll_expiry_timer()?;
+
         // Handle incoming requests until there are no more
         // Note: looping until we get Async::NotReady tells Tokio to wake us up when there are more incoming requests
         loop {
@@ -326,14 +823,144 @@ impl Connection {
         }
     }
 
+    // Treat outgoing packets that did not get a response in time as rejected.
+    // The timer only runs while there are packets waiting for a response
+    fn poll_expiry_timer(&self) -> Result<(), ()> {
+        self.expiry_waiters.register_current();
+        let mut expired_once = false;
+        loop {
+            let next_expiry = match self.next_expiry() {
+                Some(next_expiry) => next_expiry,
+                None => {
+                    *self.expiry_timer.lock() = None;
+                    return Ok(());
+                }
+            };
+            let fired = {
+                let mut timer = self.expiry_timer.lock();
+                let is_current = match *timer {
+                    Some((expires_at, _)) => expires_at == next_expiry,
+                    None => false,
+                };
+                if !is_current {
+                    let wait = (next_expiry - Utc::now())
+                        .to_std()
+                        .unwrap_or_else(|_| StdDuration::from_millis(0));
+                    *timer = Some((next_expiry, spawn(Delay::new(Instant::now() + wait))));
+                }
+                let notify = NotifyHandle::from(Arc::clone(&self.expiry_waiters));
+                timer.as_mut().unwrap().1.poll_future_notify(&notify, 0)
+            };
+            match fired {
+                // Don't keep resending packets that expire immediately, let other tasks run first
+                Ok(Async::Ready(())) if expired_once => {
+                    task::current().notify();
+                    return Ok(());
+                }
+                Ok(Async::Ready(())) => {
+                    *self.expiry_timer.lock() = None;
+                    self.expire_pending_packets()?;
+                    expired_once = true;
+                }
+                Ok(Async::NotReady) => return Ok(()),
+                Err(err) => {
+                    warn!("Error polling expiry timer: {:?}", err);
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    fn next_expiry(&self) -> Option<DateTime<Utc>> {
+        let handshake = (*self.pending_handshake.lock())
+            .as_ref()
+            .map(|handshake| handshake.expires_at);
+        let probes = (*self.pending_probes.lock())
+            .values()
+            .map(|probe| probe.expires_at)
+            .min();
+        let packets = (*self.pending_outgoing_packets.lock())
+            .values()
+            .map(|record| record.expires_at)
+            .min();
+        handshake.into_iter().chain(probes).chain(packets).min()
+    }
+
+    fn expire_pending_packets(&self) -> Result<(), ()> {
+        let now = Utc::now();
+        let handshake_expired = match *self.pending_handshake.lock() {
+            Some(ref handshake) => handshake.expires_at <= now,
+            None => false,
+        };
+        if handshake_expired {
+            let handshake = (*self.pending_handshake.lock()).take().unwrap();
+            debug!(
+                "Handshake {} expired without a response",
+                handshake.request_id
+            );
+            let reject = IlpReject::new(
+                IlpErrorCode::R00TransferTimedOut,
+                "Handshake expired",
+                "",
+                Bytes::new(),
+            );
+            self.handle_handshake_response(handshake, &reject);
+        }
+
+        (*self.pending_probes.lock()).retain(|request_id, probe| {
+            if probe.expires_at <= now {
+                debug!("Test packet {} expired without a response", request_id);
+                false
+            } else {
+                true
+            }
+        });
+
+        let expired: Vec<u32> = (*self.pending_outgoing_packets.lock())
+            .iter()
+            .filter(|(_id, record)| record.expires_at <= now)
+            .map(|(id, _record)| *id)
+            .collect();
+        if expired.is_empty() {
+            return Ok(());
+        }
+
+        {
+            let pending = self.pending_outgoing_packets.lock();
+            let mut expired_packets = self.expired_packets.lock();
+            // Only remember them for as long as the other side could still be working on them
+            let forget_before = now - *self.packet_expiry.read();
+            expired_packets.retain(|_id, record| record.expires_at > forget_before);
+            for request_id in expired.iter() {
+                if let Some(record) = pending.get(request_id) {
+                    expired_packets.insert(*request_id, record.clone());
+                }
+            }
+        }
+
+        for request_id in expired {
+            debug!("Outgoing request {} expired without a response", request_id);
+            let reject = IlpReject::new(
+                IlpErrorCode::R00TransferTimedOut,
+                "Packet expired",
+                "",
+                Bytes::new(),
+            );
+            self.handle_reject(request_id, reject)?;
+        }
+
+        // Send the money and data from the expired packets again
+        self.try_send()
+    }
+
     fn handle_incoming_prepare(&self, request_id: u32, prepare: IlpPrepare) -> Result<(), ()> {
         debug!("Handling incoming prepare {}", request_id);
 
-        let response_frames: Vec<Frame> = Vec::new();
+        let mut response_frames: Vec<Frame> = Vec::new();
 
         let fulfillment = generate_fulfillment(&self.shared_secret, &prepare.data);
         let condition = fulfillment_to_condition(&fulfillment);
-        let is_fulfillable = condition == prepare.execution_condition;
+        let mut is_fulfillable = condition == prepare.execution_condition;
 
         // TODO avoid copying data
         let stream_packet =
@@ -346,7 +973,12 @@ impl Connection {
             self.outgoing
                 .unbounded_send((
                     request_id,
-                    IlpPacket::Reject(IlpReject::new("F02", "", "", Bytes::new())),
+                    IlpPacket::Reject(IlpReject::new(
+                        IlpErrorCode::F06UnexpectedPayment,
+                        "",
+                        "",
+                        Bytes::new(),
+                    )),
                 )).map_err(|err| {
                     error!("Error sending Reject {} {:?}", request_id, err);
                 })?;
@@ -354,6 +986,15 @@ impl Connection {
         }
         let stream_packet = stream_packet.unwrap();
 
+        // The sender tells us the minimum amount this packet should deliver
+        if is_fulfillable && prepare.amount < stream_packet.prepare_amount {
+            debug!(
+                "Rejecting request {} because it only delivered {} but the sender expected at least {}",
+                request_id, prepare.amount, stream_packet.prepare_amount
+            );
+            is_fulfillable = false;
+        }
+
         debug!(
             "Prepare {} had stream packet: {:?}",
             request_id, stream_packet
@@ -361,46 +1002,113 @@ impl Connection {
 
         // Handle new streams
         for frame in stream_packet.frames.iter() {
-            match frame {
+            let result = match frame {
                 Frame::StreamMoney(frame) => {
-                    self.handle_new_stream(frame.stream_id.to_u64().unwrap());
+                    self.handle_new_stream(frame.stream_id.to_u64().unwrap_or(u64::max_value()))
                 }
                 Frame::StreamData(frame) => {
-                    self.handle_new_stream(frame.stream_id.to_u64().unwrap());
+                    self.handle_new_stream(frame.stream_id.to_u64().unwrap_or(u64::max_value()))
                 }
                 // TODO handle other frames that open streams
-                _ => {}
+                _ => Ok(()),
+            };
+            if result.is_err() {
+                return self.close_with_error(
+                    request_id,
+                    &stream_packet,
+                    ErrorCode::StreamIdError,
+                    "Invalid stream ID",
+                );
             }
         }
 
-        // Count up the total number of money "shares" in the packet
-        let total_money_shares: u64 = stream_packet.frames.iter().fold(0, |sum, frame| {
-            if let Frame::StreamMoney(frame) = frame {
-                sum + frame.shares.to_u64().unwrap()
-            } else {
-                sum
+        if self.handle_incoming_data(&stream_packet).is_err() {
+            return self.close_with_error(
+                request_id,
+                &stream_packet,
+                ErrorCode::FlowControlError,
+                "Exceeded flow control limits",
+            );
+        }
+
+        // Split the amount between the streams according to their shares
+        let money_amounts = match split_amount_by_shares(&stream_packet, prepare.amount) {
+            Some(money_amounts) => money_amounts,
+            None => {
+                warn!(
+                    "Rejecting request {} because its money frames have invalid shares",
+                    request_id
+                );
+                is_fulfillable = false;
+                Vec::new()
             }
-        });
+        };
+
+        // Reject money that would put a stream over its receive max
+        for &(stream_id, amount) in money_amounts.iter() {
+            let streams = self.streams.read();
+            let stream = match streams.get(&stream_id) {
+                Some(stream) => stream,
+                None => {
+                    debug!(
+                        "Rejecting request {} because stream {} is already closed",
+                        request_id, stream_id
+                    );
+                    is_fulfillable = false;
+                    continue;
+                }
+            };
+            if !stream.money.can_receive(amount) {
+                debug!(
+                    "Rejecting request {} because stream {} would exceed its receive max",
+                    request_id, stream_id
+                );
+                is_fulfillable = false;
+                response_frames.push(Frame::StreamMaxMoney(StreamMaxMoneyFrame {
+                    stream_id: BigUint::from(stream_id),
+                    receive_max: BigUint::from(stream.money.receive_max()),
+                    total_received: BigUint::from(stream.money.total_received()),
+                }));
+            }
+        }
 
         // Handle incoming money
         if is_fulfillable {
-            for frame in stream_packet.frames.iter() {
-                if let Frame::StreamMoney(frame) = frame {
-                    // TODO only add money to incoming if sending the fulfill is successful
-                    // TODO make sure all other checks pass first
-                    let stream_id = frame.stream_id.to_u64().unwrap();
-                    let streams = self.streams.read();
-                    let stream = streams.get(&stream_id).unwrap();
-                    let amount: u64 =
-                        frame.shares.to_u64().unwrap() * prepare.amount / total_money_shares;
+            for &(stream_id, amount) in money_amounts.iter() {
+                // TODO only add money to incoming if sending the fulfill is successful
+                // TODO make sure all other checks pass first
+                let streams = self.streams.read();
+                if let Some(stream) = streams.get(&stream_id) {
                     debug!("Stream {} received {}", stream_id, amount);
                     stream.money.add_received(amount);
+                    (*self.stats.lock()).amount_received += amount;
                     stream.money.try_wake_polling();
                 }
             }
+            response_frames.extend(self.create_receipts(&stream_packet));
+        }
+
+        self.handle_new_address(&stream_packet);
+
+        // Reply to the sender's asset details with our own
+        if self.handle_asset_details(&stream_packet) {
+            if let Some(ref asset) = *self.source_asset.read() {
+                response_frames.push(Frame::ConnectionAssetDetails(ConnectionAssetDetailsFrame {
+                    source_asset_code: asset.code.clone(),
+                    source_asset_scale: asset.scale,
+                }));
+            }
         }
 
-        self.handle_incoming_data(&stream_packet).unwrap();
+        self.handle_max_money(&stream_packet);
+        self.handle_max_data(&stream_packet);
+        self.handle_max_stream_id(&stream_packet);
+        {
+            let streams = self.streams.read();
+            response_frames.extend(self.max_money_frames(&streams));
+            response_frames.extend(self.max_data_frames(&streams));
+            response_frames.extend(self.max_stream_id_frame(&streams));
+        }
 
         self.handle_stream_closes(&stream_packet);
 
@@ -432,7 +1140,12 @@ impl Connection {
                 frames: response_frames,
             };
             let encrypted_response = response_packet.to_encrypted(&self.shared_secret).unwrap();
-            let reject = IlpPacket::Reject(IlpReject::new("F99", "", "", encrypted_response));
+            let reject = IlpPacket::Reject(IlpReject::new(
+                IlpErrorCode::F99ApplicationError,
+                "",
+                "",
+                encrypted_response,
+            ));
             debug!(
                 "Rejecting request {} and including encrypted stream packet {:?}",
                 request_id, response_packet
@@ -443,52 +1156,451 @@ impl Connection {
         Ok(())
     }
 
-    fn handle_new_stream(&self, stream_id: u64) {
-        // TODO make sure they don't open streams with our number (even or odd, depending on whether we're the client or server)
-        let is_new = !(*self.streams.read()).contains_key(&stream_id);
-        let already_closed = (*self.closed_streams.read()).contains(&stream_id);
-        if is_new && !already_closed {
-            debug!("Got new stream {}", stream_id);
-            let stream = DataMoneyStream::new(stream_id, self.clone());
-            (*self.streams.write()).insert(stream_id, stream);
-            (*self.new_streams.lock()).push_back(stream_id);
-        }
-    }
+    fn create_receipts(&self, stream_packet: &StreamPacket) -> Vec<Frame> {
+        let receipt_config = self.receipt_config.read();
+        let receipt_config = match *receipt_config {
+            Some(ref config) => config,
+            None => return Vec::new(),
+        };
 
-    fn handle_incoming_data(&self, stream_packet: &StreamPacket) -> Result<(), ()> {
-        for frame in stream_packet.frames.iter() {
-            if let Frame::StreamData(frame) = frame {
-                let stream_id = frame.stream_id.to_u64().unwrap();
-                let streams = self.streams.read();
-                let stream = streams.get(&stream_id).unwrap();
-                // TODO make sure the offset number isn't too big
-                let data = frame.data.clone();
-                let offset = frame.offset.to_usize().unwrap();
-                debug!(
-                    "Stream {} got {} bytes of incoming data",
-                    stream.id,
-                    data.len()
+        let mut stream_ids: Vec<u64> = stream_packet
+            .frames
+            .iter()
+            .filter_map(|frame| {
+                if let Frame::StreamMoney(frame) = frame {
+                    frame.stream_id.to_u64()
+                } else {
+                    None
+                }
+            }).collect();
+        stream_ids.sort();
+        stream_ids.dedup();
+
+        let streams = self.streams.read();
+        stream_ids
+            .into_iter()
+            .filter_map(|stream_id| {
+                let stream = streams.get(&stream_id)?;
+                let receipt = Receipt::new(
+                    &receipt_config.secret[..],
+                    receipt_config.nonce,
+                    stream_id,
+                    stream.money.total_received(),
                 );
-                stream.data.push_incoming_data(data, offset)?;
-                stream.data.try_wake_polling();
-            }
-        }
-        Ok(())
+                Some(Frame::StreamReceipt(StreamReceiptFrame {
+                    stream_id: BigUint::from(stream_id),
+                    receipt: receipt.to_bytes(),
+                }))
+            }).collect()
     }
 
-    fn handle_stream_closes(&self, stream_packet: &StreamPacket) {
+    fn handle_receipts(&self, stream_packet: &StreamPacket) {
+        for frame in stream_packet.frames.iter() {
+            if let Frame::StreamReceipt(frame) = frame {
+                let receipt = match Receipt::from_bytes(&frame.receipt[..]) {
+                    Ok(receipt) => receipt,
+                    Err(err) => {
+                        warn!("Ignoring invalid receipt: {:?}", err);
+                        continue;
+                    }
+                };
+                if frame.stream_id.to_u64() != Some(receipt.stream_id) {
+                    warn!(
+                        "Ignoring receipt for stream {} in frame for stream {}",
+                        receipt.stream_id, frame.stream_id
+                    );
+                    continue;
+                }
+                if let Some(stream) = (*self.streams.read()).get(&receipt.stream_id) {
+                    debug!(
+                        "Stream {} got receipt for total received: {}",
+                        receipt.stream_id, receipt.total_received
+                    );
+                    stream.money.set_receipt(receipt);
+                }
+            }
+        }
+    }
+
+    // Returns an error if the other side is not allowed to open the stream
+    fn handle_new_stream(&self, stream_id: u64) -> Result<(), ()> {
+        let is_new = !(*self.streams.read()).contains_key(&stream_id);
+        let already_closed = (*self.closed_streams.read()).contains(&stream_id);
+        if !is_new || already_closed {
+            return Ok(());
+        }
+
+        // The client opens odd-numbered streams and the server opens even-numbered ones
+        let local_parity = self.next_stream_id.load(Ordering::SeqCst) as u64 % 2;
+        if stream_id == 0 || stream_id % 2 == local_parity {
+            warn!("Remote tried to open stream {} with our parity", stream_id);
+            return Err(());
+        }
+        let max_stream_id = self.max_stream_id(&*self.streams.read());
+        if stream_id > max_stream_id {
+            warn!(
+                "Remote tried to open stream {} but the max stream id is {}",
+                stream_id, max_stream_id
+            );
+            return Err(());
+        }
+
+        debug!("Got new stream {}", stream_id);
+        {
+            let mut stream_limits = self.stream_limits.lock();
+            stream_limits.highest_remote_stream_id =
+                stream_limits.highest_remote_stream_id.max(stream_id);
+        }
+        let stream = DataMoneyStream::new(stream_id, self.clone());
+        (*self.streams.write()).insert(stream_id, stream);
+        (*self.new_streams.lock()).push_back(stream_id);
+        Ok(())
+    }
+
+    // The highest stream id the other side may open
+    fn max_stream_id(&self, streams: &HashMap<u64, DataMoneyStream>) -> u64 {
+        let local_parity = self.next_stream_id.load(Ordering::SeqCst) as u64 % 2;
+        let open_remote_streams = streams
+            .values()
+            .filter(|stream| stream.id % 2 != local_parity && !stream.is_closed())
+            .count() as u64;
+        let stream_limits = self.stream_limits.lock();
+        let available = stream_limits
+            .max_remote_streams
+            .saturating_sub(open_remote_streams);
+        // Never lower a limit we already told them about
+        max(
+            stream_limits.advertised_max_stream_id,
+            stream_limits.highest_remote_stream_id + 2 * available,
+        )
+    }
+
+    fn max_stream_id_frame(&self, streams: &HashMap<u64, DataMoneyStream>) -> Option<Frame> {
+        let max_stream_id = self.max_stream_id(streams);
+        let mut stream_limits = self.stream_limits.lock();
+        let should_advertise = stream_limits.advertised_max_stream_id == 0
+            || (max_stream_id > stream_limits.advertised_max_stream_id
+                && (stream_limits.advertise_requested
+                    || max_stream_id - stream_limits.advertised_max_stream_id
+                        >= stream_limits.max_remote_streams));
+        if should_advertise {
+            stream_limits.advertised_max_stream_id = max_stream_id;
+            stream_limits.advertise_requested = false;
+            Some(Frame::ConnectionMaxStreamId(ConnectionMaxStreamIdFrame {
+                max_stream_id: BigUint::from(max_stream_id),
+            }))
+        } else {
+            None
+        }
+    }
+
+    fn handle_max_stream_id(&self, stream_packet: &StreamPacket) {
+        for frame in stream_packet.frames.iter() {
+            match frame {
+                Frame::ConnectionMaxStreamId(frame) => {
+                    let max_stream_id = frame.max_stream_id.to_u64().unwrap_or(u64::max_value());
+                    let mut stream_limits = self.stream_limits.lock();
+                    // Before they tell us their limit we assume it is unlimited
+                    if max_stream_id > stream_limits.remote_max_stream_id
+                        || stream_limits.remote_max_stream_id == u64::max_value()
+                    {
+                        debug!("Remote set the max stream id to {}", max_stream_id);
+                        stream_limits.remote_max_stream_id = max_stream_id;
+                        stream_limits.sent_blocked = false;
+                        if let Some(task) = stream_limits.blocked_task.take() {
+                            task.notify();
+                        }
+                    }
+                }
+                Frame::ConnectionStreamIdBlocked(frame) => {
+                    debug!("Remote is blocked at max stream id {}", frame.max_stream_id);
+                    self.stream_limits.lock().advertise_requested = true;
+                }
+                _ => {}
+            }
+        }
+    }
+
+    // Returns an error if the other side sent more data than we allowed
+    fn handle_incoming_data(&self, stream_packet: &StreamPacket) -> Result<(), ()> {
+        for frame in stream_packet.frames.iter() {
+            if let Frame::StreamData(frame) = frame {
+                let stream_id = frame.stream_id.to_u64().ok_or_else(|| {
+                    warn!(
+                        "Got data for a stream ID that is too big: {}",
+                        frame.stream_id
+                    );
+                })?;
+                let streams = self.streams.read();
+                let stream = match streams.get(&stream_id) {
+                    Some(stream) => stream,
+                    None => {
+                        debug!(
+                            "Ignoring data for stream {} that is already closed",
+                            stream_id
+                        );
+                        continue;
+                    }
+                };
+                let data = frame.data.clone();
+                let offset = frame.offset.to_usize().ok_or_else(|| {
+                    warn!(
+                        "Stream {} got data with an offset that is too big",
+                        stream_id
+                    );
+                })?;
+                debug!(
+                    "Stream {} got {} bytes of incoming data",
+                    stream.id,
+                    data.len()
+                );
+                let new_bytes = stream.data.push_incoming_data(data, offset)?;
+
+                let mut data_limits = self.data_limits.lock();
+                data_limits.incoming_total += new_bytes;
+                if data_limits.advertised_max_data > 0
+                    && data_limits.incoming_total > data_limits.advertised_max_data
+                {
+                    warn!(
+                        "Got {} bytes of data in total, which exceeds the max data of {}",
+                        data_limits.incoming_total, data_limits.advertised_max_data
+                    );
+                    return Err(());
+                }
+
+                stream.data.try_wake_polling();
+            }
+        }
+        Ok(())
+    }
+
+    fn handle_max_data(&self, stream_packet: &StreamPacket) {
+        for frame in stream_packet.frames.iter() {
+            match frame {
+                Frame::ConnectionMaxData(frame) => {
+                    let max_data = frame.max_offset.to_usize().unwrap_or(usize::max_value());
+                    let mut data_limits = self.data_limits.lock();
+                    // Before they tell us their limit we assume it is unlimited
+                    if max_data > data_limits.remote_max_data
+                        || data_limits.remote_max_data == usize::max_value()
+                    {
+                        debug!("Remote set the max data to {}", max_data);
+                        data_limits.remote_max_data = max_data;
+                        data_limits.sent_blocked = false;
+                    }
+                }
+                Frame::ConnectionDataBlocked(frame) => {
+                    debug!("Remote is blocked at max data {}", frame.max_offset);
+                    self.data_limits.lock().advertise_requested = true;
+                }
+                Frame::StreamMaxData(frame) => {
+                    let stream_id = frame.stream_id.to_u64().unwrap_or(0);
+                    let max_offset = frame.max_offset.to_usize().unwrap_or(usize::max_value());
+                    if let Some(stream) = (*self.streams.read()).get(&stream_id) {
+                        stream.data.set_remote_max_offset(max_offset);
+                        stream.data.try_wake_writer();
+                    }
+                }
+                Frame::StreamDataBlocked(frame) => {
+                    let stream_id = frame.stream_id.to_u64().unwrap_or(0);
+                    debug!(
+                        "Remote is blocked on stream {} at max offset {}",
+                        stream_id, frame.max_offset
+                    );
+                    if let Some(stream) = (*self.streams.read()).get(&stream_id) {
+                        stream.data.request_max_offset_update();
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn handle_max_money(&self, stream_packet: &StreamPacket) {
+        for frame in stream_packet.frames.iter() {
+            match frame {
+                Frame::StreamMaxMoney(frame) => {
+                    let stream_id = frame.stream_id.to_u64().unwrap_or(0);
+                    let receive_max = frame.receive_max.to_u64().unwrap_or(u64::max_value());
+                    let total_received = frame.total_received.to_u64().unwrap_or(0);
+                    debug!(
+                        "Remote set the receive max of stream {} to {} (received so far: {})",
+                        stream_id, receive_max, total_received
+                    );
+                    if let Some(stream) = (*self.streams.read()).get(&stream_id) {
+                        stream
+                            .money
+                            .set_remote_receive_max(receive_max, total_received);
+                    }
+                }
+                Frame::StreamMoneyBlocked(frame) => {
+                    let stream_id = frame.stream_id.to_u64().unwrap_or(0);
+                    debug!(
+                        "Remote is blocked from sending money on stream {} (send max: {}, total sent: {})",
+                        stream_id, frame.send_max, frame.total_sent
+                    );
+                    if let Some(stream) = (*self.streams.read()).get(&stream_id) {
+                        stream.money.request_max_money_update();
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    // Frames that tell the other side how much money each stream will accept
+    fn max_money_frames(&self, streams: &HashMap<u64, DataMoneyStream>) -> Vec<Frame> {
+        streams
+            .values()
+            .filter_map(|stream| {
+                let (receive_max, total_received) = stream.money.max_money_update()?;
+                Some(Frame::StreamMaxMoney(StreamMaxMoneyFrame {
+                    stream_id: BigUint::from(stream.id),
+                    receive_max: BigUint::from(receive_max),
+                    total_received: BigUint::from(total_received),
+                }))
+            }).collect()
+    }
+
+    // Frames that raise the limits of how much data the other side can send
+    fn max_data_frames(&self, streams: &HashMap<u64, DataMoneyStream>) -> Vec<Frame> {
+        let mut frames = Vec::new();
+        let mut incoming_buffered: usize = 0;
+        for stream in streams.values() {
+            incoming_buffered += stream.data.incoming_buffered();
+            if let Some(max_offset) = stream.data.max_offset_update() {
+                frames.push(Frame::StreamMaxData(StreamMaxDataFrame {
+                    stream_id: BigUint::from(stream.id),
+                    max_offset: BigUint::from(max_offset),
+                }));
+            }
+        }
+
+        let mut data_limits = self.data_limits.lock();
+        let max_data = data_limits.incoming_total
+            + CONNECTION_RECEIVE_WINDOW.saturating_sub(incoming_buffered);
+        let should_advertise = data_limits.advertised_max_data == 0
+            || (max_data > data_limits.advertised_max_data
+                && (data_limits.advertise_requested
+                    || max_data - data_limits.advertised_max_data
+                        >= CONNECTION_RECEIVE_WINDOW / 2));
+        if should_advertise {
+            data_limits.advertised_max_data = max_data;
+            data_limits.advertise_requested = false;
+            frames.push(Frame::ConnectionMaxData(ConnectionMaxDataFrame {
+                max_offset: BigUint::from(max_data),
+            }));
+        }
+        frames
+    }
+
+    // Reject the packet and close the connection because the other side broke the protocol rules
+    fn close_with_error(
+        &self,
+        request_id: u32,
+        stream_packet: &StreamPacket,
+        code: ErrorCode,
+        message: &str,
+    ) -> Result<(), ()> {
+        warn!("Closing connection with error {:?}: {}", code, message);
+        let response_packet = StreamPacket {
+            sequence: stream_packet.sequence,
+            ilp_packet_type: PacketType::IlpReject,
+            prepare_amount: 0,
+            frames: vec![Frame::ConnectionClose(ConnectionCloseFrame {
+                code,
+                message: String::from(message),
+            })],
+        };
+        let encrypted_response = response_packet.to_encrypted(&self.shared_secret).unwrap();
+        let reject = IlpPacket::Reject(IlpReject::new(
+            IlpErrorCode::F99ApplicationError,
+            "",
+            "",
+            encrypted_response,
+        ));
+        self.close_now();
+        self.outgoing
+            .unbounded_send((request_id, reject))
+            .map_err(|err| {
+                error!("Error sending Reject {} {:?}", request_id, err);
+            })
+    }
+
+    fn handle_new_address(&self, stream_packet: &StreamPacket) {
+        for frame in stream_packet.frames.iter() {
+            if let Frame::ConnectionNewAddress(frame) = frame {
+                let mut destination_account = self.destination_account.write();
+                if *destination_account != frame.source_account {
+                    debug!(
+                        "Remote changed address from {} to {}",
+                        *destination_account, frame.source_account
+                    );
+                    *destination_account = frame.source_account.clone();
+                }
+            }
+        }
+    }
+
+    // Returns true if the packet included the other side's asset details
+    fn handle_asset_details(&self, stream_packet: &StreamPacket) -> bool {
+        let mut found = false;
+        for frame in stream_packet.frames.iter() {
+            if let Frame::ConnectionAssetDetails(frame) = frame {
+                debug!(
+                    "Remote asset is {} with scale {}",
+                    frame.source_asset_code, frame.source_asset_scale
+                );
+                *self.destination_asset.write() = Some(AssetDetails {
+                    code: frame.source_asset_code.clone(),
+                    scale: frame.source_asset_scale,
+                });
+                found = true;
+            }
+        }
+        found
+    }
+
+    fn handle_stream_closes(&self, stream_packet: &StreamPacket) {
         for frame in stream_packet.frames.iter() {
             if let Frame::StreamClose(frame) = frame {
-                let stream_id = frame.stream_id.to_u64().unwrap();
+                let stream_id = frame.stream_id.to_u64().unwrap_or(0);
                 debug!("Remote closed stream {}", stream_id);
-                let streams = self.streams.read();
-                let stream = streams.get(&stream_id).unwrap();
-                // TODO finish sending the money and data first
-                stream.set_closed();
+                // Finish sending our side of the stream and then close it too
+                if let Some(stream) = (*self.streams.read()).get(&stream_id) {
+                    if !stream.is_closing() {
+                        stream.set_closing();
+                    }
+                }
             }
         }
     }
 
+    // Whether all of the stream's money and data has been sent and acknowledged
+    fn is_drained(&self, stream: &DataMoneyStream, frames: &[Frame]) -> bool {
+        if stream.money.pending() > 0 || stream.data.has_outgoing_data() {
+            return false;
+        }
+        let has_unsent_money = stream.money.send_max() > stream.money.total_sent();
+        if has_unsent_money && stream.money.remote_receivable() != Some(0) {
+            return false;
+        }
+
+        let is_for_stream = |frame: &Frame| {
+            let stream_id = match frame {
+                Frame::StreamMoney(frame) => &frame.stream_id,
+                Frame::StreamData(frame) => &frame.stream_id,
+                _ => return false,
+            };
+            stream_id.to_u64() == Some(stream.id)
+        };
+        let in_flight = (*self.pending_outgoing_packets.lock())
+            .values()
+            .any(|record| record.original_packet.frames.iter().any(&is_for_stream));
+        let waiting_to_resend = (*self.frames_to_resend.lock()).iter().any(&is_for_stream);
+        !in_flight && !waiting_to_resend && !frames.iter().any(&is_for_stream)
+    }
+
     fn handle_connection_close(&self, stream_packet: &StreamPacket) {
         for frame in stream_packet.frames.iter() {
             if let Frame::ConnectionClose(frame) = frame {
@@ -514,6 +1626,9 @@ impl Connection {
 
         // Wake up the task polling for incoming streams so it ends
         self.try_wake_polling();
+        if let Some(task) = (*self.stream_limits.lock()).blocked_task.take() {
+            task.notify();
+        }
     }
 
     fn handle_fulfill(&self, request_id: u32, fulfill: IlpFulfill) -> Result<(), ()> {
@@ -523,34 +1638,29 @@ impl Connection {
             hex::encode(&fulfill.fulfillment[..])
         );
 
+        let entry = (*self.pending_outgoing_packets.lock()).remove(&request_id);
+        if entry.is_none() {
+            let expired = (*self.expired_packets.lock()).remove(&request_id);
+            match expired {
+                Some(record) => self.handle_late_fulfill(request_id, record, fulfill),
+                None => warn!(
+                    "Got Fulfill for request {} that was not pending",
+                    request_id
+                ),
+            }
+            return Ok(());
+        }
         let OutgoingPacketRecord {
             original_amount,
             original_packet,
-        } = (*self.pending_outgoing_packets.lock())
-            .remove(&request_id)
-            .unwrap();
+            sent_at,
+            ..
+        } = entry.unwrap();
 
-        let response = {
-            let decrypted =
-                StreamPacket::from_encrypted(&self.shared_secret, BytesMut::from(fulfill.data))
-                    .ok();
-            if let Some(packet) = decrypted {
-                if packet.sequence != original_packet.sequence {
-                    warn!("Got Fulfill with stream packet whose sequence does not match the original request. Request ID: {}, sequence: {}, fulfill packet: {:?}", request_id, original_packet.sequence, packet);
-                    None
-                } else if packet.ilp_packet_type != PacketType::IlpFulfill {
-                    warn!("Got Fulfill with stream packet that should have been on a differen type of ILP packet. Request ID: {}, fulfill packet: {:?}", request_id, packet);
-                    None
-                } else {
-                    trace!("Got Fulfill with stream packet: {:?}", packet);
-                    Some(packet)
-                }
-            } else {
-                None
-            }
-        };
+        let response = self.decrypt_fulfill_data(request_id, &original_packet, fulfill.data);
 
         (*self.congestion_controller.lock()).fulfill(request_id);
+        self.failed_money_packets.store(0, Ordering::SeqCst);
 
         let total_delivered = {
             match response.as_ref() {
@@ -559,6 +1669,14 @@ impl Connection {
             }
         };
 
+        {
+            let mut stats = self.stats.lock();
+            stats.packets_fulfilled += 1;
+            stats.amount_sent += original_amount;
+            stats.amount_delivered += total_delivered;
+            stats.record_rtt(sent_at.elapsed());
+        }
+
         for frame in original_packet.frames.iter() {
             if let Frame::StreamMoney(frame) = frame {
                 let stream_id = frame.stream_id.to_u64().unwrap();
@@ -574,7 +1692,16 @@ impl Connection {
         }
 
         if let Some(packet) = response.as_ref() {
-            self.handle_incoming_data(&packet)?;
+            if self.handle_incoming_data(&packet).is_err() {
+                self.close_now();
+                return Ok(());
+            }
+            self.handle_new_address(&packet);
+            self.handle_asset_details(&packet);
+            self.handle_max_money(&packet);
+            self.handle_max_data(&packet);
+            self.handle_max_stream_id(&packet);
+            self.handle_receipts(&packet);
         }
 
         // TODO handle response frames
@@ -598,12 +1725,93 @@ impl Connection {
         Ok(())
     }
 
+    fn decrypt_fulfill_data(
+        &self,
+        request_id: u32,
+        original_packet: &StreamPacket,
+        data: Bytes,
+    ) -> Option<StreamPacket> {
+        let decrypted =
+            StreamPacket::from_encrypted(&self.shared_secret, BytesMut::from(data)).ok();
+        if let Some(packet) = decrypted {
+            if packet.sequence != original_packet.sequence {
+                warn!("Got Fulfill with stream packet whose sequence does not match the original request. Request ID: {}, sequence: {}, fulfill packet: {:?}", request_id, original_packet.sequence, packet);
+                None
+            } else if packet.ilp_packet_type != PacketType::IlpFulfill {
+                warn!("Got Fulfill with stream packet that should have been on a differen type of ILP packet. Request ID: {}, fulfill packet: {:?}", request_id, packet);
+                None
+            } else {
+                trace!("Got Fulfill with stream packet: {:?}", packet);
+                Some(packet)
+            }
+        } else {
+            None
+        }
+    }
+
+    // The money from a packet that expired locally was already released to be sent again,
+    // but the other side got it anyway so count it as sent and delivered
+    fn handle_late_fulfill(
+        &self,
+        request_id: u32,
+        record: OutgoingPacketRecord,
+        fulfill: IlpFulfill,
+    ) {
+        debug!(
+            "Request {} was fulfilled after it expired locally",
+            request_id
+        );
+        let total_delivered = self
+            .decrypt_fulfill_data(request_id, &record.original_packet, fulfill.data)
+            .map(|packet| packet.prepare_amount)
+            .unwrap_or(0);
+
+        {
+            let mut stats = self.stats.lock();
+            stats.packets_fulfilled += 1;
+            stats.amount_sent += record.original_amount;
+            stats.amount_delivered += total_delivered;
+        }
+
+        let streams = self.streams.read();
+        for frame in record.original_packet.frames.iter() {
+            if let Frame::StreamMoney(frame) = frame {
+                let stream_id = frame.stream_id.to_u64().unwrap();
+                if let Some(stream) = streams.get(&stream_id) {
+                    let shares = frame.shares.to_u64().unwrap();
+                    stream.money.add_sent(shares);
+                    let amount_delivered = (total_delivered * shares)
+                        .checked_div(record.original_amount)
+                        .unwrap_or(0);
+                    stream.money.add_delivered(amount_delivered);
+                    stream.money.try_wake_polling();
+                }
+            }
+        }
+    }
+
     fn handle_reject(&self, request_id: u32, reject: IlpReject) -> Result<(), ()> {
         debug!(
             "Request {} was rejected with code: {}",
             request_id, reject.code
         );
 
+        let is_handshake = match *self.pending_handshake.lock() {
+            Some(ref handshake) => handshake.request_id == request_id,
+            None => false,
+        };
+        if is_handshake {
+            let handshake = (*self.pending_handshake.lock()).take().unwrap();
+            self.handle_handshake_response(handshake, &reject);
+            return Ok(());
+        }
+
+        let probe = (*self.pending_probes.lock()).remove(&request_id);
+        if let Some(probe) = probe {
+            self.handle_probe_response(probe, &reject);
+            return Ok(());
+        }
+
         let entry = (*self.pending_outgoing_packets.lock()).remove(&request_id);
         if entry.is_none() {
             return Ok(());
@@ -611,19 +1819,33 @@ impl Connection {
         let OutgoingPacketRecord {
             original_amount,
             mut original_packet,
+            expires_at,
+            sent_at,
         } = entry.unwrap();
 
+        {
+            let mut stats = self.stats.lock();
+            stats.record_reject(reject.code);
+            // Packets that expired locally never got a response to time
+            if expires_at > Utc::now() {
+                stats.record_rtt(sent_at.elapsed());
+            }
+        }
+
         // Handle F08 errors, which communicate the maximum packet amount
         if let Some(err_details) = parse_f08_error(&reject) {
-            let max_packet_amount: u64 =
-                original_amount * err_details.max_amount / err_details.amount_received;
-            debug!("Found path Maximum Packet Amount: {}", max_packet_amount);
-            (*self.congestion_controller.lock()).set_max_packet_amount(max_packet_amount);
+            match max_packet_amount_from_f08(original_amount, &err_details) {
+                Some(max_packet_amount) => {
+                    debug!("Found path Maximum Packet Amount: {}", max_packet_amount);
+                    (*self.congestion_controller.lock()).set_max_packet_amount(max_packet_amount);
+                }
+                None => warn!("Ignoring F08 error with invalid details: {:?}", err_details),
+            }
         }
 
         // Parse STREAM response packet from F99 errors
         let response = {
-            if &reject.code == "F99" && !reject.data.is_empty() {
+            if reject.code == IlpErrorCode::F99ApplicationError && !reject.data.is_empty() {
                 match StreamPacket::from_encrypted(&self.shared_secret, BytesMut::from(reject.data))
                 {
                     Ok(packet) => {
@@ -656,6 +1878,7 @@ impl Connection {
         let streams = self.streams.read();
 
         // Release pending money
+        let mut money_stream_ids: Vec<u64> = Vec::new();
         for frame in original_packet.frames.iter() {
             if let Frame::StreamMoney(frame) = frame {
                 let stream_id = frame.stream_id.to_u64().unwrap();
@@ -663,14 +1886,80 @@ impl Connection {
 
                 let shares = frame.shares.to_u64().unwrap();
                 stream.money.subtract_from_pending(shares);
+                money_stream_ids.push(stream_id);
+            }
+        }
+
+        // Otherwise streams whose money can never be delivered would keep the connection from closing
+        if !money_stream_ids.is_empty()
+            && self.failed_money_packets.fetch_add(1, Ordering::SeqCst) + 1
+                >= MAX_FAILED_MONEY_PACKETS
+        {
+            for stream_id in money_stream_ids.iter() {
+                warn!(
+                    "Giving up on sending money on stream {} after {} rejected packets",
+                    stream_id, MAX_FAILED_MONEY_PACKETS
+                );
+                let stream = streams.get(stream_id).unwrap();
+                stream.money.fail_sending(Error::SendMoneyError(format!(
+                    "{} packets were rejected",
+                    MAX_FAILED_MONEY_PACKETS
+                )));
+                stream.money.try_wake_polling();
             }
         }
+        // The handlers below take the streams lock themselves
+        drop(streams);
         // TODO handle response frames
 
         if let Some(packet) = response.as_ref() {
-            self.handle_incoming_data(&packet)?;
+            if self.handle_incoming_data(&packet).is_err() {
+                self.close_now();
+                return Ok(());
+            }
+
+            self.handle_new_address(&packet);
+
+            self.handle_asset_details(&packet);
+
+            self.handle_max_money(&packet);
+
+            self.handle_max_data(&packet);
+
+            self.handle_max_stream_id(&packet);
 
             self.handle_connection_close(&packet);
+
+            if packet.prepare_amount < original_packet.prepare_amount {
+                error!(
+                    "Exchange rate is too low, packet delivered {} but we required at least {}. Stopping streams {:?}",
+                    packet.prepare_amount, original_packet.prepare_amount, money_stream_ids
+                );
+                let streams = self.streams.read();
+                for stream_id in money_stream_ids.iter() {
+                    if let Some(stream) = streams.get(stream_id) {
+                        stream.money.fail_sending(Error::ExchangeRateError(format!(
+                            "packet delivered {} but we required at least {}",
+                            packet.prepare_amount, original_packet.prepare_amount
+                        )));
+                        stream.money.try_wake_polling();
+                    }
+                }
+            }
+        }
+
+        // Don't wait forever for the other side to acknowledge that we closed the connection
+        let is_close_packet = original_packet.frames.iter().any(|frame| {
+            if let Frame::ConnectionClose(_) = frame {
+                true
+            } else {
+                false
+            }
+        });
+        if is_close_packet {
+            debug!("ConnectionClose frame was rejected, closing connection now");
+            self.close_now();
+            return Ok(());
         }
 
         // Only resend frames if they didn't get to the receiver
@@ -683,6 +1972,9 @@ impl Connection {
                     Frame::ConnectionClose(frame) => {
                         frames_to_resend.push(Frame::ConnectionClose(frame))
                     }
+                    Frame::ConnectionNewAddress(frame) => {
+                        frames_to_resend.push(Frame::ConnectionNewAddress(frame))
+                    }
                     _ => {}
                 }
             }
@@ -691,44 +1983,234 @@ impl Connection {
         Ok(())
     }
 
-    fn send_handshake(&self) {
+    fn send_probe(&self, amount: u64) {
         let sequence = self.next_packet_sequence.fetch_add(1, Ordering::SeqCst) as u64;
         let packet = StreamPacket {
             sequence,
             ilp_packet_type: PacketType::IlpPrepare,
             prepare_amount: 0,
-            frames: vec![Frame::ConnectionNewAddress(ConnectionNewAddressFrame {
-                source_account: self.source_account.to_string(),
-            })],
+            frames: Vec::new(),
         };
-        self.send_unfulfillable_prepare(&packet);
+        debug!("Sending test packet of {}", amount);
+        let (request_id, expires_at) = self.send_unfulfillable_prepare(&packet, amount);
+        (*self.pending_probes.lock()).insert(
+            request_id,
+            ProbeRecord {
+                sequence,
+                amount,
+                expires_at,
+            },
+        );
     }
 
-    // TODO wait for response
-    fn send_unfulfillable_prepare(&self, stream_packet: &StreamPacket) -> () {
-        let request_id = random_u32();
-        let prepare = IlpPacket::Prepare(IlpPrepare::new(
-            // TODO do we need to clone this?
-            self.destination_account.to_string(),
-            0,
-            random_condition(),
-            Utc::now() + Duration::seconds(30),
-            stream_packet.to_encrypted(&self.shared_secret).unwrap(),
-        ));
-        self.outgoing.unbounded_send((request_id, prepare)).unwrap();
-    }
+    fn handle_probe_response(&self, probe: ProbeRecord, reject: &IlpReject) {
+        // Try again with a smaller amount if the packet was too big
+        if let Some(err_details) = parse_f08_error(reject) {
+            match max_packet_amount_from_f08(probe.amount, &err_details) {
+                Some(max_packet_amount) => {
+                    debug!("Found path Maximum Packet Amount: {}", max_packet_amount);
+                    (*self.congestion_controller.lock()).set_max_packet_amount(max_packet_amount);
+                    self.send_probe(max_packet_amount);
+                }
+                None => warn!("Ignoring F08 error with invalid details: {:?}", err_details),
+            }
+            return;
+        }
 
-    fn try_wake_polling(&self) {
-        if let Some(task) = (*self.recv_task.lock()).take() {
-            debug!("Notifying incoming stream poller that it should wake up");
-            task.notify();
+        if reject.code != IlpErrorCode::F99ApplicationError || reject.data.is_empty() {
+            debug!(
+                "Test packet of {} was rejected with code: {}",
+                probe.amount, reject.code
+            );
+            return;
+        }
+        let packet = match StreamPacket::from_encrypted(
+            &self.shared_secret,
+            BytesMut::from(&reject.data[..]),
+        ) {
+            Ok(packet) => packet,
+            Err(err) => {
+                warn!("Unable to decrypt response to test packet: {:?}", err);
+                return;
+            }
+        };
+        if packet.sequence != probe.sequence {
+            warn!(
+                "Got response to test packet with the wrong sequence: {:?}",
+                packet
+            );
+            return;
         }
-    }
-}
 
-impl Stream for Connection {
-    type Item = DataMoneyStream;
-    type Error = ();
+        // Larger test packets give more precise rates
+        let mut exchange_rate = self.exchange_rate.write();
+        let is_more_precise = match exchange_rate.probed {
+            Some((_rate, amount)) => probe.amount > amount,
+            None => true,
+        };
+        if is_more_precise {
+            let rate = packet.prepare_amount as f64 / probe.amount as f64;
+            debug!(
+                "Test packet of {} delivered {}, exchange rate is: {}",
+                probe.amount, packet.prepare_amount, rate
+            );
+            exchange_rate.probed = Some((rate, probe.amount));
+        }
+    }
+
+    fn send_handshake(&self) {
+        let sequence = self.next_packet_sequence.fetch_add(1, Ordering::SeqCst) as u64;
+        let mut frames = vec![Frame::ConnectionNewAddress(ConnectionNewAddressFrame {
+            source_account: self.source_account(),
+        })];
+        if let Some(ref asset) = *self.source_asset.read() {
+            frames.push(Frame::ConnectionAssetDetails(ConnectionAssetDetailsFrame {
+                source_asset_code: asset.code.clone(),
+                source_asset_scale: asset.scale,
+            }));
+        }
+        let packet = StreamPacket {
+            sequence,
+            ilp_packet_type: PacketType::IlpPrepare,
+            prepare_amount: 0,
+            frames,
+        };
+        debug!("Sending handshake");
+        let (request_id, expires_at) = self.send_unfulfillable_prepare(&packet, 0);
+        *self.pending_handshake.lock() = Some(HandshakeRecord {
+            request_id,
+            sequence,
+            expires_at,
+        });
+    }
+
+    fn handle_handshake_response(&self, handshake: HandshakeRecord, reject: &IlpReject) {
+        let result = if reject.code == IlpErrorCode::F99ApplicationError && !reject.data.is_empty()
+        {
+            match StreamPacket::from_encrypted(
+                &self.shared_secret,
+                BytesMut::from(&reject.data[..]),
+            ) {
+                Ok(ref packet) if packet.sequence != handshake.sequence => {
+                    Err(Error::ConnectionError(format!(
+                        "Got handshake response with the wrong sequence: {}",
+                        packet.sequence
+                    )))
+                }
+                Ok(packet) => Ok(packet),
+                Err(err) => {
+                    warn!("Unable to decrypt response to handshake: {:?}", err);
+                    Err(Error::InvalidSharedSecretError)
+                }
+            }
+        } else if reject.code == IlpErrorCode::F06UnexpectedPayment {
+            Err(Error::InvalidSharedSecretError)
+        } else {
+            Err(Error::UnreachableError(format!(
+                "Handshake was rejected with code: {} {}",
+                reject.code, reject.message
+            )))
+        };
+
+        match result {
+            Ok(packet) => {
+                debug!("Got handshake response: {:?}", packet);
+                self.handle_asset_details(&packet);
+                self.handle_max_money(&packet);
+                self.handle_max_data(&packet);
+                self.handle_max_stream_id(&packet);
+                self.state
+                    .store(ConnectionState::Open as usize, Ordering::SeqCst);
+                self.handle_connection_close(&packet);
+            }
+            Err(err) => {
+                warn!("Handshake failed: {}", err);
+                *self.handshake_error.lock() = Some(err);
+                self.close_now();
+            }
+        }
+    }
+
+    fn send_unfulfillable_prepare(
+        &self,
+        stream_packet: &StreamPacket,
+        amount: u64,
+    ) -> (u32, DateTime<Utc>) {
+        let (request, expires_at) = self.unfulfillable_prepare(stream_packet, amount);
+        let request_id = request.0;
+        self.outgoing.unbounded_send(request).unwrap();
+        (request_id, expires_at)
+    }
+
+    fn unfulfillable_prepare(
+        &self,
+        stream_packet: &StreamPacket,
+        amount: u64,
+    ) -> (IlpRequest, DateTime<Utc>) {
+        let request_id = random_u32();
+        let expires_at = Utc::now() + *self.packet_expiry.read();
+        let prepare = IlpPacket::Prepare(IlpPrepare::new(
+            self.destination_account(),
+            amount,
+            random_condition(),
+            expires_at,
+            stream_packet.to_encrypted(&self.shared_secret).unwrap(),
+        ));
+        ((request_id, prepare), expires_at)
+    }
+
+    fn try_wake_polling(&self) {
+        if let Some(task) = (*self.recv_task.lock()).take() {
+            debug!("Notifying incoming stream poller that it should wake up");
+            task.notify();
+        }
+    }
+}
+
+// Scales the amount we sent down to the max the connector can handle, or returns None
+// if the F08 details (which come straight from the connector) don't make sense
+fn max_packet_amount_from_f08(amount_sent: u64, details: &MaxPacketAmountDetails) -> Option<u64> {
+    if details.max_amount >= details.amount_received {
+        return None;
+    }
+    // Can't overflow because the max is smaller than the amount received
+    let max_packet_amount = (u128::from(amount_sent) * u128::from(details.max_amount)
+        / u128::from(details.amount_received)) as u64;
+    if max_packet_amount > 0 {
+        Some(max_packet_amount)
+    } else {
+        None
+    }
+}
+
+// Returns the stream ID and amount for each money frame, or None if the
+// shares the other side sent don't fit in a u64 or the amounts overflow
+fn split_amount_by_shares(stream_packet: &StreamPacket, amount: u64) -> Option<Vec<(u64, u64)>> {
+    let mut shares: Vec<(u64, u64)> = Vec::new();
+    let mut total_shares: u64 = 0;
+    for frame in stream_packet.frames.iter() {
+        if let Frame::StreamMoney(frame) = frame {
+            let stream_id = frame.stream_id.to_u64()?;
+            let frame_shares = frame.shares.to_u64()?;
+            total_shares = total_shares.checked_add(frame_shares)?;
+            shares.push((stream_id, frame_shares));
+        }
+    }
+    shares
+        .into_iter()
+        .map(|(stream_id, frame_shares)| {
+            let stream_amount = frame_shares
+                .checked_mul(amount)?
+                .checked_div(total_shares)
+                .unwrap_or(0);
+            Some((stream_id, stream_amount))
+        })
+        .collect()
+}
+
+impl Stream for Connection {
+    type Item = DataMoneyStream;
+    type Error = ();
 
     fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
         trace!("Polling for new incoming streams");
@@ -754,7 +2236,13 @@ impl Stream for Connection {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use futures::future::ok;
     use futures::sync::mpsc::unbounded;
+    use tokio::runtime::current_thread::block_on_all;
+
+    lazy_static! {
+        static ref SHARED_SECRET: Bytes = Bytes::from(&[0u8; 32][..]);
+    }
 
     fn test_conn() -> (
         Connection,
@@ -766,15 +2254,117 @@ mod tests {
         let conn = Connection::new(
             outgoing_tx,
             incoming_rx,
-            Bytes::from(&[0u8; 32][..]),
-            String::from("example.alice"),
-            String::from("example.bob"),
+            SHARED_SECRET.clone(),
+            Address::new("example.alice").unwrap(),
+            Address::new("example.bob").unwrap(),
             // Set as server so it doesn't bother sending the handshake
             true,
         );
         (conn, incoming_tx, outgoing_rx)
     }
 
+    // Send the connection a Prepare with the given frames and let it handle it
+    fn send_prepare(
+        conn: &Connection,
+        incoming: &UnboundedSender<IlpRequest>,
+        sequence: u64,
+        amount: u64,
+        frames: Vec<Frame>,
+    ) {
+        let stream_packet = StreamPacket {
+            sequence,
+            ilp_packet_type: PacketType::IlpPrepare,
+            prepare_amount: 0,
+            frames,
+        };
+        let data = stream_packet.to_encrypted(&SHARED_SECRET[..]).unwrap();
+        let prepare = IlpPrepare::new(
+            Address::new("example.alice").unwrap(),
+            amount,
+            generate_condition(&SHARED_SECRET[..], &data[..]),
+            Utc::now() + Duration::seconds(30),
+            data,
+        );
+        respond(
+            conn,
+            incoming,
+            (sequence as u32, IlpPacket::Prepare(prepare)),
+        );
+    }
+
+    fn respond(conn: &Connection, incoming: &UnboundedSender<IlpRequest>, request: IlpRequest) {
+        incoming.unbounded_send(request).unwrap();
+        block_on_all(ok(()).and_then(|_| conn.try_handle_incoming())).unwrap();
+    }
+
+    // Decrypt the STREAM packet in a packet the connection sent
+    fn decrypt(request: Option<IlpRequest>) -> (u32, IlpPacket, StreamPacket) {
+        let (request_id, packet) = request.unwrap();
+        let data = match packet {
+            IlpPacket::Fulfill(ref fulfill) => fulfill.data.clone(),
+            IlpPacket::Reject(ref reject) => reject.data.clone(),
+            IlpPacket::Prepare(ref prepare) => prepare.data.clone(),
+        };
+        let stream_packet =
+            StreamPacket::from_encrypted(&SHARED_SECRET[..], BytesMut::from(&data[..])).unwrap();
+        (request_id, packet, stream_packet)
+    }
+
+    fn next_request(
+        outgoing: &mut UnboundedReceiver<IlpRequest>,
+    ) -> (u32, IlpPacket, StreamPacket) {
+        let (request, _) = outgoing.into_future().wait().ok().unwrap();
+        decrypt(request)
+    }
+
+    fn expect_prepare(packet: IlpPacket) -> IlpPrepare {
+        match packet {
+            IlpPacket::Prepare(prepare) => prepare,
+            _ => panic!("Expected Prepare"),
+        }
+    }
+
+    // Fulfill a Prepare the connection sent, saying the whole amount arrived
+    fn fulfill(
+        conn: &Connection,
+        incoming: &UnboundedSender<IlpRequest>,
+        request: (u32, IlpPacket, StreamPacket),
+    ) {
+        let amount = expect_prepare(request.1.clone()).amount;
+        fulfill_with(conn, incoming, request, amount, Vec::new());
+    }
+
+    fn fulfill_with(
+        conn: &Connection,
+        incoming: &UnboundedSender<IlpRequest>,
+        request: (u32, IlpPacket, StreamPacket),
+        prepare_amount: u64,
+        frames: Vec<Frame>,
+    ) {
+        let (request_id, packet, stream_packet) = request;
+        let prepare = expect_prepare(packet);
+        let response_packet = StreamPacket {
+            sequence: stream_packet.sequence,
+            ilp_packet_type: PacketType::IlpFulfill,
+            prepare_amount,
+            frames,
+        };
+        let fulfill = IlpFulfill::new(
+            generate_fulfillment(&SHARED_SECRET[..], &prepare.data[..]),
+            response_packet.to_encrypted(&SHARED_SECRET[..]).unwrap(),
+        );
+        respond(conn, incoming, (request_id, IlpPacket::Fulfill(fulfill)));
+    }
+
+    fn reject(
+        conn: &Connection,
+        incoming: &UnboundedSender<IlpRequest>,
+        request: (u32, IlpPacket, StreamPacket),
+    ) {
+        let reject = IlpReject::new(IlpErrorCode::T04InsufficientLiquidity, "", "", Bytes::new());
+        respond(conn, incoming, (request.0, IlpPacket::Reject(reject)));
+    }
+
     mod max_packet_amount {
         use super::*;
         use futures::future::ok;
@@ -785,7 +2375,7 @@ mod tests {
         #[test]
         fn discovery() {
             let (conn, incoming, outgoing) = test_conn();
-            let mut stream = conn.create_stream();
+            let mut stream = conn.create_stream().wait().unwrap();
 
             // Send money
             stream.money.start_send(100).unwrap();
@@ -811,7 +2401,7 @@ mod tests {
         #[test]
         fn discovery_with_exchange_rate() {
             let (conn, incoming, outgoing) = test_conn();
-            let mut stream = conn.create_stream();
+            let mut stream = conn.create_stream().wait().unwrap();
 
             // Send money
             stream.money.start_send(1000).unwrap();
@@ -833,5 +2423,1006 @@ mod tests {
                 assert!(false);
             }
         }
+
+        #[test]
+        fn ignores_f08_errors_with_invalid_details() {
+            let (conn, incoming, mut outgoing) = test_conn();
+            let mut stream = conn.create_stream().wait().unwrap();
+            stream.money.start_send(100).unwrap();
+
+            let (request_id, _, _) = next_request(&mut outgoing);
+            let error = IlpPacket::Reject(create_f08_error(0, 0));
+            respond(&conn, &incoming, (request_id, error));
+            let (request_id, packet, _) = next_request(&mut outgoing);
+            assert_eq!(expect_prepare(packet).amount, 100);
+
+            let error = IlpPacket::Reject(create_f08_error(u64::max_value(), u64::max_value()));
+            respond(&conn, &incoming, (request_id, error));
+            let (_, packet, _) = next_request(&mut outgoing);
+            assert_eq!(expect_prepare(packet).amount, 100);
+        }
+    }
+
+    mod receipts {
+        use super::*;
+        use futures::future::ok;
+        use futures::Sink;
+        use tokio::runtime::current_thread::block_on_all;
+
+        lazy_static! {
+            static ref RECEIPT_SECRET: Bytes = Bytes::from(&[7u8; 32][..]);
+        }
+
+        #[test]
+        fn signs_receipts_for_received_money() {
+            let (conn, incoming, outgoing) = test_conn();
+            conn.enable_receipts(RECEIPT_SECRET.clone());
+
+            let stream_packet = StreamPacket {
+                sequence: 1,
+                ilp_packet_type: PacketType::IlpPrepare,
+                prepare_amount: 0,
+                frames: vec![Frame::StreamMoney(StreamMoneyFrame {
+                    stream_id: BigUint::from(1 as u64),
+                    shares: BigUint::from(1 as u64),
+                })],
+            };
+            let data = stream_packet.to_encrypted(&SHARED_SECRET[..]).unwrap();
+            let prepare = IlpPrepare::new(
+                Address::new("example.alice").unwrap(),
+                100,
+                generate_condition(&SHARED_SECRET[..], &data[..]),
+                Utc::now() + Duration::seconds(30),
+                data,
+            );
+            incoming
+                .unbounded_send((1, IlpPacket::Prepare(prepare)))
+                .unwrap();
+            block_on_all(ok(()).and_then(|_| conn.try_handle_incoming())).unwrap();
+
+            let (response, _outgoing) = outgoing.into_future().wait().unwrap();
+            let fulfill = match response.unwrap() {
+                (_, IlpPacket::Fulfill(fulfill)) => fulfill,
+                _ => panic!("Expected Fulfill"),
+            };
+            let response_packet =
+                StreamPacket::from_encrypted(&SHARED_SECRET[..], BytesMut::from(fulfill.data))
+                    .unwrap();
+            let receipt = match response_packet.frames[0] {
+                Frame::StreamReceipt(ref frame) => Receipt::from_bytes(&frame.receipt[..]).unwrap(),
+                _ => panic!("Expected StreamReceipt frame"),
+            };
+            assert_eq!(receipt.stream_id, 1);
+            assert_eq!(receipt.total_received, 100);
+            assert_eq!(Some(receipt.nonce), conn.receipt_nonce());
+            assert!(receipt.verify(&RECEIPT_SECRET[..]));
+        }
+
+        #[test]
+        fn stores_latest_receipt_on_money_stream() {
+            let (conn, incoming, mut outgoing) = test_conn();
+            let mut stream = conn.create_stream().wait().unwrap();
+
+            stream.money.start_send(100).unwrap();
+            let request = next_request(&mut outgoing);
+
+            let receipt = Receipt::new(&RECEIPT_SECRET[..], [1; 16], stream.id, 100);
+            let receipt_frame = Frame::StreamReceipt(StreamReceiptFrame {
+                stream_id: BigUint::from(stream.id),
+                receipt: receipt.to_bytes(),
+            });
+            fulfill_with(&conn, &incoming, request, 100, vec![receipt_frame]);
+
+            assert_eq!(stream.money.receipt(), Some(receipt));
+        }
+    }
+
+    mod addresses {
+        use super::*;
+
+        #[test]
+        fn uses_new_address_from_remote() {
+            let (conn, incoming, _outgoing) = test_conn();
+            let new_address = Frame::ConnectionNewAddress(ConnectionNewAddressFrame {
+                source_account: Address::new("example.carl").unwrap(),
+            });
+            send_prepare(&conn, &incoming, 1, 0, vec![new_address]);
+
+            assert_eq!(conn.destination_account(), "example.carl");
+        }
+
+        #[test]
+        fn announces_own_new_address() {
+            let (conn, _incoming, mut outgoing) = test_conn();
+
+            conn.set_source_account(Address::new("example.alice2").unwrap())
+                .unwrap();
+            assert_eq!(conn.source_account(), "example.alice2");
+
+            let (_, packet, stream_packet) = next_request(&mut outgoing);
+            assert_eq!(expect_prepare(packet).destination, "example.bob");
+            assert!(stream_packet.frames.contains(&Frame::ConnectionNewAddress(
+                ConnectionNewAddressFrame {
+                    source_account: Address::new("example.alice2").unwrap(),
+                }
+            )));
+        }
+    }
+
+    mod flow_control {
+        use super::*;
+        use std::io::Write;
+
+        fn closed_with_flow_control_error(frames: &[Frame]) -> bool {
+            frames.iter().any(|frame| match frame {
+                Frame::ConnectionClose(frame) => frame.code == ErrorCode::FlowControlError,
+                _ => false,
+            })
+        }
+
+        #[test]
+        fn closes_when_remote_exceeds_window() {
+            let (conn, incoming, mut outgoing) = test_conn();
+            let data = Frame::StreamData(StreamDataFrame {
+                stream_id: BigUint::from(1 as u64),
+                offset: BigUint::from(0 as u64),
+                data: Bytes::from(vec![0; DEFAULT_STREAM_RECEIVE_WINDOW + 1]),
+            });
+            send_prepare(&conn, &incoming, 1, 0, vec![data]);
+
+            let (_, packet, response_packet) = next_request(&mut outgoing);
+            match packet {
+                IlpPacket::Reject(_) => {}
+                _ => panic!("Expected Reject"),
+            }
+            assert!(closed_with_flow_control_error(&response_packet.frames));
+            assert!(conn.is_closed());
+        }
+
+        #[test]
+        fn closes_when_data_offset_overflows() {
+            let (conn, incoming, mut outgoing) = test_conn();
+            let data = Frame::StreamData(StreamDataFrame {
+                stream_id: BigUint::from(1 as u64),
+                offset: BigUint::from(usize::max_value()),
+                data: Bytes::from(&b"hello"[..]),
+            });
+            send_prepare(&conn, &incoming, 1, 0, vec![data]);
+
+            let (_, _, response_packet) = next_request(&mut outgoing);
+            assert!(closed_with_flow_control_error(&response_packet.frames));
+            assert!(conn.is_closed());
+        }
+
+        #[test]
+        fn only_sends_up_to_remote_window() {
+            let (conn, _incoming, outgoing) = test_conn();
+            let mut stream = conn.create_stream().wait().unwrap();
+
+            let written = stream
+                .data
+                .write(&vec![0; DEFAULT_STREAM_RECEIVE_WINDOW + 100][..])
+                .unwrap();
+            assert_eq!(written, DEFAULT_STREAM_RECEIVE_WINDOW);
+            let (request, outgoing) = outgoing.into_future().wait().unwrap();
+            let data_len = decrypt(request)
+                .2
+                .frames
+                .iter()
+                .fold(0, |sum, frame| match frame {
+                    Frame::StreamData(frame) => sum + frame.data.len(),
+                    _ => sum,
+                });
+            assert_eq!(data_len, DEFAULT_STREAM_RECEIVE_WINDOW);
+
+            // The rest has to wait until the other side raises the limit
+            stream.data.write(&[0; 10][..]).unwrap();
+            let (request, _outgoing) = outgoing.into_future().wait().unwrap();
+            let frames = decrypt(request).2.frames;
+            assert!(
+                frames.contains(&Frame::StreamDataBlocked(StreamDataBlockedFrame {
+                    stream_id: BigUint::from(stream.id),
+                    max_offset: BigUint::from(DEFAULT_STREAM_RECEIVE_WINDOW),
+                }))
+            );
+            assert!(frames.iter().all(|frame| match frame {
+                Frame::StreamData(_) => false,
+                _ => true,
+            }));
+        }
+    }
+
+    mod expiry {
+        use super::*;
+        use futures::future::ok;
+        use futures::Sink;
+        use tokio::runtime::current_thread::block_on_all;
+        use tokio::timer::Delay;
+
+        #[test]
+        fn resends_money_from_expired_packets() {
+            let (conn, _incoming, outgoing) = test_conn();
+            conn.set_packet_expiry(Duration::milliseconds(0));
+            let mut stream = conn.create_stream().wait().unwrap();
+
+            stream.money.start_send(100).unwrap();
+            let (request, outgoing) = outgoing.into_future().wait().unwrap();
+            let (first_request_id, prepare) = request.unwrap();
+            if let IlpPacket::Prepare(prepare) = prepare {
+                assert_eq!(prepare.amount, 100);
+            } else {
+                assert!(false);
+            }
+
+            // Wait for the expiry timer to fire
+            let wait = StdDuration::from_millis(10);
+            block_on_all(
+                Delay::new(Instant::now() + wait)
+                    .map_err(|_| ())
+                    .and_then(|_| conn.try_handle_incoming()),
+            ).unwrap();
+
+            let (request, _outgoing) = outgoing.into_future().wait().unwrap();
+            let (request_id, prepare) = request.unwrap();
+            assert_ne!(request_id, first_request_id);
+            if let IlpPacket::Prepare(prepare) = prepare {
+                assert_eq!(prepare.amount, 100);
+            } else {
+                assert!(false);
+            }
+        }
+
+        #[test]
+        fn counts_fulfills_that_arrive_after_expiry() {
+            let (conn, incoming, mut outgoing) = test_conn();
+            conn.set_packet_expiry(Duration::milliseconds(0));
+            let mut stream = conn.create_stream().wait().unwrap();
+
+            stream.money.start_send(100).unwrap();
+            let request = next_request(&mut outgoing);
+
+            let wait = StdDuration::from_millis(10);
+            block_on_all(
+                Delay::new(Instant::now() + wait)
+                    .map_err(|_| ())
+                    .and_then(|_| conn.try_handle_incoming()),
+            )
+            .unwrap();
+            assert_eq!(stream.money.total_sent(), 0);
+
+            fulfill(&conn, &incoming, request);
+            assert_eq!(stream.money.total_sent(), 100);
+            assert_eq!(stream.money.total_delivered(), 100);
+            assert_eq!(conn.stats().amount_sent, 100);
+        }
+
+        #[test]
+        fn only_sets_timer_while_waiting_for_responses() {
+            let (conn, _incoming, _outgoing) = test_conn();
+            block_on_all(ok(()).and_then(|_| conn.try_handle_incoming())).unwrap();
+            assert!(conn.expiry_timer.lock().is_none());
+
+            let mut stream = conn.create_stream().wait().unwrap();
+            stream.money.start_send(100).unwrap();
+            block_on_all(ok(()).and_then(|_| conn.try_handle_incoming())).unwrap();
+            assert!(conn.expiry_timer.lock().is_some());
+        }
+
+        #[test]
+        fn ignores_fulfills_for_unknown_requests() {
+            let (conn, incoming, _outgoing) = test_conn();
+            let fulfill = IlpFulfill::new(Bytes::from(&[0u8; 32][..]), Bytes::new());
+            incoming
+                .unbounded_send((1234, IlpPacket::Fulfill(fulfill)))
+                .unwrap();
+            block_on_all(ok(()).and_then(|_| conn.try_handle_incoming())).unwrap();
+        }
+    }
+
+    mod exchange_rate {
+        use super::*;
+        use futures::future::ok;
+        use futures::Sink;
+        use tokio::runtime::current_thread::block_on_all;
+
+        fn reject_with_amount(prepare: &IlpPrepare, amount_received: u64) -> IlpPacket {
+            let sequence =
+                StreamPacket::from_encrypted(&SHARED_SECRET[..], BytesMut::from(&prepare.data[..]))
+                    .unwrap()
+                    .sequence;
+            let response = StreamPacket {
+                sequence,
+                ilp_packet_type: PacketType::IlpReject,
+                prepare_amount: amount_received,
+                frames: Vec::new(),
+            };
+            IlpPacket::Reject(IlpReject::new(
+                IlpErrorCode::F99ApplicationError,
+                "",
+                "",
+                response.to_encrypted(&SHARED_SECRET[..]).unwrap(),
+            ))
+        }
+
+        #[test]
+        fn probes_rate_and_sets_min_destination_amount() {
+            let (conn, incoming, outgoing) = test_conn();
+            let rate_future = conn.probe_exchange_rate();
+
+            let mut outgoing = outgoing;
+            for _ in 0..PROBE_AMOUNTS.len() {
+                let (request, rest) = outgoing.into_future().wait().unwrap();
+                outgoing = rest;
+                let (request_id, prepare) = request.unwrap();
+                if let IlpPacket::Prepare(prepare) = prepare {
+                    let reject = reject_with_amount(&prepare, prepare.amount / 2);
+                    incoming.unbounded_send((request_id, reject)).unwrap();
+                } else {
+                    assert!(false);
+                }
+            }
+            let rate = block_on_all(rate_future).unwrap();
+            assert!((rate - 0.5).abs() < f64::EPSILON);
+
+            conn.set_max_slippage(0.1);
+            let mut stream = conn.create_stream().wait().unwrap();
+            stream.money.start_send(100).unwrap();
+            let (request, _outgoing) = outgoing.into_future().wait().unwrap();
+            if let (_, IlpPacket::Prepare(prepare)) = request.unwrap() {
+                let stream_packet = StreamPacket::from_encrypted(
+                    &SHARED_SECRET[..],
+                    BytesMut::from(&prepare.data[..]),
+                ).unwrap();
+                assert_eq!(stream_packet.prepare_amount, 45);
+            } else {
+                assert!(false);
+            }
+        }
+
+        #[test]
+        fn stops_streams_whose_money_is_delivered_at_too_low_a_rate() {
+            let (conn, incoming, outgoing) = test_conn();
+            conn.set_min_exchange_rate(1.0);
+            let mut stream = conn.create_stream().wait().unwrap();
+            let mut other_stream = conn.create_stream().wait().unwrap();
+            stream.money.start_send(100).unwrap();
+
+            let (request, outgoing) = outgoing.into_future().wait().unwrap();
+            let (request_id, prepare) = request.unwrap();
+            if let IlpPacket::Prepare(prepare) = prepare {
+                let reject = reject_with_amount(&prepare, 50);
+                incoming.unbounded_send((request_id, reject)).unwrap();
+            } else {
+                assert!(false);
+            }
+            block_on_all(ok(()).and_then(|_| conn.try_handle_incoming())).unwrap();
+
+            assert_eq!(stream.money.send_max(), stream.money.total_sent());
+            match block_on_all(stream.money.clone().flush()).err() {
+                Some(Error::ExchangeRateError(_)) => {}
+                _ => panic!("Expected ExchangeRateError"),
+            }
+            assert_ne!(
+                conn.state.load(Ordering::SeqCst),
+                ConnectionState::Closing as usize
+            );
+            assert!(!conn.is_closed());
+
+            // Other streams can keep sending
+            other_stream.money.start_send(10).unwrap();
+            let (request, _outgoing) = outgoing.into_future().wait().unwrap();
+            match request.unwrap() {
+                (_, IlpPacket::Prepare(prepare)) => assert_eq!(prepare.amount, 10),
+                _ => panic!("Expected Prepare"),
+            }
+        }
+
+        #[test]
+        fn rejects_packets_that_deliver_too_little() {
+            let (conn, incoming, outgoing) = test_conn();
+
+            let stream_packet = StreamPacket {
+                sequence: 1,
+                ilp_packet_type: PacketType::IlpPrepare,
+                prepare_amount: 200,
+                frames: vec![Frame::StreamMoney(StreamMoneyFrame {
+                    stream_id: BigUint::from(1 as u64),
+                    shares: BigUint::from(1 as u64),
+                })],
+            };
+            let data = stream_packet.to_encrypted(&SHARED_SECRET[..]).unwrap();
+            let prepare = IlpPrepare::new(
+                Address::new("example.alice").unwrap(),
+                100,
+                generate_condition(&SHARED_SECRET[..], &data[..]),
+                Utc::now() + Duration::seconds(30),
+                data,
+            );
+            incoming
+                .unbounded_send((1, IlpPacket::Prepare(prepare)))
+                .unwrap();
+            block_on_all(ok(()).and_then(|_| conn.try_handle_incoming())).unwrap();
+
+            let (response, _outgoing) = outgoing.into_future().wait().unwrap();
+            let reject = match response.unwrap() {
+                (_, IlpPacket::Reject(reject)) => reject,
+                _ => panic!("Expected Reject"),
+            };
+            assert_eq!(reject.code, IlpErrorCode::F99ApplicationError);
+            let response_packet =
+                StreamPacket::from_encrypted(&SHARED_SECRET[..], BytesMut::from(reject.data))
+                    .unwrap();
+            assert_eq!(response_packet.prepare_amount, 100);
+            let streams = conn.streams.read();
+            assert_eq!(streams.get(&1).unwrap().money.total_received(), 0);
+        }
+    }
+
+    mod handshake {
+        use super::*;
+        use futures::future::ok;
+        use tokio::runtime::current_thread::block_on_all;
+
+        fn client_conn() -> (
+            Connection,
+            UnboundedSender<IlpRequest>,
+            UnboundedReceiver<IlpRequest>,
+        ) {
+            let (incoming_tx, incoming_rx) = unbounded::<IlpRequest>();
+            let (outgoing_tx, outgoing_rx) = unbounded::<IlpRequest>();
+            let conn = Connection::new(
+                outgoing_tx,
+                incoming_rx,
+                SHARED_SECRET.clone(),
+                Address::new("example.alice").unwrap(),
+                Address::new("example.bob").unwrap(),
+                false,
+            );
+            conn.set_source_asset(AssetDetails {
+                code: String::from("USD"),
+                scale: 2,
+            });
+            (conn, incoming_tx, outgoing_rx)
+        }
+
+        #[test]
+        fn opens_after_response() {
+            let (conn, incoming, outgoing) = client_conn();
+            let handshake = conn.handshake();
+            assert!(!conn.is_closed());
+
+            let (request, _outgoing) = outgoing.into_future().wait().unwrap();
+            let (request_id, _, packet) = decrypt(request);
+            let new_address = Frame::ConnectionNewAddress(ConnectionNewAddressFrame {
+                source_account: Address::new("example.alice").unwrap(),
+            });
+            let asset_details = Frame::ConnectionAssetDetails(ConnectionAssetDetailsFrame {
+                source_asset_code: String::from("USD"),
+                source_asset_scale: 2,
+            });
+            assert!(packet.frames.contains(&new_address));
+            assert!(packet.frames.contains(&asset_details));
+
+            let response = StreamPacket {
+                sequence: packet.sequence,
+                ilp_packet_type: PacketType::IlpReject,
+                prepare_amount: 0,
+                frames: vec![Frame::ConnectionAssetDetails(ConnectionAssetDetailsFrame {
+                    source_asset_code: String::from("EUR"),
+                    source_asset_scale: 3,
+                })],
+            };
+            let reject = IlpReject::new(
+                IlpErrorCode::F99ApplicationError,
+                "",
+                "",
+                response.to_encrypted(&SHARED_SECRET[..]).unwrap(),
+            );
+            incoming
+                .unbounded_send((request_id, IlpPacket::Reject(reject)))
+                .unwrap();
+
+            block_on_all(handshake).unwrap();
+            assert_eq!(
+                conn.state.load(Ordering::SeqCst),
+                ConnectionState::Open as usize
+            );
+            assert_eq!(
+                conn.destination_asset(),
+                Some(AssetDetails {
+                    code: String::from("EUR"),
+                    scale: 3,
+                })
+            );
+        }
+
+        #[test]
+        fn fails_if_unreachable() {
+            let (conn, incoming, outgoing) = client_conn();
+            let handshake = conn.handshake();
+
+            let (request, _outgoing) = outgoing.into_future().wait().unwrap();
+            let (request_id, _, _packet) = decrypt(request);
+            let reject = IlpReject::new(IlpErrorCode::F02Unreachable, "", "", Bytes::new());
+            incoming
+                .unbounded_send((request_id, IlpPacket::Reject(reject)))
+                .unwrap();
+
+            match block_on_all(handshake) {
+                Err(Error::UnreachableError(_)) => {}
+                _ => panic!("Expected UnreachableError"),
+            }
+            assert!(conn.is_closed());
+        }
+
+        #[test]
+        fn fails_if_shared_secret_is_wrong() {
+            let (conn, incoming, outgoing) = client_conn();
+            let handshake = conn.handshake();
+
+            let (request, _outgoing) = outgoing.into_future().wait().unwrap();
+            let (request_id, _, packet) = decrypt(request);
+            let response = StreamPacket {
+                sequence: packet.sequence,
+                ilp_packet_type: PacketType::IlpReject,
+                prepare_amount: 0,
+                frames: Vec::new(),
+            };
+            let reject = IlpReject::new(
+                IlpErrorCode::F99ApplicationError,
+                "",
+                "",
+                response.to_encrypted(&[1u8; 32][..]).unwrap(),
+            );
+            incoming
+                .unbounded_send((request_id, IlpPacket::Reject(reject)))
+                .unwrap();
+
+            match block_on_all(handshake) {
+                Err(Error::InvalidSharedSecretError) => {}
+                _ => panic!("Expected InvalidSharedSecretError"),
+            }
+            assert!(conn.is_closed());
+        }
+
+        #[test]
+        fn receiver_replies_with_asset_details() {
+            let (conn, incoming, outgoing) = test_conn();
+            conn.set_source_asset(AssetDetails {
+                code: String::from("EUR"),
+                scale: 3,
+            });
+
+            let stream_packet = StreamPacket {
+                sequence: 1,
+                ilp_packet_type: PacketType::IlpPrepare,
+                prepare_amount: 0,
+                frames: vec![Frame::ConnectionAssetDetails(ConnectionAssetDetailsFrame {
+                    source_asset_code: String::from("USD"),
+                    source_asset_scale: 2,
+                })],
+            };
+            let prepare = IlpPrepare::new(
+                Address::new("example.alice").unwrap(),
+                0,
+                random_condition(),
+                Utc::now() + Duration::seconds(30),
+                stream_packet.to_encrypted(&SHARED_SECRET[..]).unwrap(),
+            );
+            incoming
+                .unbounded_send((1, IlpPacket::Prepare(prepare)))
+                .unwrap();
+            block_on_all(ok(()).and_then(|_| conn.try_handle_incoming())).unwrap();
+
+            let (response, _outgoing) = outgoing.into_future().wait().unwrap();
+            let reject = match response.unwrap() {
+                (_, IlpPacket::Reject(reject)) => reject,
+                _ => panic!("Expected Reject"),
+            };
+            let response_packet =
+                StreamPacket::from_encrypted(&SHARED_SECRET[..], BytesMut::from(reject.data))
+                    .unwrap();
+            let asset_details = Frame::ConnectionAssetDetails(ConnectionAssetDetailsFrame {
+                source_asset_code: String::from("EUR"),
+                source_asset_scale: 3,
+            });
+            assert!(response_packet.frames.contains(&asset_details));
+            assert_eq!(
+                conn.destination_asset(),
+                Some(AssetDetails {
+                    code: String::from("USD"),
+                    scale: 2,
+                })
+            );
+        }
+    }
+
+    mod asset_details {
+        use super::*;
+
+        #[test]
+        fn formats_amounts() {
+            let usd = AssetDetails {
+                code: String::from("USD"),
+                scale: 2,
+            };
+            assert_eq!(usd.format_amount(1000), "10.00 USD");
+            assert_eq!(usd.format_amount(5), "0.05 USD");
+            let xrp = AssetDetails {
+                code: String::from("XRP"),
+                scale: 0,
+            };
+            assert_eq!(xrp.format_amount(893), "893 XRP");
+        }
+    }
+
+    mod stream_limits {
+        use super::*;
+        use futures::future::lazy;
+        use tokio::runtime::current_thread::block_on_all;
+
+        fn open_stream(stream_id: u64) -> Frame {
+            Frame::StreamMoney(StreamMoneyFrame {
+                stream_id: BigUint::from(stream_id),
+                shares: BigUint::from(1 as u64),
+            })
+        }
+
+        fn closed_with_stream_id_error(frames: &[Frame]) -> bool {
+            frames.iter().any(|frame| {
+                if let Frame::ConnectionClose(frame) = frame {
+                    frame.code == ErrorCode::StreamIdError
+                } else {
+                    false
+                }
+            })
+        }
+
+        #[test]
+        fn rejects_streams_with_our_parity() {
+            let (conn, incoming, outgoing) = test_conn();
+            send_prepare(&conn, &incoming, 1, 0, vec![open_stream(2)]);
+
+            let (response, _outgoing) = outgoing.into_future().wait().unwrap();
+            assert!(closed_with_stream_id_error(&decrypt(response).2.frames));
+            assert!(conn.is_closed());
+        }
+
+        #[test]
+        fn rejects_streams_over_the_limit() {
+            let (conn, incoming, outgoing) = test_conn();
+            conn.set_max_remote_streams(2);
+            send_prepare(&conn, &incoming, 1, 0, vec![open_stream(3)]);
+            let (response, outgoing) = outgoing.into_future().wait().unwrap();
+            let frames = decrypt(response).2.frames;
+            assert!(!closed_with_stream_id_error(&frames));
+            let max_stream_id = Frame::ConnectionMaxStreamId(ConnectionMaxStreamIdFrame {
+                max_stream_id: BigUint::from(5 as u64),
+            });
+            assert!(frames.contains(&max_stream_id));
+
+            send_prepare(&conn, &incoming, 1, 0, vec![open_stream(7)]);
+            let (response, _outgoing) = outgoing.into_future().wait().unwrap();
+            assert!(closed_with_stream_id_error(&decrypt(response).2.frames));
+            assert!(conn.is_closed());
+        }
+
+        #[test]
+        fn create_stream_waits_for_remote_limit() {
+            let (conn, incoming, outgoing) = test_conn();
+            send_prepare(
+                &conn,
+                &incoming,
+                1,
+                0,
+                vec![Frame::ConnectionMaxStreamId(ConnectionMaxStreamIdFrame {
+                    max_stream_id: BigUint::from(2 as u64),
+                })],
+            );
+            let (_response, outgoing) = outgoing.into_future().wait().unwrap();
+
+            let first = conn.create_stream().wait().unwrap();
+            assert_eq!(first.id, 2);
+
+            let conn_clone = conn.clone();
+            let second = block_on_all(lazy(move || {
+                let mut second = conn_clone.create_stream();
+                assert!(second.poll().unwrap().is_not_ready());
+                Ok(second) as Result<CreateStreamFuture, ()>
+            })).unwrap();
+            let (request, _outgoing) = outgoing.into_future().wait().unwrap();
+            let blocked = Frame::ConnectionStreamIdBlocked(ConnectionStreamIdBlockedFrame {
+                max_stream_id: BigUint::from(2 as u64),
+            });
+            assert!(decrypt(request).2.frames.contains(&blocked));
+
+            send_prepare(
+                &conn,
+                &incoming,
+                1,
+                0,
+                vec![Frame::ConnectionMaxStreamId(ConnectionMaxStreamIdFrame {
+                    max_stream_id: BigUint::from(4 as u64),
+                })],
+            );
+            assert_eq!(block_on_all(second).unwrap().id, 4);
+        }
+    }
+
+    mod money_limits {
+        use super::*;
+        use futures::future::lazy;
+        use futures::Sink;
+        use tokio::runtime::current_thread::block_on_all;
+
+        fn send_money(stream_id: u64) -> Frame {
+            Frame::StreamMoney(StreamMoneyFrame {
+                stream_id: BigUint::from(stream_id),
+                shares: BigUint::from(1 as u64),
+            })
+        }
+
+        #[test]
+        fn rejects_money_over_receive_max() {
+            let (conn, incoming, outgoing) = test_conn();
+            send_prepare(&conn, &incoming, 1, 100, vec![send_money(1)]);
+            let (response, outgoing) = outgoing.into_future().wait().unwrap();
+            match decrypt(response).1 {
+                IlpPacket::Fulfill(_) => {}
+                _ => panic!("Expected Fulfill"),
+            }
+
+            let stream = (*conn.streams.read()).get(&1).unwrap().clone();
+            stream.money.set_receive_max(150);
+            let (request, outgoing) = outgoing.into_future().wait().unwrap();
+            let max_money = Frame::StreamMaxMoney(StreamMaxMoneyFrame {
+                stream_id: BigUint::from(1 as u64),
+                receive_max: BigUint::from(150 as u64),
+                total_received: BigUint::from(100 as u64),
+            });
+            assert!(decrypt(request).2.frames.contains(&max_money));
+
+            send_prepare(&conn, &incoming, 2, 100, vec![send_money(1)]);
+            let (response, _outgoing) = outgoing.into_future().wait().unwrap();
+            let (_request_id, packet, stream_packet) = decrypt(response);
+            match packet {
+                IlpPacket::Reject(_) => {}
+                _ => panic!("Expected Reject"),
+            }
+            assert!(stream_packet.frames.contains(&max_money));
+            assert_eq!(stream.money.total_received(), 100);
+        }
+
+        #[test]
+        fn rejects_shares_that_overflow() {
+            let (conn, incoming, outgoing) = test_conn();
+            let frame = Frame::StreamMoney(StreamMoneyFrame {
+                stream_id: BigUint::from(1 as u64),
+                shares: BigUint::from(u64::max_value()),
+            });
+            send_prepare(&conn, &incoming, 1, 100, vec![frame]);
+            let (response, _outgoing) = outgoing.into_future().wait().unwrap();
+            match decrypt(response).1 {
+                IlpPacket::Reject(ref reject) => {
+                    assert_eq!(reject.code, IlpErrorCode::F99ApplicationError)
+                }
+                _ => panic!("Expected Reject"),
+            }
+        }
+
+        #[test]
+        fn sender_stops_at_remote_receive_max() {
+            let (conn, incoming, outgoing) = test_conn();
+            let stream = conn.create_stream().wait().unwrap();
+            send_prepare(
+                &conn,
+                &incoming,
+                1,
+                0,
+                vec![Frame::StreamMaxMoney(StreamMaxMoneyFrame {
+                    stream_id: BigUint::from(stream.id),
+                    receive_max: BigUint::from(50 as u64),
+                    total_received: BigUint::from(0 as u64),
+                })],
+            );
+            let (_response, outgoing) = outgoing.into_future().wait().unwrap();
+
+            let mut send = stream.money.clone().send(100);
+            block_on_all(lazy(|| {
+                assert!(send.poll().unwrap().is_not_ready());
+                Ok(()) as Result<(), ()>
+            }))
+            .unwrap();
+            let (request, outgoing) = outgoing.into_future().wait().unwrap();
+            let request = decrypt(request);
+            assert_eq!(expect_prepare(request.1.clone()).amount, 50);
+            fulfill(&conn, &incoming, request);
+
+            let (request, _outgoing) = outgoing.into_future().wait().unwrap();
+            let (_request_id, packet, stream_packet) = decrypt(request);
+            match packet {
+                IlpPacket::Prepare(prepare) => assert_eq!(prepare.amount, 0),
+                _ => panic!("Expected Prepare"),
+            }
+            let blocked = Frame::StreamMoneyBlocked(StreamMoneyBlockedFrame {
+                stream_id: BigUint::from(stream.id),
+                send_max: BigUint::from(100 as u64),
+                total_sent: BigUint::from(50 as u64),
+            });
+            assert!(stream_packet.frames.contains(&blocked));
+            assert_eq!(stream.money.total_sent(), 50);
+
+            // The sender is told instead of waiting for the receiver to raise the limit
+            match block_on_all(send).err() {
+                Some(Error::ReceiveMaxError(_)) => {}
+                _ => panic!("Expected ReceiveMaxError"),
+            }
+        }
+    }
+
+    mod closing {
+        use super::*;
+        use futures::future::{lazy, ok};
+        use futures::Sink;
+        use std::io::Write;
+        use tokio::runtime::current_thread::block_on_all;
+
+        fn has_stream_close(frames: &[Frame], stream_id: u64) -> bool {
+            frames.iter().any(|frame| {
+                if let Frame::StreamClose(frame) = frame {
+                    frame.stream_id == BigUint::from(stream_id)
+                } else {
+                    false
+                }
+            })
+        }
+
+        fn has_connection_close(frames: &[Frame]) -> bool {
+            frames.iter().any(|frame| {
+                if let Frame::ConnectionClose(_) = frame {
+                    true
+                } else {
+                    false
+                }
+            })
+        }
+
+        #[test]
+        fn close_waits_for_money_to_be_acknowledged() {
+            let (conn, incoming, mut outgoing) = test_conn();
+            let mut stream = conn.create_stream().wait().unwrap();
+            stream.money.start_send(100).unwrap();
+            let money_request = next_request(&mut outgoing);
+
+            let mut close_future = conn.close();
+            block_on_all(lazy(|| {
+                assert!(close_future.poll().unwrap().is_not_ready());
+                // Nothing else is sent until the money is fulfilled
+                assert!(outgoing.poll().unwrap().is_not_ready());
+                Ok(()) as Result<(), ()>
+            })).unwrap();
+
+            fulfill(&conn, &incoming, money_request);
+            let close_request = next_request(&mut outgoing);
+            assert!(has_stream_close(&close_request.2.frames, stream.id));
+            assert!(has_connection_close(&close_request.2.frames));
+            assert_eq!(stream.money.total_sent(), 100);
+
+            fulfill(&conn, &incoming, close_request);
+            block_on_all(close_future).unwrap();
+            assert!(conn.is_closed());
+        }
+
+        #[test]
+        fn close_resolves_if_close_packet_is_rejected() {
+            let (conn, incoming, mut outgoing) = test_conn();
+            let close_future = conn.close();
+            block_on_all(ok(()).and_then(|_| conn.try_send())).unwrap();
+            let close_request = next_request(&mut outgoing);
+            assert!(has_connection_close(&close_request.2.frames));
+
+            reject(&conn, &incoming, close_request);
+            block_on_all(close_future).unwrap();
+            assert!(conn.is_closed());
+        }
+
+        #[test]
+        fn close_gives_up_on_money_that_cannot_be_delivered() {
+            let (conn, incoming, mut outgoing) = test_conn();
+            let mut stream = conn.create_stream().wait().unwrap();
+            stream.money.start_send(100).unwrap();
+            let close_future = conn.close();
+
+            for _ in 0..MAX_FAILED_MONEY_PACKETS {
+                let money_request = next_request(&mut outgoing);
+                assert!(!has_connection_close(&money_request.2.frames));
+                reject(&conn, &incoming, money_request);
+            }
+            let close_request = next_request(&mut outgoing);
+            assert!(has_stream_close(&close_request.2.frames, stream.id));
+            assert!(has_connection_close(&close_request.2.frames));
+            assert_eq!(stream.money.total_sent(), 0);
+
+            fulfill(&conn, &incoming, close_request);
+            block_on_all(close_future).unwrap();
+            match block_on_all(stream.money.clone().flush()).err() {
+                Some(Error::SendMoneyError(_)) => {}
+                _ => panic!("Expected SendMoneyError"),
+            }
+        }
+
+        #[test]
+        fn remote_close_lets_us_finish_sending_data() {
+            let (conn, incoming, mut outgoing) = test_conn();
+            let open_stream = Frame::StreamMoney(StreamMoneyFrame {
+                stream_id: BigUint::from(1 as u64),
+                shares: BigUint::from(1 as u64),
+            });
+            send_prepare(&conn, &incoming, 1, 10, vec![open_stream]);
+            next_request(&mut outgoing);
+
+            let mut stream = (*conn.streams.read()).get(&1).unwrap().clone();
+            stream.data.write_all(b"hello").unwrap();
+            let data_request = next_request(&mut outgoing);
+
+            let close_stream = Frame::StreamClose(StreamCloseFrame {
+                stream_id: BigUint::from(1 as u64),
+                code: ErrorCode::NoError,
+                message: String::new(),
+            });
+            send_prepare(&conn, &incoming, 2, 0, vec![close_stream]);
+            let response = next_request(&mut outgoing);
+            assert!(!has_stream_close(&response.2.frames, 1));
+            assert!(stream.is_closing());
+
+            fulfill(&conn, &incoming, data_request);
+            let close_request = next_request(&mut outgoing);
+            assert!(has_stream_close(&close_request.2.frames, 1));
+            assert!(stream.is_closed());
+        }
+    }
+
+    mod stats {
+        use super::*;
+        use futures::Sink;
+
+        #[test]
+        fn tracks_packets_and_amounts() {
+            let (conn, incoming, mut outgoing) = test_conn();
+            let mut stream = conn.create_stream().wait().unwrap();
+            stream.money.start_send(100).unwrap();
+
+            let request = next_request(&mut outgoing);
+            assert_eq!(expect_prepare(request.1.clone()).amount, 100);
+            assert_eq!(conn.stats().amount_in_flight, 100);
+            assert_eq!(stream.stats().amount_in_flight, 100);
+            reject(&conn, &incoming, request);
+
+            let request = next_request(&mut outgoing);
+            assert_eq!(expect_prepare(request.1.clone()).amount, 100);
+            fulfill_with(&conn, &incoming, request, 200, Vec::new());
+
+            let stats = conn.stats();
+            assert_eq!(stats.packets_sent, 2);
+            assert_eq!(stats.packets_fulfilled, 1);
+            assert_eq!(
+                stats
+                    .packets_rejected
+                    .get(&IlpErrorCode::T04InsufficientLiquidity),
+                Some(&1)
+            );
+            assert_eq!(stats.amount_sent, 100);
+            assert_eq!(stats.amount_delivered, 200);
+            assert_eq!(stats.amount_in_flight, 0);
+            assert_eq!(stats.exchange_rate, Some(2.0));
+            assert!(stats.min_rtt.is_some());
+            assert!(stats.smoothed_rtt.is_some());
+            assert!(stats.congestion_window.is_some());
+
+            let stream_stats = stream.stats();
+            assert_eq!(stream_stats.amount_sent, 100);
+            assert_eq!(stream_stats.amount_delivered, 200);
+            assert_eq!(stream_stats.amount_in_flight, 0);
+        }
     }
 }
@@ -1,15 +1,24 @@
 use super::connection::Connection;
+use super::receipt::Receipt;
+use super::stats::StreamStats;
+use super::Error;
 use bytes::{BufMut, Bytes, BytesMut};
 use futures::task;
 use futures::task::Task;
 use futures::{Async, AsyncSink, Future, Poll, Sink, StartSend, Stream};
 use parking_lot::{Mutex, RwLock};
+use std::cmp::{max, min};
 use std::collections::{HashMap, VecDeque};
 use std::io::{Error as IoError, ErrorKind, Read, Write};
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
 use tokio_io::{AsyncRead, AsyncWrite};
 
+/// How many bytes each side may send on a stream beyond what the receiver has read.
+/// Both sides assume this until the receiver advertises a different limit.
+pub const DEFAULT_STREAM_RECEIVE_WINDOW: usize = 65_536;
+const MAX_OUTGOING_BUFFER: usize = DEFAULT_STREAM_RECEIVE_WINDOW;
+
 #[derive(PartialEq)]
 pub enum StreamState {
     Open,
@@ -34,6 +43,18 @@ impl DataMoneyStream {
         }
     }
 
+    /// A snapshot of the money and data this stream has sent and received so far
+    pub fn stats(&self) -> StreamStats {
+        StreamStats {
+            amount_sent: self.money.total_sent(),
+            amount_delivered: self.money.total_delivered(),
+            amount_received: self.money.total_received(),
+            amount_in_flight: self.money.pending(),
+            bytes_sent: self.data.bytes_sent(),
+            bytes_received: self.data.bytes_received(),
+        }
+    }
+
     pub(super) fn new(id: u64, connection: Connection) -> DataMoneyStream {
         let state = Arc::new(RwLock::new(StreamState::Open));
         let connection = Arc::new(connection);
@@ -48,6 +69,15 @@ impl DataMoneyStream {
                 delivered: Arc::new(AtomicUsize::new(0)),
                 received: Arc::new(AtomicUsize::new(0)),
                 last_reported_received: Arc::new(AtomicUsize::new(0)),
+                receipt: Arc::new(Mutex::new(None)),
+                limits: Arc::new(Mutex::new(MoneyLimits {
+                    receive_max: u64::max_value(),
+                    advertised_receive_max: None,
+                    remote_receive_max: u64::max_value(),
+                    remote_received: 0,
+                    sent_blocked: false,
+                })),
+                send_error: Arc::new(Mutex::new(None)),
                 recv_task: Arc::new(Mutex::new(None)),
             },
             data: DataStream {
@@ -56,12 +86,19 @@ impl DataMoneyStream {
                 incoming: Arc::new(Mutex::new(IncomingData {
                     offset: 0,
                     buffer: HashMap::new(),
+                    max_offset_received: 0,
+                    advertised_max_offset: DEFAULT_STREAM_RECEIVE_WINDOW,
+                    advertise_requested: false,
                 })),
                 outgoing: Arc::new(Mutex::new(OutgoingData {
                     offset: 0,
                     buffer: VecDeque::new(),
+                    buffered: 0,
+                    remote_max_offset: DEFAULT_STREAM_RECEIVE_WINDOW,
+                    sent_blocked: false,
                 })),
                 recv_task: Arc::new(Mutex::new(None)),
+                send_task: Arc::new(Mutex::new(None)),
             },
             state: Arc::clone(&state),
             connection: Arc::clone(&connection),
@@ -72,6 +109,10 @@ impl DataMoneyStream {
         *self.state.read() == StreamState::Closing
     }
 
+    pub(super) fn is_closed(&self) -> bool {
+        *self.state.read() == StreamState::Closed
+    }
+
     pub(super) fn set_closing(&self) {
         *self.state.write() = StreamState::Closing;
 
@@ -122,9 +163,23 @@ pub struct MoneyStream {
     delivered: Arc<AtomicUsize>,
     received: Arc<AtomicUsize>,
     last_reported_received: Arc<AtomicUsize>,
+    receipt: Arc<Mutex<Option<Receipt>>>,
+    limits: Arc<Mutex<MoneyLimits>>,
+    // Why we gave up on sending the rest of the money, reported by the next poll_complete
+    send_error: Arc<Mutex<Option<Error>>>,
     recv_task: Arc<Mutex<Option<Task>>>,
 }
 
+// Limits on how much money each side will accept, in that side's units
+struct MoneyLimits {
+    receive_max: u64,
+    advertised_receive_max: Option<u64>,
+    // The other side's limit is unknown until they tell us
+    remote_receive_max: u64,
+    remote_received: u64,
+    sent_blocked: bool,
+}
+
 impl MoneyStream {
     pub fn total_sent(&self) -> u64 {
         self.sent.load(Ordering::SeqCst) as u64
@@ -138,6 +193,94 @@ impl MoneyStream {
         self.received.load(Ordering::SeqCst) as u64
     }
 
+    /// The receipt with the highest total received that the other side has sent us for this stream.
+    ///
+    /// Only a verifier that knows the receipt secret can check the signature,
+    /// so this should be passed along rather than trusted directly.
+    pub fn receipt(&self) -> Option<Receipt> {
+        (*self.receipt.lock()).clone()
+    }
+
+    /// Limit the total amount this stream will accept.
+    ///
+    /// The other side is told about the limit and packets that would exceed it are rejected.
+    pub fn set_receive_max(&self, receive_max: u64) {
+        (*self.limits.lock()).receive_max = receive_max;
+        if self.connection.try_send().is_err() {
+            warn!("Error telling the other side about the new receive max");
+        }
+    }
+
+    pub fn receive_max(&self) -> u64 {
+        (*self.limits.lock()).receive_max
+    }
+
+    pub(super) fn can_receive(&self, amount: u64) -> bool {
+        match self.total_received().checked_add(amount) {
+            Some(total) => total <= (*self.limits.lock()).receive_max,
+            None => false,
+        }
+    }
+
+    // Returns the receive max and total received if the other side should be told about them
+    pub(super) fn max_money_update(&self) -> Option<(u64, u64)> {
+        let mut limits = self.limits.lock();
+        let is_unlimited = limits.receive_max == u64::max_value();
+        if limits.advertised_receive_max == Some(limits.receive_max)
+            || (limits.advertised_receive_max.is_none() && is_unlimited)
+        {
+            return None;
+        }
+        limits.advertised_receive_max = Some(limits.receive_max);
+        Some((limits.receive_max, self.total_received()))
+    }
+
+    pub(super) fn request_max_money_update(&self) {
+        (*self.limits.lock()).advertised_receive_max = None;
+    }
+
+    pub(super) fn set_remote_receive_max(&self, receive_max: u64, total_received: u64) {
+        let mut limits = self.limits.lock();
+        if receive_max > limits.remote_receive_max || limits.remote_receive_max == u64::max_value()
+        {
+            limits.sent_blocked = false;
+        }
+        limits.remote_receive_max = receive_max;
+        limits.remote_received = max(limits.remote_received, total_received);
+    }
+
+    // How much more the other side will accept, in their units, or None if there is no limit
+    pub(super) fn remote_receivable(&self) -> Option<u64> {
+        let limits = self.limits.lock();
+        if limits.remote_receive_max == u64::max_value() {
+            return None;
+        }
+        let received = max(limits.remote_received, self.total_delivered());
+        Some(limits.remote_receive_max.saturating_sub(received))
+    }
+
+    // Returns the send max and total sent the first time we are blocked by the other side's limit
+    pub(super) fn money_blocked(&self) -> Option<(u64, u64)> {
+        let mut limits = self.limits.lock();
+        if limits.sent_blocked {
+            return None;
+        }
+        limits.sent_blocked = true;
+        Some((self.send_max(), self.total_sent()))
+    }
+
+    pub(super) fn set_receipt(&self, receipt: Receipt) {
+        let mut latest = self.receipt.lock();
+        // Receipts may arrive out of order, so only keep the one with the highest amount
+        let is_newer = match *latest {
+            Some(ref latest) => receipt.total_received > latest.total_received,
+            None => true,
+        };
+        if is_newer {
+            *latest = Some(receipt);
+        }
+    }
+
     pub(super) fn pending(&self) -> u64 {
         self.pending.load(Ordering::SeqCst) as u64
     }
@@ -155,10 +298,25 @@ impl MoneyStream {
         self.sent.fetch_add(amount as usize, Ordering::SeqCst);
     }
 
+    // For money that was sent again because we thought it had not gone through
+    pub(super) fn add_sent(&self, amount: u64) {
+        self.sent.fetch_add(amount as usize, Ordering::SeqCst);
+    }
+
     pub(super) fn send_max(&self) -> u64 {
         self.send_max.load(Ordering::SeqCst) as u64
     }
 
+    // Give up on the money that has not been sent yet and fail the sender
+    pub(super) fn fail_sending(&self, error: Error) {
+        self.send_max
+            .store(self.sent.load(Ordering::SeqCst), Ordering::SeqCst);
+        let mut send_error = self.send_error.lock();
+        if send_error.is_none() {
+            *send_error = Some(error);
+        }
+    }
+
     pub(super) fn add_received(&self, amount: u64) {
         self.received.fetch_add(amount as usize, Ordering::SeqCst);
     }
@@ -204,21 +362,41 @@ impl Stream for MoneyStream {
 
 impl Sink for MoneyStream {
     type SinkItem = u64;
-    type SinkError = ();
+    type SinkError = Error;
 
     fn start_send(&mut self, amount: u64) -> StartSend<Self::SinkItem, Self::SinkError> {
         if *self.state.read() != StreamState::Open {
             debug!("Cannot send money through stream because it is already closed or closing");
-            return Err(());
+            return Err(Error::ConnectionError(String::from("Stream is closed")));
         }
         self.send_max.fetch_add(amount as usize, Ordering::SeqCst);
-        self.connection.try_send()?;
+        self.connection
+            .try_send()
+            .map_err(|_| connection_closed())?;
         Ok(AsyncSink::Ready)
     }
 
     fn poll_complete(&mut self) -> Poll<(), Self::SinkError> {
-        self.connection.try_send()?;
-        self.connection.try_handle_incoming()?;
+        self.connection
+            .try_send()
+            .map_err(|_| connection_closed())?;
+        self.connection
+            .try_handle_incoming()
+            .map_err(|_| connection_closed())?;
+
+        // Nothing more will get through unless the other side raises their receive max,
+        // which they may never do (for example if they only want to be paid an invoice)
+        let (total_sent, send_max) = (self.total_sent(), self.send_max());
+        if total_sent < send_max && self.pending() == 0 && self.remote_receivable() == Some(0) {
+            debug!("Stream blocked by the remote receive max, stopping");
+            self.fail_sending(Error::ReceiveMaxError(format!(
+                "sent {} of {}",
+                total_sent, send_max
+            )));
+        }
+        if let Some(error) = (*self.send_error.lock()).take() {
+            return Err(error);
+        }
 
         if self.sent.load(Ordering::SeqCst) >= self.send_max.load(Ordering::SeqCst) {
             Ok(Async::Ready(()))
@@ -229,6 +407,10 @@ impl Sink for MoneyStream {
     }
 }
 
+fn connection_closed() -> Error {
+    Error::ConnectionError(String::from("Connection closed"))
+}
+
 #[derive(Clone)]
 pub struct DataStream {
     connection: Arc<Connection>,
@@ -236,6 +418,8 @@ pub struct DataStream {
     incoming: Arc<Mutex<IncomingData>>,
     outgoing: Arc<Mutex<OutgoingData>>,
     recv_task: Arc<Mutex<Option<Task>>>,
+    // This is used to wake the writer when there is room in the buffer again
+    send_task: Arc<Mutex<Option<Task>>>,
 }
 
 struct IncomingData {
@@ -243,23 +427,130 @@ struct IncomingData {
     // TODO should we allow duplicate bytes and let the other side to resize chunks of data?
     // (we would need a sorted list instead of a HashMap to allow this)
     buffer: HashMap<usize, Bytes>,
+    max_offset_received: usize,
+    advertised_max_offset: usize,
+    advertise_requested: bool,
 }
 
 struct OutgoingData {
     offset: usize,
     buffer: VecDeque<Bytes>,
+    buffered: usize,
+    remote_max_offset: usize,
+    sent_blocked: bool,
 }
 
 impl DataStream {
-    pub(super) fn push_incoming_data(&self, data: Bytes, offset: usize) -> Result<(), ()> {
-        // TODO error if the buffer is too full
+    /// Buffer incoming data and return how many bytes beyond the previous highest offset it contained.
+    /// Returns an error if the data goes past the max offset we advertised
+    pub(super) fn push_incoming_data(&self, mut data: Bytes, offset: usize) -> Result<usize, ()> {
         // TODO don't block
-        self.incoming.lock().buffer.insert(offset, data);
-        Ok(())
+        let mut incoming = self.incoming.lock();
+        let end = match offset.checked_add(data.len()) {
+            Some(end) => end,
+            None => {
+                warn!("Got data with an offset that is too big: {}", offset);
+                return Err(());
+            }
+        };
+        if end > incoming.advertised_max_offset {
+            warn!(
+                "Got data up to offset {}, which exceeds the max offset of {}",
+                end, incoming.advertised_max_offset
+            );
+            return Err(());
+        }
+
+        let new_bytes = end.saturating_sub(incoming.max_offset_received);
+        if end > incoming.max_offset_received {
+            incoming.max_offset_received = end;
+        }
+
+        // Ignore data that has already been read
+        if end <= incoming.offset {
+            return Ok(new_bytes);
+        }
+        let offset = if offset < incoming.offset {
+            data = data.slice_from(incoming.offset - offset);
+            incoming.offset
+        } else {
+            offset
+        };
+        incoming.buffer.insert(offset, data);
+        Ok(new_bytes)
+    }
+
+    /// The number of bytes that have been received but not yet read
+    pub(super) fn incoming_buffered(&self) -> usize {
+        let incoming = self.incoming.lock();
+        incoming.max_offset_received - incoming.offset
+    }
+
+    /// Returns the new max offset to advertise to the other side,
+    /// if enough data has been read since the last time (or they asked for it)
+    pub(super) fn max_offset_update(&self) -> Option<usize> {
+        let mut incoming = self.incoming.lock();
+        let max_offset = incoming.offset + DEFAULT_STREAM_RECEIVE_WINDOW;
+        let should_advertise = max_offset > incoming.advertised_max_offset
+            && (incoming.advertise_requested
+                || max_offset - incoming.advertised_max_offset
+                    >= DEFAULT_STREAM_RECEIVE_WINDOW / 2);
+        if should_advertise {
+            incoming.advertised_max_offset = max_offset;
+            incoming.advertise_requested = false;
+            Some(max_offset)
+        } else {
+            None
+        }
+    }
+
+    pub(super) fn request_max_offset_update(&self) {
+        self.incoming.lock().advertise_requested = true;
+    }
+
+    pub(super) fn bytes_sent(&self) -> u64 {
+        self.outgoing.lock().offset as u64
+    }
+
+    pub(super) fn bytes_received(&self) -> u64 {
+        self.incoming.lock().max_offset_received as u64
+    }
+
+    pub(super) fn has_outgoing_data(&self) -> bool {
+        self.outgoing.lock().buffered > 0
+    }
+
+    pub(super) fn set_remote_max_offset(&self, max_offset: usize) {
+        let mut outgoing = self.outgoing.lock();
+        if max_offset > outgoing.remote_max_offset {
+            trace!("Remote raised the max offset to {}", max_offset);
+            outgoing.remote_max_offset = max_offset;
+            outgoing.sent_blocked = false;
+        }
+    }
+
+    /// Returns the max offset if we have data to send but the other side's window is full.
+    /// This only returns it once for each limit so we don't keep telling them
+    pub(super) fn data_blocked(&self) -> Option<usize> {
+        let mut outgoing = self.outgoing.lock();
+        if outgoing.buffered > 0
+            && outgoing.offset >= outgoing.remote_max_offset
+            && !outgoing.sent_blocked
+        {
+            outgoing.sent_blocked = true;
+            Some(outgoing.remote_max_offset)
+        } else {
+            None
+        }
     }
 
     pub(super) fn get_outgoing_data(&self, max_size: usize) -> Option<(Bytes, usize)> {
         let mut outgoing = self.outgoing.lock();
+        // Don't send more than the other side is willing to buffer
+        let max_size = min(
+            max_size,
+            outgoing.remote_max_offset.saturating_sub(outgoing.offset),
+        );
         // TODO make sure we're not copying data here
         let outgoing_offset = outgoing.offset;
         let mut chunks: Vec<Bytes> = Vec::new();
@@ -287,6 +578,8 @@ impl DataStream {
             }
 
             outgoing.offset += size;
+            outgoing.buffered -= size;
+            self.try_wake_writer();
             Some((data.freeze(), outgoing_offset))
         } else {
             None
@@ -299,6 +592,13 @@ impl DataStream {
             task.notify();
         }
     }
+
+    pub(super) fn try_wake_writer(&self) {
+        if let Some(task) = (*self.send_task.lock()).take() {
+            trace!("Notifying the DataStream writer that it should wake up");
+            task.notify();
+        }
+    }
 }
 
 impl Read for DataStream {
@@ -318,31 +618,41 @@ impl Read for DataStream {
         // MoneyStream happens to poll for incoming packets and gets data for us
         *self.recv_task.lock() = Some(task::current());
 
-        let mut incoming = self.incoming.lock();
-        let incoming_offset = incoming.offset;
-        if let Some(mut from_buf) = incoming.buffer.remove(&incoming_offset) {
-            trace!("DataStream has incoming data");
-            if from_buf.len() >= buf.len() {
-                let to_copy = from_buf.split_to(buf.len());
-                buf.copy_from_slice(&to_copy[..]);
-                incoming.offset += to_copy.len();
-
-                // Put the rest back in the queue
-                if !from_buf.is_empty() {
-                    let incoming_offset = incoming.offset;
-                    incoming.buffer.insert(incoming_offset, from_buf);
+        let read = {
+            let mut incoming = self.incoming.lock();
+            let incoming_offset = incoming.offset;
+            if let Some(mut from_buf) = incoming.buffer.remove(&incoming_offset) {
+                trace!("DataStream has incoming data");
+                if from_buf.len() >= buf.len() {
+                    let to_copy = from_buf.split_to(buf.len());
+                    buf.copy_from_slice(&to_copy[..]);
+                    incoming.offset += to_copy.len();
+
+                    // Put the rest back in the queue
+                    if !from_buf.is_empty() {
+                        let incoming_offset = incoming.offset;
+                        incoming.buffer.insert(incoming_offset, from_buf);
+                    }
+
+                    Some(to_copy.len())
+                } else {
+                    let (mut buf_slice, _rest) = buf.split_at_mut(from_buf.len());
+                    buf_slice.copy_from_slice(&from_buf[..]);
+                    incoming.offset += from_buf.len();
+                    Some(from_buf.len())
                 }
-
-                incoming.offset += to_copy.len();
-                trace!("Reading {} bytes of data", to_copy.len());
-                Ok(to_copy.len())
             } else {
-                let (mut buf_slice, _rest) = buf.split_at_mut(from_buf.len());
-                buf_slice.copy_from_slice(&from_buf[..]);
-                incoming.offset += from_buf.len();
-                trace!("Reading {} bytes of data", from_buf.len());
-                Ok(from_buf.len())
+                None
             }
+        };
+
+        if let Some(read) = read {
+            trace!("Reading {} bytes of data", read);
+            // Let the other side know if they can send more data now
+            self.connection.try_send().map_err(|_| {
+                IoError::new(ErrorKind::Other, "Error trying to send through Connection")
+            })?;
+            Ok(read)
         } else if *self.state.read() != StreamState::Open {
             debug!("Data stream ended");
             Ok(0)
@@ -366,14 +676,27 @@ impl Write for DataStream {
             ));
         }
 
-        // TODO limit buffer size
-        let mut outgoing = self.outgoing.lock();
-        outgoing.buffer.push_back(Bytes::from(buf));
+        let written = {
+            let mut outgoing = self.outgoing.lock();
+            let space = MAX_OUTGOING_BUFFER.saturating_sub(outgoing.buffered);
+            if space == 0 {
+                trace!("Outgoing buffer is full, waiting for the other side to read more");
+                *self.send_task.lock() = Some(task::current());
+                return Err(IoError::new(
+                    ErrorKind::WouldBlock,
+                    "Outgoing buffer is full",
+                ));
+            }
+            let written = min(space, buf.len());
+            outgoing.buffer.push_back(Bytes::from(&buf[..written]));
+            outgoing.buffered += written;
+            written
+        };
 
         self.connection.try_send().map_err(|_| {
             IoError::new(ErrorKind::Other, "Error trying to send through Connection")
         })?;
-        Ok(buf.len())
+        Ok(written)
     }
 
     fn flush(&mut self) -> Result<(), IoError> {
@@ -393,6 +716,7 @@ impl Write for DataStream {
         if outgoing.buffer.is_empty() {
             Ok(())
         } else {
+            *self.send_task.lock() = Some(task::current());
             Err(IoError::new(
                 ErrorKind::WouldBlock,
                 "Not finished sending yet",
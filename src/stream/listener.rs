@@ -1,24 +1,33 @@
+use super::congestion::CongestionController;
 use super::crypto;
 use super::packet::*;
 use super::Error;
-use super::{plugin_to_channels, Connection};
+use super::{plugin_to_channels, AssetDetails, Connection};
 use base64;
 use bytes::{Bytes, BytesMut};
 use futures::sync::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
 use futures::Future;
 use futures::{Async, Poll, Sink, Stream};
 use ildcp;
-use ilp::{IlpPacket, IlpPrepare, IlpReject, PacketType};
+use ilp::{
+    Address, ErrorCode as IlpErrorCode, IlpPacket, IlpPrepare, IlpReject, PacketType, ParseError,
+};
 use parking_lot::{Mutex, RwLock};
 use plugin::{IlpRequest, Plugin};
-use std::collections::{HashMap, HashSet};
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 use stream_cancel::{Trigger, Valved};
 use tokio;
+use tokio::timer::Interval;
 
 lazy_static! {
     static ref TAG_ENCRYPTION_KEY_STRING: &'static [u8] = b"ilp_stream_tag_encryption_aes";
 }
+const DEFAULT_IDLE_TIMEOUT_SECS: u64 = 60;
+const TIMEOUT_CHECK_INTERVAL_MS: u64 = 1000;
+// How many closed connection IDs to remember so late packets for them are rejected
+const MAX_CLOSED_CONNECTIONS: usize = 10_000;
 
 fn encrypt_tag(server_secret: &[u8], tag: &str) -> String {
     let key = crypto::hmac_sha256(server_secret, &TAG_ENCRYPTION_KEY_STRING);
@@ -38,12 +47,16 @@ fn decrypt_tag(server_secret: &[u8], encrypted: &str) -> String {
 
 #[derive(Clone)]
 pub struct ConnectionGenerator {
-    source_account: String,
+    source_account: Arc<RwLock<Address>>,
     server_secret: Bytes,
 }
 
 impl ConnectionGenerator {
-    pub fn generate_address_and_secret(&self, connection_tag: &str) -> (String, Bytes) {
+    /// Fails if the connection tag makes the address too long
+    pub fn generate_address_and_secret(
+        &self,
+        connection_tag: &str,
+    ) -> Result<(Address, Bytes), ParseError> {
         let token_bytes = crypto::generate_token();
         let token = base64::encode_config(&token_bytes, base64::URL_SAFE_NO_PAD);
 
@@ -51,14 +64,15 @@ impl ConnectionGenerator {
         // so removing it would cause the packets to be rejected
         let shared_secret =
             crypto::generate_shared_secret_from_token(&self.server_secret, &token.as_bytes());
-        let destination_account = if connection_tag.is_empty() {
-            format!("{}.{}", self.source_account, token)
+        let local_part = if connection_tag.is_empty() {
+            token
         } else {
             let encrypted_tag = encrypt_tag(&self.server_secret, connection_tag);
             // TODO don't use the ~ so it's harder to identify which part is which from the outside
-            format!("{}.{}~{}", self.source_account, token, encrypted_tag)
+            format!("{}~{}", token, encrypted_tag)
         };
-        (destination_account, shared_secret)
+        let destination_account = self.source_account.read().with_suffix(&local_part)?;
+        Ok((destination_account, shared_secret))
     }
 }
 
@@ -72,19 +86,72 @@ impl ConnectionGenerator {
 pub type PrepareToSharedSecretGenerator =
     Box<dyn Fn(&str, &IlpPrepare) -> Result<(String, Bytes), IlpReject> + Send + Sync>;
 
-type ConnectionMap = HashMap<String, (UnboundedSender<IlpRequest>, Trigger, Connection)>;
+type ConnectionMap = HashMap<String, ConnectionEntry>;
+
+struct ConnectionEntry {
+    incoming: UnboundedSender<IlpRequest>,
+    // Stops forwarding outgoing packets when dropped
+    trigger: Trigger,
+    conn: Connection,
+    opened_at: Instant,
+    last_activity: Arc<Mutex<Instant>>,
+}
+
+// Remembers the most recently closed connections, forgetting the oldest ones first
+struct ClosedConnections {
+    ids: HashSet<String>,
+    order: VecDeque<String>,
+    capacity: usize,
+}
+
+impl ClosedConnections {
+    fn new(capacity: usize) -> Self {
+        ClosedConnections {
+            ids: HashSet::new(),
+            order: VecDeque::new(),
+            capacity,
+        }
+    }
+
+    fn insert(&mut self, id: String) {
+        if self.ids.contains(&id) {
+            return;
+        }
+        if self.order.len() >= self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.ids.remove(&oldest);
+            }
+        }
+        self.ids.insert(id.clone());
+        self.order.push_back(id);
+    }
+
+    fn contains(&self, id: &str) -> bool {
+        self.ids.contains(id)
+    }
+}
 
 pub struct StreamListener {
     outgoing_sender: UnboundedSender<IlpRequest>,
     incoming_receiver: UnboundedReceiver<IlpRequest>,
-    source_account: String,
+    // Shared with the ConnectionGenerator so new addresses use the current one
+    source_account: Arc<RwLock<Address>>,
     // TODO do these need to be wrapped in Mutexes?
     connections: Arc<RwLock<ConnectionMap>>,
     pending_requests: Arc<Mutex<HashMap<u32, Arc<String>>>>,
-    closed_connections: Arc<Mutex<HashSet<String>>>,
+    closed_connections: Arc<Mutex<ClosedConnections>>,
     prepare_handler: Arc<PrepareToSharedSecretGenerator>,
+    receipt_secret: Option<Bytes>,
+    congestion_controller_factory: Option<Arc<CongestionControllerFactory>>,
+    max_remote_streams: Option<u64>,
+    source_asset: AssetDetails,
+    idle_timeout: Duration,
+    max_connection_duration: Option<Duration>,
+    timeout_check: Interval,
 }
 
+type CongestionControllerFactory = dyn Fn() -> Box<dyn CongestionController> + Send + Sync;
+
 type PrepareHandler =
     Box<dyn Fn(&str, &IlpPrepare) -> Result<(String, Bytes), IlpReject> + Send + Sync>;
 
@@ -107,7 +174,12 @@ impl StreamListener {
                     let local_address_parts: Vec<&str> = local_address.split('.').collect();
                     if local_address_parts.is_empty() {
                         warn!("Got Prepare with no Connection ID: {}", prepare.destination);
-                        return Err(IlpReject::new("F02", "", "", Bytes::new()));
+                        return Err(IlpReject::new(
+                            IlpErrorCode::F02Unreachable,
+                            "",
+                            "",
+                            Bytes::new(),
+                        ));
                     }
                     let connection_id = local_address_parts[0];
 
@@ -128,18 +200,31 @@ impl StreamListener {
                     }
                 });
 
+                let source_account = Arc::new(RwLock::new(config.client_address));
                 let listener = StreamListener {
                     outgoing_sender,
                     incoming_receiver,
-                    source_account: config.client_address.clone(),
+                    source_account: Arc::clone(&source_account),
                     connections: Arc::new(RwLock::new(HashMap::new())),
                     pending_requests: Arc::new(Mutex::new(HashMap::new())),
-                    closed_connections: Arc::new(Mutex::new(HashSet::new())),
+                    closed_connections: Arc::new(Mutex::new(ClosedConnections::new(
+                        MAX_CLOSED_CONNECTIONS,
+                    ))),
                     prepare_handler: Arc::new(prepare_handler),
+                    receipt_secret: None,
+                    congestion_controller_factory: None,
+                    max_remote_streams: None,
+                    source_asset: AssetDetails {
+                        code: config.asset_code,
+                        scale: config.asset_scale,
+                    },
+                    idle_timeout: Duration::from_secs(DEFAULT_IDLE_TIMEOUT_SECS),
+                    max_connection_duration: None,
+                    timeout_check: timeout_check_interval(),
                 };
 
                 let generator = ConnectionGenerator {
-                    source_account: config.client_address,
+                    source_account,
                     server_secret,
                 };
 
@@ -162,18 +247,94 @@ impl StreamListener {
                 let listener = StreamListener {
                     outgoing_sender,
                     incoming_receiver,
-                    source_account: config.client_address.clone(),
+                    source_account: Arc::new(RwLock::new(config.client_address)),
                     connections: Arc::new(RwLock::new(HashMap::new())),
                     pending_requests: Arc::new(Mutex::new(HashMap::new())),
-                    closed_connections: Arc::new(Mutex::new(HashSet::new())),
+                    closed_connections: Arc::new(Mutex::new(ClosedConnections::new(
+                        MAX_CLOSED_CONNECTIONS,
+                    ))),
                     prepare_handler: Arc::new(prepare_handler),
+                    receipt_secret: None,
+                    congestion_controller_factory: None,
+                    max_remote_streams: None,
+                    source_asset: AssetDetails {
+                        code: config.asset_code,
+                        scale: config.asset_scale,
+                    },
+                    idle_timeout: Duration::from_secs(DEFAULT_IDLE_TIMEOUT_SECS),
+                    max_connection_duration: None,
+                    timeout_check: timeout_check_interval(),
                 };
                 Ok(listener)
             })
     }
 
-    pub fn source_account(&self) -> String {
-        self.source_account.to_string()
+    pub fn source_account(&self) -> Address {
+        self.source_account.read().clone()
+    }
+
+    pub fn source_asset(&self) -> AssetDetails {
+        self.source_asset.clone()
+    }
+
+    /// Use a different congestion control algorithm on all connections accepted from now on
+    pub fn set_congestion_controller<F>(&mut self, factory: F)
+    where
+        F: Fn() -> Box<dyn CongestionController> + Send + Sync + 'static,
+    {
+        self.congestion_controller_factory = Some(Arc::new(factory));
+    }
+
+    /// Change the listener's address, for example after re-running ILDCP
+    /// when the upstream connection is re-established.
+    ///
+    /// Open connections tell the other side to use their address under the new one.
+    pub fn set_source_account(&mut self, source_account: Address) {
+        let old_account = self.source_account();
+        debug!(
+            "Changing listener address from {} to {}",
+            old_account, source_account
+        );
+        for (id, entry) in (*self.connections.read()).iter() {
+            let conn = &entry.conn;
+            let conn_account = conn.source_account();
+            let new_conn_account = conn_account
+                .get(old_account.len() + 1..)
+                .ok_or(())
+                .and_then(|local_part| source_account.with_suffix(local_part).map_err(|_| ()));
+            match new_conn_account {
+                Ok(new_conn_account) => {
+                    if conn.set_source_account(new_conn_account).is_err() {
+                        warn!("Error telling connection {} about the new address", id);
+                    }
+                }
+                Err(_) => warn!(
+                    "Cannot move connection {} with address {} to {}",
+                    id, conn_account, source_account
+                ),
+            }
+        }
+        *self.source_account.write() = source_account;
+    }
+
+    /// Limit how many streams the sender may have open at once on each connection accepted from now on
+    pub fn set_max_remote_streams(&mut self, max_remote_streams: u64) {
+        self.max_remote_streams = Some(max_remote_streams);
+    }
+
+    /// Close connections that have not sent or received any packets for this long
+    pub fn set_idle_timeout(&mut self, idle_timeout: Duration) {
+        self.idle_timeout = idle_timeout;
+    }
+
+    /// Close connections once they have been open this long, even if they are still active
+    pub fn set_max_connection_duration(&mut self, max_connection_duration: Duration) {
+        self.max_connection_duration = Some(max_connection_duration);
+    }
+
+    /// Sign STREAM receipts with the given secret on all connections accepted from now on
+    pub fn set_receipt_secret(&mut self, receipt_secret: Bytes) {
+        self.receipt_secret = Some(receipt_secret);
     }
 
     fn handle_new_connection(
@@ -197,7 +358,7 @@ impl StreamListener {
                     }
                 });
                 if let Some(Frame::ConnectionNewAddress(address_frame)) = frame {
-                    address_frame.source_account.to_string()
+                    address_frame.source_account.clone()
                 } else {
                     warn!(
                         "Got new Connection frame that did not have the sender's address {:?}",
@@ -213,7 +374,12 @@ impl StreamListener {
                     self.outgoing_sender
                         .unbounded_send((
                             request_id,
-                            IlpPacket::Reject(IlpReject::new("F99", "", "", data)),
+                            IlpPacket::Reject(IlpReject::new(
+                                IlpErrorCode::F99ApplicationError,
+                                "",
+                                "",
+                                data,
+                            )),
                         )).map_err(|_| {
                             error!("Error sending reject");
                         })?;
@@ -227,7 +393,12 @@ impl StreamListener {
                 self.outgoing_sender
                     .unbounded_send((
                         request_id,
-                        IlpPacket::Reject(IlpReject::new("F02", "", "", Bytes::new())),
+                        IlpPacket::Reject(IlpReject::new(
+                            IlpErrorCode::F06UnexpectedPayment,
+                            "",
+                            "",
+                            Bytes::new(),
+                        )),
                     )).map_err(|_| {
                         error!("Error sending reject");
                     })?;
@@ -244,8 +415,11 @@ impl StreamListener {
         let connection_id = Arc::new(connection_id.to_string());
         let pending_requests = Arc::clone(&self.pending_requests);
         let connection_id_clone = Arc::clone(&connection_id);
+        let last_activity = Arc::new(Mutex::new(Instant::now()));
+        let last_activity_clone = Arc::clone(&last_activity);
         let outgoing_rx = outgoing_rx.inspect(move |(request_id, packet)| {
             let connection_id = Arc::clone(&connection_id_clone);
+            *last_activity_clone.lock() = Instant::now();
             if let IlpPacket::Prepare(_prepare) = packet {
                 // TODO avoid storing the connection_id over and over
                 (*pending_requests.lock()).insert(*request_id, connection_id);
@@ -271,10 +445,20 @@ impl StreamListener {
             outgoing_tx,
             incoming_rx,
             Bytes::from(shared_secret),
-            self.source_account.to_string(),
+            prepare.destination.clone(),
             destination_account,
             true,
         );
+        conn.set_source_asset(self.source_asset.clone());
+        if let Some(ref receipt_secret) = self.receipt_secret {
+            conn.enable_receipts(receipt_secret.clone());
+        }
+        if let Some(ref factory) = self.congestion_controller_factory {
+            conn.set_congestion_controller(factory());
+        }
+        if let Some(max_remote_streams) = self.max_remote_streams {
+            conn.set_max_remote_streams(max_remote_streams);
+        }
 
         incoming_tx
             .unbounded_send((request_id, IlpPacket::Prepare(prepare)))
@@ -289,24 +473,41 @@ impl StreamListener {
 
         (*self.connections.write()).insert(
             connection_id.to_string(),
-            (incoming_tx.clone(), trigger, conn.clone()),
+            ConnectionEntry {
+                incoming: incoming_tx.clone(),
+                trigger,
+                conn: conn.clone(),
+                opened_at: Instant::now(),
+                last_activity,
+            },
         );
 
         Ok(Some(conn))
     }
 
     fn handle_response(&mut self, request_id: u32, response: IlpPacket) {
-        let pending_requests = self.pending_requests.lock();
-        if let Some(connection_id) = pending_requests.get(&request_id) {
+        let connection_id = (*self.pending_requests.lock()).remove(&request_id);
+        if let Some(connection_id) = connection_id {
             let connection_id = connection_id.to_string();
             let connections = self.connections.read();
-            let (incoming_tx, _trigger, _conn) = connections.get(&connection_id).unwrap();
+            let entry = match connections.get(&connection_id) {
+                Some(entry) => entry,
+                None => {
+                    debug!(
+                        "Ignoring response {} for connection {} that was already closed",
+                        request_id, connection_id
+                    );
+                    return;
+                }
+            };
+            *entry.last_activity.lock() = Instant::now();
             trace!(
                 "Sending response for request {} to connection {}",
                 request_id,
                 connection_id
             );
-            incoming_tx
+            entry
+                .incoming
                 .unbounded_send((request_id, response))
                 .or_else(|err| -> Result<(), ()> {
                     error!(
@@ -323,10 +524,50 @@ impl StreamListener {
         }
     }
 
+    fn check_for_timeouts(&mut self) {
+        let mut should_check = false;
+        loop {
+            match self.timeout_check.poll() {
+                Ok(Async::Ready(Some(_))) => should_check = true,
+                Ok(Async::Ready(None)) | Ok(Async::NotReady) => break,
+                Err(err) => {
+                    warn!("Error polling connection timeout timer: {:?}", err);
+                    break;
+                }
+            }
+        }
+        if !should_check {
+            return;
+        }
+
+        let now = Instant::now();
+        let timed_out: Vec<(String, Connection)> = (*self.connections.read())
+            .iter()
+            .filter(|(_id, entry)| {
+                let is_idle = now.duration_since(*entry.last_activity.lock()) >= self.idle_timeout;
+                let is_too_old = match self.max_connection_duration {
+                    Some(max_duration) => now.duration_since(entry.opened_at) >= max_duration,
+                    None => false,
+                };
+                !entry.conn.is_closed() && (is_idle || is_too_old)
+            }).map(|(id, entry)| (id.to_string(), entry.conn.clone()))
+            .collect();
+
+        for (id, conn) in timed_out {
+            debug!("Connection {} timed out, closing it", id);
+            // Send the close frame directly because the connection's packets
+            // stop being forwarded as soon as it is removed
+            let request = conn.abort(ErrorCode::NoError, "Connection timed out");
+            if self.outgoing_sender.unbounded_send(request).is_err() {
+                warn!("Error telling connection {} that it timed out", id);
+            }
+        }
+    }
+
     fn check_for_closed_connections(&self) {
         let mut connections_to_remove: Vec<String> = Vec::new();
-        for (id, (_sender, _trigger, conn)) in (*self.connections.read()).iter() {
-            if conn.is_closed() {
+        for (id, entry) in (*self.connections.read()).iter() {
+            if entry.conn.is_closed() {
                 connections_to_remove.push(id.to_string());
             }
         }
@@ -337,12 +578,16 @@ impl StreamListener {
             for id in connections_to_remove.iter() {
                 debug!("Connection {} was closed, removing entry", id);
                 let entry = connections.remove(id.as_str());
-                if let Some((mut sender, trigger, _conn)) = entry {
-                    sender.close().unwrap();
-                    drop(trigger);
+                if let Some(mut entry) = entry {
+                    entry.incoming.close().unwrap();
+                    drop(entry.trigger);
                 }
                 closed_connections.insert(id.to_string());
             }
+            // Forget requests that the removed connections were waiting on
+            (*self.pending_requests.lock()).retain(|_request_id, connection_id| {
+                !connections_to_remove.contains(connection_id.as_ref())
+            });
         }
     }
 }
@@ -355,7 +600,7 @@ impl Stream for StreamListener {
         loop {
             trace!("Polling plugin for more incoming packets");
 
-            // TODO timeout connections so they close even if we don't get another packet
+            self.check_for_timeouts();
             self.check_for_closed_connections();
 
             let next = try_ready!(self.incoming_receiver.poll());
@@ -373,10 +618,31 @@ impl Stream for StreamListener {
                     // Handle new Connections or figure out which existing Connection to forward the Prepare to
 
                     // First, generate the shared_secret
-                    let local_address = prepare
-                        .destination
-                        .clone()
-                        .split_off(self.source_account.len() + 1);
+                    let local_address = {
+                        let source_account = self.source_account.read();
+                        if prepare.destination.starts_with(source_account.as_str()) {
+                            prepare
+                                .destination
+                                .get(source_account.len() + 1..)
+                                .map(|local_address| local_address.to_string())
+                        } else {
+                            None
+                        }
+                    };
+                    let local_address = match local_address {
+                        Some(local_address) => local_address,
+                        None => {
+                            warn!("Got Prepare for another address: {}", prepare.destination);
+                            let reject =
+                                IlpReject::new(IlpErrorCode::F02Unreachable, "", "", Bytes::new());
+                            self.outgoing_sender
+                                .unbounded_send((request_id, IlpPacket::Reject(reject)))
+                                .map_err(|_| {
+                                    error!("Error sending reject");
+                                })?;
+                            continue;
+                        }
+                    };
                     let (connection_id, shared_secret) = {
                         match (self.prepare_handler)(&local_address, &prepare) {
                             Ok((connection_id, shared_secret)) => (connection_id, shared_secret),
@@ -398,7 +664,12 @@ impl Stream for StreamListener {
                         self.outgoing_sender
                             .unbounded_send((
                                 request_id,
-                                IlpPacket::Reject(IlpReject::new("F02", "", "", Bytes::new())),
+                                IlpPacket::Reject(IlpReject::new(
+                                    IlpErrorCode::F02Unreachable,
+                                    "",
+                                    "",
+                                    Bytes::new(),
+                                )),
                             )).map_err(|_| {
                                 error!("Error sending reject");
                             })?;
@@ -425,8 +696,10 @@ impl Stream for StreamListener {
                         );
                         // Send the packet to the Connection
                         let connections = self.connections.read();
-                        let (channel, _trigger, _conn) = connections.get(&connection_id).unwrap();
-                        channel
+                        let entry = connections.get(&connection_id).unwrap();
+                        *entry.last_activity.lock() = Instant::now();
+                        entry
+                            .incoming
                             .unbounded_send((request_id, IlpPacket::Prepare(prepare)))
                             .unwrap();
                         continue;
@@ -444,3 +717,43 @@ impl Stream for StreamListener {
         }
     }
 }
+
+fn timeout_check_interval() -> Interval {
+    let interval = Duration::from_millis(TIMEOUT_CHECK_INTERVAL_MS);
+    Interval::new(Instant::now() + interval, interval)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn closed_connections_forget_the_oldest() {
+        let mut closed = ClosedConnections::new(2);
+        closed.insert(String::from("a"));
+        closed.insert(String::from("b"));
+        closed.insert(String::from("a"));
+        assert!(closed.contains("a"));
+        closed.insert(String::from("c"));
+        assert!(!closed.contains("a"));
+        assert!(closed.contains("b"));
+        assert!(closed.contains("c"));
+    }
+
+    #[test]
+    fn generates_addresses_under_source_account() {
+        let generator = ConnectionGenerator {
+            source_account: Arc::new(RwLock::new(Address::new("example.alice").unwrap())),
+            server_secret: Bytes::from(&[0u8; 32][..]),
+        };
+        let (address, _secret) = generator.generate_address_and_secret("").unwrap();
+        assert!(address.starts_with("example.alice"));
+        assert_eq!(address.segments().count(), 3);
+
+        let (address, _secret) = generator.generate_address_and_secret("invoice").unwrap();
+        assert!(address.contains('~'));
+
+        let long_tag = "a".repeat(1024);
+        assert!(generator.generate_address_and_secret(&long_tag).is_err());
+    }
+}
@@ -5,11 +5,17 @@ mod crypto;
 mod data_money_stream;
 mod listener;
 mod packet;
+mod receipt;
+mod stats;
 
 pub use self::client::connect_async;
-pub use self::connection::Connection;
+pub use self::congestion::{AimdController, CongestionController, RateBasedController};
+pub use self::connection::{AssetDetails, Connection, ExchangeRateFuture, HandshakeFuture};
+pub(crate) use self::crypto::random_u32;
 pub use self::data_money_stream::{DataMoneyStream, DataStream, MoneyStream};
 pub use self::listener::{ConnectionGenerator, PrepareToSharedSecretGenerator, StreamListener};
+pub use self::receipt::Receipt;
+pub use self::stats::{ConnectionStats, StreamStats};
 use self::packet::*;
 
 use futures::sync::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
@@ -66,8 +72,18 @@ where
     (outgoing_sender, incoming_receiver)
 }
 
-#[derive(Fail, Debug)]
+#[derive(Fail, Debug, Clone)]
 pub enum Error {
     #[fail(display = "Error connecting: {}", _0)]
     ConnectionError(String),
+    #[fail(display = "Destination unreachable: {}", _0)]
+    UnreachableError(String),
+    #[fail(display = "Receiver could not decrypt our packets, the shared secret may be wrong")]
+    InvalidSharedSecretError,
+    #[fail(display = "Receiver will not accept any more money: {}", _0)]
+    ReceiveMaxError(String),
+    #[fail(display = "Exchange rate is below the minimum: {}", _0)]
+    ExchangeRateError(String),
+    #[fail(display = "Unable to deliver money: {}", _0)]
+    SendMoneyError(String),
 }
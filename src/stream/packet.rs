@@ -2,7 +2,7 @@ use super::crypto::{decrypt, encrypt};
 use byteorder::{ReadBytesExt, WriteBytesExt};
 use bytes::{Bytes, BytesMut};
 use errors::ParseError;
-use ilp::PacketType as IlpPacketType;
+use ilp::{Address, PacketType as IlpPacketType};
 use num_bigint::BigUint;
 use num_traits::cast::ToPrimitive;
 use oer::{ReadOerExt, WriteOerExt};
@@ -102,6 +102,9 @@ impl StreamPacket {
                 FrameType::StreamDataBlocked => {
                     Frame::StreamDataBlocked(StreamDataBlockedFrame::read_contents(&mut contents)?)
                 }
+                FrameType::StreamReceipt => {
+                    Frame::StreamReceipt(StreamReceiptFrame::read_contents(&mut contents)?)
+                }
                 FrameType::Unknown => {
                     warn!(
                         "Ignoring unknown frame of type {}: {:x?}",
@@ -196,6 +199,10 @@ impl StreamPacket {
                     writer.write_u8(FrameType::StreamDataBlocked as u8)?;
                     frame.write_contents(&mut contents)?;
                 }
+                Frame::StreamReceipt(ref frame) => {
+                    writer.write_u8(FrameType::StreamReceipt as u8)?;
+                    frame.write_contents(&mut contents)?;
+                }
                 Frame::Unknown => continue,
             }
             writer.write_var_octet_string(&contents)?;
@@ -220,6 +227,7 @@ pub enum Frame {
     StreamData(StreamDataFrame),
     StreamMaxData(StreamMaxDataFrame),
     StreamDataBlocked(StreamDataBlockedFrame),
+    StreamReceipt(StreamReceiptFrame),
     Unknown,
 }
 
@@ -240,6 +248,7 @@ pub enum FrameType {
     StreamData = 0x14,
     StreamMaxData = 0x15,
     StreamDataBlocked = 0x16,
+    StreamReceipt = 0x17,
     Unknown,
 }
 impl From<u8> for FrameType {
@@ -259,6 +268,7 @@ impl From<u8> for FrameType {
             0x14 => FrameType::StreamData,
             0x15 => FrameType::StreamMaxData,
             0x16 => FrameType::StreamDataBlocked,
+            0x17 => FrameType::StreamReceipt,
             _ => FrameType::Unknown,
         }
     }
@@ -324,12 +334,15 @@ impl SerializableFrame for ConnectionCloseFrame {
 
 #[derive(Debug, PartialEq, Clone)]
 pub struct ConnectionNewAddressFrame {
-    pub source_account: String,
+    pub source_account: Address,
 }
 
 impl SerializableFrame for ConnectionNewAddressFrame {
     fn read_contents(reader: &mut impl ReadOerExt) -> Result<Self, ParseError> {
         let source_account = String::from_utf8(reader.read_var_octet_string()?)?;
+        let source_account = Address::new(&source_account).map_err(|err| {
+            ParseError::InvalidPacket(format!("Invalid source account: {}", err))
+        })?;
 
         Ok(ConnectionNewAddressFrame { source_account })
     }
@@ -617,6 +630,27 @@ impl SerializableFrame for StreamDataBlockedFrame {
     }
 }
 
+#[derive(Debug, PartialEq, Clone)]
+pub struct StreamReceiptFrame {
+    pub stream_id: BigUint,
+    pub receipt: Bytes,
+}
+
+impl SerializableFrame for StreamReceiptFrame {
+    fn read_contents(reader: &mut impl ReadOerExt) -> Result<Self, ParseError> {
+        let stream_id = reader.read_var_uint()?;
+        let receipt = Bytes::from(reader.read_var_octet_string()?);
+
+        Ok(StreamReceiptFrame { stream_id, receipt })
+    }
+
+    fn write_contents(&self, writer: &mut impl WriteOerExt) -> Result<(), ParseError> {
+        writer.write_var_uint(&self.stream_id)?;
+        writer.write_var_octet_string(&self.receipt)?;
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod serialization {
     use super::*;
@@ -632,7 +666,7 @@ mod serialization {
                     message: String::from("oop")
                 }),
                 Frame::ConnectionNewAddress(ConnectionNewAddressFrame {
-                    source_account: String::from("example.blah")
+                    source_account: Address::new("example.blah").unwrap()
                 }),
                 Frame::ConnectionMaxData(ConnectionMaxDataFrame {
                     max_offset: BigUint::from(1000 as u64)
@@ -706,4 +740,23 @@ mod serialization {
             *PACKET
         );
     }
+
+    #[test]
+    fn it_roundtrips_receipt_frames() {
+        let packet = StreamPacket {
+            sequence: 2,
+            ilp_packet_type: IlpPacketType::from(13),
+            prepare_amount: 10,
+            frames: vec![Frame::StreamReceipt(StreamReceiptFrame {
+                stream_id: BigUint::from(1 as u64),
+                receipt: Bytes::from(&[9u8; 58][..]),
+            })],
+        };
+        let serialized = packet.to_bytes_unencrypted().unwrap();
+        assert_eq!(&serialized[8..12], &[0x17, 61, 1, 1]);
+        assert_eq!(
+            StreamPacket::from_bytes_unencrypted(&serialized[..]).unwrap(),
+            packet
+        );
+    }
 }
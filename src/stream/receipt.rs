@@ -0,0 +1,148 @@
+use super::crypto::hmac_sha256;
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use bytes::Bytes;
+use errors::ParseError;
+use num_bigint::BigUint;
+use num_traits::cast::ToPrimitive;
+use oer::{ReadOerExt, WriteOerExt};
+use ring::constant_time::verify_slices_are_equal;
+use ring::rand::{SecureRandom, SystemRandom};
+use std::io::{Cursor, Read};
+
+const RECEIPT_VERSION: u8 = 1;
+pub const RECEIPT_NONCE_LENGTH: usize = 16;
+const RECEIPT_HMAC_LENGTH: usize = 32;
+
+/// A signed proof of the total amount received on a stream, as defined in RFC 0039 (STREAM Receipts).
+///
+/// The receiver signs each receipt with a secret shared with a third-party verifier,
+/// so the verifier can confirm the payment without trusting the sender.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Receipt {
+    pub nonce: [u8; RECEIPT_NONCE_LENGTH],
+    pub stream_id: u64,
+    pub total_received: u64,
+    pub hmac: [u8; RECEIPT_HMAC_LENGTH],
+}
+
+impl Receipt {
+    pub fn new(
+        receipt_secret: &[u8],
+        nonce: [u8; RECEIPT_NONCE_LENGTH],
+        stream_id: u64,
+        total_received: u64,
+    ) -> Self {
+        let body = receipt_body(&nonce, stream_id, total_received);
+        let mut hmac = [0; RECEIPT_HMAC_LENGTH];
+        hmac.copy_from_slice(&hmac_sha256(receipt_secret, &body)[..]);
+        Receipt {
+            nonce,
+            stream_id,
+            total_received,
+            hmac,
+        }
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ParseError> {
+        let mut reader = Cursor::new(bytes);
+        let version = reader.read_u8()?;
+        if version != RECEIPT_VERSION {
+            return Err(ParseError::InvalidPacket(format!(
+                "Unsupported receipt version: {}",
+                version
+            )));
+        }
+        let mut nonce = [0; RECEIPT_NONCE_LENGTH];
+        reader.read_exact(&mut nonce)?;
+        let stream_id = reader.read_var_uint()?.to_u64().ok_or_else(|| {
+            ParseError::InvalidPacket(String::from("Receipt stream id too large"))
+        })?;
+        let total_received = reader.read_u64::<BigEndian>()?;
+        let mut hmac = [0; RECEIPT_HMAC_LENGTH];
+        reader.read_exact(&mut hmac)?;
+
+        if reader.position() as usize != bytes.len() {
+            return Err(ParseError::InvalidPacket(String::from(
+                "Receipt has extra bytes",
+            )));
+        }
+
+        Ok(Receipt {
+            nonce,
+            stream_id,
+            total_received,
+            hmac,
+        })
+    }
+
+    pub fn to_bytes(&self) -> Bytes {
+        let mut bytes = receipt_body(&self.nonce, self.stream_id, self.total_received);
+        bytes.extend_from_slice(&self.hmac[..]);
+        Bytes::from(bytes)
+    }
+
+    /// Check that the receipt was signed with the given receipt secret
+    pub fn verify(&self, receipt_secret: &[u8]) -> bool {
+        let body = receipt_body(&self.nonce, self.stream_id, self.total_received);
+        let expected = hmac_sha256(receipt_secret, &body);
+        verify_slices_are_equal(&expected[..], &self.hmac[..]).is_ok()
+    }
+}
+
+pub fn random_receipt_nonce() -> [u8; RECEIPT_NONCE_LENGTH] {
+    let mut nonce = [0; RECEIPT_NONCE_LENGTH];
+    SystemRandom::new().fill(&mut nonce).unwrap();
+    nonce
+}
+
+fn receipt_body(nonce: &[u8], stream_id: u64, total_received: u64) -> Vec<u8> {
+    let mut body = Vec::with_capacity(1 + RECEIPT_NONCE_LENGTH + 9 + 8 + RECEIPT_HMAC_LENGTH);
+    // Writing to a Vec cannot fail
+    body.write_u8(RECEIPT_VERSION).unwrap();
+    body.extend_from_slice(nonce);
+    body.write_var_uint(&BigUint::from(stream_id)).unwrap();
+    body.write_u64::<BigEndian>(total_received).unwrap();
+    body
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    lazy_static! {
+        static ref RECEIPT_SECRET: Vec<u8> = vec![7; 32];
+        static ref NONCE: [u8; 16] = [1; 16];
+    }
+
+    #[test]
+    fn roundtrip() {
+        let receipt = Receipt::new(&RECEIPT_SECRET[..], *NONCE, 1, 500);
+        let bytes = receipt.to_bytes();
+        assert_eq!(bytes.len(), 58);
+        assert_eq!(Receipt::from_bytes(&bytes[..]).unwrap(), receipt);
+    }
+
+    #[test]
+    fn verifies_with_secret() {
+        let receipt = Receipt::new(&RECEIPT_SECRET[..], *NONCE, 3, 1000);
+        assert!(receipt.verify(&RECEIPT_SECRET[..]));
+        assert!(!receipt.verify(&[8; 32][..]));
+    }
+
+    #[test]
+    fn detects_tampering() {
+        let mut receipt = Receipt::new(&RECEIPT_SECRET[..], *NONCE, 3, 1000);
+        receipt.total_received = 2000;
+        assert!(!receipt.verify(&RECEIPT_SECRET[..]));
+    }
+
+    #[test]
+    fn rejects_invalid_bytes() {
+        let mut bytes = Receipt::new(&RECEIPT_SECRET[..], *NONCE, 1, 500)
+            .to_bytes()
+            .to_vec();
+        bytes[0] = 2;
+        assert!(Receipt::from_bytes(&bytes[..]).is_err());
+        assert!(Receipt::from_bytes(&bytes[..20]).is_err());
+    }
+}
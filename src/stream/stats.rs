@@ -0,0 +1,83 @@
+use ilp::ErrorCode;
+use std::collections::HashMap;
+use std::time::Duration;
+
+/// A snapshot of what a Connection has sent and received so far
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ConnectionStats {
+    /// Prepare packets carrying STREAM frames, not counting exchange rate probes or the handshake
+    pub packets_sent: u64,
+    pub packets_fulfilled: u64,
+    pub packets_rejected: HashMap<ErrorCode, u64>,
+    /// Total amount of the fulfilled packets, in the source units
+    pub amount_sent: u64,
+    /// Total amount the receiver reported getting for the fulfilled packets, in the destination units
+    pub amount_delivered: u64,
+    pub amount_received: u64,
+    /// Amount of the outgoing packets that are still waiting for a response
+    pub amount_in_flight: u64,
+    /// The amount the congestion controller currently allows in flight, if it uses a window
+    pub congestion_window: Option<u64>,
+    /// The rate the fulfilled packets were delivered at, as destination units per source unit
+    pub exchange_rate: Option<f64>,
+    pub min_rtt: Option<Duration>,
+    pub smoothed_rtt: Option<Duration>,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+}
+
+impl ConnectionStats {
+    pub(super) fn record_reject(&mut self, code: ErrorCode) {
+        *self.packets_rejected.entry(code).or_insert(0) += 1;
+    }
+
+    pub(super) fn record_rtt(&mut self, rtt: Duration) {
+        if self.min_rtt.map(|min_rtt| rtt < min_rtt).unwrap_or(true) {
+            self.min_rtt = Some(rtt);
+        }
+        // Weight new samples by 1/8, like TCP does (RFC 6298)
+        self.smoothed_rtt = Some(match self.smoothed_rtt {
+            Some(smoothed_rtt) => (smoothed_rtt * 7 + rtt) / 8,
+            None => rtt,
+        });
+    }
+}
+
+/// A snapshot of the money and data a single stream has sent and received so far
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct StreamStats {
+    pub amount_sent: u64,
+    pub amount_delivered: u64,
+    pub amount_received: u64,
+    pub amount_in_flight: u64,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn smooths_rtt() {
+        let mut stats = ConnectionStats::default();
+        stats.record_rtt(Duration::from_millis(800));
+        assert_eq!(stats.smoothed_rtt, Some(Duration::from_millis(800)));
+        stats.record_rtt(Duration::from_millis(0));
+        assert_eq!(stats.smoothed_rtt, Some(Duration::from_millis(700)));
+        assert_eq!(stats.min_rtt, Some(Duration::from_millis(0)));
+    }
+
+    #[test]
+    fn counts_rejects_by_code() {
+        let mut stats = ConnectionStats::default();
+        stats.record_reject(ErrorCode::T04InsufficientLiquidity);
+        stats.record_reject(ErrorCode::T04InsufficientLiquidity);
+        stats.record_reject(ErrorCode::F99ApplicationError);
+        assert_eq!(
+            stats.packets_rejected[&ErrorCode::T04InsufficientLiquidity],
+            2
+        );
+        assert_eq!(stats.packets_rejected[&ErrorCode::F99ApplicationError], 1);
+    }
+}